@@ -0,0 +1,165 @@
+use std::{fmt, net::Ipv6Addr};
+
+use sha2::{Digest, Sha256};
+
+use crate::crypto::ed25519::PublicKey;
+
+/// Default prefix for addresses derived with [`AddressScheme::Sha256`].
+pub const DEFAULT_SHA256_PREFIX: u8 = 0x03;
+
+/// Wire identifier of [`AddressScheme::Yggdrasil`].
+const SCHEME_YGGDRASIL: u8 = 0;
+
+/// Wire identifier of [`AddressScheme::Sha256`].
+const SCHEME_SHA256: u8 = 1;
+
+/// Wire identifier of [`AddressScheme::YggdrasilWithPrefix`].
+const SCHEME_YGGDRASIL_WITH_PREFIX: u8 = 2;
+
+/// The way an overlay address is derived from a [`PublicKey`]. All nodes in the network must use
+/// the same scheme, as a node must be able to verify the address of a peer from its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressScheme {
+    /// The algorithm used by yggdrasil, see [`PublicKey::address`].
+    #[default]
+    Yggdrasil,
+    /// The algorithm used by yggdrasil, but with addresses starting with the configured prefix,
+    /// see [`PublicKey::address_with_prefix`]. This keeps the addresses of an isolated overlay
+    /// from colliding with yggdrasil addresses.
+    YggdrasilWithPrefix {
+        /// First byte of the address.
+        prefix: u8,
+    },
+    /// The first byte is the configured prefix, followed by the first 15 bytes of the SHA-256
+    /// hash of the public key.
+    Sha256 {
+        /// First byte of the address.
+        prefix: u8,
+    },
+}
+
+impl AddressScheme {
+    /// Derive the overlay address of the given [`PublicKey`] according to this scheme.
+    pub fn derive(&self, key: &PublicKey) -> Ipv6Addr {
+        match self {
+            AddressScheme::Yggdrasil => key.address(),
+            AddressScheme::YggdrasilWithPrefix { prefix } => key.address_with_prefix(&[*prefix]),
+            AddressScheme::Sha256 { prefix } => {
+                let hash = Sha256::digest(key.as_bytes());
+                let mut raw = [0; 16];
+                raw[0] = *prefix;
+                raw[1..].copy_from_slice(&hash[..15]);
+                Ipv6Addr::from(raw)
+            }
+        }
+    }
+
+    /// The network holding all addresses derived with this scheme, as network address and prefix
+    /// length.
+    pub fn overlay_prefix(&self) -> (Ipv6Addr, u8) {
+        match self {
+            // Addresses start with 0x02, and subnets with 0x03.
+            AddressScheme::Yggdrasil => (Ipv6Addr::new(0x0200, 0, 0, 0, 0, 0, 0, 0), 7),
+            AddressScheme::YggdrasilWithPrefix { prefix } | AddressScheme::Sha256 { prefix } => {
+                (Ipv6Addr::new((*prefix as u16) << 8, 0, 0, 0, 0, 0, 0, 0), 8)
+            }
+        }
+    }
+
+    /// Encode the scheme, including its parameters, for use on the wire.
+    pub fn to_wire(self) -> u32 {
+        match self {
+            AddressScheme::Yggdrasil => (SCHEME_YGGDRASIL as u32) << 24,
+            AddressScheme::YggdrasilWithPrefix { prefix } => {
+                (SCHEME_YGGDRASIL_WITH_PREFIX as u32) << 24 | prefix as u32
+            }
+            AddressScheme::Sha256 { prefix } => (SCHEME_SHA256 as u32) << 24 | prefix as u32,
+        }
+    }
+
+    /// Decode a scheme from its wire representation, returning [`None`] if the scheme is unknown.
+    pub fn from_wire(raw: u32) -> Option<Self> {
+        match (raw >> 24) as u8 {
+            SCHEME_YGGDRASIL => Some(AddressScheme::Yggdrasil),
+            SCHEME_SHA256 => Some(AddressScheme::Sha256 { prefix: raw as u8 }),
+            SCHEME_YGGDRASIL_WITH_PREFIX => {
+                Some(AddressScheme::YggdrasilWithPrefix { prefix: raw as u8 })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AddressScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressScheme::Yggdrasil => f.pad("yggdrasil"),
+            AddressScheme::YggdrasilWithPrefix { prefix } => {
+                write!(f, "yggdrasil (prefix {:#04x})", prefix)
+            }
+            AddressScheme::Sha256 { prefix } => write!(f, "sha256 (prefix {:#04x})", prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+
+    #[test]
+    fn schemes_are_deterministic() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let same_key = SecretKey::from_bytes([1; 32]).public_key();
+        let other_key = SecretKey::from_bytes([2; 32]).public_key();
+
+        for scheme in [
+            AddressScheme::Yggdrasil,
+            AddressScheme::Sha256 {
+                prefix: DEFAULT_SHA256_PREFIX,
+            },
+        ] {
+            assert_eq!(scheme.derive(&key), scheme.derive(&same_key));
+            assert_ne!(scheme.derive(&key), scheme.derive(&other_key));
+        }
+    }
+
+    #[test]
+    fn sha256_uses_configured_prefix() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let addr = AddressScheme::Sha256 { prefix: 0xfd }.derive(&key);
+        assert_eq!(addr.octets()[0], 0xfd);
+        assert_eq!(&addr.octets()[1..], &Sha256::digest(key.as_bytes())[..15]);
+    }
+
+    #[test]
+    fn overlay_prefix_contains_derived_addresses() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        for scheme in [
+            AddressScheme::Yggdrasil,
+            AddressScheme::YggdrasilWithPrefix { prefix: 0xfd },
+            AddressScheme::Sha256 { prefix: 0xfd },
+        ] {
+            let (network, prefix_len) = scheme.overlay_prefix();
+            let mask = u128::MAX << (128 - prefix_len);
+            assert_eq!(
+                u128::from(scheme.derive(&key)) & mask,
+                u128::from(network),
+                "{}",
+                scheme
+            );
+        }
+    }
+
+    #[test]
+    fn wire_roundtrip() {
+        for scheme in [
+            AddressScheme::Yggdrasil,
+            AddressScheme::YggdrasilWithPrefix { prefix: 0xfd },
+            AddressScheme::Sha256 { prefix: 0xfd },
+        ] {
+            assert_eq!(AddressScheme::from_wire(scheme.to_wire()), Some(scheme));
+        }
+        assert_eq!(AddressScheme::from_wire(0xff00_0000), None);
+    }
+}
@@ -0,0 +1,524 @@
+//! Local control socket to inspect and change a running node.
+//!
+//! The protocol is line based. A client sends a single command per line, consisting of a
+//! command name and its arguments separated by whitespace. Every command is answered with zero
+//! or more lines of output, followed by a status line which is either `ok`, or `error` followed
+//! by a description of what went wrong. Multiple commands can be sent on the same connection.
+//!
+//! The following commands are supported:
+//!
+//! - `peers`: list the connected peers, one per line, as
+//!   `<public key> <address> <remote address or -> <data or no-data> <health>
+//!   <observed address or -> <rtt or -> <control decode errors>`. The observed address is the
+//!   address an inbound peer connected from, which differs from the address it listens on if it
+//!   is behind a NAT. The rtt is `<last>/<min>/<mean>/<max>/<jitter>` over the recent pings, in
+//!   milliseconds. The control decode errors are the amount of control frames received on the
+//!   current connection to the peer which failed to decode.
+//! - `add-peer <address>`: keep a connection to the peer at the given address.
+//! - `persistent-peers`: list the addresses added with `add-peer` or on startup, one per line.
+//! - `remove-peer <public key or address>`: disconnect the peer with the given key, or stop
+//!   keeping a connection to the peer at the given address.
+//! - `ping <public key>`: ping a connected peer, and print the round trip time in milliseconds.
+//! - `routes`: list the reachable subnets, one per line, as `<subnet> <direct or next hop>`,
+//!   where the next hop is the public key of the peer the subnet is reached through.
+//! - `pin <address>`: never close the data connection to the subnet of the address for being
+//!   idle.
+//! - `unpin <address>`: allow closing the data connection to the subnet of the address again
+//!   once it is idle.
+//! - `pause`: stop accepting new connections. Existing connections keep working.
+//! - `resume`: accept new connections again.
+//! - `stats`: print traffic statistics, one `<name> <value>` pair per line.
+//! - `peer-stats`: print the traffic of every peer we had a data connection with, one per line,
+//!   as `<public key> <bytes tx> <bytes rx> <packets tx> <packets rx> <idle seconds or ->`.
+//! - `reset-stats`: reset the per-peer traffic counters and the queue high-water marks.
+//! - `reload-keys`: reload the allowed and denied public keys and the local keys from the config
+//!   file, and disconnect peers which are no longer allowed.
+//! - `rotate-identity`: replace the identity of the node with a newly generated one, and print
+//!   the new public key and address as `<public key> <address>`. Connected peers are told about
+//!   the new identity. The new secret key replaces the one in the key file, if the node has
+//!   one.
+use std::{
+    io,
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use crate::config::Config;
+use crate::core::Core;
+use crate::crypto::ed25519::{PublicKey, SecretKey};
+use crate::net::{PeerAddr, Subnet};
+use crate::routing::RouteKind;
+
+/// Longest command line which is accepted. Longer lines are rejected, and the connection is
+/// closed.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Time a peer gets to answer a ping sent with the `ping` command.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the control socket of `core` on the given listener. Every connection is handled in its
+/// own task. Settings are reloaded from the config file at `config`, if the node was started
+/// with one. A rotated identity is saved in `key_file`, if set. This only returns if the
+/// listener fails.
+pub async fn serve(
+    listener: UnixListener,
+    core: Arc<Core>,
+    config: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+) -> io::Result<()> {
+    loop {
+        let (con, _) = listener.accept().await?;
+        let core = core.clone();
+        let config = config.clone();
+        let key_file = key_file.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_connection(con, &core, config.as_deref(), key_file.as_deref()).await
+            {
+                debug!("Control socket connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer all commands sent on the connection, until the client closes it.
+async fn handle_connection(
+    con: UnixStream,
+    core: &Arc<Core>,
+    config: Option<&Path>,
+    key_file: Option<&Path>,
+) -> io::Result<()> {
+    let mut lines = Framed::new(con, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+    while let Some(line) = lines.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                lines
+                    .send("error command too long")
+                    .await
+                    .map_err(into_io)?;
+                return Ok(());
+            }
+            Err(LinesCodecError::Io(e)) => return Err(e),
+        };
+        let status = match execute(core, config, key_file, &line).await {
+            Ok(output) => {
+                for line in output {
+                    lines.send(line).await.map_err(into_io)?;
+                }
+                "ok".to_string()
+            }
+            Err(e) => format!("error {}", e),
+        };
+        lines.send(status).await.map_err(into_io)?;
+    }
+    Ok(())
+}
+
+/// Execute a single command line, returning its output lines, or a description of why the
+/// command failed.
+async fn execute(
+    core: &Arc<Core>,
+    config: Option<&Path>,
+    key_file: Option<&Path>,
+    line: &str,
+) -> Result<Vec<String>, String> {
+    let mut args = line.split_whitespace();
+    let command = args.next().ok_or("empty command")?;
+    let args: Vec<_> = args.collect();
+    match (command, &args[..]) {
+        ("peers", []) => Ok(core
+            .connected_peers()
+            .into_iter()
+            .map(|peer| {
+                format!(
+                    "{} {} {} {} {} {} {} {}",
+                    peer.key,
+                    peer.address,
+                    peer.remote.map_or("-".to_string(), |r| r.to_string()),
+                    if peer.data_connection {
+                        "data"
+                    } else {
+                        "no-data"
+                    },
+                    peer.health,
+                    peer.observed_addr
+                        .map_or("-".to_string(), |r| r.to_string()),
+                    peer.rtt.map_or("-".to_string(), |rtt| {
+                        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+                        format!(
+                            "{:.3}/{:.3}/{:.3}/{:.3}/{:.3}",
+                            ms(rtt.last),
+                            ms(rtt.min),
+                            ms(rtt.mean),
+                            ms(rtt.max),
+                            ms(rtt.jitter)
+                        )
+                    }),
+                    peer.control_decode_errors,
+                )
+            })
+            .collect()),
+        ("add-peer", [addr]) => {
+            let addr: PeerAddr = addr
+                .parse()
+                .map_err(|_| format!("invalid peer address {}", addr))?;
+            if !core.add_persistent_peer(addr) {
+                return Err("peer already added".to_string());
+            }
+            Ok(Vec::new())
+        }
+        ("persistent-peers", []) => Ok(core
+            .persistent_peers()
+            .into_iter()
+            .map(|addr| addr.to_string())
+            .collect()),
+        ("remove-peer", [peer]) => {
+            let removed = match peer.parse::<PeerAddr>() {
+                Ok(addr) => core.remove_persistent_peer(&addr),
+                Err(_) => {
+                    let key: PublicKey = peer
+                        .parse()
+                        .map_err(|e| format!("invalid public key: {}", e))?;
+                    core.remove_peer(&key).await
+                }
+            };
+            if !removed {
+                return Err("unknown peer".to_string());
+            }
+            Ok(Vec::new())
+        }
+        ("ping", [key]) => {
+            let key: PublicKey = key
+                .parse()
+                .map_err(|e| format!("invalid public key: {}", e))?;
+            let rtt = core
+                .ping(&key, PING_TIMEOUT)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(vec![format!("{:.3}", rtt.as_secs_f64() * 1000.0)])
+        }
+        ("routes", []) => Ok(core
+            .reachable_subnets()
+            .into_iter()
+            .map(|(subnet, kind)| match kind {
+                RouteKind::Direct => format!("{} direct", subnet),
+                RouteKind::Learned(next_hop) => format!("{} {}", subnet, next_hop),
+            })
+            .collect()),
+        ("pin", [addr]) => {
+            core.pin_subnet(parse_subnet(addr)?);
+            Ok(Vec::new())
+        }
+        ("unpin", [addr]) => {
+            core.unpin_subnet(&parse_subnet(addr)?);
+            Ok(Vec::new())
+        }
+        ("pause", []) => {
+            core.pause_accepting();
+            Ok(Vec::new())
+        }
+        ("resume", []) => {
+            core.resume_accepting();
+            Ok(Vec::new())
+        }
+        ("stats", []) => Ok(vec![
+            format!("control_peers {}", core.active_control_peers()),
+            format!("data_peers {}", core.active_data_peers()),
+            format!("bytes_tx {}", core.bytes_tx()),
+            format!("bytes_rx {}", core.bytes_rx()),
+            format!("dropped_packets {}", core.dropped_packets()),
+            format!("spoofed_packets {}", core.spoofed_packets()),
+            format!("replayed_packets {}", core.replayed_packets()),
+            format!("control_decode_errors {}", core.control_decode_errors()),
+            format!("connection_queue_peak {}", core.connection_queue_peak()),
+            format!(
+                "connection_queue_blocked {}",
+                core.connection_queue_blocked()
+            ),
+            format!(
+                "connection_queue_dropped {}",
+                core.connection_queue_dropped()
+            ),
+        ]),
+        ("peer-stats", []) => Ok(core
+            .peer_traffic_stats()
+            .into_iter()
+            .map(|(key, traffic)| {
+                format!(
+                    "{} {} {} {} {} {}",
+                    key,
+                    traffic.bytes_tx,
+                    traffic.bytes_rx,
+                    traffic.packets_tx,
+                    traffic.packets_rx,
+                    traffic
+                        .last_activity
+                        .map_or("-".to_string(), |t| t.elapsed().as_secs().to_string())
+                )
+            })
+            .collect()),
+        ("reset-stats", []) => {
+            core.reset_stats();
+            Ok(Vec::new())
+        }
+        ("reload-keys", []) => {
+            let path = config.ok_or("no config file to reload")?;
+            let config = Config::load(path).map_err(|e| e.to_string())?;
+            core.set_key_filter(config.key_filter()).await;
+            for key in core.local_keys() {
+                if !config.local_keys.contains(&key) {
+                    core.remove_local_key(&key);
+                }
+            }
+            for key in config.local_keys {
+                core.add_local_key(key);
+            }
+            Ok(Vec::new())
+        }
+        ("rotate-identity", []) => {
+            let secret_key = SecretKey::generate();
+            // Save the key first, so a restart doesn't bring back the old identity.
+            if let Some(path) = key_file {
+                secret_key
+                    .save_to_file(path)
+                    .map_err(|e| format!("failed to save key file: {}", e))?;
+            }
+            core.rotate_identity(secret_key);
+            Ok(vec![format!("{} {}", core.public_key(), core.address())])
+        }
+        (
+            "peers" | "add-peer" | "persistent-peers" | "remove-peer" | "ping" | "routes" | "pin"
+            | "unpin" | "pause" | "resume" | "stats" | "peer-stats" | "reset-stats" | "reload-keys"
+            | "rotate-identity",
+            _,
+        ) => Err(format!("wrong number of arguments for {}", command)),
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
+
+/// Parse an overlay address given as argument into the subnet it is in.
+fn parse_subnet(addr: &str) -> Result<Subnet, String> {
+    let addr: Ipv6Addr = addr
+        .parse()
+        .map_err(|_| format!("invalid address {}", addr))?;
+    Ok(Subnet::from_address(addr))
+}
+
+/// Convert an error of the [`LinesCodec`] into an [`io::Error`].
+fn into_io(e: LinesCodecError) -> io::Error {
+    match e {
+        LinesCodecError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// Send a command over the connection, and collect the response up to and including the
+    /// status line.
+    async fn command(con: &mut BufReader<UnixStream>, command: &str) -> Vec<String> {
+        con.get_mut()
+            .write_all(format!("{}\n", command).as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        loop {
+            let mut line = String::new();
+            con.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_string();
+            let done = line == "ok" || line.starts_with("error");
+            response.push(line);
+            if done {
+                return response;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_commands() {
+        let core = Core::new(
+            crate::crypto::ed25519::SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let dir = std::env::temp_dir().join(format!("styx-admin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let _ = std::fs::remove_file(&path);
+        tokio::spawn(serve(
+            UnixListener::bind(&path).unwrap(),
+            core.clone(),
+            None,
+            None,
+        ));
+        let mut con = BufReader::new(UnixStream::connect(&path).await.unwrap());
+
+        assert_eq!(command(&mut con, "peers").await, ["ok"]);
+        let stats = command(&mut con, "stats").await;
+        assert_eq!(stats.len(), 12);
+        assert_eq!(stats[0], "control_peers 0");
+        assert_eq!(stats[11], "ok");
+        assert_eq!(command(&mut con, "routes").await, ["ok"]);
+        assert_eq!(command(&mut con, "pause").await, ["ok"]);
+        assert!(!core.is_accepting());
+        assert_eq!(command(&mut con, "resume").await, ["ok"]);
+        assert!(core.is_accepting());
+        assert_eq!(command(&mut con, "pin 200:1234::1").await, ["ok"]);
+        assert_eq!(command(&mut con, "unpin 200:1234::1").await, ["ok"]);
+        assert_eq!(command(&mut con, "peer-stats").await, ["ok"]);
+        assert_eq!(command(&mut con, "reset-stats").await, ["ok"]);
+        assert_eq!(
+            command(&mut con, "reload-keys").await,
+            ["error no config file to reload"]
+        );
+
+        assert_eq!(command(&mut con, "add-peer 127.0.0.1:1").await, ["ok"]);
+        assert_eq!(
+            command(&mut con, "add-peer 127.0.0.1:1").await,
+            ["error peer already added"]
+        );
+        assert_eq!(
+            command(&mut con, "persistent-peers").await,
+            ["127.0.0.1:1", "ok"]
+        );
+        assert_eq!(command(&mut con, "remove-peer 127.0.0.1:1").await, ["ok"]);
+        assert_eq!(
+            command(&mut con, "remove-peer 127.0.0.1:1").await,
+            ["error unknown peer"]
+        );
+        let key = crate::crypto::ed25519::SecretKey::from_bytes([2; 32]).public_key();
+        assert_eq!(
+            command(&mut con, &format!("remove-peer {}", key)).await,
+            ["error unknown peer"]
+        );
+        assert_eq!(
+            command(&mut con, &format!("ping {}", key)).await,
+            ["error not connected to peer"]
+        );
+
+        // Malformed input is rejected, and the connection stays usable.
+        assert_eq!(
+            command(&mut con, "add-peer nonsense").await,
+            ["error invalid peer address nonsense"]
+        );
+        assert_eq!(
+            command(&mut con, "remove-peer 1234").await,
+            ["error invalid public key: invalid key length, expected 32 bytes but got 2"]
+        );
+        assert_eq!(
+            command(&mut con, "pin nonsense").await,
+            ["error invalid address nonsense"]
+        );
+        assert_eq!(
+            command(&mut con, "peers now").await,
+            ["error wrong number of arguments for peers"]
+        );
+        assert_eq!(command(&mut con, "").await, ["error empty command"]);
+        assert_eq!(
+            command(&mut con, "reboot").await,
+            ["error unknown command reboot"]
+        );
+        assert_eq!(
+            command(&mut con, &"a".repeat(MAX_LINE_LENGTH + 1)).await,
+            ["error command too long"]
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_keys_from_config() {
+        let core = Core::new(
+            crate::crypto::ed25519::SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let dir = std::env::temp_dir().join(format!("styx-admin-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let config = dir.join("styx.toml");
+        let _ = std::fs::remove_file(&path);
+        tokio::spawn(serve(
+            UnixListener::bind(&path).unwrap(),
+            core.clone(),
+            Some(config.clone()),
+            None,
+        ));
+        let mut con = BufReader::new(UnixStream::connect(&path).await.unwrap());
+
+        let key = crate::crypto::ed25519::SecretKey::from_bytes([2; 32]).public_key();
+        let local = crate::crypto::ed25519::SecretKey::from_bytes([3; 32]).public_key();
+        std::fs::write(
+            &config,
+            format!("denied_keys = [\"{}\"]\nlocal_keys = [\"{}\"]", key, local),
+        )
+        .unwrap();
+        assert_eq!(command(&mut con, "reload-keys").await, ["ok"]);
+        assert_eq!(core.local_keys(), [local]);
+        // A broken config file leaves the current lists in place.
+        std::fs::write(&config, "denied_keys = 1").unwrap();
+        assert_eq!(
+            command(&mut con, "reload-keys").await,
+            ["error invalid config on line 1: denied_keys must be an array of strings"]
+        );
+        std::fs::write(&config, "").unwrap();
+        assert_eq!(command(&mut con, "reload-keys").await, ["ok"]);
+        assert!(core.local_keys().is_empty());
+
+        core.shutdown(Duration::from_millis(10)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_identity() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let dir = std::env::temp_dir().join(format!("styx-admin-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let key_file = dir.join("styx.key");
+        let _ = std::fs::remove_file(&path);
+        tokio::spawn(serve(
+            UnixListener::bind(&path).unwrap(),
+            core.clone(),
+            None,
+            Some(key_file.clone()),
+        ));
+        let mut con = BufReader::new(UnixStream::connect(&path).await.unwrap());
+
+        let old = core.public_key();
+        let response = command(&mut con, "rotate-identity").await;
+        assert_eq!(
+            response,
+            [
+                format!("{} {}", core.public_key(), core.address()),
+                "ok".to_string()
+            ]
+        );
+        assert_ne!(core.public_key(), old);
+        // The new identity is used after a restart.
+        assert_eq!(
+            SecretKey::load_from_file(&key_file).unwrap().public_key(),
+            core.public_key()
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponentially growing delay between attempts of an operation which keeps failing, e.g.
+/// reconnecting to a peer.
+pub struct Backoff {
+    /// Delay before the first retry.
+    initial: Duration,
+    /// Upper bound of the delay.
+    max: Duration,
+    /// Delay before the next retry, without jitter.
+    current: Duration,
+}
+
+impl Backoff {
+    /// Create a new [`Backoff`], starting at `initial` and doubling on every attempt up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Get the delay to wait before the next attempt, and grow the delay for the attempt after
+    /// that. A random jitter of up to half of the delay is subtracted, so nodes which lost a
+    /// connection at the same time don't retry in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let jitter = rand::thread_rng().gen_range(0, delay.as_millis() as u64 / 2 + 1);
+        delay - Duration::from_millis(jitter)
+    }
+
+    /// Start over from the initial delay, e.g. once the operation succeeded.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_max_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        for expected in [1, 2, 4, 5, 5] {
+            let delay = backoff.next_delay();
+            let expected = Duration::from_secs(expected);
+            assert!(delay <= expected && delay >= expected / 2, "{:?}", delay);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
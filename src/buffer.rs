@@ -0,0 +1,102 @@
+use bytes::{Bytes, BytesMut};
+
+/// Default amount of packets which fit in a single allocation of a [`PacketBuffer`].
+pub const DEFAULT_PACKETS_PER_CHUNK: usize = 64;
+
+/// A buffer to read packets into, which hands out every packet as [`Bytes`] without copying it.
+///
+/// Packets are carved out of a large chunk, so a single allocation is shared by many packets.
+/// Once all packets of a chunk are dropped, e.g. because they are sent to a peer, the chunk is
+/// reused for the next packets, so in steady state no allocations happen at all. If packets are
+/// kept around for longer, a fresh chunk is allocated instead.
+pub struct PacketBuffer {
+    /// The remainder of the current chunk.
+    buf: BytesMut,
+    /// Maximum size of a single packet.
+    packet_size: usize,
+    /// Size of a chunk.
+    chunk_size: usize,
+}
+
+impl PacketBuffer {
+    /// Create a new [`PacketBuffer`] for packets of at most `packet_size` bytes, with chunks of
+    /// [`DEFAULT_PACKETS_PER_CHUNK`] packets.
+    pub fn new(packet_size: usize) -> Self {
+        Self::with_chunk_size(packet_size, DEFAULT_PACKETS_PER_CHUNK)
+    }
+
+    /// Create a new [`PacketBuffer`] for packets of at most `packet_size` bytes, with chunks of
+    /// `packets_per_chunk` packets.
+    pub fn with_chunk_size(packet_size: usize, packets_per_chunk: usize) -> Self {
+        let chunk_size = packet_size * packets_per_chunk.max(1);
+        Self {
+            buf: BytesMut::with_capacity(chunk_size),
+            packet_size,
+            chunk_size,
+        }
+    }
+
+    /// Get a slot to read the next packet into, which is large enough for a packet of the
+    /// maximum size. Once the packet is read, it must be taken with [`PacketBuffer::take`].
+    pub fn slot(&mut self) -> &mut [u8] {
+        if self.buf.capacity() < self.packet_size {
+            // This reclaims the chunk if all packets taken from it are dropped, and allocates a
+            // new chunk otherwise.
+            self.buf.reserve(self.chunk_size);
+        }
+        self.buf.resize(self.packet_size, 0);
+        &mut self.buf
+    }
+
+    /// Take the first `len` bytes of the slot as a packet.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `len` is larger than the size of a packet.
+    pub fn take(&mut self, len: usize) -> Bytes {
+        assert!(len <= self.packet_size, "packet larger than slot");
+        self.buf.truncate(len);
+        self.buf.split().freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_packets() {
+        let mut buffer = PacketBuffer::with_chunk_size(8, 2);
+        let mut packets = Vec::new();
+        for i in 0..5u8 {
+            buffer.slot()[..3].copy_from_slice(&[i; 3]);
+            packets.push(buffer.take(3));
+        }
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet[..], [i as u8; 3]);
+        }
+    }
+
+    #[test]
+    fn reuses_chunks_once_packets_are_dropped() {
+        let mut buffer = PacketBuffer::with_chunk_size(8, 4);
+        let first = buffer.slot().as_ptr();
+        for _ in 0..100 {
+            buffer.slot();
+            // Dropped right away, like a packet which is sent immediately.
+            drop(buffer.take(8));
+        }
+        // All packets came from the first chunk.
+        assert_eq!(buffer.slot().as_ptr(), first);
+
+        // Packets which are kept around force a new chunk.
+        let kept: Vec<_> = (0..4)
+            .map(|_| {
+                buffer.slot();
+                buffer.take(8)
+            })
+            .collect();
+        assert_ne!(buffer.slot().as_ptr(), first);
+        drop(kept);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use tokio::net::TcpListener;
+
+use crate::config::Config;
+use crate::crypto::ed25519::SecretKey;
+use crate::tun::Tun;
+
+/// Outcome of a single check of [`run`].
+pub struct Check {
+    /// What was checked.
+    pub name: &'static str,
+    /// A description of what was found if the check passed, or of the problem if it failed.
+    pub result: Result<String, String>,
+}
+
+impl Check {
+    /// Whether the check passed.
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.result {
+            Ok(ref detail) => write!(f, "ok   {}: {}", self.name, detail),
+            Err(ref problem) => write!(f, "FAIL {}: {}", self.name, problem),
+        }
+    }
+}
+
+/// Check if a node could be started with the given config, without starting it. Every resource
+/// the node needs is acquired and released again right away, so this catches missing
+/// permissions and addresses which are already in use. Nothing is changed on the system, in
+/// particular a missing key file is not generated.
+pub async fn run(
+    config: &Config,
+    metrics_addr: Option<SocketAddr>,
+    control_socket: Option<&Path>,
+) -> Vec<Check> {
+    let mut checks = vec![Check {
+        name: "key file",
+        result: check_key_file(&config.key_file),
+    }];
+    if config.listen_addrs.is_empty() {
+        checks.push(Check {
+            name: "listen address",
+            result: Err("not set on the command line or in the config file".to_string()),
+        });
+    }
+    // Every address is checked on its own, as the node starts as long as one can be bound.
+    for &addr in &config.listen_addrs {
+        checks.push(Check {
+            name: "listen address",
+            result: check_bind(addr).await,
+        });
+    }
+    checks.push(Check {
+        name: "peers",
+        result: Ok(format!("{} configured", config.peers.len())),
+    });
+    checks.push(Check {
+        name: "interface",
+        result: Tun::create(&config.interface_name, config.mtu)
+            .map(|_| format!("{} can be created", config.interface_name))
+            .map_err(|e| format!("can't create {}: {}", config.interface_name, e)),
+    });
+    if let Some(addr) = metrics_addr {
+        checks.push(Check {
+            name: "metrics address",
+            result: check_bind(addr).await,
+        });
+    }
+    if let Some(path) = control_socket {
+        checks.push(Check {
+            name: "control socket",
+            result: match path.try_exists() {
+                Ok(false) => Ok(format!("{} is available", path.display())),
+                Ok(true) => Err(format!("{} already exists", path.display())),
+                Err(e) => Err(format!("can't access {}: {}", path.display(), e)),
+            },
+        });
+    }
+    checks
+}
+
+/// Check that the key file holds a valid key, or that it can be generated.
+fn check_key_file(path: &Path) -> Result<String, String> {
+    match path.try_exists() {
+        Ok(true) => SecretKey::load_from_file(path)
+            .map(|key| format!("{} holds key {}", path.display(), key.public_key()))
+            .map_err(|e| format!("can't load {}: {}", path.display(), e)),
+        Ok(false) => {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            if dir.is_dir() {
+                Ok(format!(
+                    "{} does not exist, a new key will be generated",
+                    path.display()
+                ))
+            } else {
+                Err(format!(
+                    "{} does not exist, and directory {} to generate it in is missing",
+                    path.display(),
+                    dir.display()
+                ))
+            }
+        }
+        Err(e) => Err(format!("can't access {}: {}", path.display(), e)),
+    }
+}
+
+/// Check that a TCP listener can be bound on the address.
+async fn check_bind(addr: SocketAddr) -> Result<String, String> {
+    TcpListener::bind(addr)
+        .await
+        .map(|_| format!("{} can be bound", addr))
+        .map_err(|e| format!("can't bind {}: {}", addr, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_addresses_in_use_and_missing_keys() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            listen_addrs: vec![taken.local_addr().unwrap()],
+            key_file: PathBuf::from("/nonexistent/styx.key"),
+            ..Config::default()
+        };
+        let checks = run(&config, Some("127.0.0.1:0".parse().unwrap()), None).await;
+        let result = |name| {
+            checks
+                .iter()
+                .find(|check| check.name == name)
+                .unwrap()
+                .passed()
+        };
+        assert!(!result("key file"));
+        assert!(!result("listen address"));
+        assert!(result("peers"));
+        assert!(result("metrics address"));
+        assert!(checks
+            .iter()
+            .find(|check| check.name == "listen address")
+            .unwrap()
+            .to_string()
+            .starts_with("FAIL listen address: can't bind"));
+    }
+}
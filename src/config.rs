@@ -0,0 +1,573 @@
+use std::{
+    fmt, io,
+    iter::Peekable,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::Chars,
+};
+
+use crate::{
+    crypto::ed25519::PublicKey,
+    data,
+    net::{self, PeerAddr},
+    peer::KeyFilter,
+    transport::TransportKind,
+    tun,
+};
+
+/// Default name of the interface.
+pub const DEFAULT_INTERFACE_NAME: &str = "styx";
+
+/// Default path of the file holding the secret key.
+pub const DEFAULT_KEY_FILE: &str = "styx.key";
+
+/// Settings of a node, which can be loaded from a config file.
+///
+/// Config files use a subset of TOML: top level `key = value` pairs, where values are strings,
+/// integers, booleans, or arrays of those. Tables are not supported. For example:
+///
+/// ```toml
+/// listen_address = ["0.0.0.0:9651", "[2001:db8::1]:9651"]
+/// peers = ["192.0.2.1:9651", "peer.example.com:9651"]
+/// interface_name = "styx"
+/// mtu = 1420
+/// jumbo_mtu = 262_144
+/// key_file = "/var/lib/styx/styx.key"
+/// rate_limit = 12_500_000
+/// tcp_nodelay = true
+/// recv_buffer_size = 4_194_304
+/// data_transport = "udp"
+/// compression = true
+/// relay_only = false
+/// denied_keys = ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The local IPs and ports to listen on for incoming connections.
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Peers to keep a connection to.
+    pub peers: Vec<PeerAddr>,
+    /// Name of the interface.
+    pub interface_name: String,
+    /// MTU of the interface.
+    pub mtu: i32,
+    /// MTU of the overlay with jumbo packets enabled, which can exceed the largest MTU of the
+    /// interface. Replaces `mtu` if set. Jumbo packets are only exchanged with peers which
+    /// support them as well.
+    pub jumbo_mtu: Option<usize>,
+    /// File holding the secret key of the node.
+    pub key_file: PathBuf,
+    /// Maximum amount of packet bytes per second accepted from a single peer, unlimited if not
+    /// set.
+    pub rate_limit: Option<u64>,
+    /// Set `TCP_NODELAY` on data connections. Control connections always have it set.
+    pub tcp_nodelay: bool,
+    /// Size of the send buffer of data connections, sized by the kernel if not set.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the receive buffer of data connections, sized by the kernel if not set.
+    pub recv_buffer_size: Option<usize>,
+    /// Transport of data connections, either "tcp" or "udp".
+    pub data_transport: TransportKind,
+    /// Compress packets on data connections, if the peer supports it.
+    pub compression: bool,
+    /// Only relay packets between peers, without creating an interface.
+    pub relay_only: bool,
+    /// If set, only peers with one of these public keys can connect.
+    pub allowed_keys: Option<Vec<PublicKey>>,
+    /// Public keys of peers which can't connect.
+    pub denied_keys: Vec<PublicKey>,
+    /// Public keys of additional identities hosted by this node, packets for which are written
+    /// to the interface rather than relayed.
+    pub local_keys: Vec<PublicKey>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            peers: Vec::new(),
+            interface_name: DEFAULT_INTERFACE_NAME.to_string(),
+            mtu: tun::DEFAULT_MTU,
+            jumbo_mtu: None,
+            key_file: PathBuf::from(DEFAULT_KEY_FILE),
+            rate_limit: None,
+            tcp_nodelay: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            data_transport: TransportKind::Tcp,
+            compression: false,
+            relay_only: false,
+            allowed_keys: None,
+            denied_keys: Vec::new(),
+            local_keys: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load a [`Config`] from the file at the given path. Settings which are not present in the
+    /// file keep their default value.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path).map_err(Error::Io)?)
+    }
+
+    /// Parse a [`Config`] from the contents of a config file. Settings which are not present
+    /// keep their default value.
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let mut config = Config::default();
+        let mut parser = Parser::new(input);
+        let mut seen = Vec::new();
+        while let Some((line, key, value)) = parser.next_entry()? {
+            if seen.contains(&key) {
+                return Err(Error::Parse {
+                    line,
+                    msg: format!("duplicate key {}", key),
+                });
+            }
+            config
+                .set(&key, value)
+                .map_err(|msg| Error::Parse { line, msg })?;
+            seen.push(key);
+        }
+        Ok(config)
+    }
+
+    /// The filter of peers which can connect, built from the allowed and denied keys.
+    pub fn key_filter(&self) -> KeyFilter {
+        KeyFilter {
+            allowlist: self
+                .allowed_keys
+                .as_ref()
+                .map(|keys| keys.iter().cloned().collect()),
+            denylist: self.denied_keys.iter().cloned().collect(),
+        }
+    }
+
+    /// Set the setting with the given key.
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "listen_address" => {
+                // A single address, or an array of them.
+                let values = match value {
+                    Value::Array(values) => values,
+                    value => vec![value],
+                };
+                self.listen_addrs = values
+                    .into_iter()
+                    .map(|value| {
+                        let addr = value.into_string(key)?;
+                        net::parse_socket_addr(&addr)
+                            .map_err(|e| format!("invalid listen_address {}: {}", addr, e))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "peers" => {
+                self.peers = match value {
+                    Value::Array(values) => values
+                        .into_iter()
+                        .map(|value| {
+                            let peer = value.into_string(key)?;
+                            peer.parse()
+                                .map_err(|_| format!("invalid peer address {}", peer))
+                        })
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err("peers must be an array of strings".to_string()),
+                };
+            }
+            "interface_name" => self.interface_name = value.into_string(key)?,
+            "mtu" => {
+                let mtu = match value {
+                    Value::Integer(mtu) => mtu,
+                    _ => return Err("mtu must be an integer".to_string()),
+                };
+                if !(tun::MIN_MTU as i64..=tun::MAX_MTU as i64).contains(&mtu) {
+                    return Err(format!(
+                        "mtu must be between {} and {}",
+                        tun::MIN_MTU,
+                        tun::MAX_MTU
+                    ));
+                }
+                self.mtu = mtu as i32;
+            }
+            "jumbo_mtu" => {
+                let mtu = match value {
+                    Value::Integer(mtu) => mtu,
+                    _ => return Err("jumbo_mtu must be an integer".to_string()),
+                };
+                if !(tun::MIN_MTU as i64..=data::MAX_JUMBO_MTU as i64).contains(&mtu) {
+                    return Err(format!(
+                        "jumbo_mtu must be between {} and {}",
+                        tun::MIN_MTU,
+                        data::MAX_JUMBO_MTU
+                    ));
+                }
+                self.jumbo_mtu = Some(mtu as usize);
+            }
+            "key_file" => self.key_file = PathBuf::from(value.into_string(key)?),
+            "rate_limit" => match value {
+                Value::Integer(limit) if limit > 0 => self.rate_limit = Some(limit as u64),
+                _ => return Err("rate_limit must be a positive integer".to_string()),
+            },
+            "tcp_nodelay" => match value {
+                Value::Boolean(nodelay) => self.tcp_nodelay = nodelay,
+                _ => return Err("tcp_nodelay must be a boolean".to_string()),
+            },
+            "compression" => match value {
+                Value::Boolean(compression) => self.compression = compression,
+                _ => return Err("compression must be a boolean".to_string()),
+            },
+            "relay_only" => match value {
+                Value::Boolean(relay_only) => self.relay_only = relay_only,
+                _ => return Err("relay_only must be a boolean".to_string()),
+            },
+            "send_buffer_size" => self.send_buffer_size = Some(value.into_size(key)?),
+            "recv_buffer_size" => self.recv_buffer_size = Some(value.into_size(key)?),
+            "data_transport" => {
+                self.data_transport = match value.into_string(key)?.as_str() {
+                    "tcp" => TransportKind::Tcp,
+                    "udp" => TransportKind::Udp,
+                    other => return Err(format!("unknown data_transport {}", other)),
+                }
+            }
+            "allowed_keys" => self.allowed_keys = Some(value.into_keys(key)?),
+            "denied_keys" => self.denied_keys = value.into_keys(key)?,
+            "local_keys" => self.local_keys = value.into_keys(key)?,
+            _ => return Err(format!("unknown key {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Errors which can occur while loading a [`Config`].
+#[derive(Debug)]
+pub enum Error {
+    /// The config file could not be read.
+    Io(io::Error),
+    /// The config file is malformed, or holds an invalid setting.
+    Parse {
+        /// Line on which the error was found, starting at 1.
+        line: usize,
+        /// Description of the error.
+        msg: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "could not read config file: {}", e),
+            Error::Parse { line, msg } => write!(f, "invalid config on line {}: {}", line, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse { .. } => None,
+        }
+    }
+}
+
+/// A value in a config file.
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Get the value as a string, or an error mentioning the key if it is not a string.
+    fn into_string(self, key: &str) -> Result<String, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(format!("{} must be a string", key)),
+        }
+    }
+
+    /// Get the value as a list of public keys, or an error mentioning the key if it is not an
+    /// array of hex encoded public keys.
+    fn into_keys(self, key: &str) -> Result<Vec<PublicKey>, String> {
+        match self {
+            Value::Array(values) => values
+                .into_iter()
+                .map(|value| {
+                    let public_key = value.into_string(key)?;
+                    public_key
+                        .parse()
+                        .map_err(|e| format!("invalid public key {}: {}", public_key, e))
+                })
+                .collect(),
+            _ => Err(format!("{} must be an array of strings", key)),
+        }
+    }
+
+    /// Get the value as a positive size, or an error mentioning the key if it is not one.
+    fn into_size(self, key: &str) -> Result<usize, String> {
+        match self {
+            Value::Integer(size) if size > 0 => Ok(size as usize),
+            _ => Err(format!("{} must be a positive integer", key)),
+        }
+    }
+}
+
+/// Parser for the subset of TOML supported in config files.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Current line, starting at 1.
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    /// Parse the next `key = value` pair, returning the line it starts on. Returns [`None`] at
+    /// the end of the input.
+    fn next_entry(&mut self) -> Result<Option<(usize, String, Value)>, Error> {
+        self.skip_whitespace(true);
+        let line = self.line;
+        match self.chars.peek() {
+            None => return Ok(None),
+            Some('[') => return Err(self.error("tables are not supported")),
+            Some(_) => (),
+        }
+
+        let mut key = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        {
+            key.push(c);
+        }
+        if key.is_empty() {
+            return Err(self.error("expected a key"));
+        }
+        self.skip_whitespace(false);
+        if self.chars.next_if_eq(&'=').is_none() {
+            return Err(self.error("expected = after key"));
+        }
+        self.skip_whitespace(false);
+        let value = self.value()?;
+        self.skip_whitespace(false);
+        match self.chars.peek() {
+            None | Some('\n') => Ok(Some((line, key, value))),
+            Some(_) => Err(self.error("expected a new line after value")),
+        }
+    }
+
+    /// Parse a single value.
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.chars.peek() {
+            Some('"') => self.string().map(Value::String),
+            Some('[') => self.array().map(Value::Array),
+            Some(c) if c.is_ascii_digit() || *c == '-' || *c == '+' => self.integer(),
+            Some('t' | 'f') => self.boolean(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parse a basic string.
+    fn string(&mut self) -> Result<String, Error> {
+        // Opening quote.
+        self.chars.next();
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    _ => return Err(self.error("unsupported escape sequence in string")),
+                },
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// Parse a decimal integer.
+    fn integer(&mut self) -> Result<Value, Error> {
+        let mut raw = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '_'))
+        {
+            if c != '_' {
+                raw.push(c);
+            }
+        }
+        raw.parse()
+            .map(Value::Integer)
+            .map_err(|_| self.error("invalid integer"))
+    }
+
+    /// Parse a boolean.
+    fn boolean(&mut self) -> Result<Value, Error> {
+        let mut raw = String::new();
+        while let Some(c) = self.chars.next_if(char::is_ascii_lowercase) {
+            raw.push(c);
+        }
+        match raw.as_str() {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => Err(self.error("invalid boolean")),
+        }
+    }
+
+    /// Parse an array, which can span multiple lines.
+    fn array(&mut self) -> Result<Vec<Value>, Error> {
+        // Opening bracket.
+        self.chars.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace(true);
+            if self.chars.next_if_eq(&']').is_some() {
+                return Ok(values);
+            }
+            values.push(self.value()?);
+            self.skip_whitespace(true);
+            match self.chars.next() {
+                Some(',') => (),
+                Some(']') => return Ok(values),
+                _ => return Err(self.error("expected , or ] in array")),
+            }
+        }
+    }
+
+    /// Skip spaces, tabs and comments, and new lines if `newlines` is set.
+    fn skip_whitespace(&mut self, newlines: bool) {
+        while let Some(c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => (),
+                '\n' if newlines => self.line += 1,
+                '#' => {
+                    while self.chars.next_if(|c| *c != '\n').is_some() {}
+                    continue;
+                }
+                _ => return,
+            }
+            self.chars.next();
+        }
+    }
+
+    /// Create a parse error on the current line.
+    fn error(&self, msg: &str) -> Error {
+        Error::Parse {
+            line: self.line,
+            msg: msg.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_file() {
+        let config = Config::parse(
+            r#"
+            # Node settings.
+            listen_address = "[::]:9651" # All addresses.
+            peers = [
+                "192.0.2.1:9651",
+                "peer.example.com:9651", # Trailing comma is allowed.
+            ]
+            mtu = 9_000
+            jumbo_mtu = 262_144
+            key_file = "/var/lib/styx/styx.key"
+            rate_limit = 1_000_000
+            tcp_nodelay = true
+            send_buffer_size = 65536
+            data_transport = "udp"
+            compression = true
+            relay_only = true
+            denied_keys = ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+            local_keys = ["60303ae22b998861bce3b28f33eec1be758a213c86c93c076dbe9f558c11c752"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                listen_addrs: vec!["[::]:9651".parse().unwrap()],
+                peers: vec![
+                    "192.0.2.1:9651".parse().unwrap(),
+                    "peer.example.com:9651".parse().unwrap()
+                ],
+                interface_name: DEFAULT_INTERFACE_NAME.to_string(),
+                mtu: 9000,
+                jumbo_mtu: Some(262_144),
+                key_file: PathBuf::from("/var/lib/styx/styx.key"),
+                rate_limit: Some(1_000_000),
+                tcp_nodelay: true,
+                send_buffer_size: Some(65536),
+                recv_buffer_size: None,
+                data_transport: TransportKind::Udp,
+                compression: true,
+                relay_only: true,
+                allowed_keys: None,
+                denied_keys: vec![
+                    "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+                        .parse()
+                        .unwrap()
+                ],
+                local_keys: vec![
+                    "60303ae22b998861bce3b28f33eec1be758a213c86c93c076dbe9f558c11c752"
+                        .parse()
+                        .unwrap()
+                ],
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config =
+            Config::parse(r#"listen_address = ["0.0.0.0:9651", "[2001:db8::1]:9651"]"#).unwrap();
+        assert_eq!(
+            config.listen_addrs,
+            [
+                "0.0.0.0:9651".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:9651".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_line_of_invalid_setting() {
+        for (input, line) in [
+            ("mtu = 1420\nmtu = 1500", 2),
+            ("\n\nmtu = 100", 3),
+            ("jumbo_mtu = 2_000_000", 1),
+            ("interface_name = 3", 1),
+            ("listen_address = \"nope\"", 1),
+            ("listen_address = [\"[::]:1\", 2]", 1),
+            ("peers = [\"a:1\",\n\"b:2\" \"c:3\"]", 2),
+            ("peers = [\"no-port\"]", 1),
+            ("unknown = 1", 1),
+            ("rate_limit = 0", 1),
+            ("tcp_nodelay = yes", 1),
+            ("tcp_nodelay = 1", 1),
+            ("recv_buffer_size = -1", 1),
+            ("data_transport = \"quic\"", 1),
+            ("allowed_keys = [\"abcd\"]", 1),
+            ("denied_keys = \"abcd\"", 1),
+            ("[table]", 1),
+            ("key_file = \"unterminated", 1),
+        ] {
+            match Config::parse(input) {
+                Err(Error::Parse { line: got, .. }) => assert_eq!(got, line, "{}", input),
+                res => panic!("expected parse error for {:?}, got {:?}", input, res),
+            }
+        }
+    }
+}
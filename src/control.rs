@@ -1,25 +1,173 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 /// Size of the header sent on the wire before every frame.
 const HEADER_WIRE_SIZE: usize = 4;
 
-// TODO: proper version, this is just a placeholder.
-const PROTO_VERSION: u8 = 0;
+/// Highest version of the control protocol we support. The version used on a connection is
+/// negotiated during the handshake.
+pub const PROTO_VERSION: u8 = 1;
+
+/// Oldest version of the control protocol we support.
+pub const MIN_PROTO_VERSION: u8 = 1;
 
 // Types for different frames.
 
 /// Type for the PING frame.
 const TYPE_PING: u8 = 0;
 
-/// Minimal size of an actual ping frame.
+/// Type for the PONG frame.
+const TYPE_PONG: u8 = 1;
+
+/// Type for the PEER_ANNOUNCE frame.
+const TYPE_PEER_ANNOUNCE: u8 = 2;
+
+/// Type for the DISCONNECT frame.
+const TYPE_DISCONNECT: u8 = 3;
+
+/// Type for the HELLO frame.
+const TYPE_HELLO: u8 = 4;
+
+/// Type for the ROTATE frame.
+const TYPE_ROTATE: u8 = 5;
+
+/// Minimal size of an actual ping frame. This is also the minimal size of a pong frame.
 const MINIMAL_PING_FRAME_SIZE: u16 = 4;
 
+/// Minimal size of a peer announce frame, which is the public key followed by the amount of
+/// addresses.
+const MINIMAL_PEER_ANNOUNCE_FRAME_SIZE: u16 = 33;
+
+/// Minimal size of a disconnect frame, which is the reason code.
+const MINIMAL_DISCONNECT_FRAME_SIZE: u16 = 1;
+
+/// Minimal size of a hello frame, which is the MTU followed by the capabilities.
+const MINIMAL_HELLO_FRAME_SIZE: u16 = 6;
+
+/// Minimal size of a rotate frame, which is the new public key followed by the signature.
+const MINIMAL_ROTATE_FRAME_SIZE: u16 = 32 + 64;
+
+/// Prefix of the message signed in a rotate frame, so the signature can't be mistaken for one
+/// made for another purpose, e.g. the answer to a handshake challenge.
+const ROTATE_CONTEXT: &[u8] = b"styx identity rotation";
+
+/// Maximum amount of addresses in a single peer announce frame.
+pub const MAX_ANNOUNCED_ADDRS: usize = 16;
+
+/// Largest frame body accepted, excluding the header. The largest frame we send is a peer
+/// announce frame with [`MAX_ANNOUNCED_ADDRS`] IPv6 addresses, this leaves room for frames to be
+/// extended in the future, without letting a peer make us buffer up to 64KiB per connection.
+pub const MAX_CONTROL_FRAME_SIZE: u16 = 1024;
+
+/// Address family marker of an IPv4 address in a peer announce frame.
+const ADDR_FAMILY_V4: u8 = 4;
+
+/// Address family marker of an IPv6 address in a peer announce frame.
+const ADDR_FAMILY_V6: u8 = 6;
+
+/// Amount of consecutive frames which can fail to decode before the decoder gives up on the
+/// connection.
+pub const MAX_CONSECUTIVE_DECODE_ERRORS: usize = 10;
+
 /// Frames transmitted over a control connection to a peer. Control frames don't hold actual data,
 /// as that is send and received over a dedicated connection.
+#[derive(Debug)]
 pub enum ControlFrame {
     /// A ping frame, containing the ID of the ping.
     Ping(u32),
+    /// A reply to a ping frame, containing the ID of the ping it answers.
+    Pong(u32),
+    /// Knowledge of another peer, with the addresses it can be reached on. At most
+    /// [`MAX_ANNOUNCED_ADDRS`] addresses can be announced in a single frame.
+    PeerAnnounce {
+        /// The public key of the announced peer.
+        public_key: [u8; 32],
+        /// Addresses the announced peer listens on.
+        addrs: Vec<SocketAddr>,
+    },
+    /// The sender is about to close the connection. The receiver should consider the sender
+    /// gone right away, rather than waiting for the connection to time out. The reason is one of
+    /// the [`DisconnectReason`] codes.
+    Disconnect {
+        /// Why the connection is closed.
+        reason: u8,
+    },
+    /// Parameters of the data path of the sender, sent as the first frame on a new connection.
+    /// Peers which don't send this frame, or send it with all capabilities cleared, predate it.
+    Hello {
+        /// MTU of the overlay interface of the sender.
+        mtu: u16,
+        /// Capabilities of the sender, as the bits of
+        /// [`Features`](crate::handshake::Features).
+        capabilities: u32,
+    },
+    /// The sender replaced its identity with a new one. The receiver should consider the
+    /// connection to be with the new identity from now on, and reach the sender on the subnet of
+    /// the new key.
+    Rotate {
+        /// The new public key of the sender.
+        public_key: [u8; 32],
+        /// Signature of the [`rotate_message`] for the new key, made with the old key, which
+        /// proves the rotation comes from the owner of the old identity.
+        signature: [u8; 64],
+    },
+}
+
+/// The message signed by the old key of a node in a [`ControlFrame::Rotate`] announcing the
+/// given new key.
+pub fn rotate_message(public_key: &[u8; 32]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(ROTATE_CONTEXT.len() + public_key.len());
+    msg.extend_from_slice(ROTATE_CONTEXT);
+    msg.extend_from_slice(public_key);
+    msg
+}
+
+/// Reason codes of a [`ControlFrame::Disconnect`]. Peers might send codes which are not listed
+/// here, which should be treated like [`DisconnectReason::Unspecified`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DisconnectReason {
+    /// No specific reason.
+    Unspecified = 0,
+    /// The node is shutting down.
+    Shutdown = 1,
+    /// The peer violated the protocol, e.g. by sending too many malformed frames.
+    ProtocolError = 2,
+    /// The connection is replaced by another connection to the same peer.
+    Replaced = 3,
+    /// The peer was removed, e.g. by an operator.
+    Removed = 4,
+}
+
+impl DisconnectReason {
+    /// Decode a reason code received on the wire. Unknown codes are mapped to
+    /// [`DisconnectReason::Unspecified`].
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => DisconnectReason::Shutdown,
+            2 => DisconnectReason::ProtocolError,
+            3 => DisconnectReason::Replaced,
+            4 => DisconnectReason::Removed,
+            _ => DisconnectReason::Unspecified,
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            DisconnectReason::Unspecified => "unspecified",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::ProtocolError => "protocol error",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Removed => "removed",
+        })
+    }
 }
 
 /// Header used to send frames on the wire.
@@ -34,16 +182,144 @@ struct FrameHeader {
     len: u16,
 }
 
+/// Errors of the [`ControlCodec`]. At the codec boundary these are wrapped in an [`io::Error`],
+/// use [`ControlError::from_io`] to get them back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlError {
+    /// The frame has a type we don't know, it might be sent by a newer peer.
+    UnknownFrameType(u8),
+    /// The frame uses a newer protocol version than the one negotiated for the connection.
+    UnsupportedVersion(u8),
+    /// A ping or pong frame is too short to hold the ping ID.
+    MalformedPing,
+    /// A disconnect frame is too short to hold the reason.
+    MalformedDisconnect,
+    /// A hello frame is too short to hold the MTU and capabilities.
+    MalformedHello,
+    /// A rotate frame is too short to hold the public key and signature.
+    MalformedRotate,
+    /// A peer announce frame is truncated, or announces too many addresses.
+    MalformedPeerAnnounce,
+    /// The frame is larger than [`MAX_CONTROL_FRAME_SIZE`], with the given size.
+    FrameTooLarge(u16),
+    /// [`MAX_CONSECUTIVE_DECODE_ERRORS`] frames in a row failed to decode.
+    TooManyErrors,
+    /// A peer announce frame to send holds more than [`MAX_ANNOUNCED_ADDRS`] addresses.
+    TooManyAddrs,
+}
+
+impl ControlError {
+    /// Get the [`ControlError`] wrapped in an error returned by the codec, if any. Errors of the
+    /// underlying connection don't hold one.
+    pub fn from_io(err: &io::Error) -> Option<&ControlError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Whether decoding can continue after this error. The data of a frame which can't be
+    /// decoded is skipped, so the next frame can still be decoded, unless the frame is too large
+    /// to be buffered, or the remote keeps sending malformed frames.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            ControlError::FrameTooLarge(_) | ControlError::TooManyErrors
+        )
+    }
+
+    /// The kind of the [`io::Error`] this is wrapped in.
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            ControlError::UnknownFrameType(_) => io::ErrorKind::InvalidData,
+            ControlError::UnsupportedVersion(_) => io::ErrorKind::Unsupported,
+            ControlError::MalformedPing
+            | ControlError::MalformedDisconnect
+            | ControlError::MalformedHello
+            | ControlError::MalformedRotate
+            | ControlError::MalformedPeerAnnounce
+            | ControlError::TooManyAddrs => io::ErrorKind::InvalidInput,
+            ControlError::FrameTooLarge(_) | ControlError::TooManyErrors => {
+                io::ErrorKind::ConnectionAborted
+            }
+        }
+    }
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::UnknownFrameType(_type) => write!(f, "unknown frame type {}", _type),
+            ControlError::UnsupportedVersion(version) => {
+                write!(f, "frame has unsupported protocol version {}", version)
+            }
+            ControlError::MalformedPing => {
+                f.pad("insufficient data to decode a ping or pong frame")
+            }
+            ControlError::MalformedDisconnect => {
+                f.pad("insufficient data to decode a disconnect frame")
+            }
+            ControlError::MalformedHello => f.pad("insufficient data to decode a hello frame"),
+            ControlError::MalformedRotate => f.pad("insufficient data to decode a rotate frame"),
+            ControlError::MalformedPeerAnnounce => f.pad("malformed peer announce frame"),
+            ControlError::FrameTooLarge(len) => write!(
+                f,
+                "frame of {} bytes exceeds maximum frame size of {} bytes",
+                len, MAX_CONTROL_FRAME_SIZE
+            ),
+            ControlError::TooManyErrors => f.pad("too many consecutive frames failed to decode"),
+            ControlError::TooManyAddrs => f.pad("too many addresses in peer announce frame"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+impl From<ControlError> for io::Error {
+    fn from(err: ControlError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
 /// A [`Codec`](tokio_util::codec) for control frames.
+///
+/// If a frame can't be decoded, the data of the frame is removed from the buffer and a
+/// recoverable [`ControlError`] is returned, after which decoding can continue. Once
+/// [`MAX_CONSECUTIVE_DECODE_ERRORS`] frames in a row failed to decode, or if a frame is larger
+/// than [`MAX_CONTROL_FRAME_SIZE`], an error which is not recoverable is returned instead, and
+/// the connection should be closed, see [`ControlError::is_recoverable`].
 pub struct ControlCodec {
+    /// Version of the protocol used on the connection. Frames are sent with this version, and
+    /// frames with a newer version are rejected.
+    version: u8,
     /// Save a header after we decode one, even if we didn't receive the remainder of the data yet.
     header: Option<FrameHeader>,
+    /// Amount of frames which failed to decode since the last successfully decoded frame.
+    consecutive_errors: usize,
 }
 
 impl ControlCodec {
-    /// Create a new [`ControlCodec`].
+    /// Create a new [`ControlCodec`], using the highest protocol version we support.
+    #[cfg(test)]
     pub fn new() -> Self {
-        Self { header: None }
+        Self::with_version(PROTO_VERSION)
+    }
+
+    /// Create a new [`ControlCodec`] for the given protocol version, as negotiated during the
+    /// handshake.
+    pub fn with_version(version: u8) -> Self {
+        Self {
+            version,
+            header: None,
+            consecutive_errors: 0,
+        }
+    }
+
+    /// Account for a frame which could not be decoded, escalating the error if too many frames in
+    /// a row failed to decode.
+    fn decode_error(&mut self, err: ControlError) -> ControlError {
+        self.consecutive_errors += 1;
+        if self.consecutive_errors >= MAX_CONSECUTIVE_DECODE_ERRORS {
+            return ControlError::TooManyErrors;
+        }
+        err
     }
 }
 
@@ -75,6 +351,12 @@ impl Decoder for ControlCodec {
             // Don't advance the buffer manually as that is already done by reading the individual
             // header pieces.
 
+            // The frame isn't buffered, so its data can't be skipped either, and there is no way
+            // to find the start of the next frame.
+            if len > MAX_CONTROL_FRAME_SIZE {
+                return Err(ControlError::FrameTooLarge(len).into());
+            }
+
             FrameHeader {
                 version,
                 _type,
@@ -95,10 +377,36 @@ impl Decoder for ControlCodec {
             return Ok(None);
         }
 
-        // Decode the frame.
+        // Decode the frame. The remote must not use a newer version than the one negotiated,
+        // as we can't know how to interpret the frame.
+        let res = if header.version > self.version {
+            src.advance(header.len as usize);
+            Err(ControlError::UnsupportedVersion(header.version))
+        } else {
+            self.decode_frame(&header, src)
+        };
+
+        match res {
+            Ok(frame) => {
+                self.consecutive_errors = 0;
+                Ok(frame)
+            }
+            Err(e) => Err(self.decode_error(e).into()),
+        }
+    }
+}
+
+impl ControlCodec {
+    /// Decode the body of a frame with the given header. The buffer holds at least the full
+    /// frame, which is removed from it, even if the frame is malformed.
+    fn decode_frame(
+        &self,
+        header: &FrameHeader,
+        src: &mut BytesMut,
+    ) -> Result<Option<ControlFrame>, ControlError> {
         match header._type {
-            TYPE_PING => {
-                // First 4 bytes are the ping ID.
+            TYPE_PING | TYPE_PONG => {
+                // First 4 bytes are the ping ID. Pong frames have the exact same layout.
                 // NOTE: we need 4 bytes for the ping ID, but we will allow an arbitrary amount of
                 // bytes to be passed after this. This _might_ be useful if at some point other data
                 // is included, as older peers won't return a hard error when they fail to decode
@@ -108,10 +416,7 @@ impl Decoder for ControlCodec {
                     // Malformed frame, remove the data and return an error. By removing the data
                     // we might be able to save the connection.
                     src.advance(header.len as usize);
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "insufficient data to decode a ping frame",
-                    ))
+                    Err(ControlError::MalformedPing)
                 } else {
                     // SAFETY: we checked that we have sufficient data (buffer is at least header.len
                     // bytes large, and header.len is at least 4 bytes to decode the ID).
@@ -121,31 +426,132 @@ impl Decoder for ControlCodec {
                     // already advanced 4 bytes by reading the ID. This subtraction is safe as we
                     // checked header.len() is at least this large.
                     src.advance(header.len as usize - 4);
-                    Ok(Some(ControlFrame::Ping(id)))
+                    if header._type == TYPE_PING {
+                        Ok(Some(ControlFrame::Ping(id)))
+                    } else {
+                        Ok(Some(ControlFrame::Pong(id)))
+                    }
                 }
             }
+            TYPE_DISCONNECT => {
+                // Like ping frames, trailing data is allowed.
+                if header.len < MINIMAL_DISCONNECT_FRAME_SIZE {
+                    src.advance(header.len as usize);
+                    Err(ControlError::MalformedDisconnect)
+                } else {
+                    let reason = src.get_u8();
+                    src.advance(header.len as usize - 1);
+                    Ok(Some(ControlFrame::Disconnect { reason }))
+                }
+            }
+            TYPE_HELLO => {
+                // Trailing data is allowed, so capabilities which don't fit in the bitfield can
+                // be added later.
+                if header.len < MINIMAL_HELLO_FRAME_SIZE {
+                    src.advance(header.len as usize);
+                    Err(ControlError::MalformedHello)
+                } else {
+                    let mtu = src.get_u16();
+                    let capabilities = src.get_u32();
+                    src.advance(header.len as usize - MINIMAL_HELLO_FRAME_SIZE as usize);
+                    Ok(Some(ControlFrame::Hello { mtu, capabilities }))
+                }
+            }
+            TYPE_ROTATE => {
+                // Like hello frames, trailing data is allowed.
+                if header.len < MINIMAL_ROTATE_FRAME_SIZE {
+                    src.advance(header.len as usize);
+                    Err(ControlError::MalformedRotate)
+                } else {
+                    let mut public_key = [0; 32];
+                    src.copy_to_slice(&mut public_key);
+                    let mut signature = [0; 64];
+                    src.copy_to_slice(&mut signature);
+                    src.advance(header.len as usize - MINIMAL_ROTATE_FRAME_SIZE as usize);
+                    Ok(Some(ControlFrame::Rotate {
+                        public_key,
+                        signature,
+                    }))
+                }
+            }
+            TYPE_PEER_ANNOUNCE => {
+                // Take the whole frame, so a malformed frame never leaves data behind.
+                let mut frame = src.split_to(header.len as usize);
+                decode_peer_announce(&mut frame)
+                    .map(Some)
+                    .ok_or(ControlError::MalformedPeerAnnounce)
+            }
             _ => {
                 // Unknown frame. This is an error. However, we clear the specified amount of bytes
                 // from the buffer, as this might allow us to recover the connection. This is
                 // helpful for instance, if the remote is on a newer version and didn't verify that
                 // we can decode the frame.
                 src.advance(header.len as usize);
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "unknown version",
-                ))
+                Err(ControlError::UnknownFrameType(header._type))
             }
         }
     }
 }
 
+/// Decode the body of a peer announce frame. Data after the last address is ignored, like in
+/// ping frames. Returns [`None`] if the body is malformed.
+fn decode_peer_announce(frame: &mut BytesMut) -> Option<ControlFrame> {
+    if frame.len() < MINIMAL_PEER_ANNOUNCE_FRAME_SIZE as usize {
+        return None;
+    }
+    let mut public_key = [0; 32];
+    frame.copy_to_slice(&mut public_key);
+    let count = frame.get_u8() as usize;
+    if count > MAX_ANNOUNCED_ADDRS {
+        return None;
+    }
+    let mut addrs = Vec::with_capacity(count);
+    for _ in 0..count {
+        if frame.remaining() < 1 {
+            return None;
+        }
+        let ip = match frame.get_u8() {
+            ADDR_FAMILY_V4 if frame.remaining() >= 4 + 2 => {
+                IpAddr::V4(Ipv4Addr::from(frame.get_u32()))
+            }
+            ADDR_FAMILY_V6 if frame.remaining() >= 16 + 2 => {
+                IpAddr::V6(Ipv6Addr::from(frame.get_u128()))
+            }
+            _ => return None,
+        };
+        addrs.push(SocketAddr::new(ip, frame.get_u16()));
+    }
+    Some(ControlFrame::PeerAnnounce { public_key, addrs })
+}
+
+/// Size of an address on the wire in a peer announce frame.
+fn announced_addr_size(addr: &SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => 1 + 4 + 2,
+        SocketAddr::V6(_) => 1 + 16 + 2,
+    }
+}
+
 impl Encoder<ControlFrame> for ControlCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: ControlFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Get type of the frame
-        let (_type, len) = match item {
+        let (_type, len) = match &item {
             ControlFrame::Ping(_) => (TYPE_PING, MINIMAL_PING_FRAME_SIZE),
+            ControlFrame::Pong(_) => (TYPE_PONG, MINIMAL_PING_FRAME_SIZE),
+            ControlFrame::PeerAnnounce { addrs, .. } => {
+                if addrs.len() > MAX_ANNOUNCED_ADDRS {
+                    return Err(ControlError::TooManyAddrs.into());
+                }
+                // Can't overflow, as the amount of addresses is bounded.
+                let len = MINIMAL_PEER_ANNOUNCE_FRAME_SIZE as usize
+                    + addrs.iter().map(announced_addr_size).sum::<usize>();
+                (TYPE_PEER_ANNOUNCE, len as u16)
+            }
+            ControlFrame::Disconnect { .. } => (TYPE_DISCONNECT, MINIMAL_DISCONNECT_FRAME_SIZE),
+            ControlFrame::Hello { .. } => (TYPE_HELLO, MINIMAL_HELLO_FRAME_SIZE),
+            ControlFrame::Rotate { .. } => (TYPE_ROTATE, MINIMAL_ROTATE_FRAME_SIZE),
         };
 
         // Reserve sufficient data in the buffer.
@@ -155,15 +561,45 @@ impl Encoder<ControlFrame> for ControlCodec {
         // - 1 byte version
         // - 1 byte type
         // - 2 byte frame length
-        dst.put_u8(PROTO_VERSION);
+        dst.put_u8(self.version);
         dst.put_u8(_type);
         dst.put_u16(len);
 
         match item {
-            ControlFrame::Ping(id) => {
+            ControlFrame::Ping(id) | ControlFrame::Pong(id) => {
                 // write the ID
                 dst.put_u32(id)
             }
+            ControlFrame::PeerAnnounce { public_key, addrs } => {
+                dst.put_slice(&public_key);
+                // The amount of addresses was checked when calculating the frame length.
+                dst.put_u8(addrs.len() as u8);
+                for addr in addrs {
+                    match addr.ip() {
+                        IpAddr::V4(ip) => {
+                            dst.put_u8(ADDR_FAMILY_V4);
+                            dst.put_slice(&ip.octets());
+                        }
+                        IpAddr::V6(ip) => {
+                            dst.put_u8(ADDR_FAMILY_V6);
+                            dst.put_slice(&ip.octets());
+                        }
+                    }
+                    dst.put_u16(addr.port());
+                }
+            }
+            ControlFrame::Disconnect { reason } => dst.put_u8(reason),
+            ControlFrame::Hello { mtu, capabilities } => {
+                dst.put_u16(mtu);
+                dst.put_u32(capabilities);
+            }
+            ControlFrame::Rotate {
+                public_key,
+                signature,
+            } => {
+                dst.put_slice(&public_key);
+                dst.put_slice(&signature);
+            }
         }
 
         Ok(())
@@ -174,7 +610,7 @@ impl Encoder<ControlFrame> for ControlCodec {
 mod tests {
     use super::*;
     use futures::{sink::SinkExt, stream::StreamExt};
-    use tokio::io;
+    use tokio::io::{self, AsyncWriteExt};
     use tokio_util::codec;
 
     #[tokio::test]
@@ -193,4 +629,419 @@ mod tests {
             _ => panic!("Received frame is not a Ping frame with ID 1"),
         }
     }
+
+    #[tokio::test]
+    async fn can_send_pong_frame() {
+        let (client, server) = io::duplex(1024);
+
+        let mut client_sink = codec::Framed::new(client, ControlCodec::new());
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        client_sink.send(ControlFrame::Pong(42)).await.unwrap();
+        match server_stream.next().await.unwrap().unwrap() {
+            ControlFrame::Pong(42) => (),
+            _ => panic!("Received frame is not a Pong frame with ID 42"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_send_peer_announce_frame() {
+        let (client, server) = io::duplex(1024);
+
+        let mut client_sink = codec::Framed::new(client, ControlCodec::new());
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        let announced: Vec<SocketAddr> = vec![
+            "1.2.3.4:9651".parse().unwrap(),
+            "[2a02:1802::1]:9652".parse().unwrap(),
+        ];
+        client_sink
+            .send(ControlFrame::PeerAnnounce {
+                public_key: [7; 32],
+                addrs: announced.clone(),
+            })
+            .await
+            .unwrap();
+        // Frames after a peer announce are still decoded.
+        client_sink.send(ControlFrame::Ping(3)).await.unwrap();
+        match server_stream.next().await.unwrap().unwrap() {
+            ControlFrame::PeerAnnounce { public_key, addrs } => {
+                assert_eq!(public_key, [7; 32]);
+                assert_eq!(addrs, announced);
+            }
+            _ => panic!("Received frame is not a PeerAnnounce frame"),
+        }
+        assert!(matches!(
+            server_stream.next().await.unwrap().unwrap(),
+            ControlFrame::Ping(3)
+        ));
+    }
+
+    #[tokio::test]
+    async fn can_send_disconnect_frame() {
+        let (client, server) = io::duplex(1024);
+
+        let mut client_sink = codec::Framed::new(client, ControlCodec::new());
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        client_sink
+            .send(ControlFrame::Disconnect {
+                reason: DisconnectReason::Shutdown as u8,
+            })
+            .await
+            .unwrap();
+        match server_stream.next().await.unwrap().unwrap() {
+            ControlFrame::Disconnect { reason } => {
+                assert_eq!(
+                    DisconnectReason::from_code(reason),
+                    DisconnectReason::Shutdown
+                )
+            }
+            _ => panic!("Received frame is not a Disconnect frame"),
+        }
+        assert_eq!(
+            DisconnectReason::from_code(200),
+            DisconnectReason::Unspecified
+        );
+    }
+
+    #[tokio::test]
+    async fn can_send_hello_frame() {
+        let (client, server) = io::duplex(1024);
+
+        let mut client_sink = codec::Framed::new(client, ControlCodec::new());
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        client_sink
+            .send(ControlFrame::Hello {
+                mtu: 1420,
+                capabilities: 0b1010,
+            })
+            .await
+            .unwrap();
+        match server_stream.next().await.unwrap().unwrap() {
+            ControlFrame::Hello { mtu, capabilities } => {
+                assert_eq!(mtu, 1420);
+                assert_eq!(capabilities, 0b1010);
+            }
+            _ => panic!("Received frame is not a Hello frame"),
+        }
+
+        // Data appended by newer peers is skipped, a truncated frame is rejected.
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[PROTO_VERSION, TYPE_HELLO, 0, 8, 5, 0, 0, 0, 0, 1, 9, 9]);
+        buf.put_slice(&[PROTO_VERSION, TYPE_HELLO, 0, 2, 5, 0]);
+        let mut codec = ControlCodec::new();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(ControlFrame::Hello {
+                mtu: 1280,
+                capabilities: 1
+            })
+        ));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn can_send_rotate_frame() {
+        let (client, server) = io::duplex(1024);
+
+        let mut client_sink = codec::Framed::new(client, ControlCodec::new());
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        client_sink
+            .send(ControlFrame::Rotate {
+                public_key: [3; 32],
+                signature: [4; 64],
+            })
+            .await
+            .unwrap();
+        match server_stream.next().await.unwrap().unwrap() {
+            ControlFrame::Rotate {
+                public_key,
+                signature,
+            } => {
+                assert_eq!(public_key, [3; 32]);
+                assert_eq!(signature, [4; 64]);
+            }
+            _ => panic!("Received frame is not a Rotate frame"),
+        }
+
+        // A truncated frame is rejected, without affecting the next frame.
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[PROTO_VERSION, TYPE_ROTATE, 0, 32]);
+        buf.put_slice(&[3; 32]);
+        buf.put_slice(&[PROTO_VERSION, TYPE_PING, 0, 4, 0, 0, 0, 7]);
+        let mut codec = ControlCodec::new();
+        assert_eq!(
+            codec.decode(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(ControlFrame::Ping(7))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_truncated_peer_announce_frames() {
+        let mut codec = ControlCodec::new();
+        let too_many = ControlFrame::PeerAnnounce {
+            public_key: [7; 32],
+            addrs: vec!["1.2.3.4:9651".parse().unwrap(); MAX_ANNOUNCED_ADDRS + 1],
+        };
+        let err = codec.encode(too_many, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // A frame announcing more addresses than allowed.
+        let mut frame = BytesMut::new();
+        frame.put_slice(&[PROTO_VERSION, TYPE_PEER_ANNOUNCE, 0, 33]);
+        frame.put_slice(&[7; 32]);
+        frame.put_u8(MAX_ANNOUNCED_ADDRS as u8 + 1);
+        // A frame announcing an address which is not included.
+        frame.put_slice(&[PROTO_VERSION, TYPE_PEER_ANNOUNCE, 0, 34]);
+        frame.put_slice(&[7; 32]);
+        frame.put_slice(&[1, ADDR_FAMILY_V6]);
+        frame.put_slice(&[PROTO_VERSION, TYPE_PING, 0, 4, 0, 0, 0, 9]);
+
+        for _ in 0..2 {
+            let err = codec.decode(&mut frame).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        // The malformed frames are skipped entirely.
+        assert!(matches!(
+            codec.decode(&mut frame).unwrap(),
+            Some(ControlFrame::Ping(9))
+        ));
+    }
+
+    #[test]
+    fn io_errors_without_control_error() {
+        let err = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(ControlError::from_io(&err), None);
+        let err = std::io::Error::other("not a control error");
+        assert_eq!(ControlError::from_io(&err), None);
+
+        let err = std::io::Error::from(ControlError::MalformedPing);
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "insufficient data to decode a ping or pong frame"
+        );
+    }
+
+    #[test]
+    fn rejects_frames_with_newer_version() {
+        let mut codec = ControlCodec::with_version(1);
+        let mut frame = BytesMut::new();
+        frame.put_slice(&[2, TYPE_PING, 0, 4, 0, 0, 0, 1]);
+        frame.put_slice(&[1, TYPE_PING, 0, 4, 0, 0, 0, 2]);
+
+        let err = codec.decode(&mut frame).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(
+            ControlError::from_io(&err),
+            Some(&ControlError::UnsupportedVersion(2))
+        );
+        assert!(matches!(
+            codec.decode(&mut frame).unwrap(),
+            Some(ControlFrame::Ping(2))
+        ));
+
+        // Frames are sent with the negotiated version.
+        let mut buf = BytesMut::new();
+        codec.encode(ControlFrame::Ping(3), &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+    }
+
+    #[tokio::test]
+    async fn escalates_after_consecutive_decode_errors() {
+        let (mut client, server) = io::duplex(1024);
+
+        let mut server_stream = codec::Framed::new(server, ControlCodec::new());
+
+        for i in 1..=MAX_CONSECUTIVE_DECODE_ERRORS {
+            // A frame of an unknown type, without any data. Frames are sent one by one, as after
+            // an error the stream only resumes decoding once new data arrives.
+            client.write_all(&[PROTO_VERSION, 255, 0, 0]).await.unwrap();
+            // After an error, the stream returns None once before it continues decoding.
+            let err = loop {
+                if let Some(res) = server_stream.next().await {
+                    break res.err().unwrap();
+                }
+            };
+            let control_err = ControlError::from_io(&err).unwrap();
+            if i < MAX_CONSECUTIVE_DECODE_ERRORS {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+                assert_eq!(control_err, &ControlError::UnknownFrameType(255));
+                assert!(control_err.is_recoverable());
+            } else {
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+                assert_eq!(control_err, &ControlError::TooManyErrors);
+                assert!(!control_err.is_recoverable());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_frames_exceeding_maximum_size() {
+        let mut codec = ControlCodec::new();
+        let mut buf = BytesMut::from(&[PROTO_VERSION, TYPE_PING, 0xFF, 0xFF, 0, 0, 0, 1][..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            ControlError::from_io(&err),
+            Some(&ControlError::FrameTooLarge(0xFFFF))
+        );
+        // Nothing is reserved for the announced frame.
+        assert!(buf.capacity() < MAX_CONTROL_FRAME_SIZE as usize);
+
+        // Frames up to the maximum size are still accepted.
+        let mut buf = BytesMut::from(&[PROTO_VERSION, TYPE_PING][..]);
+        buf.put_u16(MAX_CONTROL_FRAME_SIZE);
+        buf.put_u32(7);
+        buf.resize(HEADER_WIRE_SIZE + MAX_CONTROL_FRAME_SIZE as usize, 0);
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(ControlFrame::Ping(7))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    /// Generate a byte stream for the decoder. Random bytes almost always announce a frame longer
+    /// than the rest of the stream, so the stream is mostly built from headers with a plausible
+    /// version, type and length, followed by a body which may or may not match the header.
+    fn fuzz_stream(rng: &mut impl rand::Rng) -> Vec<u8> {
+        let mut stream = BytesMut::new();
+        for _ in 0..rng.gen_range(0, 20) {
+            match rng.gen_range(0, 4) {
+                0 => {
+                    let frame = match rng.gen_range(0, 6) {
+                        0 => ControlFrame::Ping(rng.gen()),
+                        1 => ControlFrame::Pong(rng.gen()),
+                        2 => ControlFrame::PeerAnnounce {
+                            public_key: rng.gen(),
+                            addrs: (0..rng.gen_range(0, MAX_ANNOUNCED_ADDRS + 1))
+                                .map(|_| {
+                                    if rng.gen() {
+                                        SocketAddr::new(
+                                            IpAddr::V4(rng.gen::<u32>().into()),
+                                            rng.gen(),
+                                        )
+                                    } else {
+                                        SocketAddr::new(
+                                            IpAddr::V6(rng.gen::<u128>().into()),
+                                            rng.gen(),
+                                        )
+                                    }
+                                })
+                                .collect(),
+                        },
+                        3 => ControlFrame::Disconnect { reason: rng.gen() },
+                        4 => ControlFrame::Hello {
+                            mtu: rng.gen(),
+                            capabilities: rng.gen(),
+                        },
+                        _ => {
+                            let mut signature = [0; 64];
+                            rng.fill(&mut signature[..]);
+                            ControlFrame::Rotate {
+                                public_key: rng.gen(),
+                                signature,
+                            }
+                        }
+                    };
+                    ControlCodec::new().encode(frame, &mut stream).unwrap();
+                }
+                1 => {
+                    let len = rng.gen_range(0, 64);
+                    stream.put_u8(rng.gen_range(0, PROTO_VERSION + 2));
+                    stream.put_u8(rng.gen_range(0, TYPE_ROTATE + 2));
+                    stream.put_u16(len);
+                    for _ in 0..len {
+                        stream.put_u8(rng.gen());
+                    }
+                }
+                2 => {
+                    // A peer announce frame with a count and address families which might not
+                    // match its length.
+                    let len = rng.gen_range(0, 200);
+                    stream.put_slice(&[PROTO_VERSION, TYPE_PEER_ANNOUNCE]);
+                    stream.put_u16(len);
+                    for i in 0..len {
+                        stream.put_u8(match i {
+                            32 => rng.gen_range(0, MAX_ANNOUNCED_ADDRS as u8 + 2),
+                            _ if rng.gen_range(0, 4) == 0 => {
+                                [ADDR_FAMILY_V4, ADDR_FAMILY_V6][rng.gen_range(0, 2)]
+                            }
+                            _ => rng.gen(),
+                        });
+                    }
+                }
+                _ => {
+                    for _ in 0..rng.gen_range(0, 16) {
+                        stream.put_u8(rng.gen());
+                    }
+                }
+            }
+        }
+        stream.to_vec()
+    }
+
+    /// Decode everything available in the buffer, recording every result.
+    fn decode_available(codec: &mut ControlCodec, buf: &mut BytesMut, results: &mut Vec<String>) {
+        loop {
+            match codec.decode(buf) {
+                Ok(Some(frame)) => results.push(format!("{:?}", frame)),
+                Ok(None) => return,
+                Err(e) => results.push(format!("{:?}: {}", e.kind(), e)),
+            }
+        }
+    }
+
+    /// Feed arbitrary byte streams into the decoder, split in arbitrary chunks. Decoding must never
+    /// panic, and must give the same results regardless of how the stream is split. Set
+    /// `STYX_FUZZ_ITERATIONS` to run more iterations than the default.
+    #[test]
+    fn decodes_arbitrary_chunked_streams() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let iterations = std::env::var("STYX_FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(2000);
+        for seed in 0..iterations {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let stream = fuzz_stream(&mut rng);
+
+            let mut whole_codec = ControlCodec::new();
+            let mut whole_buf = BytesMut::from(&stream[..]);
+            let mut whole = Vec::new();
+            decode_available(&mut whole_codec, &mut whole_buf, &mut whole);
+
+            let mut chunked_codec = ControlCodec::new();
+            let mut chunked_buf = BytesMut::new();
+            let mut chunked = Vec::new();
+            let mut rest = &stream[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(rng.gen_range(1, rest.len() + 1).min(32));
+                chunked_buf.put_slice(chunk);
+                decode_available(&mut chunked_codec, &mut chunked_buf, &mut chunked);
+                rest = tail;
+            }
+
+            assert_eq!(whole, chunked, "seed {}", seed);
+            assert_eq!(whole_buf, chunked_buf, "seed {}", seed);
+            assert_eq!(
+                whole_codec.header.map(|h| h.len),
+                chunked_codec.header.map(|h| h.len),
+                "seed {}",
+                seed
+            );
+        }
+    }
 }
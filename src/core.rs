@@ -1,149 +1,5234 @@
-use std::collections::HashMap;
-use std::{collections::HashSet, net::Ipv6Addr, sync::Arc};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Mutex, RwLock,
+};
+use std::time::{Duration, Instant};
+use std::{
+    collections::HashSet,
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
 
-use futures::StreamExt;
-use log::{debug, error};
+use bytes::Bytes;
+use futures::{future::join_all, stream::SplitStream, SinkExt, StreamExt};
+use log::{debug, error, info, trace, warn};
 use tokio::{
-    io::AsyncReadExt,
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{broadcast, mpsc, mpsc::error::TrySendError, oneshot, watch, Notify, Semaphore},
+    task::JoinHandle,
 };
-use tokio_util::codec::Framed;
+use tokio_util::{codec::Framed, sync::CancellationToken};
+use x25519_dalek::StaticSecret;
 
-use crate::control::ControlCodec;
-use crate::crypto::ed25519::PUBLIC_KEY_LENGTH;
-use crate::net::Subnet;
+use crate::address::AddressScheme;
+use crate::backoff::Backoff;
+use crate::buffer::PacketBuffer;
+use crate::control::{
+    rotate_message, ControlCodec, ControlError, ControlFrame, DisconnectReason, MAX_ANNOUNCED_ADDRS,
+};
+use crate::crypto::session::Session;
+use crate::data::EncryptedDataCodec;
+use crate::handshake;
+use crate::handshake::{
+    accept_handshake, initiate_handshake, ConnectionKind, Features, HandshakeResult,
+};
+use crate::net::{Dialer, PeerAddr, SocketOptions, Subnet};
+use crate::netlink::KernelRoutes;
+use crate::pcap::PacketCapture;
+use crate::ratelimit::TokenBucket;
+use crate::routing::{RouteKind, RoutingTable};
+use crate::sampling::{PacketMeta, Sampler};
+use crate::stats::{
+    ConnectionQueues, LastActivity, PeerTraffic, PeerTrafficStats, QueueDepths, QueueGauge,
+    RttStats, RttWindow, TrafficCounters,
+};
+use crate::transport::{DataTransport, PacketTransport, TransportKind, UdpTransport};
+use crate::tun::Tun;
 use crate::{
-    crypto::ed25519::{PublicKey, SecretKey},
-    peer::Peer,
+    crypto::ed25519::{PublicKey, SecretKey, Signature},
+    peer::{AddressPolicy, KeyFilter, Peer},
 };
 
-/// Magic number to identify a control connection. Value is the ASCII byte value of CTRL.
-const CONTROL_MAGIC: u32 = 0x43_54_52_4C;
+mod builder;
+pub mod packet;
+
+pub use builder::CoreBuilder;
+
+/// Amount of control frames which can be queued for sending to a single peer.
+const CONTROL_QUEUE_SIZE: usize = 16;
+
+/// Amount of events buffered for every subscriber, see [`Core::subscribe`].
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// Time a peer gets to receive a disconnect frame before the control connection is closed anyway.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Amount of packets which can be queued for sending on a single data connection.
+const DATA_QUEUE_SIZE: usize = 1024;
+
+/// Amount of packets which are held for a subnet while its data connection is reopened. Packets
+/// beyond this are dropped.
+const PENDING_DIAL_QUEUE_SIZE: usize = 64;
+
+/// Interval at which kernel routes are synced, to pick up routes learned from peers, which don't
+/// come with an event.
+const KERNEL_ROUTE_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time to wait before accepting connections again after accepting one failed, e.g. because we
+/// ran out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Default time a remote gets to complete the handshake on an inbound connection.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time a single write to a connection or the TUN interface gets to complete.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum amount of ICMPv6 error messages generated per second.
+const ICMP_RATE_LIMIT: u64 = 100;
 
-/// Magic number to identify a data connection. Value is the ASCII byte value of DATA.
-const DATA_MAGIC: u32 = 0x44_41_54_41;
+/// Default amount of keepalive pings in a row a peer can miss before it is considered dead.
+pub const DEFAULT_KEEPALIVE_MAX_MISSED: u32 = 3;
+
+/// Default round trip time of keepalive pings above which a peer is considered degraded.
+pub const DEFAULT_DEGRADED_RTT: Duration = Duration::from_millis(500);
+
+/// Default amount of inbound connections which can be in the handshake at the same time.
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// Default time between sweeps for idle data connections, see [`Core::set_idle_timeout`].
+pub const DEFAULT_IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default amount of inbound connections which completed their handshake, and can be queued
+/// until they are registered.
+pub const DEFAULT_CONNECTION_QUEUE_SIZE: usize = 10;
+
+/// Delay before reconnecting to a persistent peer the first time after the connection is lost.
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between attempts to reconnect to a persistent peer.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A connection to a persistent peer which stays up for this long is considered stable, and the
+/// backoff starts over if it is lost.
+const RECONNECT_STABLE_AFTER: Duration = Duration::from_secs(30);
 
 /// Different types of connection which can be mad.
 enum Connection {
-    /// The remote indicates this is a control connection, originating from the given peer.
-    Control(TcpStream, PublicKey),
-    /// The remote indicates this is a data connection, originating from the given peer.
-    Data(TcpStream, PublicKey),
+    /// The remote indicates this is a control connection, originating from the given peer, using
+    /// the given protocol version.
+    Control(TcpStream, PublicKey, u8),
+    /// The remote indicates this is a data connection, originating from the given peer. The
+    /// transport is boxed as it is a lot larger than a control connection.
+    Data(Box<DataTransport>, PublicKey),
+}
+
+/// An established data connection.
+struct DataConnection {
+    /// Unique ID of the connection, to tell it apart from other connections to the same subnet.
+    id: u64,
+    /// Packets waiting to be sent on the connection.
+    packets: mpsc::Sender<Bytes>,
+    /// The peer on the other end of the connection.
+    peer: PublicKey,
+    /// The node which opened the connection.
+    initiator: PublicKey,
+    /// Largest packet which can be sent on the connection.
+    max_packet_size: usize,
+    /// Time a packet was last sent or received on the connection.
+    activity: Arc<LastActivity>,
+    /// Task driving the connection. Once the packet queue is closed, the task sends all
+    /// remaining packets and closes the connection.
+    task: JoinHandle<()>,
+}
+
+/// A change in the connections of a [`Core`], see [`Core::subscribe`]. Every event carries the
+/// public key of the peer, or our own for [`CoreEvent::IdentityRotated`], and the overlay
+/// address derived from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoreEvent {
+    /// A control connection to the peer is established.
+    PeerConnected { key: PublicKey, address: Ipv6Addr },
+    /// The control connection to the peer is closed.
+    PeerDisconnected { key: PublicKey, address: Ipv6Addr },
+    /// A data connection to the subnet of the peer is established.
+    DataChannelUp { key: PublicKey, address: Ipv6Addr },
+    /// The data connection to the subnet of the peer is closed.
+    DataChannelDown { key: PublicKey, address: Ipv6Addr },
+    /// The health of the peer changed, as seen by the keepalive pings on its control connection.
+    PeerHealthChanged {
+        key: PublicKey,
+        address: Ipv6Addr,
+        health: PeerHealth,
+    },
+    /// Our own identity was replaced with [`Core::rotate_identity`].
+    IdentityRotated { key: PublicKey, address: Ipv6Addr },
+}
+
+/// Health of a peer we have a control connection with, as determined by keepalive pings, see
+/// [`Keepalive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerHealth {
+    /// The control connection is established, but no keepalive ping was answered yet.
+    Connecting,
+    /// The last keepalive ping was answered within [`Keepalive::degraded_rtt`]. Peers are
+    /// always healthy if keepalive pings are disabled.
+    Healthy,
+    /// The peer missed keepalive pings, or answered the last one slower than
+    /// [`Keepalive::degraded_rtt`].
+    Degraded,
+    /// The peer missed [`Keepalive::max_missed`] keepalive pings in a row. The control
+    /// connection is closed right after a peer is found dead.
+    Dead,
+}
+
+impl fmt::Display for PeerHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            PeerHealth::Connecting => "connecting",
+            PeerHealth::Healthy => "healthy",
+            PeerHealth::Degraded => "degraded",
+            PeerHealth::Dead => "dead",
+        })
+    }
+}
+
+/// An active control connection to a peer.
+struct ControlConnection {
+    /// Unique ID of the connection, to tell it apart from other connections to the same peer.
+    id: u64,
+    /// Handle to send frames over the connection.
+    frames: mpsc::Sender<ControlFrame>,
+    /// The node which opened the connection.
+    initiator: PublicKey,
+    /// Cancelled to close the connection, e.g. because it is replaced.
+    close: CancellationToken,
+    /// Address of the remote end of the connection, if it could be determined.
+    remote: Option<SocketAddr>,
+    /// Address the peer connected to us from, if the peer opened the connection.
+    observed_addr: Option<SocketAddr>,
+    /// Health of the peer, updated by the keepalive pings on the connection.
+    health: PeerHealth,
+    /// Lowest MTU of us and the peer, once the peer sent a [`ControlFrame::Hello`].
+    mtu: Option<u16>,
+    /// Capabilities supported by both us and the peer, once the peer sent a
+    /// [`ControlFrame::Hello`].
+    capabilities: Option<Features>,
+    /// Recent round trip times of pings on the connection.
+    rtt: RttWindow,
+    /// Amount of frames received on the connection which failed to decode.
+    decode_errors: u64,
+}
+
+/// Information about a peer we have a control connection with, see [`Core::connected_peers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Public key of the peer.
+    pub key: PublicKey,
+    /// Overlay address of the peer, derived from its key.
+    pub address: Ipv6Addr,
+    /// Address of the remote end of the control connection, if it could be determined.
+    pub remote: Option<SocketAddr>,
+    /// Address the peer connected to us from, as we observed it, if the peer opened the control
+    /// connection. If the peer is behind a NAT this is the address of the NAT mapping, rather than
+    /// an address the peer listens on.
+    pub observed_addr: Option<SocketAddr>,
+    /// Whether a data connection to the subnet of the peer is established.
+    pub data_connection: bool,
+    /// Health of the peer.
+    pub health: PeerHealth,
+    /// Lowest MTU of us and the peer, if the peer announced its MTU.
+    pub mtu: Option<u16>,
+    /// Capabilities supported by both us and the peer, if the peer announced its capabilities.
+    pub capabilities: Option<Features>,
+    /// Statistics over the recent round trip times to the peer, if any ping was answered.
+    pub rtt: Option<RttStats>,
+    /// Amount of control frames received from the peer on the current control connection which
+    /// failed to decode.
+    pub control_decode_errors: u64,
+}
+
+/// A peer we keep a control connection to.
+struct PersistentPeer {
+    /// Task reconnecting to the peer.
+    task: JoinHandle<()>,
+    /// Key of the peer, once we connected to it.
+    key: Option<PublicKey>,
+}
+
+/// State shared between the [`Core`] and the task driving a data connection.
+struct DataConContext {
+    /// Subnet of the remote.
+    subnet: Subnet,
+    /// Interface packets received on the connection are written to, if any.
+    tun: Option<Arc<Tun>>,
+    /// Channel packets received on the connection are routed through in relay-only mode.
+    relay: Option<mpsc::Sender<Bytes>>,
+    /// ID of the connection, see [`DataConnection::id`].
+    id: u64,
+    /// Queue gauges of the connection.
+    queues: Arc<ConnectionQueues>,
+    /// Time a packet was last sent or received on the connection.
+    activity: Arc<LastActivity>,
+    /// All active data connections, the connection removes itself once it is closed.
+    active_data_peers: Arc<Mutex<HashMap<Subnet, DataConnection>>>,
+    /// Counter of received packets with a source address the remote can't send from.
+    spoofed_packets: Arc<AtomicU64>,
+    /// Routes to subnets we are not directly connected to, the remote can relay packets from
+    /// the subnets it is the next hop of.
+    routing_table: Arc<RwLock<RoutingTable>>,
+    /// Counters of the traffic on all data connections.
+    traffic: Arc<TrafficCounters>,
+    /// Counters of the traffic with the remote.
+    peer_traffic: Arc<PeerTraffic>,
+    /// Maximum amount of packet bytes per second accepted from the remote, if limited.
+    rate_limit: Option<u64>,
+    /// Time a single write to the connection or the interface gets to complete.
+    write_timeout: Duration,
+    /// Captures packets written to the interface, if enabled.
+    capture: Option<Arc<PacketCapture>>,
+    /// Overlay address of the remote.
+    address: Ipv6Addr,
+    /// Channel to publish a [`CoreEvent::DataChannelDown`] on once the connection closes.
+    events: broadcast::Sender<CoreEvent>,
+}
+
+/// Settings for detecting dead control connections, and for judging the health of peers, see
+/// [`PeerHealth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time between the pong of a keepalive ping and the next keepalive ping.
+    pub interval: Duration,
+    /// Time the peer gets to reply to a keepalive ping, after which the ping is missed, and the
+    /// next one is sent right away.
+    pub timeout: Duration,
+    /// Amount of keepalive pings in a row the peer can miss before the connection is closed.
+    pub max_missed: u32,
+    /// Round trip time of keepalive pings above which the peer is degraded.
+    pub degraded_rtt: Duration,
+}
+
+/// Settings for closing data connections which don't carry any traffic.
+struct IdleEviction {
+    /// Data connections without traffic for this long are closed. Eviction is disabled if this
+    /// is not set.
+    timeout: Option<Duration>,
+    /// Time between checks for idle data connections.
+    sweep_interval: Duration,
+    /// Subnets to which the data connection is never closed for being idle.
+    pinned: HashSet<Subnet>,
+}
+
+impl Default for IdleEviction {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            sweep_interval: DEFAULT_IDLE_SWEEP_INTERVAL,
+            pinned: HashSet::new(),
+        }
+    }
+}
+
+impl IdleEviction {
+    /// The time after which an idle data connection to the given subnet is closed, if any.
+    fn timeout_for(&self, subnet: &Subnet) -> Option<Duration> {
+        if self.pinned.contains(subnet) {
+            return None;
+        }
+        self.timeout
+    }
+}
+
+/// Decide if a new connection should replace an existing connection to the same peer.
+///
+/// If both connections were opened by the same node, the new connection wins, as the node
+/// likely reconnected after the old connection silently died. If the connections were opened by
+/// different nodes, i.e. both sides connected to each other at the same time, the connection
+/// opened by the node with the lowest public key wins. Both sides apply the same rule, so they
+/// end up keeping the same connection.
+fn new_connection_wins(existing_initiator: &PublicKey, new_initiator: &PublicKey) -> bool {
+    if existing_initiator == new_initiator {
+        return true;
+    }
+    new_initiator.as_bytes() < existing_initiator.as_bytes()
+}
+
+/// Send a disconnect frame with the given reason on a control connection, and wait until it is
+/// written out. This can take arbitrarily long if the peer doesn't read from the connection, so
+/// it should be bounded by a timeout.
+async fn send_disconnect(frame_tx: &mpsc::Sender<ControlFrame>, reason: DisconnectReason) {
+    let frame = ControlFrame::Disconnect {
+        reason: reason as u8,
+    };
+    if frame_tx.send(frame).await.is_ok() {
+        // The writer stops, and drops the receiver, once the disconnect frame is written.
+        frame_tx.closed().await;
+    }
+}
+
+/// Set up the transport of a data connection, once the session is established on it. If both
+/// sides support UDP data connections, packets are sent over UDP, otherwise they are framed on
+/// the TCP connection, compressed if both sides support it. Packets sent over UDP are never
+/// compressed, replayed datagrams are counted in `replayed`.
+async fn data_transport(
+    mut con: TcpStream,
+    session: Session,
+    features: Features,
+    max_packet_size: usize,
+    replayed: Arc<AtomicU64>,
+) -> std::io::Result<DataTransport> {
+    if features.contains(Features::UDP_DATA) {
+        return UdpTransport::negotiate(&mut con, session, max_packet_size, replayed)
+            .await
+            .map(DataTransport::from);
+    }
+    let codec = EncryptedDataCodec::negotiated(session, features, max_packet_size);
+    Ok(Framed::new(con, codec).into())
+}
+
+/// A ping which was sent, but not answered yet.
+struct OutstandingPing {
+    /// The peer the ping was sent to.
+    peer: PublicKey,
+    /// The time the ping was sent.
+    sent: Instant,
+    /// Channel to report the round trip time on once the pong arrives.
+    rtt: oneshot::Sender<Duration>,
+}
+
+/// The identity of the local node.
+struct Identity {
+    secret: SecretKey,
+    public: PublicKey,
+}
+
+impl Identity {
+    /// Create a new [`Identity`] from the given [`SecretKey`].
+    fn new(secret: SecretKey) -> Self {
+        let public = secret.public_key();
+        Self { secret, public }
+    }
 }
 
 /// The main control structure of the network.
+///
+/// Multiple instances can run in the same process, as all state is owned by the instance. The
+/// only constraints are the ones imposed by the host: every instance needs its own listen
+/// address, and if it manages a TUN interface, a unique interface name.
+///
+/// The [`Core`] is shared between the tasks driving the connections behind an [`Arc`], so all
+/// mutable state is behind a lock or an atomic. The connection maps use a synchronous
+/// [`Mutex`] rather than an async lock or an actor task owning the state: every access is a
+/// short lookup or update, so contention is low, and the maps can be used from synchronous
+/// code like [`Drop`] and the packet forwarding fast path without a round trip through a
+/// channel. The flip side is that a guard must never be held across an `.await`, which the
+/// compiler enforces for spawned tasks, as the guards are not [`Send`]. Locks are only poisoned
+/// if a thread panicked while holding them, in which case the state can't be trusted anymore, so
+/// they are unwrapped.
 pub struct Core {
-    identity: SecretKey,
-    identity_public: PublicKey,
+    /// The identity used on the control plane. Data connections are not tied to this, so it can
+    /// be replaced without interrupting traffic.
+    identity: RwLock<Identity>,
+    /// The scheme used to derive addresses from public keys.
+    address_scheme: AddressScheme,
+    /// Public keys of additional identities hosted by this instance, besides our own identity.
+    local_keys: RwLock<HashSet<PublicKey>>,
+    /// Subnets of our own identity and of the local keys. Packets addressed to these are
+    /// terminated here, all other packets are relayed to a peer.
+    local_subnets: RwLock<HashSet<Subnet>>,
+    /// Listeners for inbound connections, each accepted by its own task.
+    listeners: Vec<Arc<TcpListener>>,
+    /// Peers we learned about, either because we are connected to them, or because they were
+    /// announced to us.
+    peer_cache: Mutex<HashSet<Peer>>,
+    /// Whether to connect to peers which are announced to us.
+    dial_announced_peers: AtomicBool,
+    /// Keep track of active control connections. There is at most 1 control connection per peer.
+    active_peers: Mutex<HashMap<PublicKey, ControlConnection>>,
+    /// ID of the next control connection.
+    next_control_con_id: AtomicU64,
+    /// Keep track of active data connections. There is at most 1 data connection per subnet.
+    /// If multiple connections to the same peer are ever needed (e.g. multipath), these should be
+    /// keyed by the subnet and a path identifier.
+    active_data_peers: Arc<Mutex<HashMap<Subnet, DataConnection>>>,
+    /// ID of the next data connection.
+    next_data_con_id: AtomicU64,
+    /// Settings for closing idle data connections.
+    idle_eviction: RwLock<IdleEviction>,
+    /// Wakes the task sweeping for idle data connections once the sweep interval changes.
+    idle_sweep_changed: Notify,
+    /// Maximum amount of packet bytes per second accepted on a single data connection.
+    rate_limit: RwLock<Option<u64>>,
+    /// Addresses and keys of peers we opened a data connection to, so the connection can be
+    /// reopened if it is closed while idle.
+    dial_addrs: Mutex<HashMap<Subnet, (SocketAddr, PublicKey)>>,
+    /// Packets held for subnets whose data connection is being reopened.
+    pending_dials: Mutex<HashMap<Subnet, Vec<Bytes>>>,
+    /// Routes to subnets we are not directly connected to, learned from the peers announced by
+    /// our peers, or added with [`Core::add_route`].
+    routing_table: Arc<RwLock<RoutingTable>>,
+    /// Kernel routes for reachable subnets, if enabled. Syncing them blocks on netlink, so this
+    /// is shared with the blocking thread pool.
+    kernel_routes: Option<Arc<Mutex<KernelRoutes>>>,
+    /// Queue depths of data connections.
+    queue_stats: Mutex<HashMap<Subnet, Arc<ConnectionQueues>>>,
+    /// Total amount of control frames which failed to decode.
+    control_decode_errors: AtomicUsize,
+    /// Inbound connections which completed their handshake, waiting to be registered.
+    connection_queue: QueueGauge,
+    /// Total amount of inbound control connections which had to wait for room in the connection
+    /// queue.
+    connection_queue_blocked: AtomicU64,
+    /// Total amount of inbound data connections which were dropped because the connection queue
+    /// was full.
+    connection_queue_dropped: AtomicU64,
+    /// Total amount of packets which could not be routed to a peer.
+    dropped_packets: AtomicU64,
+    /// Total amount of packets received from a peer with a source address outside of the subnet
+    /// of that peer.
+    spoofed_packets: Arc<AtomicU64>,
+    /// Total amount of datagrams received on UDP data connections which were dropped because
+    /// they were already received, or are too old.
+    replayed_packets: Arc<AtomicU64>,
+    /// Amount of sequence numbers below the highest one received for which datagrams on UDP
+    /// data connections are still accepted.
+    replay_window: AtomicUsize,
+    /// Amount of packet data sent and received on data connections.
+    traffic: Arc<TrafficCounters>,
+    /// Traffic sent to and received from every peer we had a data connection with.
+    peer_traffic: Mutex<HashMap<PublicKey, Arc<PeerTraffic>>>,
+    /// ID of the next ping we send.
+    next_ping_id: AtomicU32,
+    /// Pings for which we did not receive a pong yet, with the peer they were sent to, the time
+    /// they were sent, and a channel to report the round trip time on.
+    outstanding_pings: Mutex<HashMap<u32, OutstandingPing>>,
+    /// Exports flow records for a sample of the forwarded packets, if enabled.
+    sampler: Option<Sampler>,
+    /// Captures packets read from and written to the interface, if enabled.
+    capture: Option<Arc<PacketCapture>>,
+    /// Whether new connections are accepted and established. Existing connections are not
+    /// affected by this.
+    accepting: watch::Sender<bool>,
+    /// Policy for addresses advertised by peers, which must be checked before dialing them.
+    address_policy: AddressPolicy,
+    /// Filter of peers which can connect to us.
+    key_filter: RwLock<KeyFilter>,
+    /// Cancelled once the instance is shut down, which stops all background tasks.
+    shutdown: CancellationToken,
+    /// Opens outbound connections to peers.
+    dialer: Dialer,
+    /// Queues of the interface packets are forwarded from, and packets received from peers are
+    /// written to.
+    tun: Vec<Arc<Tun>>,
+    /// In relay-only mode, packets received from peers are routed to other peers through this
+    /// channel, instead of being written to the interface, see [`CoreBuilder::relay_only`].
+    relay: Option<mpsc::Sender<Bytes>>,
+    /// MTU of the overlay, packets read from the interface which are larger are not forwarded.
+    mtu: usize,
+    /// Largest packet sent or received on data connections, derived from the MTU of the
+    /// interface.
+    max_packet_size: usize,
+    /// Whether jumbo packets are announced on data connections, see [`CoreBuilder::jumbo`].
+    jumbo: bool,
+    /// Bounds the rate of generated ICMPv6 error messages, so they can't be used for
+    /// amplification.
+    icmp_limiter: Mutex<TokenBucket>,
+    /// Peers we keep a control connection to, by the address they are dialed on.
+    persistent_peers: Mutex<HashMap<PeerAddr, PersistentPeer>>,
+    /// Keepalive settings of control connections, keepalive pings are disabled if this is not
+    /// set.
+    keepalive: RwLock<Option<Keepalive>>,
+    /// Socket options of control connections.
+    control_socket_options: RwLock<SocketOptions>,
+    /// Socket options of data connections.
+    data_socket_options: RwLock<SocketOptions>,
+    /// Transport used for data connections, if the remote supports it.
+    data_transport: RwLock<TransportKind>,
+    /// Whether packets on data connections are compressed, if the remote supports it.
+    compression: AtomicBool,
+    /// Time a remote gets to complete the handshake on an inbound connection.
+    handshake_timeout: RwLock<Duration>,
+    /// Time a single write to a connection or the TUN interface gets to complete. A connection
+    /// which can't be written to in time is closed.
+    write_timeout: RwLock<Duration>,
+    /// Permits for inbound connections in the handshake, bounding how many there are at once.
+    handshake_slots: RwLock<Arc<Semaphore>>,
+    /// Channel on which connection changes are published.
+    events: broadcast::Sender<CoreEvent>,
+}
+
+/// Errors returned when pinging a peer.
+#[derive(Debug)]
+pub enum PingError {
+    /// We don't have a control connection to the peer.
+    UnknownPeer,
+    /// The control connection closed before the pong was received.
+    ConnectionClosed,
+    /// The peer did not reply in time.
+    Timeout,
+}
 
-    listener: Arc<TcpListener>,
-    peer_cache: HashSet<Peer>,
-    /// Keep track of active control connections
-    active_peers: HashMap<PublicKey, TcpStream>,
-    /// Keep track of active data connections
-    active_data_peers: HashMap<Subnet, TcpStream>,
+impl fmt::Display for PingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PingError::UnknownPeer => f.pad("not connected to peer"),
+            PingError::ConnectionClosed => f.pad("connection closed before receiving a pong"),
+            PingError::Timeout => f.pad("peer did not reply in time"),
+        }
+    }
 }
 
+impl std::error::Error for PingError {}
+
 impl Core {
     /// Create a new Core from the given secret key. The listener must be provided, and the Core
-    /// will automatically start accepting requests once it is fully initialized.
+    /// will automatically start accepting requests once it is fully initialized. Use a
+    /// [`CoreBuilder`] for any other settings, or to set up a Core without running it right away.
     ///
     /// # Panics
     ///
     /// This function will panic if not called from withing a tokio runtime.
+    #[cfg(test)]
     pub fn new(identity: SecretKey, listener: TcpListener) -> Arc<Self> {
-        let identity_public = identity.public_key();
-
-        let (tx, con_receiver) = mpsc::channel(10);
-        let listener = Arc::new(listener);
-
-        let core = Arc::new(Self {
-            identity,
-            identity_public,
-            listener,
-            peer_cache: HashSet::new(),
-            active_peers: HashMap::new(),
-            active_data_peers: HashMap::new(),
-        });
-
-        tokio::spawn(Core::start_listener(core.listener.clone(), tx));
-        tokio::spawn(Core::handle_connections(core.clone(), con_receiver));
-
+        let (core, run) = CoreBuilder::new(identity).listener(listener).assemble();
+        tokio::spawn(run);
         core
     }
 
     /// Get our own address as calculated from the public key of our identity.
     pub fn address(&self) -> Ipv6Addr {
-        self.identity_public.address()
+        self.address_scheme
+            .derive(&self.identity.read().unwrap().public)
     }
 
-    /// Drive the core. This future does not resolve until the listener is shut down.
-    async fn handle_connections(self: Arc<Self>, mut con_receiver: mpsc::Receiver<Connection>) {
-        while let Some(connection) = con_receiver.recv().await {
-            match connection {
-                Connection::Control(con, peer) => {
-                    tokio::spawn(Core::spawn_control_con(con));
-                }
-                Connection::Data(con, peer) => {
-                    tokio::spawn(Core::spawn_data_con());
-                }
+    /// Get the local addresses of the listeners of this instance.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Get the public key of our current identity.
+    pub fn public_key(&self) -> PublicKey {
+        self.identity.read().unwrap().public.clone()
+    }
+
+    /// Get a copy of the secret key of the local node, used to prove our identity when opening
+    /// connections.
+    fn secret_key(&self) -> SecretKey {
+        SecretKey::from_bytes(*self.identity.read().unwrap().secret.as_bytes())
+    }
+
+    /// Get the public key of the local node, along with the X25519 equivalent of the matching
+    /// secret key, used to establish sessions.
+    fn session_identity(&self) -> (PublicKey, StaticSecret) {
+        let identity = self.identity.read().unwrap();
+        (identity.public.clone(), identity.secret.to_x25519())
+    }
+
+    /// Replace the identity used on the control plane with a new one, returning the old one.
+    ///
+    /// Connected peers are sent a [`ControlFrame::Rotate`] signed with the old identity, after
+    /// which they consider their control connection to be with the new identity, and reach us
+    /// on the subnet of the new key. Existing data connections are left untouched, and keep
+    /// forwarding traffic until they are closed, which the peers do to replace them with
+    /// connections to the new subnet. New connections will use the new identity. A
+    /// [`CoreEvent::IdentityRotated`] is published, so the address of the interface can follow.
+    pub fn rotate_identity(&self, identity: SecretKey) -> SecretKey {
+        let mut current = self.identity.write().unwrap();
+        let old = std::mem::replace(&mut *current, Identity::new(identity));
+        let key = current.public.clone();
+        drop(current);
+        let address = self.address_scheme.derive(&key);
+        debug!("Rotated identity, new address {}", address);
+        self.update_local_subnets();
+
+        let public_key = *key.as_bytes();
+        let signature = *old.secret.sign(&rotate_message(&public_key)).as_bytes();
+        for (peer, con) in self.active_peers.lock().unwrap().iter() {
+            let frame = ControlFrame::Rotate {
+                public_key,
+                signature,
+            };
+            // A peer which doesn't read its control connection anymore is about to be dropped
+            // anyway.
+            if con.frames.try_send(frame).is_err() {
+                debug!(
+                    "Failed to announce rotated identity to {}",
+                    self.address_scheme.derive(peer)
+                );
             }
         }
+        self.publish(CoreEvent::IdentityRotated { key, address });
+        old.secret
     }
 
-    async fn spawn_control_con(con: TcpStream) {
-        let framed = Framed::new(con, ControlCodec::new());
-        let (mut tx, mut rx) = framed.split();
-        todo!();
+    /// Host an additional identity on this instance. Packets addressed to its subnet are
+    /// terminated here, i.e. written to the interface, rather than relayed to a peer. Only the
+    /// public key is needed, as the identity is not used on the control plane. Returns false if
+    /// the key was already hosted.
+    pub fn add_local_key(&self, key: PublicKey) -> bool {
+        let added = self.local_keys.write().unwrap().insert(key);
+        if added {
+            self.update_local_subnets();
+        }
+        added
     }
 
-    async fn spawn_data_con() {
-        todo!();
+    /// Stop hosting an identity added with [`Core::add_local_key`]. Returns false if the key was
+    /// not hosted.
+    pub fn remove_local_key(&self, key: &PublicKey) -> bool {
+        let removed = self.local_keys.write().unwrap().remove(key);
+        if removed {
+            self.update_local_subnets();
+        }
+        removed
     }
 
-    /// Start listening for new inbound connections.
-    async fn start_listener(listener: Arc<TcpListener>, tx: mpsc::Sender<Connection>) {
-        loop {
-            let (mut con, remote) = listener.accept().await.unwrap();
-            debug!("Accepted new connection from {}", remote);
-            let tx = tx.clone();
-            tokio::spawn(async move {
-                let mut buffer = [0; PUBLIC_KEY_LENGTH];
-                if let Err(e) = con.read_exact(&mut buffer[..]).await {
-                    debug!("Connection closed while reading remote public key: {}", e);
-                    return;
+    /// Get the public keys of the additional identities hosted by this instance.
+    pub fn local_keys(&self) -> Vec<PublicKey> {
+        self.local_keys.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Check if packets to the given address are for us, i.e. if it is in the subnet of our own
+    /// identity, or of one of the local keys.
+    pub fn is_local(&self, address: Ipv6Addr) -> bool {
+        self.local_subnets
+            .read()
+            .unwrap()
+            .contains(&Subnet::from_address(address))
+    }
+
+    /// Recalculate the local subnets after our identity or the local keys changed.
+    fn update_local_subnets(&self) {
+        let mut subnets = HashSet::new();
+        subnets.insert(Subnet::from_address(self.address()));
+        for key in self.local_keys.read().unwrap().iter() {
+            subnets.insert(Subnet::from_address(self.address_scheme.derive(key)));
+        }
+        *self.local_subnets.write().unwrap() = subnets;
+    }
+
+    /// Route packets for the given subnet through the peer with the given key, as long as we
+    /// don't have a data connection to the subnet ourselves. An existing route to the subnet is
+    /// replaced, and its next hop returned. Routes are removed once the control connection to
+    /// their next hop closes.
+    pub fn add_route(&self, subnet: Subnet, next_hop: PublicKey) -> Option<PublicKey> {
+        self.routing_table.write().unwrap().insert(subnet, next_hop)
+    }
+
+    /// Remove the route to the given subnet, if any, and return its next hop.
+    pub fn remove_route(&self, subnet: &Subnet) -> Option<PublicKey> {
+        self.routing_table.write().unwrap().remove(subnet)
+    }
+
+    /// Get a snapshot of all subnets which are currently reachable, and how they are reached.
+    /// Subnets we have a data connection to are reported as [`RouteKind::Direct`], even if a
+    /// learned route exists as well, unless the peer owning the subnet is not
+    /// [`PeerHealth::Healthy`] while the next hop of the learned route is.
+    pub fn reachable_subnets(&self) -> Vec<(Subnet, RouteKind)> {
+        let active_peers = self.active_peers.lock().unwrap();
+        let health: HashMap<_, _> = active_peers
+            .iter()
+            .map(|(key, con)| {
+                let subnet = Subnet::from_address(self.address_scheme.derive(key));
+                (subnet, con.health)
+            })
+            .collect();
+        let active_data_peers = self.active_data_peers.lock().unwrap();
+        let routing_table = self.routing_table.read().unwrap();
+        let mut subnets: Vec<_> = active_data_peers
+            .keys()
+            .filter(|subnet| {
+                // Without a control connection, nothing is known about the health of the peer.
+                let direct_healthy = health
+                    .get(subnet)
+                    .is_none_or(|health| *health == PeerHealth::Healthy);
+                let next_hop_healthy = routing_table.next_hop(subnet).is_some_and(|next_hop| {
+                    active_peers
+                        .get(next_hop)
+                        .is_some_and(|con| con.health == PeerHealth::Healthy)
+                });
+                direct_healthy || !next_hop_healthy
+            })
+            .map(|subnet| (*subnet, RouteKind::Direct))
+            .collect();
+        subnets.extend(
+            routing_table
+                .iter()
+                .filter(|(subnet, _)| !subnets.iter().any(|(direct, _)| direct == *subnet))
+                .map(|(subnet, next_hop)| (*subnet, RouteKind::Learned(next_hop.clone())))
+                .collect::<Vec<_>>(),
+        );
+        subnets
+    }
+
+    /// Make sure the kernel has a route for every reachable subnet, and no longer has a route
+    /// for subnets which became unreachable. This does nothing if kernel routes are not enabled.
+    /// The netlink calls block, so they run on the blocking thread pool.
+    async fn sync_kernel_routes(&self) -> std::io::Result<()> {
+        let kernel_routes = match self.kernel_routes {
+            Some(ref kernel_routes) => kernel_routes.clone(),
+            None => return Ok(()),
+        };
+        let reachable: Vec<_> = self
+            .reachable_subnets()
+            .into_iter()
+            .map(|(subnet, _)| subnet)
+            .collect();
+        tokio::task::spawn_blocking(move || kernel_routes.lock().unwrap().sync(&reachable))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// Get the queue gauges for the data connection to the given subnet, creating them if they
+    /// don't exist yet. The returned gauges should be updated as items enter and leave the queues
+    /// of the connection.
+    pub fn connection_queues(&self, subnet: Subnet) -> Arc<ConnectionQueues> {
+        self.queue_stats
+            .lock()
+            .unwrap()
+            .entry(subnet)
+            .or_default()
+            .clone()
+    }
+
+    /// Get a snapshot of the queue depths of all data connections.
+    pub fn queue_depths(&self) -> Vec<(Subnet, QueueDepths)> {
+        self.queue_stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(subnet, queues)| (*subnet, queues.snapshot()))
+            .collect()
+    }
+
+    /// Amount of inbound connections which completed their handshake, and are waiting to be
+    /// registered.
+    pub fn connection_queue_depth(&self) -> usize {
+        self.connection_queue.current()
+    }
+
+    /// Highest amount of inbound connections waiting to be registered since the last reset. If
+    /// this reaches the size of the connection queue, connections arrive faster than they are
+    /// registered.
+    pub fn connection_queue_peak(&self) -> usize {
+        self.connection_queue.peak()
+    }
+
+    /// Total amount of inbound control connections which had to wait for room in the
+    /// connection queue.
+    pub fn connection_queue_blocked(&self) -> u64 {
+        self.connection_queue_blocked.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of inbound data connections which were dropped because the connection queue
+    /// was full.
+    pub fn connection_queue_dropped(&self) -> u64 {
+        self.connection_queue_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of control frames received from any peer which failed to decode.
+    pub fn control_decode_errors(&self) -> usize {
+        self.control_decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of packets which were dropped by [`Core::route_packet`].
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of packets received on data connections which were dropped because their
+    /// source address is not in the subnet of the peer which sent them.
+    pub fn spoofed_packets(&self) -> u64 {
+        self.spoofed_packets.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of datagrams received on UDP data connections which were dropped because
+    /// they were already received, or are too far behind the latest one.
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of packet bytes sent on data connections.
+    pub fn bytes_tx(&self) -> usize {
+        self.traffic.bytes_tx()
+    }
+
+    /// Total amount of packet bytes received on data connections.
+    pub fn bytes_rx(&self) -> usize {
+        self.traffic.bytes_rx()
+    }
+
+    /// Amount of peers we currently have a control connection with.
+    pub fn active_control_peers(&self) -> usize {
+        self.active_peers.lock().unwrap().len()
+    }
+
+    /// Get a snapshot of all peers we currently have a control connection with.
+    pub fn connected_peers(&self) -> Vec<PeerInfo> {
+        let active_peers = self.active_peers.lock().unwrap();
+        let active_data_peers = self.active_data_peers.lock().unwrap();
+        active_peers
+            .iter()
+            .map(|(key, con)| {
+                let address = self.address_scheme.derive(key);
+                PeerInfo {
+                    key: key.clone(),
+                    address,
+                    remote: con.remote,
+                    observed_addr: con.observed_addr,
+                    data_connection: active_data_peers.contains_key(&Subnet::from_address(address)),
+                    health: con.health,
+                    mtu: con.mtu,
+                    capabilities: con.capabilities,
+                    rtt: con.rtt.stats(),
+                    control_decode_errors: con.decode_errors,
                 }
-                let pk = match PublicKey::from_bytes(buffer) {
-                    Ok(pk) => pk,
-                    Err(e) => {
-                        debug!(
-                            "Closing connection after client sent invalid public key: {}",
-                            e
-                        );
-                        return;
-                    }
-                };
-                let magic = match con.read_u32().await {
-                    Ok(m) => m,
-                    Err(e) => {
-                        // It could be that the remote closed the connection, which is fine
-                        debug!("Connection to {} closed because of {}", remote, e);
-                        return;
-                    }
-                };
-                if let Err(e) = match magic {
-                    CONTROL_MAGIC => tx.send(Connection::Control(con, pk)).await,
-                    DATA_MAGIC => tx.send(Connection::Data(con, pk)).await,
-                    _ => {
-                        debug!("Connection closed after sending unexpected identification data");
-                        return;
-                    }
-                } {
-                    // Couldn't send data to core
-                    error!("Could not pass connection to core: {}", e);
+            })
+            .collect()
+    }
+
+    /// Amount of subnets we currently have a data connection to.
+    pub fn active_data_peers(&self) -> usize {
+        self.active_data_peers.lock().unwrap().len()
+    }
+
+    /// Get the traffic counters of the given peer, creating them if they don't exist yet.
+    fn peer_traffic(&self, peer: &PublicKey) -> Arc<PeerTraffic> {
+        self.peer_traffic
+            .lock()
+            .unwrap()
+            .entry(peer.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Get a snapshot of the traffic of every peer we had a data connection with.
+    pub fn peer_traffic_stats(&self) -> Vec<(PublicKey, PeerTrafficStats)> {
+        self.peer_traffic
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, traffic)| (peer.clone(), traffic.snapshot()))
+            .collect()
+    }
+
+    /// Reset all high-water marks, and the traffic counters of all peers. The global traffic
+    /// counters keep counting.
+    pub fn reset_stats(&self) {
+        for queues in self.queue_stats.lock().unwrap().values() {
+            queues.reset();
+        }
+        for traffic in self.peer_traffic.lock().unwrap().values() {
+            traffic.reset();
+        }
+        self.connection_queue.reset_peak();
+    }
+
+    /// Account for a packet forwarded over a data connection. If packet sampling is enabled, this
+    /// might export a flow record for the packet.
+    fn forwarded_packet(&self, packet: &[u8]) {
+        if let Some(sampler) = &self.sampler {
+            sampler.sample(packet);
+        }
+    }
+
+    /// Stop accepting new connections. Inbound connections are left in the backlog of the
+    /// listener until [`Core::resume_accepting`] is called, and no new outbound connections
+    /// should be established. Existing connections keep working as before.
+    pub fn pause_accepting(&self) {
+        self.accepting.send_replace(false);
+    }
+
+    /// Resume accepting new connections after [`Core::pause_accepting`] was called.
+    /// Connections which queued up in the meantime are accepted.
+    pub fn resume_accepting(&self) {
+        self.accepting.send_replace(true);
+    }
+
+    /// Whether new connections are currently accepted.
+    pub fn is_accepting(&self) -> bool {
+        *self.accepting.borrow()
+    }
+
+    /// Queue a packet for sending on the data connection to the given subnet. Returns false if
+    /// there is no data connection to the subnet, if the packet is too large for the
+    /// connection, e.g. because the peer does not support jumbo packets, or if its queue is
+    /// full, in which case the packet is dropped.
+    pub fn send_packet(&self, subnet: Subnet, packet: Bytes) -> bool {
+        let packets = match self.active_data_peers.lock().unwrap().get(&subnet) {
+            Some(con) if packet.len() > con.max_packet_size => {
+                debug!(
+                    "Dropping packet of {} bytes to {}, which is too large for the connection",
+                    packet.len(),
+                    subnet
+                );
+                return false;
+            }
+            Some(con) => con.packets.clone(),
+            None => return false,
+        };
+        self.forwarded_packet(&packet);
+        let queues = self.connection_queues(subnet);
+        // Account for the packet first, so the connection can't dequeue it before it is counted.
+        queues.send.enqueued();
+        if packets.try_send(packet).is_err() {
+            queues.send.dequeued();
+            return false;
+        }
+        true
+    }
+
+    /// Queue a packet for sending to the given subnet, like [`Core::send_packet`]. If there is
+    /// no data connection to the subnet, but we opened one before which was closed in the
+    /// meantime, e.g. because it was idle, the connection is reopened in the background. The
+    /// packet is held until then, so packets to other subnets don't wait on the dial.
+    pub fn forward_packet(self: &Arc<Self>, subnet: Subnet, packet: Bytes) -> bool {
+        if self.active_data_peers.lock().unwrap().contains_key(&subnet) {
+            return self.send_packet(subnet, packet);
+        }
+        let dial = self.dial_addrs.lock().unwrap().get(&subnet).cloned();
+        let (addr, peer) = match dial {
+            Some(dial) => dial,
+            None => return self.send_through_next_hop(subnet, packet),
+        };
+        match self.pending_dials.lock().unwrap().entry(subnet) {
+            // The connection is already being reopened.
+            Entry::Occupied(mut pending) => {
+                if pending.get().len() >= PENDING_DIAL_QUEUE_SIZE {
+                    return false;
+                }
+                pending.get_mut().push(packet);
+                return true;
+            }
+            Entry::Vacant(pending) => {
+                pending.insert(vec![packet]);
+            }
+        }
+        debug!("Reopening data connection to {}", subnet);
+        let core = self.clone();
+        tokio::spawn(async move {
+            let res = core.open_data_connection(addr, peer).await;
+            let packets = core
+                .pending_dials
+                .lock()
+                .unwrap()
+                .remove(&subnet)
+                .unwrap_or_default();
+            let dropped = match res {
+                Ok(()) => packets
+                    .into_iter()
+                    .filter(|packet| !core.send_packet(subnet, packet.clone()))
+                    .count(),
+                Err(e) => {
+                    debug!("Failed to reopen data connection to {}: {}", subnet, e);
+                    packets.len()
                 }
+            };
+            core.dropped_packets
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+        });
+        true
+    }
+
+    /// Queue a packet for a subnet we don't have a data connection to on the data connection to
+    /// the next hop of the learned route to the subnet, if any, see [`Core::add_route`].
+    fn send_through_next_hop(&self, subnet: Subnet, packet: Bytes) -> bool {
+        let next_hop = match self.routing_table.read().unwrap().next_hop(&subnet) {
+            Some(next_hop) => Subnet::from_address(self.address_scheme.derive(next_hop)),
+            None => return false,
+        };
+        trace!("Relaying packet to {} through {}", subnet, next_hop);
+        self.send_packet(next_hop, packet)
+    }
+
+    /// Route an IPv6 packet to the data connection of the subnet containing its destination
+    /// address, see [`Core::forward_packet`]. Packets addressed to a local subnet, see
+    /// [`Core::is_local`], are written back to the interface instead. Returns false if the
+    /// packet is dropped, because it is not a valid IPv6 packet, or it can't be forwarded to its
+    /// destination.
+    pub async fn route_packet(self: &Arc<Self>, packet: Bytes) -> bool {
+        let header = match packet::parse_header(&packet) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Dropping {}", e);
+                self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        };
+        trace!("Routing packet {:?}", header);
+        if packet.len() > self.mtu {
+            debug!(
+                "Dropping packet of {} bytes, which exceeds the MTU of {}",
+                packet.len(),
+                self.mtu
+            );
+            self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+            self.packet_too_big(&packet, &header).await;
+            return false;
+        }
+        if self.is_local(header.dst) {
+            return self.deliver_local(&packet).await;
+        }
+        let subnet = Subnet::from_address(header.dst);
+        if !self.forward_packet(subnet, packet) {
+            debug!("Dropping packet to unreachable subnet {}", subnet);
+            self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Terminate a packet addressed to a local subnet, by writing it to the interface. With
+    /// several hosted identities, the interface (or policy routing on the host) takes care of
+    /// handing the packet to the right one. Returns false if the packet is dropped.
+    async fn deliver_local(&self, packet: &[u8]) -> bool {
+        let tun = match self.tun.first() {
+            Some(tun) => tun,
+            // E.g. in relay-only mode.
+            None => {
+                debug!("Dropping packet to local subnet, there is no interface");
+                self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        };
+        trace!("Delivering packet to local subnet");
+        if let Some(capture) = &self.capture {
+            capture.capture(packet);
+        }
+        match tokio::time::timeout(self.write_timeout(), tun.send(packet)).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                debug!("Failed to write local packet to TUN: {}", e);
+                self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Err(_) => {
+                debug!("Timed out writing local packet to TUN");
+                self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Tell the sender of a packet which exceeds the MTU to send smaller packets, by writing an
+    /// ICMPv6 "Packet Too Big" message to the interface. Messages beyond [`ICMP_RATE_LIMIT`] per
+    /// second are not sent.
+    async fn packet_too_big(&self, packet: &[u8], header: &packet::Ipv6Header) {
+        let tun = match self.tun.first() {
+            Some(tun) => tun,
+            None => return,
+        };
+        let message = match packet::packet_too_big(self.address(), packet, header, self.mtu as u32)
+        {
+            Some(message) => message,
+            None => return,
+        };
+        if !self.icmp_limiter.lock().unwrap().try_take(1) {
+            trace!("Not sending packet too big to {}, rate limited", header.src);
+            return;
+        }
+        if let Some(capture) = &self.capture {
+            capture.capture(&message);
+        }
+        match tokio::time::timeout(self.write_timeout(), tun.send(&message)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => debug!("Failed to write packet too big to TUN: {}", e),
+            Err(_) => debug!("Timed out writing packet too big to TUN"),
+        }
+    }
+
+    /// Close data connections which don't carry any traffic for the given duration. Connections
+    /// we opened are reopened on demand by [`Core::forward_packet`]. If `timeout` is [`None`],
+    /// idle connections are kept. Control connections are never closed for being idle, as they
+    /// carry keepalives.
+    ///
+    /// Idle connections are found by a periodic sweep, so a connection can stay open for up to
+    /// the timeout plus the sweep interval, see [`Core::set_idle_sweep_interval`].
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.idle_eviction.write().unwrap().timeout = timeout;
+    }
+
+    /// Set the time between sweeps for idle data connections, see [`Core::set_idle_timeout`].
+    /// A shorter interval closes idle connections closer to the timeout, at the cost of
+    /// checking all connections more often.
+    pub fn set_idle_sweep_interval(&self, interval: Duration) {
+        self.idle_eviction.write().unwrap().sweep_interval = interval;
+        self.idle_sweep_changed.notify_one();
+    }
+
+    /// Close all data connections which didn't carry any traffic for the idle timeout, except
+    /// for pinned subnets. Packets which are still queued on them are sent first. Returns the
+    /// amount of connections which are closed.
+    fn reap_idle_data_connections(&self) -> usize {
+        let idle_eviction = self.idle_eviction.read().unwrap();
+        if idle_eviction.timeout.is_none() {
+            return 0;
+        }
+        let mut active_data_peers = self.active_data_peers.lock().unwrap();
+        let idle: Vec<_> = active_data_peers
+            .iter()
+            .filter(|(subnet, con)| {
+                idle_eviction
+                    .timeout_for(subnet)
+                    .is_some_and(|timeout| con.activity.idle() >= timeout)
+            })
+            .map(|(subnet, _)| *subnet)
+            .collect();
+        // Dropping the connection closes it once its queue is empty. The dial address is kept,
+        // so the connection is reopened once there is something to send.
+        let closed: Vec<_> = idle
+            .iter()
+            .filter_map(|subnet| active_data_peers.remove(subnet))
+            .collect();
+        drop(active_data_peers);
+        drop(idle_eviction);
+        for con in &closed {
+            debug!(
+                "Closing idle data connection to {}",
+                self.address_scheme.derive(&con.peer)
+            );
+            self.publish(CoreEvent::DataChannelDown {
+                key: con.peer.clone(),
+                address: self.address_scheme.derive(&con.peer),
             });
         }
+        closed.len()
+    }
+
+    /// Periodically close idle data connections, until the instance is shut down.
+    async fn reap_idle(self: Arc<Self>) {
+        loop {
+            let interval = self.idle_eviction.read().unwrap().sweep_interval;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => (),
+                // Start waiting for the new interval right away.
+                _ = self.idle_sweep_changed.notified() => continue,
+                _ = self.shutdown.cancelled() => return,
+            }
+            self.reap_idle_data_connections();
+        }
+    }
+
+    /// Keep the kernel routes in sync with the reachable subnets whenever a connection comes or
+    /// goes, until the instance is shut down, after which all installed routes are removed.
+    async fn maintain_kernel_routes(self: Arc<Self>) {
+        let kernel_routes = match self.kernel_routes {
+            Some(ref kernel_routes) => kernel_routes.clone(),
+            None => return,
+        };
+        let mut events = self.subscribe();
+        let mut interval = tokio::time::interval(KERNEL_ROUTE_SYNC_INTERVAL);
+        loop {
+            tokio::select! {
+                // Missed events are covered by the sync as well.
+                _ = events.recv() => (),
+                _ = interval.tick() => (),
+                _ = self.shutdown.cancelled() => break,
+            }
+            if let Err(e) = self.sync_kernel_routes().await {
+                warn!("Failed to sync kernel routes: {}", e);
+            }
+        }
+        let res = tokio::task::spawn_blocking(move || kernel_routes.lock().unwrap().clear())
+            .await
+            .map_err(std::io::Error::other);
+        if let Err(e) = res.and_then(|res| res) {
+            warn!("Failed to remove kernel routes: {}", e);
+        }
+    }
+
+    /// Limit the amount of packet bytes per second accepted on a single data connection, so a
+    /// single peer can't starve the others. Once a peer exceeds the limit, its connection is not
+    /// read from until it is within the limit again, which makes the peer back off through TCP
+    /// flow control instead of dropping packets. If `limit` is [`None`], connections are not
+    /// limited. This only affects connections established after this is called.
+    pub fn set_rate_limit(&self, limit: Option<u64>) {
+        *self.rate_limit.write().unwrap() = limit.filter(|limit| *limit > 0);
+    }
+
+    /// Connect to peers which are announced to us by other peers, if we are not connected to them
+    /// yet. Only addresses allowed by the address policy are dialed.
+    pub fn set_dial_announced_peers(&self, dial: bool) {
+        self.dial_announced_peers.store(dial, Ordering::Relaxed);
+    }
+
+    /// Periodically ping peers on control connections to judge their [`PeerHealth`], and close
+    /// the connection if a peer misses too many pings in a row. If `keepalive` is [`None`], no
+    /// keepalive pings are sent, and all peers are considered healthy. This only affects
+    /// connections established after this is called.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) {
+        *self.keepalive.write().unwrap() = keepalive;
+    }
+
+    /// Set the socket options of connections of the given kind. By default, control
+    /// connections use [`SocketOptions::CONTROL`], and data connections use the defaults of the
+    /// kernel. This only affects connections established after this is called.
+    pub fn set_socket_options(&self, kind: ConnectionKind, options: SocketOptions) {
+        let lock = match kind {
+            ConnectionKind::Control => &self.control_socket_options,
+            ConnectionKind::Data => &self.data_socket_options,
+        };
+        *lock.write().unwrap() = options;
+    }
+
+    /// The socket options of connections of the given kind.
+    fn socket_options(&self, kind: ConnectionKind) -> SocketOptions {
+        let lock = match kind {
+            ConnectionKind::Control => &self.control_socket_options,
+            ConnectionKind::Data => &self.data_socket_options,
+        };
+        *lock.read().unwrap()
+    }
+
+    /// Apply the socket options of connections of the given kind to a new connection. Failing
+    /// to do so only affects performance, so it is not fatal.
+    fn apply_socket_options(&self, con: &TcpStream, kind: ConnectionKind) {
+        if let Err(e) = self.socket_options(kind).apply(con) {
+            warn!("Failed to set socket options: {}", e);
+        }
+    }
+
+    /// Set the time a remote gets to complete the handshake, including establishing the session
+    /// of data connections, after it connected to us. Connections which don't complete it in
+    /// time are closed. This only affects connections accepted after this is called.
+    pub fn set_handshake_timeout(&self, timeout: Duration) {
+        *self.handshake_timeout.write().unwrap() = timeout;
+    }
+
+    /// Limit the amount of inbound connections which are in the handshake at the same time.
+    /// Once the limit is reached, no new connections are accepted until a handshake finishes,
+    /// so they queue up in the backlog of the listener instead of using up memory. Connections
+    /// which are in the handshake when this is called don't count towards the new limit.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `max` is 0.
+    pub fn set_max_pending_handshakes(&self, max: usize) {
+        assert!(max > 0, "at least 1 handshake must be allowed");
+        *self.handshake_slots.write().unwrap() = Arc::new(Semaphore::new(max));
+    }
+
+    /// Time a remote gets to complete the handshake on an inbound connection.
+    fn handshake_timeout(&self) -> Duration {
+        *self.handshake_timeout.read().unwrap()
+    }
+
+    /// Set the time a single write to a connection or the TUN interface gets to complete. A
+    /// peer which doesn't read from its connection for this long has the connection closed,
+    /// rather than having packets for it back up indefinitely. A packet which can't be written
+    /// to the TUN interface in time is dropped. This only affects connections established after
+    /// this is called.
+    pub fn set_write_timeout(&self, timeout: Duration) {
+        *self.write_timeout.write().unwrap() = timeout;
+    }
+
+    /// Time a single write to a connection or the TUN interface gets to complete.
+    fn write_timeout(&self) -> Duration {
+        *self.write_timeout.read().unwrap()
+    }
+
+    /// Set the transport of data connections. This is only used if the remote supports it as
+    /// well, otherwise data connections fall back to TCP. This only affects connections
+    /// established after this is called.
+    pub fn set_data_transport(&self, transport: TransportKind) {
+        *self.data_transport.write().unwrap() = transport;
+    }
+
+    /// Set the amount of sequence numbers below the highest one received for which datagrams on
+    /// UDP data connections are still accepted. A larger window tolerates more reordering. This
+    /// only affects connections established after this is called.
+    pub fn set_replay_window(&self, size: usize) {
+        self.replay_window.store(size, Ordering::Relaxed);
+    }
+
+    /// Establish the session of a data connection, see [`Session::establish`].
+    async fn establish_session(
+        &self,
+        con: &mut TcpStream,
+        secret: &StaticSecret,
+        public_key: &PublicKey,
+        remote: &PublicKey,
+    ) -> std::io::Result<Session> {
+        let session = Session::establish(con, secret, public_key, remote).await?;
+        Ok(session.with_replay_window(self.replay_window.load(Ordering::Relaxed)))
+    }
+
+    /// Compress packets on data connections. This is only used if the remote supports it as
+    /// well. This only affects connections established after this is called.
+    pub fn set_compression(&self, enabled: bool) {
+        self.compression.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Features of data connections we announce in handshakes.
+    fn data_features(&self) -> Features {
+        let transport = match *self.data_transport.read().unwrap() {
+            TransportKind::Tcp => Features::NONE,
+            TransportKind::Udp => Features::UDP_DATA,
+        };
+        let transport = if self.jumbo {
+            transport.union(Features::JUMBO)
+        } else {
+            transport
+        };
+        if self.compression.load(Ordering::Relaxed) {
+            transport.union(Features::COMPRESSION)
+        } else {
+            transport
+        }
+    }
+
+    /// Capabilities we announce in a [`ControlFrame::Hello`].
+    fn capabilities(&self) -> Features {
+        self.data_features().union(Features::ENCRYPTION)
+    }
+
+    /// Store the parameters agreed on with the peer, if `id` is still its control connection.
+    /// A peer without any capabilities predates [`ControlFrame::Hello`], so nothing is agreed on
+    /// and data connections are negotiated in their handshake only, as before.
+    fn hello_received(&self, peer: &PublicKey, id: u64, mtu: u16, capabilities: Features) {
+        let (mtu, capabilities) = if capabilities == Features::NONE {
+            debug!(
+                "Peer {} does not announce capabilities",
+                self.address_scheme.derive(peer)
+            );
+            (None, None)
+        } else {
+            let mtu = mtu.min(self.mtu.min(u16::MAX as usize) as u16);
+            let capabilities = self.capabilities().intersection(capabilities);
+            debug!(
+                "Agreed on MTU {} and capabilities {:#x} with {}",
+                mtu,
+                capabilities.bits(),
+                self.address_scheme.derive(peer)
+            );
+            (Some(mtu), Some(capabilities))
+        };
+        if let Some(con) = self.active_peers.lock().unwrap().get_mut(peer) {
+            if con.id == id {
+                con.mtu = mtu;
+                con.capabilities = capabilities;
+            }
+        }
+    }
+
+    /// Move everything we know about `peer` to the new identity it announced in a
+    /// [`ControlFrame::Rotate`] on control connection `id`: the control connection, the routes
+    /// through the peer, and the key of the persistent peer it is. The data connection to the
+    /// old subnet of the peer is closed, as it would drop packets from the new subnet as
+    /// spoofed, so a connection to the new subnet can take its place. Returns the new key of
+    /// the peer, or the reason to close the connection if the rotation is not valid or the new
+    /// key is not allowed.
+    fn rotation_received(
+        &self,
+        peer: &PublicKey,
+        id: u64,
+        public_key: [u8; 32],
+        signature: [u8; 64],
+    ) -> Result<PublicKey, DisconnectReason> {
+        let old_address = self.address_scheme.derive(peer);
+        let key = PublicKey::from_bytes(public_key).map_err(|e| {
+            debug!("Peer {} rotated to an invalid key: {}", old_address, e);
+            DisconnectReason::ProtocolError
+        })?;
+        peer.verify(
+            &rotate_message(&public_key),
+            &Signature::from_bytes(signature),
+        )
+        .map_err(|e| {
+            debug!("Peer {} sent an invalid rotation: {}", old_address, e);
+            DisconnectReason::ProtocolError
+        })?;
+        if let Err(e) = self.key_filter.read().unwrap().check(&key) {
+            info!(
+                "Peer {} rotated to a key which is not allowed: {}",
+                old_address, e
+            );
+            return Err(DisconnectReason::Removed);
+        }
+        let address = self.address_scheme.derive(&key);
+        {
+            let mut active_peers = self.active_peers.lock().unwrap();
+            // The connection might already be replaced by a new one, which is told about the
+            // rotation itself.
+            if active_peers.get(peer).map(|con| con.id) != Some(id) {
+                return Ok(key);
+            }
+            // Can't fail, we just checked that the connection is there.
+            let mut con = active_peers.remove(peer).unwrap();
+            if con.initiator == *peer {
+                con.initiator = key.clone();
+            }
+            if let Some(existing) = active_peers.insert(key.clone(), con) {
+                existing.close.cancel();
+            }
+        }
+        info!(
+            "Peer {} rotated its identity, new address {}",
+            old_address, address
+        );
+        self.routing_table
+            .write()
+            .unwrap()
+            .replace_next_hop(peer, &key);
+        for persistent in self.persistent_peers.lock().unwrap().values_mut() {
+            if persistent.key.as_ref() == Some(peer) {
+                persistent.key = Some(key.clone());
+            }
+        }
+        self.remove_data_connection(peer);
+        self.publish(CoreEvent::PeerDisconnected {
+            key: peer.clone(),
+            address: old_address,
+        });
+        self.publish(CoreEvent::PeerConnected {
+            key: key.clone(),
+            address,
+        });
+        Ok(key)
+    }
+
+    /// Limit data connection features to the capabilities agreed on with the peer over its
+    /// control connection, if any.
+    fn agreed_features(&self, peer: &PublicKey, features: Features) -> Features {
+        match self
+            .active_peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .and_then(|con| con.capabilities)
+        {
+            Some(capabilities) => features.intersection(capabilities),
+            None => features,
+        }
+    }
+
+    /// Never close the data connection to the given subnet for being idle.
+    pub fn pin_subnet(&self, subnet: Subnet) {
+        self.idle_eviction.write().unwrap().pinned.insert(subnet);
+    }
+
+    /// Allow closing the data connection to the given subnet if it is idle again, after it was
+    /// pinned with [`Core::pin_subnet`].
+    pub fn unpin_subnet(&self, subnet: &Subnet) {
+        self.idle_eviction.write().unwrap().pinned.remove(subnet);
+    }
+
+    /// Shut down this instance, in an order which minimizes the amount of lost packets:
+    ///
+    /// 1. Stop accepting new connections.
+    /// 2. Stop queueing new packets on data connections, and send the packets which are already
+    ///    queued. Data connections are closed once their queue is empty.
+    /// 3. Close all other connections and stop all background tasks.
+    ///
+    /// If the queued packets can't be sent within `deadline`, the remaining data connections are
+    /// closed anyway. Reading packets from the TUN interface must be stopped before this is
+    /// called, and the interface must only be dropped once this returns, as packets read in the
+    /// meantime can't be sent anymore.
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.pause_accepting();
+
+        // Dropping the packet queues makes the data connections send the remaining packets and
+        // close afterwards.
+        let mut tasks: Vec<_> = self
+            .active_data_peers
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, con)| con.task)
+            .collect();
+        if tokio::time::timeout(deadline, join_all(tasks.iter_mut()))
+            .await
+            .is_err()
+        {
+            debug!("Not all queued packets were sent before the shutdown deadline");
+            for task in tasks {
+                task.abort();
+            }
+        }
+
+        // Let peers know we are gone, so they don't route through us until the connections time
+        // out.
+        let peers: Vec<_> = self
+            .active_peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|con| con.frames.clone())
+            .collect();
+        let goodbyes = peers
+            .iter()
+            .map(|frame_tx| send_disconnect(frame_tx, DisconnectReason::Shutdown));
+        if tokio::time::timeout(DISCONNECT_TIMEOUT, join_all(goodbyes))
+            .await
+            .is_err()
+        {
+            debug!("Not all peers received a disconnect frame before the deadline");
+        }
+
+        self.shutdown.cancel();
+        // Dropping the senders closes the control connections, in case a connection task did
+        // not get to run yet.
+        let active_peers: Vec<_> = self.active_peers.lock().unwrap().drain().collect();
+        for (peer, _) in active_peers {
+            self.publish(CoreEvent::PeerDisconnected {
+                address: self.address_scheme.derive(&peer),
+                key: peer,
+            });
+        }
+    }
+
+    /// Subscribe to changes in the connections of this instance. Only events which happen after
+    /// this is called are received.
+    ///
+    /// Publishing events never waits for subscribers. A subscriber which falls more than
+    /// [`EVENT_QUEUE_SIZE`] events behind misses the oldest ones, and is told how many it missed
+    /// with [`broadcast::error::RecvError::Lagged`], after which it receives the newer events
+    /// again. Such a subscriber can resynchronize with [`Core::connected_peers`].
+    pub fn subscribe(&self) -> broadcast::Receiver<CoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish an event to all subscribers, if there are any.
+    fn publish(&self, event: CoreEvent) {
+        trace!("Publishing {:?}", event);
+        // This only fails if there are no subscribers.
+        let _ = self.events.send(event);
+    }
+
+    /// Update the health of the peer, if `id` is still its control connection, and publish the
+    /// change.
+    fn set_peer_health(&self, peer: &PublicKey, id: u64, health: PeerHealth) {
+        match self.active_peers.lock().unwrap().get_mut(peer) {
+            Some(con) if con.id == id && con.health != health => con.health = health,
+            _ => return,
+        }
+        debug!("Peer {} is {}", self.address_scheme.derive(peer), health);
+        self.publish(CoreEvent::PeerHealthChanged {
+            key: peer.clone(),
+            address: self.address_scheme.derive(peer),
+            health,
+        });
+    }
+
+    /// Count a control frame received on the given connection to the peer which failed to
+    /// decode.
+    fn record_decode_error(&self, peer: &PublicKey, id: u64) {
+        self.control_decode_errors.fetch_add(1, Ordering::Relaxed);
+        match self.active_peers.lock().unwrap().get_mut(peer) {
+            Some(con) if con.id == id => con.decode_errors += 1,
+            _ => (),
+        }
+    }
+
+    /// Record a round trip time measured on the control connection to the given peer.
+    fn record_rtt(&self, peer: &PublicKey, rtt: Duration) {
+        if let Some(con) = self.active_peers.lock().unwrap().get_mut(peer) {
+            con.rtt.record(rtt);
+        }
+    }
+
+    /// Send a ping to the given peer, and wait for the reply. The round trip time is returned if
+    /// the peer replies within the given timeout.
+    pub async fn ping(&self, peer: &PublicKey, timeout: Duration) -> Result<Duration, PingError> {
+        let sender = self
+            .active_peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|con| con.frames.clone())
+            .ok_or(PingError::UnknownPeer)?;
+
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.outstanding_pings.lock().unwrap().insert(
+            id,
+            OutstandingPing {
+                peer: peer.clone(),
+                sent: Instant::now(),
+                rtt: tx,
+            },
+        );
+
+        let res = if sender.send(ControlFrame::Ping(id)).await.is_err() {
+            Err(PingError::ConnectionClosed)
+        } else {
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(rtt)) => return Ok(rtt),
+                // The pong sender is only dropped once the pong is received, so this can't
+                // happen, but handle it just in case.
+                Ok(Err(_)) => Err(PingError::ConnectionClosed),
+                Err(_) => Err(PingError::Timeout),
+            }
+        };
+
+        // Clean up the outstanding ping.
+        self.outstanding_pings.lock().unwrap().remove(&id);
+        res
+    }
+
+    /// Process a pong frame. Pongs for unknown pings, e.g. because the ping already timed out or
+    /// was already answered, and pongs for pings sent to a different peer, are ignored.
+    fn pong_received(&self, peer: &PublicKey, id: u32) {
+        let mut outstanding_pings = self.outstanding_pings.lock().unwrap();
+        match outstanding_pings.get(&id) {
+            Some(ping) if &ping.peer == peer => (),
+            _ => {
+                debug!(
+                    "Ignoring pong {} from {}",
+                    id,
+                    self.address_scheme.derive(peer)
+                );
+                return;
+            }
+        }
+        let ping = outstanding_pings
+            .remove(&id)
+            .expect("ping exists, as checked above");
+        drop(outstanding_pings);
+
+        let rtt = ping.sent.elapsed();
+        debug!(
+            "Round trip time to {} is {:?}",
+            self.address_scheme.derive(peer),
+            rtt
+        );
+        self.record_rtt(peer, rtt);
+        // If the receiver is dropped, the pinger is no longer interested in the result.
+        let _ = ping.rtt.send(rtt);
+    }
+
+    /// Process a peer announce frame received from `from`. The announced addresses which are
+    /// allowed by the address policy are added to the peer cache, and if enabled, we connect to
+    /// the announced peer if we are not connected to it yet. Announcements of ourselves are
+    /// ignored.
+    fn peer_announced(
+        self: &Arc<Self>,
+        from: &PublicKey,
+        public_key: [u8; 32],
+        addrs: Vec<SocketAddr>,
+    ) {
+        let key = match PublicKey::from_bytes(public_key) {
+            Ok(key) => key,
+            Err(e) => {
+                debug!(
+                    "Ignoring invalid peer announced by {}: {}",
+                    self.address_scheme.derive(from),
+                    e
+                );
+                return;
+            }
+        };
+        if key == self.public_key() {
+            return;
+        }
+        // The announcing peer is connected to the announced peer, so it can relay packets to it.
+        if key != *from {
+            let subnet = Subnet::from_address(self.address_scheme.derive(&key));
+            if self.add_route(subnet, from.clone()).as_ref() != Some(from) {
+                debug!(
+                    "Learned route to {} through {}",
+                    subnet,
+                    self.address_scheme.derive(from)
+                );
+            }
+        }
+
+        let mut peer_cache = self.peer_cache.lock().unwrap();
+        let mut peer = peer_cache
+            .take(&Peer::new(key.clone(), Vec::new()))
+            .unwrap_or_else(|| Peer::new(key.clone(), Vec::new()));
+        peer.adopt_advertised_addrs(addrs, &self.address_policy);
+        let dial_addrs = peer.listen_addrs().to_vec();
+        peer_cache.insert(peer);
+        drop(peer_cache);
+
+        if !self.dial_announced_peers.load(Ordering::Relaxed)
+            || self.active_peers.lock().unwrap().contains_key(&key)
+        {
+            return;
+        }
+        let core = self.clone();
+        tokio::spawn(async move {
+            for addr in dial_addrs {
+                match core.connect_to_peer(addr).await {
+                    Ok(remote) if remote == key => return,
+                    Ok(remote) => debug!(
+                        "Peer at {} is {}, not the announced peer {}",
+                        addr,
+                        core.address_scheme.derive(&remote),
+                        core.address_scheme.derive(&key)
+                    ),
+                    Err(e) => debug!("Failed to connect to announced peer at {}: {}", addr, e),
+                }
+            }
+        });
+    }
+
+    /// Announce the peers we have a control connection to, to the given peer, which just sent
+    /// its [`ControlFrame::Hello`], and announce the peer to all of them. Peers which are both
+    /// connected to us learn a route to each other through us this way. Only our direct peers
+    /// are announced, so learned routes span a single intermediate node. Returns false if the
+    /// control connection to the peer can't be written to anymore.
+    async fn exchange_announcements(
+        &self,
+        peer: &PublicKey,
+        frame_tx: &mpsc::Sender<ControlFrame>,
+    ) -> bool {
+        let others: Vec<_> = self
+            .active_peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| *key != peer)
+            .map(|(key, con)| (key.clone(), con.frames.clone()))
+            .collect();
+        for (other, frames) in others {
+            // Announcements are best effort, rather skip one than wait on a slow peer.
+            let _ = frames.try_send(self.announcement(peer));
+            if frame_tx.send(self.announcement(&other)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Build a [`ControlFrame::PeerAnnounce`] for the given peer, with the addresses it is known
+    /// to listen on.
+    fn announcement(&self, key: &PublicKey) -> ControlFrame {
+        let mut addrs = Vec::new();
+        // A control connection we opened ourselves reaches a listener of the peer.
+        if let Some(con) = self.active_peers.lock().unwrap().get(key) {
+            if con.initiator != *key {
+                addrs.extend(con.remote);
+            }
+        }
+        if let Some(peer) = self
+            .peer_cache
+            .lock()
+            .unwrap()
+            .get(&Peer::new(key.clone(), Vec::new()))
+        {
+            for addr in peer.listen_addrs() {
+                if !addrs.contains(addr) {
+                    addrs.push(*addr);
+                }
+            }
+        }
+        addrs.truncate(MAX_ANNOUNCED_ADDRS);
+        ControlFrame::PeerAnnounce {
+            public_key: *key.as_bytes(),
+            addrs,
+        }
+    }
+
+    /// Drive the core. This future does not resolve until the listener is shut down.
+    async fn handle_connections(self: Arc<Self>, mut con_receiver: mpsc::Receiver<Connection>) {
+        loop {
+            let connection = tokio::select! {
+                connection = con_receiver.recv() => match connection {
+                    Some(connection) => {
+                        self.connection_queue.dequeued();
+                        connection
+                    }
+                    None => return,
+                },
+                _ = self.shutdown.cancelled() => return,
+            };
+            let peer = match connection {
+                Connection::Control(_, ref peer, _) | Connection::Data(_, ref peer) => peer,
+            };
+            if let Err(reason) = self.key_filter.read().unwrap().check(peer) {
+                // Dropping the connection closes it.
+                info!(
+                    "Rejected connection from {}: {}",
+                    self.address_scheme.derive(peer),
+                    reason
+                );
+                continue;
+            }
+            match connection {
+                Connection::Control(con, peer, version) => {
+                    // Inbound connections are always initiated by the remote.
+                    self.register_control_con(con, peer.clone(), peer, version);
+                }
+                Connection::Data(con, peer) => {
+                    // Inbound connections are always initiated by the remote.
+                    self.register_data_con(*con, peer.clone(), peer);
+                }
+            }
+        }
+    }
+
+    /// Open a control connection to the peer listening on the given address. The public key of
+    /// the peer is returned once the connection is established.
+    pub async fn connect_to_peer(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+    ) -> Result<PublicKey, handshake::Error> {
+        let (key, _) = self.dial_control(&addr.into()).await?;
+        Ok(key)
+    }
+
+    /// Keep a control connection to the peer listening on the given address. The connection is
+    /// opened in the background, and reopened with an exponential backoff whenever it fails or
+    /// is lost, until the peer is removed with [`Core::remove_persistent_peer`] or the instance
+    /// is shut down. Returns false if the peer was already persistent, or if the address is the
+    /// one we listen on ourselves.
+    ///
+    /// If the address is a hostname, it is resolved on every connection attempt, and all its
+    /// addresses are tried with [`Dialer::connect_any`].
+    pub fn add_persistent_peer(self: &Arc<Self>, addr: impl Into<PeerAddr>) -> bool {
+        let addr = addr.into();
+        if matches!(addr, PeerAddr::Socket(addr) if self.is_own_address(addr)) {
+            warn!(
+                "Not connecting to {}, which is our own listen address",
+                addr
+            );
+            return false;
+        }
+        let mut persistent_peers = self.persistent_peers.lock().unwrap();
+        if persistent_peers.contains_key(&addr) {
+            return false;
+        }
+        let task = tokio::spawn(self.clone().keep_connected(addr.clone()));
+        persistent_peers.insert(addr, PersistentPeer { task, key: None });
+        true
+    }
+
+    /// Stop reconnecting to the peer at the given address. An existing connection to the peer
+    /// is left open. Returns false if the peer was not persistent.
+    pub fn remove_persistent_peer(&self, addr: &PeerAddr) -> bool {
+        match self.persistent_peers.lock().unwrap().remove(addr) {
+            Some(peer) => {
+                peer.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replace the filter of peers which can connect to us. Connected peers which are not
+    /// allowed by the new filter are removed, see [`Core::remove_peer`].
+    pub async fn set_key_filter(&self, filter: KeyFilter) {
+        let rejected: Vec<_> = self
+            .connected_peers()
+            .into_iter()
+            .filter(|peer| filter.check(&peer.key).is_err())
+            .collect();
+        *self.key_filter.write().unwrap() = filter;
+        for peer in rejected {
+            info!("Removing peer {} which is no longer allowed", peer.address);
+            self.remove_peer(&peer.key).await;
+        }
+    }
+
+    /// Remove the peer with the given key: stop reconnecting to it if it is a persistent peer,
+    /// and close the control and data connections to it. The peer is sent a
+    /// [`DisconnectReason::Removed`] frame first, so it can tear down its side as well. The peer
+    /// can still connect to us again afterwards. Returns false if we neither had a connection to
+    /// the peer, nor was it a persistent peer.
+    pub async fn remove_peer(&self, key: &PublicKey) -> bool {
+        let mut removed = false;
+        // Stop reconnecting first, so the peer doesn't come back while we close the connections.
+        self.persistent_peers.lock().unwrap().retain(|addr, peer| {
+            if peer.key.as_ref() != Some(key) {
+                return true;
+            }
+            debug!(
+                "Removing persistent peer {} at {}",
+                self.address_scheme.derive(key),
+                addr
+            );
+            peer.task.abort();
+            removed = true;
+            false
+        });
+
+        let control = self.active_peers.lock().unwrap().remove(key);
+        if let Some(con) = control {
+            removed = true;
+            self.publish(CoreEvent::PeerDisconnected {
+                key: key.clone(),
+                address: self.address_scheme.derive(key),
+            });
+            if tokio::time::timeout(
+                DISCONNECT_TIMEOUT,
+                send_disconnect(&con.frames, DisconnectReason::Removed),
+            )
+            .await
+            .is_err()
+            {
+                debug!(
+                    "Peer {} did not receive disconnect frame",
+                    self.address_scheme.derive(key)
+                );
+            }
+            con.close.cancel();
+        }
+
+        let subnet = Subnet::from_address(self.address_scheme.derive(key));
+        removed |= self.active_data_peers.lock().unwrap().contains_key(&subnet);
+        self.remove_data_connection(key);
+        // Don't relay packets to or through the peer anymore either.
+        self.remove_route(&subnet);
+        self.routing_table.write().unwrap().remove_next_hop(key);
+        if removed {
+            info!("Removed peer {}", self.address_scheme.derive(key));
+        }
+        removed
+    }
+
+    /// Check if connecting to the given address would reach one of our own listeners.
+    fn is_own_address(&self, addr: SocketAddr) -> bool {
+        self.local_addrs().into_iter().any(|local| {
+            // If we listen on all addresses, we can at least be reached over loopback.
+            addr == local
+                || (local.ip().is_unspecified()
+                    && local.port() == addr.port()
+                    && (addr.ip().is_loopback() || addr.ip().is_unspecified()))
+        })
+    }
+
+    /// Addresses of all peers added with [`Core::add_persistent_peer`].
+    pub fn persistent_peers(&self) -> Vec<PeerAddr> {
+        self.persistent_peers
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Connect to the peer at the given address, and reconnect every time the connection is
+    /// lost or can't be established.
+    async fn keep_connected(self: Arc<Self>, addr: PeerAddr) {
+        let mut backoff = Backoff::new(RECONNECT_MIN_BACKOFF, RECONNECT_MAX_BACKOFF);
+        loop {
+            match self.dial_control(&addr).await {
+                Ok((key, task)) => {
+                    info!(
+                        "Connected to peer {} at {}",
+                        self.address_scheme.derive(&key),
+                        addr
+                    );
+                    if let Some(peer) = self.persistent_peers.lock().unwrap().get_mut(&addr) {
+                        peer.key = Some(key.clone());
+                    }
+                    let connected = Instant::now();
+                    tokio::select! {
+                        _ = task => (),
+                        _ = self.shutdown.cancelled() => return,
+                    }
+                    if connected.elapsed() >= RECONNECT_STABLE_AFTER {
+                        backoff.reset();
+                    }
+                    info!(
+                        "Lost connection to peer {} at {}",
+                        self.address_scheme.derive(&key),
+                        addr
+                    );
+                }
+                Err(e) => warn!("Failed to connect to peer at {}: {}", addr, e),
+            }
+            let delay = backoff.next_delay();
+            debug!("Reconnecting to {} in {:?}", addr, delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = self.shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    /// Open and register a control connection to the peer at the given address. If the address
+    /// resolves to multiple IP addresses, they are raced with [`Dialer::connect_any`]. Returns
+    /// the key of the peer, and the task processing the connection, which finishes once the
+    /// connection is closed.
+    async fn dial_control(
+        self: &Arc<Self>,
+        addr: &PeerAddr,
+    ) -> Result<(PublicKey, JoinHandle<()>), handshake::Error> {
+        if !self.is_accepting() {
+            return Err(handshake::Error::Io(std::io::Error::other(
+                "not accepting new connections",
+            )));
+        }
+        let mut remotes = addr.resolve().await?;
+        remotes.retain(|remote| !self.is_own_address(*remote));
+        let mut con = self.dialer.connect_any(&remotes).await?;
+        self.apply_socket_options(&con, ConnectionKind::Control);
+        let HandshakeResult { key, version, .. } = initiate_handshake(
+            &mut con,
+            &self.secret_key(),
+            ConnectionKind::Control,
+            Features::NONE,
+            self.address_scheme,
+        )
+        .await?;
+        // Our own addresses are filtered above, but we can't know all the addresses which reach
+        // us, e.g. through port forwarding.
+        if key == self.public_key() {
+            warn!(
+                "Peer at {} is ourselves, remove it from the configured peers",
+                addr
+            );
+            return Err(handshake::Error::SelfConnection);
+        }
+        debug!(
+            "Connected to peer {} at {}",
+            self.address_scheme.derive(&key),
+            addr
+        );
+        let task = self.register_control_con(con, key.clone(), self.public_key(), version);
+        Ok((key, task))
+    }
+
+    /// Open a data connection to the given peer in the background, if we opened the control
+    /// connection to it and there is no data connection to its subnet yet. The peer listens on
+    /// the address of the control connection. This waits for the [`ControlFrame::Hello`] of the
+    /// peer, so the features of the data connection can be agreed on. Peers which connected to
+    /// us open the data connection themselves.
+    fn dial_data(self: &Arc<Self>, peer: &PublicKey) {
+        let remote = match self.active_peers.lock().unwrap().get(peer) {
+            Some(con) if con.initiator != *peer => con.remote,
+            _ => None,
+        };
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return,
+        };
+        let subnet = Subnet::from_address(self.address_scheme.derive(peer));
+        if self.active_data_peers.lock().unwrap().contains_key(&subnet) {
+            return;
+        }
+        let core = self.clone();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = core.open_data_connection(remote, peer.clone()).await {
+                debug!(
+                    "Failed to open data connection to {}: {}",
+                    core.address_scheme.derive(&peer),
+                    e
+                );
+            }
+        });
+    }
+
+    /// Register a new control connection to the given peer, and start processing the frames
+    /// received on it, using the negotiated protocol version. Frames can be sent to the peer as
+    /// soon as this returns. The returned task finishes once the connection is closed.
+    ///
+    /// If a control connection to the peer already exists, only one of them is kept, according
+    /// to [`new_connection_wins`]. The other one is closed with a
+    /// [`DisconnectReason::Replaced`] frame.
+    fn register_control_con(
+        self: &Arc<Self>,
+        con: TcpStream,
+        peer: PublicKey,
+        initiator: PublicKey,
+        version: u8,
+    ) -> JoinHandle<()> {
+        let remote = con.peer_addr().ok();
+        // Connections the peer opened show where it connects from, rather than where it listens.
+        let observed_addr = remote.filter(|_| initiator == peer);
+        let framed = Framed::new(con, ControlCodec::with_version(version));
+        let (mut sink, stream) = framed.split();
+
+        let id = self.next_control_con_id.fetch_add(1, Ordering::Relaxed);
+        let close = CancellationToken::new();
+        let (frame_tx, mut frame_rx) = mpsc::channel(CONTROL_QUEUE_SIZE);
+        let mut active_peers = self.active_peers.lock().unwrap();
+        let connected = !active_peers.contains_key(&peer);
+        let keep = match active_peers.get(&peer) {
+            Some(existing) if !new_connection_wins(&existing.initiator, &initiator) => false,
+            Some(existing) => {
+                debug!(
+                    "Replacing control connection to {}",
+                    self.address_scheme.derive(&peer)
+                );
+                existing.close.cancel();
+                true
+            }
+            None => true,
+        };
+        if keep {
+            active_peers.insert(
+                peer.clone(),
+                ControlConnection {
+                    id,
+                    frames: frame_tx.clone(),
+                    initiator,
+                    close: close.clone(),
+                    remote,
+                    observed_addr,
+                    // Nothing is known about the peer until it answers a keepalive ping.
+                    health: match *self.keepalive.read().unwrap() {
+                        Some(_) => PeerHealth::Connecting,
+                        None => PeerHealth::Healthy,
+                    },
+                    mtu: None,
+                    capabilities: None,
+                    rtt: RttWindow::default(),
+                    decode_errors: 0,
+                },
+            );
+        } else {
+            debug!(
+                "Closing duplicate control connection to {}",
+                self.address_scheme.derive(&peer)
+            );
+            close.cancel();
+        }
+        drop(active_peers);
+        if connected {
+            self.publish(CoreEvent::PeerConnected {
+                key: peer.clone(),
+                address: self.address_scheme.derive(&peer),
+            });
+        }
+        let write_timeout = self.write_timeout();
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = frame_rx.recv().await {
+                // Nothing can be sent after a disconnect frame.
+                let last = matches!(frame, ControlFrame::Disconnect { .. });
+                match tokio::time::timeout(write_timeout, sink.send(frame)).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => {
+                        debug!("Failed to send control frame: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("Timed out sending control frame");
+                        return;
+                    }
+                }
+                if last {
+                    return;
+                }
+            }
+        });
+
+        // The queue is empty, so this can't fail.
+        let _ = frame_tx.try_send(ControlFrame::Hello {
+            mtu: self.mtu.min(u16::MAX as usize) as u16,
+            capabilities: self.capabilities().bits(),
+        });
+
+        tokio::spawn(
+            self.clone()
+                .spawn_control_con(stream, frame_tx, writer, peer, id, close),
+        )
+    }
+
+    /// Process frames received on a control connection until it is closed, or `close` is
+    /// cancelled.
+    async fn spawn_control_con(
+        self: Arc<Self>,
+        mut stream: SplitStream<Framed<TcpStream, ControlCodec>>,
+        frame_tx: mpsc::Sender<ControlFrame>,
+        mut writer: JoinHandle<()>,
+        mut peer: PublicKey,
+        id: u64,
+        close: CancellationToken,
+    ) {
+        let mut errored = false;
+        // Reason to tell the peer why we close the connection, if we close it.
+        let mut disconnect = None;
+        let keepalive = *self.keepalive.read().unwrap();
+        let mut next_keepalive = Instant::now() + keepalive.map(|k| k.interval).unwrap_or_default();
+        // ID and send time of the keepalive ping we are waiting on a pong for, if any.
+        let mut keepalive_ping: Option<(u32, Instant)> = None;
+        // Amount of keepalive pings in a row which were not answered in time.
+        let mut missed = 0;
+        loop {
+            let frame = tokio::select! {
+                frame = stream.next() => frame,
+                _ = tokio::time::sleep_until(next_keepalive.into()), if keepalive.is_some() => {
+                    let keepalive = keepalive.expect("branch is only enabled with keepalive");
+                    if keepalive_ping.is_some() {
+                        missed += 1;
+                        if missed >= keepalive.max_missed {
+                            debug!("Peer {} did not reply to {} keepalive pings", self.address_scheme.derive(&peer), missed);
+                            self.set_peer_health(&peer, id, PeerHealth::Dead);
+                            break;
+                        }
+                        debug!("Peer {} did not reply to keepalive ping", self.address_scheme.derive(&peer));
+                        self.set_peer_health(&peer, id, PeerHealth::Degraded);
+                    }
+                    let ping = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+                    if frame_tx.send(ControlFrame::Ping(ping)).await.is_err() {
+                        break;
+                    }
+                    keepalive_ping = Some((ping, Instant::now()));
+                    next_keepalive = Instant::now() + keepalive.timeout;
+                    continue;
+                }
+                _ = close.cancelled() => {
+                    disconnect = Some(DisconnectReason::Replaced);
+                    break;
+                }
+                // Frames can't be sent anymore, e.g. because the peer stopped reading.
+                _ = &mut writer => {
+                    debug!("Control connection to {} can't be written to", self.address_scheme.derive(&peer));
+                    break;
+                }
+                _ = self.shutdown.cancelled() => break,
+            };
+            match frame {
+                Some(Ok(frame)) => {
+                    errored = false;
+                    match frame {
+                        ControlFrame::Ping(id) => {
+                            if frame_tx.send(ControlFrame::Pong(id)).await.is_err() {
+                                break;
+                            }
+                        }
+                        ControlFrame::Pong(ping)
+                            if keepalive_ping.map(|(expected, _)| expected) == Some(ping) =>
+                        {
+                            let (_, sent) = keepalive_ping
+                                .take()
+                                .expect("guard checked a keepalive ping is outstanding");
+                            let keepalive =
+                                keepalive.expect("keepalive pings are only sent with keepalive");
+                            missed = 0;
+                            let rtt = sent.elapsed();
+                            self.record_rtt(&peer, rtt);
+                            let health = if rtt > keepalive.degraded_rtt {
+                                PeerHealth::Degraded
+                            } else {
+                                PeerHealth::Healthy
+                            };
+                            self.set_peer_health(&peer, id, health);
+                            next_keepalive = Instant::now() + keepalive.interval;
+                        }
+                        ControlFrame::Pong(id) => self.pong_received(&peer, id),
+                        ControlFrame::PeerAnnounce { public_key, addrs } => {
+                            self.peer_announced(&peer, public_key, addrs)
+                        }
+                        ControlFrame::Hello { mtu, capabilities } => {
+                            self.hello_received(&peer, id, mtu, Features::from_bits(capabilities));
+                            self.dial_data(&peer);
+                            if !self.exchange_announcements(&peer, &frame_tx).await {
+                                break;
+                            }
+                        }
+                        ControlFrame::Rotate {
+                            public_key,
+                            signature,
+                        } => match self.rotation_received(&peer, id, public_key, signature) {
+                            Ok(key) => {
+                                peer = key;
+                                self.dial_data(&peer);
+                            }
+                            Err(reason) => {
+                                disconnect = Some(reason);
+                                break;
+                            }
+                        },
+                        ControlFrame::Disconnect { reason } => {
+                            let reason = DisconnectReason::from_code(reason);
+                            info!(
+                                "Peer {} disconnected: {}",
+                                self.address_scheme.derive(&peer),
+                                reason
+                            );
+                            // A replaced connection means the peer is still there.
+                            if reason != DisconnectReason::Replaced {
+                                self.remove_data_connection(&peer);
+                            }
+                            break;
+                        }
+                    }
+                }
+                Some(Err(e)) => match ControlError::from_io(&e) {
+                    // The malformed frame is skipped, decoding continues with the next one.
+                    Some(err) if err.is_recoverable() => {
+                        self.record_decode_error(&peer, id);
+                        debug!(
+                            "Failed to decode control frame from {}: {}",
+                            self.address_scheme.derive(&peer),
+                            err
+                        );
+                        errored = true;
+                    }
+                    Some(err) => {
+                        self.record_decode_error(&peer, id);
+                        debug!(
+                            "Closing control connection to {}: {}",
+                            self.address_scheme.derive(&peer),
+                            err
+                        );
+                        disconnect = Some(DisconnectReason::ProtocolError);
+                        break;
+                    }
+                    None => {
+                        debug!(
+                            "Control connection to {} failed: {}",
+                            self.address_scheme.derive(&peer),
+                            e
+                        );
+                        break;
+                    }
+                },
+                // After an error, the stream returns None once, after which it continues
+                // decoding.
+                None if errored => errored = false,
+                None => break,
+            }
+        }
+
+        {
+            // The connection might already be replaced by a new one, which must be kept.
+            let mut active_peers = self.active_peers.lock().unwrap();
+            if active_peers.get(&peer).map(|con| con.id) == Some(id) {
+                active_peers.remove(&peer);
+                drop(active_peers);
+                self.routing_table.write().unwrap().remove_next_hop(&peer);
+                self.publish(CoreEvent::PeerDisconnected {
+                    address: self.address_scheme.derive(&peer),
+                    key: peer,
+                });
+            }
+        }
+        if let Some(reason) = disconnect {
+            let _ =
+                tokio::time::timeout(DISCONNECT_TIMEOUT, send_disconnect(&frame_tx, reason)).await;
+        }
+        writer.abort();
+    }
+
+    /// Close the data connection to the subnet of the given peer, if any, and forget how to
+    /// reopen it.
+    fn remove_data_connection(&self, peer: &PublicKey) {
+        let address = self.address_scheme.derive(peer);
+        let subnet = Subnet::from_address(address);
+        self.dial_addrs.lock().unwrap().remove(&subnet);
+        // Dropping the connection closes it once its queue is empty.
+        if self
+            .active_data_peers
+            .lock()
+            .unwrap()
+            .remove(&subnet)
+            .is_some()
+        {
+            debug!("Closed data connection to {}", subnet);
+            self.publish(CoreEvent::DataChannelDown {
+                key: peer.clone(),
+                address,
+            });
+        }
+    }
+
+    /// Open a data connection to the peer with the given public key, listening on the given
+    /// address.
+    pub async fn open_data_connection(
+        &self,
+        addr: SocketAddr,
+        peer: PublicKey,
+    ) -> Result<(), handshake::Error> {
+        if !self.is_accepting() {
+            return Err(handshake::Error::Io(std::io::Error::other(
+                "not accepting new connections",
+            )));
+        }
+        let mut con = self.dialer.connect(addr).await?;
+        self.apply_socket_options(&con, ConnectionKind::Data);
+        let identity = self.secret_key();
+        let (public_key, secret) = (identity.public_key(), identity.to_x25519());
+        let local_features = self.agreed_features(&peer, self.data_features());
+        let HandshakeResult { key, features, .. } = initiate_handshake(
+            &mut con,
+            &identity,
+            ConnectionKind::Data,
+            local_features,
+            self.address_scheme,
+        )
+        .await?;
+        if key != peer {
+            return Err(handshake::Error::KeyMismatch);
+        }
+        let session = self
+            .establish_session(&mut con, &secret, &public_key, &peer)
+            .await?;
+        let con = data_transport(
+            con,
+            session,
+            local_features.intersection(features),
+            self.max_packet_size,
+            self.replayed_packets.clone(),
+        )
+        .await?;
+        let subnet = Subnet::from_address(self.address_scheme.derive(&peer));
+        self.dial_addrs
+            .lock()
+            .unwrap()
+            .insert(subnet, (addr, peer.clone()));
+        self.register_data_con(con, peer, public_key);
+        Ok(())
+    }
+
+    /// Register a new data connection to the given peer. If a data connection to the subnet of
+    /// the peer already exists, only one of them is kept, according to
+    /// [`new_connection_wins`]. The other one is closed. Returns true if the new connection is
+    /// kept.
+    fn register_data_con(
+        &self,
+        con: impl Into<DataTransport>,
+        peer: PublicKey,
+        initiator: PublicKey,
+    ) -> bool {
+        let subnet = Subnet::from_address(self.address_scheme.derive(&peer));
+        let mut active_data_peers = self.active_data_peers.lock().unwrap();
+        if let Some(existing) = active_data_peers.get(&subnet) {
+            if !new_connection_wins(&existing.initiator, &initiator) {
+                debug!("Closing duplicate data connection to {}", subnet);
+                // Dropping the stream closes it.
+                return false;
+            }
+            debug!("Replacing existing data connection to {}", subnet);
+        }
+        let event = (!active_data_peers.contains_key(&subnet)).then(|| CoreEvent::DataChannelUp {
+            key: peer.clone(),
+            address: self.address_scheme.derive(&peer),
+        });
+        // The old connection, if any, is dropped here. It sends the packets which are still in
+        // its queue, and closes afterwards.
+        active_data_peers.insert(
+            subnet,
+            self.spawn_data_connection(subnet, con, peer, initiator),
+        );
+        drop(active_data_peers);
+        if let Some(event) = event {
+            self.publish(event);
+        }
+        true
+    }
+
+    /// Start driving a data connection to the given subnet. The connection must still be
+    /// registered in `active_data_peers`.
+    fn spawn_data_connection(
+        &self,
+        subnet: Subnet,
+        con: impl Into<DataTransport>,
+        peer: PublicKey,
+        initiator: PublicKey,
+    ) -> DataConnection {
+        let con = con.into();
+        let id = self.next_data_con_id.fetch_add(1, Ordering::Relaxed);
+        let (packets, packet_rx) = mpsc::channel(DATA_QUEUE_SIZE);
+        let activity = Arc::new(LastActivity::new());
+        let ctx = DataConContext {
+            subnet,
+            // Spread the connections over the queues.
+            tun: (!self.tun.is_empty()).then(|| self.tun[id as usize % self.tun.len()].clone()),
+            relay: self.relay.clone(),
+            id,
+            queues: self.connection_queues(subnet),
+            activity: activity.clone(),
+            active_data_peers: self.active_data_peers.clone(),
+            spoofed_packets: self.spoofed_packets.clone(),
+            routing_table: self.routing_table.clone(),
+            traffic: self.traffic.clone(),
+            peer_traffic: self.peer_traffic(&peer),
+            rate_limit: *self.rate_limit.read().unwrap(),
+            write_timeout: self.write_timeout(),
+            capture: self.capture.clone(),
+            address: self.address_scheme.derive(&peer),
+            events: self.events.clone(),
+        };
+        DataConnection {
+            id,
+            packets,
+            peer: peer.clone(),
+            initiator,
+            max_packet_size: con.max_packet_size(),
+            activity,
+            task: tokio::spawn(Core::spawn_data_con(con, peer, packet_rx, ctx)),
+        }
+    }
+
+    /// Drive a data connection to the given peer, sending packets in the order they are queued,
+    /// and writing received packets to the TUN interface. Once the queue is closed, the
+    /// connection is closed as well. If the connection closes for another reason, e.g. because
+    /// the remote closed it, it is removed from `active_data_peers`.
+    async fn spawn_data_con(
+        mut transport: DataTransport,
+        peer: PublicKey,
+        mut packets: mpsc::Receiver<Bytes>,
+        ctx: DataConContext,
+    ) {
+        let mut rate_limit = ctx.rate_limit.map(TokenBucket::new);
+        // Time until which the connection is not read from, because the remote exceeded the rate
+        // limit.
+        let mut throttled_until = None;
+        loop {
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => {
+                        ctx.activity.touch();
+                        ctx.queues.send.dequeued();
+                        let len = packet.len();
+                        match tokio::time::timeout(ctx.write_timeout, transport.send_packet(packet)).await {
+                            Ok(Ok(())) => (),
+                            Ok(Err(e)) => {
+                                debug!("Failed to send packet to {}: {}", ctx.address, e);
+                                break;
+                            }
+                            Err(_) => {
+                                debug!("Timed out sending packet to {}", ctx.address);
+                                break;
+                            }
+                        }
+                        ctx.traffic.sent(len);
+                        ctx.peer_traffic.sent(len);
+                    }
+                    // All queued packets are sent.
+                    None => break,
+                },
+                packet = transport.recv_packet(), if throttled_until.is_none() => match packet {
+                    Some(Ok(packet)) => {
+                        ctx.activity.touch();
+                        ctx.traffic.received(packet.len());
+                        ctx.peer_traffic.received(packet.len());
+                        if let Some(ref mut bucket) = rate_limit {
+                            throttled_until = bucket.take(packet.len());
+                        }
+                        // The remote is only allowed to send packets from its own subnet, or relay
+                        // packets from subnets we route through it, so it can't impersonate other
+                        // nodes.
+                        let allowed = PacketMeta::from_ipv6(&packet).is_some_and(|meta| {
+                            meta.src == ctx.subnet
+                                || ctx.routing_table.read().unwrap().next_hop(&meta.src) == Some(&peer)
+                        });
+                        if !allowed {
+                            debug!("Dropping packet from {} with spoofed source", ctx.address);
+                            ctx.spoofed_packets.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                        if let Some(ref relay) = ctx.relay {
+                            // Rather drop the packet than stall the connection if routing can't
+                            // keep up.
+                            if relay.try_send(packet.freeze()).is_err() {
+                                debug!("Dropping packet from {}, relay queue is full", ctx.address);
+                            }
+                            continue;
+                        }
+                        if let Some(ref tun) = ctx.tun {
+                            if let Some(ref capture) = ctx.capture {
+                                capture.capture(&packet);
+                            }
+                            // A single packet which can't be written is not a reason to close
+                            // the connection.
+                            match tokio::time::timeout(ctx.write_timeout, tun.send(&packet)).await {
+                                Ok(Ok(_)) => (),
+                                Ok(Err(e)) => debug!("Failed to write packet from {} to TUN: {}", ctx.address, e),
+                                Err(_) => debug!("Timed out writing packet from {} to TUN", ctx.address),
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        debug!("Failed to receive packet from {}: {}", ctx.address, e);
+                        break;
+                    }
+                    None => {
+                        debug!("Data connection to {} closed by remote", ctx.address);
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep_until(throttled_until.unwrap_or_else(tokio::time::Instant::now)), if throttled_until.is_some() => {
+                    throttled_until = None;
+                }
+            }
+        }
+
+        // Closing flushes the connection, which takes forever if the remote stopped reading.
+        match tokio::time::timeout(ctx.write_timeout, transport.close()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => debug!("Failed to close data connection: {}", e),
+            Err(_) => debug!("Timed out closing data connection to {}", ctx.address),
+        }
+
+        let mut active_data_peers = ctx.active_data_peers.lock().unwrap();
+        // The connection might have been replaced in the meantime.
+        if active_data_peers.get(&ctx.subnet).map(|con| con.id) == Some(ctx.id) {
+            active_data_peers.remove(&ctx.subnet);
+            let _ = ctx.events.send(CoreEvent::DataChannelDown {
+                key: peer,
+                address: ctx.address,
+            });
+        }
+    }
+
+    /// Read packets from the TUN interface, and forward them to the data connection of the subnet
+    /// they are addressed to. Packets which can't be forwarded are dropped, without affecting
+    /// other packets. Reading stops once the instance is shut down, or if the interface can't be
+    /// read anymore.
+    async fn read_tun(self: Arc<Self>, tun: Arc<Tun>) {
+        // Packets are handed to the data connections without copying them. The interface can't
+        // hand us packets larger than its MTU, even if jumbo packets are enabled.
+        let mut buf = PacketBuffer::new(self.max_packet_size.min(crate::tun::MAX_MTU as usize));
+        loop {
+            let n = tokio::select! {
+                res = tun.recv(buf.slot()) => match res {
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Failed to read from TUN interface {}: {}", tun.name(), e);
+                        return;
+                    }
+                },
+                _ = self.shutdown.cancelled() => return,
+            };
+            let packet = buf.take(n);
+            if let Some(capture) = &self.capture {
+                capture.capture(&packet);
+            }
+            self.route_packet(packet).await;
+        }
+    }
+
+    /// Route packets received on data connections in relay-only mode, as if they were read from
+    /// the interface. Without an interface, packets addressed to a local subnet are dropped.
+    async fn relay_packets(self: Arc<Self>, mut packets: mpsc::Receiver<Bytes>) {
+        loop {
+            let packet = tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => packet,
+                    None => return,
+                },
+                _ = self.shutdown.cancelled() => return,
+            };
+            self.route_packet(packet).await;
+        }
+    }
+
+    /// Start listening for new inbound connections on the listener, passing them to `tx` once
+    /// their handshake completes.
+    async fn start_listener(
+        self: Arc<Self>,
+        listener: Arc<TcpListener>,
+        mut accepting: watch::Receiver<bool>,
+        tx: mpsc::Sender<Connection>,
+    ) {
+        loop {
+            // While paused, new connections queue up in the backlog of the listener.
+            while !*accepting.borrow_and_update() {
+                tokio::select! {
+                    res = accepting.changed() => if res.is_err() {
+                        // Core is gone.
+                        return;
+                    },
+                    _ = self.shutdown.cancelled() => return,
+                }
+            }
+            // Only accept a connection once there is room for its handshake.
+            let slots = self.handshake_slots.read().unwrap().clone();
+            let permit = tokio::select! {
+                permit = slots.acquire_owned() => permit.expect("semaphore is never closed"),
+                // Accepting might have been paused, check again.
+                _ = accepting.changed() => continue,
+                _ = self.shutdown.cancelled() => return,
+            };
+            let (mut con, remote) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Most errors are transient, and only affect the connection which is
+                        // being accepted, don't stop accepting others.
+                        warn!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                },
+                // Accepting might have been paused, check again.
+                _ = accepting.changed() => continue,
+                _ = self.shutdown.cancelled() => return,
+            };
+            debug!("Accepted new connection from {}", remote);
+            let tx = tx.clone();
+            let (public_key, secret) = self.session_identity();
+            let address_scheme = self.address_scheme;
+            let max_packet_size = self.max_packet_size;
+            let control_options = self.socket_options(ConnectionKind::Control);
+            let data_options = self.socket_options(ConnectionKind::Data);
+            let data_features = self.data_features();
+            let handshake_timeout = self.handshake_timeout();
+            let core = self.clone();
+            tokio::spawn(async move {
+                // A remote which doesn't complete the handshake would otherwise tie up this task
+                // forever.
+                let establish_core = core.clone();
+                let establish = async move {
+                    let core = establish_core;
+                    let HandshakeResult {
+                        key,
+                        kind,
+                        version,
+                        features,
+                    } = match accept_handshake(&mut con, &public_key, data_features, address_scheme)
+                        .await
+                    {
+                        Ok(res) => res,
+                        Err(e) => {
+                            // It could be that the remote closed the connection, which is fine
+                            debug!("Connection to {} closed during handshake: {}", remote, e);
+                            return None;
+                        }
+                    };
+                    if key == public_key {
+                        warn!(
+                            "Closing connection from {} which identified as ourselves",
+                            remote
+                        );
+                        return None;
+                    }
+                    // The kind of the connection is only known after the handshake.
+                    let options = match kind {
+                        ConnectionKind::Control => control_options,
+                        ConnectionKind::Data => data_options,
+                    };
+                    if let Err(e) = options.apply(&con) {
+                        warn!("Failed to set socket options for {}: {}", remote, e);
+                    }
+                    Some(match kind {
+                        ConnectionKind::Control => Connection::Control(con, key, version),
+                        ConnectionKind::Data => {
+                            let session = match core
+                                .establish_session(&mut con, &secret, &public_key, &key)
+                                .await
+                            {
+                                Ok(session) => session,
+                                Err(e) => {
+                                    debug!("Failed to establish session with {}: {}", remote, e);
+                                    return None;
+                                }
+                            };
+                            let features =
+                                core.agreed_features(&key, data_features.intersection(features));
+                            let replayed = core.replayed_packets.clone();
+                            match data_transport(con, session, features, max_packet_size, replayed)
+                                .await
+                            {
+                                Ok(transport) => Connection::Data(Box::new(transport), key),
+                                Err(e) => {
+                                    debug!(
+                                        "Failed to set up data transport with {}: {}",
+                                        remote, e
+                                    );
+                                    return None;
+                                }
+                            }
+                        }
+                    })
+                };
+                let res = tokio::time::timeout(handshake_timeout, establish).await;
+                drop(permit);
+                let connection = match res {
+                    Ok(Some(connection)) => connection,
+                    Ok(None) => return,
+                    Err(_) => {
+                        debug!(
+                            "Handshake with {} not completed within {:?}",
+                            remote, handshake_timeout
+                        );
+                        return;
+                    }
+                };
+                core.queue_connection(&tx, connection).await;
+            });
+        }
+    }
+
+    /// Pass a connection which completed its handshake to [`Core::handle_connections`]. If the
+    /// connection queue is full, the core can't keep up with the rate at which connections
+    /// arrive. Control connections then wait for room, as dropping one makes the peer reconnect
+    /// with a backoff, and holds up the exchange of routes. Data connections are dropped
+    /// instead, so a burst of them doesn't pile up waiting tasks: the remote reopens them once it
+    /// has packets to send. Both cases are counted, see [`Core::connection_queue_blocked`] and
+    /// [`Core::connection_queue_dropped`].
+    async fn queue_connection(&self, tx: &mpsc::Sender<Connection>, connection: Connection) {
+        // Account for the connection first, so it can't be dequeued before it is counted.
+        self.connection_queue.enqueued();
+        let connection = match tx.try_send(connection) {
+            Ok(()) => return,
+            Err(TrySendError::Full(connection @ Connection::Control(..))) => connection,
+            Err(TrySendError::Full(Connection::Data(_, peer))) => {
+                debug!(
+                    "Dropping data connection from {}, connection queue is full",
+                    self.address_scheme.derive(&peer)
+                );
+                self.connection_queue.dequeued();
+                self.connection_queue_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.connection_queue.dequeued();
+                error!("Could not pass connection to core: channel closed");
+                return;
+            }
+        };
+        debug!("Connection queue is full, waiting for room");
+        self.connection_queue_blocked
+            .fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = tx.send(connection).await {
+            self.connection_queue.dequeued();
+            // Couldn't send data to core
+            error!("Could not pass connection to core: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{MAX_CONSECUTIVE_DECODE_ERRORS, PROTO_VERSION};
+    use crate::data::DEFAULT_MAX_PACKET_SIZE;
+    use crate::handshake::{answer_challenge, read_handshake, write_handshake};
+    use crate::transport::DataStream;
+    use crate::tun::DEFAULT_MTU;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Open a connection of the given kind to the core, as the peer with the given key.
+    async fn connect(core: &Core, peer: &SecretKey, kind: ConnectionKind) -> TcpStream {
+        let mut con = TcpStream::connect(core.local_addrs()[0]).await.unwrap();
+        initiate_handshake(
+            &mut con,
+            peer,
+            kind,
+            Features::NONE,
+            AddressScheme::Yggdrasil,
+        )
+        .await
+        .unwrap();
+        con
+    }
+
+    /// Accept a connection opened by a core, as the peer with the given key.
+    async fn accept(listener: &TcpListener, peer: &PublicKey) -> (TcpStream, HandshakeResult) {
+        let (mut con, _) = listener.accept().await.unwrap();
+        let res = accept_handshake(&mut con, peer, Features::NONE, AddressScheme::Yggdrasil)
+            .await
+            .unwrap();
+        (con, res)
+    }
+
+    /// Wrap a control connection to a core, skipping the [`ControlFrame::Hello`] the core sends
+    /// first.
+    async fn control_framed(con: TcpStream) -> Framed<TcpStream, ControlCodec> {
+        let mut con = Framed::new(con, ControlCodec::new());
+        match tokio::time::timeout(Duration::from_secs(5), con.next()).await {
+            Ok(Some(Ok(ControlFrame::Hello { .. }))) => con,
+            _ => panic!("expected a hello frame"),
+        }
+    }
+
+    /// Open a data connection to the core, as the peer with the given key.
+    async fn connect_data(core: &Core, peer: &SecretKey) -> DataStream {
+        let public_key = peer.public_key();
+        let mut con = connect(core, peer, ConnectionKind::Data).await;
+        let session =
+            Session::establish(&mut con, &peer.to_x25519(), &public_key, &core.public_key())
+                .await
+                .unwrap();
+        Framed::new(
+            con,
+            EncryptedDataCodec::new(session, DEFAULT_MAX_PACKET_SIZE),
+        )
+    }
+
+    /// Accept a data connection opened by a core, as the peer with the given key.
+    async fn accept_data(
+        listener: &TcpListener,
+        peer: &SecretKey,
+    ) -> (DataStream, HandshakeResult) {
+        let public_key = peer.public_key();
+        let (mut con, res) = accept(listener, &public_key).await;
+        let session = Session::establish(&mut con, &peer.to_x25519(), &public_key, &res.key)
+            .await
+            .unwrap();
+        let con = Framed::new(
+            con,
+            EncryptedDataCodec::new(session, DEFAULT_MAX_PACKET_SIZE),
+        );
+        (con, res)
+    }
+
+    /// Open a connection to the listener, and establish a session on it, without a handshake.
+    /// Returns the accepted side, with the session of `local`, and the connected side, with the
+    /// session of `remote`.
+    async fn data_stream_pair(
+        listener: &TcpListener,
+        local: &SecretKey,
+        remote: &SecretKey,
+    ) -> (DataStream, DataStream) {
+        let addr = listener.local_addr().unwrap();
+        let (remote_con, local_con) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (mut remote_con, (mut local_con, _)) = (remote_con.unwrap(), local_con.unwrap());
+        let (local_secret, remote_secret) = (local.to_x25519(), remote.to_x25519());
+        let (local_key, remote_key) = (local.public_key(), remote.public_key());
+        let (local_session, remote_session) = tokio::join!(
+            Session::establish(&mut local_con, &local_secret, &local_key, &remote_key),
+            Session::establish(&mut remote_con, &remote_secret, &remote_key, &local_key),
+        );
+        (
+            Framed::new(
+                local_con,
+                EncryptedDataCodec::new(local_session.unwrap(), DEFAULT_MAX_PACKET_SIZE),
+            ),
+            Framed::new(
+                remote_con,
+                EncryptedDataCodec::new(remote_session.unwrap(), DEFAULT_MAX_PACKET_SIZE),
+            ),
+        )
+    }
+
+    /// Build a minimal IPv6 packet without payload.
+    fn ipv6_packet(src: Ipv6Addr, dst: Ipv6Addr) -> Bytes {
+        let mut packet = vec![0; 40];
+        packet[0] = 6 << 4;
+        // No next header.
+        packet[6] = 59;
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&src.octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+        packet.into()
+    }
+
+    /// Start building a [`Core`] with the identity tests use for the local node.
+    fn test_builder(listener: TcpListener) -> CoreBuilder {
+        CoreBuilder::new(SecretKey::from_bytes([1; 32])).listener(listener)
+    }
+
+    /// Create a [`Core`] without running it, so tests can freely set up the state without
+    /// background tasks interfering.
+    fn test_core(listener: TcpListener) -> Arc<Core> {
+        test_builder(listener).assemble().0
+    }
+
+    #[tokio::test]
+    async fn rotating_identity_keeps_data_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (local, mut remote) = data_stream_pair(
+            &listener,
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([3; 32]),
+        )
+        .await;
+
+        let subnet = Subnet::new([1; 8]);
+        let core = test_core(listener);
+        core.active_data_peers.lock().unwrap().insert(
+            subnet,
+            core.spawn_data_connection(subnet, local, core.public_key(), core.public_key()),
+        );
+        let old_address = core.address();
+
+        // Data is in flight while the identity is rotated.
+        assert!(core.send_packet(subnet, Bytes::from_static(b"in flight")));
+        let old = core.rotate_identity(SecretKey::from_bytes([2; 32]));
+        assert_eq!(old.as_bytes(), &[1; 32]);
+        assert_ne!(core.address(), old_address);
+
+        assert_eq!(&remote.next().await.unwrap().unwrap()[..], b"in flight");
+        assert!(core.send_packet(subnet, Bytes::from_static(b"after rotation")));
+        assert_eq!(
+            &remote.next().await.unwrap().unwrap()[..],
+            b"after rotation"
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_learn_rotated_identity() {
+        let new_core = |seed: u8| async move {
+            let (core, run) = CoreBuilder::new(SecretKey::from_bytes([seed; 32]))
+                .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+                .assemble();
+            tokio::spawn(run);
+            core
+        };
+        let core = new_core(1).await;
+        let peer = new_core(2).await;
+        peer.connect_to_peer(core.local_addrs()[0]).await.unwrap();
+        let direct = |subnet: Subnet| {
+            peer.reachable_subnets()
+                .iter()
+                .any(|(s, kind)| *s == subnet && matches!(kind, RouteKind::Direct))
+        };
+        let old_subnet = Subnet::from_address(core.address());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !direct(old_subnet) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut events = core.subscribe();
+        core.rotate_identity(SecretKey::from_bytes([3; 32]));
+        assert_eq!(
+            next_event(&mut events).await,
+            CoreEvent::IdentityRotated {
+                key: core.public_key(),
+                address: core.address(),
+            }
+        );
+        let new_subnet = Subnet::from_address(core.address());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !direct(new_subnet) || direct(old_subnet) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let peers = peer.connected_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].key, core.public_key());
+        assert_eq!(peers[0].address, core.address());
+
+        // Packets to the new address arrive over the new data connection.
+        assert!(
+            peer.route_packet(ipv6_packet(peer.address(), core.address()))
+                .await
+        );
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.bytes_rx() < 40 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        for core in [core, peer] {
+            core.shutdown(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_direct_and_learned_subnets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (con, _remote) = data_stream_pair(
+            &listener,
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+
+        let next_hop = SecretKey::from_bytes([2; 32]).public_key();
+        let direct = Subnet::new([1; 8]);
+        let learned = Subnet::new([2; 8]);
+
+        let core = test_core(listener);
+        core.add_route(learned, next_hop.clone());
+        core.active_data_peers.lock().unwrap().insert(
+            direct,
+            core.spawn_data_connection(direct, con, next_hop.clone(), next_hop.clone()),
+        );
+
+        let subnets = core.reachable_subnets();
+        assert_eq!(subnets.len(), 2);
+        for (subnet, kind) in subnets {
+            match kind {
+                RouteKind::Direct => assert_eq!(subnet, direct),
+                RouteKind::Learned(hop) => {
+                    assert_eq!(subnet, learned);
+                    assert_eq!(hop.as_bytes(), next_hop.as_bytes());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_queue_depths() {
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let subnet = Subnet::new([1; 8]);
+
+        let queues = core.connection_queues(subnet);
+        for _ in 0..4 {
+            queues.send.enqueued();
+        }
+        queues.send.dequeued();
+
+        let depths = core.queue_depths();
+        assert_eq!(depths.len(), 1);
+        assert_eq!(depths[0].0, subnet);
+        assert_eq!(depths[0].1.send, 3);
+        assert_eq!(depths[0].1.send_peak, 4);
+
+        queues.send.dequeued();
+        core.reset_stats();
+        assert_eq!(core.queue_depths()[0].1.send_peak, 2);
+    }
+
+    #[tokio::test]
+    async fn multiple_instances_form_a_mesh() {
+        let mut cores = Vec::new();
+        for i in 1..=3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            cores.push(Core::new(SecretKey::from_bytes([i; 32]), listener));
+        }
+
+        let addresses: HashSet<_> = cores.iter().map(|core| core.address()).collect();
+        assert_eq!(addresses.len(), 3);
+
+        // Connect every instance to every other instance.
+        for (i, local) in cores.iter().enumerate() {
+            for remote in cores.iter().skip(i + 1) {
+                let key = local
+                    .connect_to_peer(remote.local_addrs()[0])
+                    .await
+                    .unwrap();
+                assert_eq!(key, remote.public_key());
+            }
+        }
+
+        // All connections are served, in both directions.
+        for (i, local) in cores.iter().enumerate() {
+            for (j, remote) in cores.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let rtt = loop {
+                    match local
+                        .ping(&remote.public_key(), Duration::from_secs(1))
+                        .await
+                    {
+                        Ok(rtt) => break rtt,
+                        // The inbound side might not have registered the connection yet.
+                        Err(PingError::UnknownPeer) => {
+                            tokio::time::sleep(Duration::from_millis(1)).await
+                        }
+                        Err(e) => panic!("Ping from {} to {} failed: {}", i, j, e),
+                    }
+                };
+                assert!(rtt < Duration::from_secs(1));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_control_connection_after_repeated_decode_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+        let peer = SecretKey::from_bytes([2; 32]);
+
+        let mut con = connect(&core, &peer, ConnectionKind::Control).await;
+        for i in 0..MAX_CONSECUTIVE_DECODE_ERRORS {
+            // Frame of an unknown type.
+            con.write_all(&[0, 255, 0, 0]).await.unwrap();
+            // Give the remote time to process the frame, as it only continues decoding after an
+            // error once new data arrives.
+            while core.control_decode_errors() <= i {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        }
+
+        // Remote told us why, and closed the connection.
+        let mut con = control_framed(con).await;
+        let timeout = std::time::Duration::from_secs(1);
+        match tokio::time::timeout(timeout, con.next()).await.unwrap() {
+            Some(Ok(ControlFrame::Disconnect { reason })) => assert_eq!(
+                DisconnectReason::from_code(reason),
+                DisconnectReason::ProtocolError
+            ),
+            _ => panic!("Expected a disconnect frame"),
+        }
+        assert!(tokio::time::timeout(timeout, con.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(core.control_decode_errors(), MAX_CONSECUTIVE_DECODE_ERRORS);
+    }
+
+    #[tokio::test]
+    async fn survives_single_malformed_control_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+
+        let mut con = connect(&core, &peer_secret, ConnectionKind::Control).await;
+        // Ping frame which is too short to hold an ID.
+        con.write_all(&[0, 0, 0, 2, 0, 0]).await.unwrap();
+        while core.control_decode_errors() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let mut con = control_framed(con).await;
+        con.send(ControlFrame::Ping(7)).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), con.next()).await {
+            Ok(Some(Ok(ControlFrame::Pong(7)))) => (),
+            _ => panic!("Expected a pong with ID 7"),
+        }
+        assert!(core.active_peers.lock().unwrap().contains_key(&peer));
+        assert_eq!(core.connected_peers()[0].control_decode_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn ping_reports_rtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+
+        // Remote which answers pings.
+        let con = connect(&core, &peer_secret, ConnectionKind::Control).await;
+        tokio::spawn(async move {
+            let mut framed = control_framed(con).await;
+            while let Some(Ok(frame)) = framed.next().await {
+                if let ControlFrame::Ping(id) = frame {
+                    framed.send(ControlFrame::Pong(id)).await.unwrap();
+                }
+            }
+        });
+
+        let timeout = Duration::from_secs(1);
+        let rtt = loop {
+            match core.ping(&peer, timeout).await {
+                Ok(rtt) => break rtt,
+                // Connection might not be registered yet.
+                Err(PingError::UnknownPeer) => tokio::time::sleep(Duration::from_millis(1)).await,
+                Err(e) => panic!("Ping failed: {}", e),
+            }
+        };
+        assert!(rtt < timeout);
+        assert!(core.outstanding_pings.lock().unwrap().is_empty());
+        let stats = core.connected_peers()[0].rtt.unwrap();
+        assert_eq!((stats.last, stats.min, stats.max), (rtt, rtt, rtt));
+    }
+
+    #[tokio::test]
+    async fn ignores_unknown_pongs() {
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let other = SecretKey::from_bytes([3; 32]).public_key();
+
+        let (tx, mut rx) = oneshot::channel();
+        core.outstanding_pings.lock().unwrap().insert(
+            1,
+            OutstandingPing {
+                peer: peer.clone(),
+                sent: Instant::now(),
+                rtt: tx,
+            },
+        );
+
+        // Unknown ID, and a pong from a peer the ping was not sent to.
+        core.pong_received(&peer, 2);
+        core.pong_received(&other, 1);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(core.outstanding_pings.lock().unwrap().len(), 1);
+
+        core.pong_received(&peer, 1);
+        assert!(rx.try_recv().is_ok());
+        // Already answered.
+        core.pong_received(&peer, 1);
+        assert!(core.outstanding_pings.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_data_connection_replaces_old_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+        let peer = SecretKey::from_bytes([2; 32]);
+
+        let mut old = connect_data(&core, &peer).await;
+        let mut new = connect_data(&core, &peer).await;
+
+        // Old connection is closed once the new one is registered.
+        assert!(tokio::time::timeout(Duration::from_secs(1), old.next())
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(core.active_data_peers.lock().unwrap().len(), 1);
+        assert!(tokio::time::timeout(Duration::from_millis(50), new.next())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn simultaneous_connections_keep_lowest_initiator() {
+        let low = SecretKey::from_bytes([1; 32]).public_key();
+        let high = SecretKey::from_bytes([2; 32]).public_key();
+        let (low, high) = if low.as_bytes() < high.as_bytes() {
+            (low, high)
+        } else {
+            (high, low)
+        };
+
+        assert!(new_connection_wins(&high, &low));
+        assert!(!new_connection_wins(&low, &high));
+        assert!(new_connection_wins(&low, &low));
+    }
+
+    #[tokio::test]
+    async fn connects_to_peer() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+
+        let (key, (con, handshake)) = tokio::join!(
+            core.connect_to_peer(remote.local_addr().unwrap()),
+            accept(&remote, &peer)
+        );
+        assert_eq!(key.unwrap(), peer);
+        assert_eq!(handshake.key, core.public_key());
+        assert_eq!(handshake.kind, ConnectionKind::Control);
+        assert!(core.active_peers.lock().unwrap().contains_key(&peer));
+
+        // The connection is served like an inbound one.
+        let mut con = control_framed(con).await;
+        con.send(ControlFrame::Ping(1)).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), con.next()).await {
+            Ok(Some(Ok(ControlFrame::Pong(1)))) => (),
+            _ => panic!("Expected a pong with ID 1"),
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_data_connection_after_hello() {
+        let local = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let remote = Core::new(
+            SecretKey::from_bytes([2; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let mut local_events = local.subscribe();
+        let mut remote_events = remote.subscribe();
+
+        local
+            .connect_to_peer(remote.local_addrs()[0])
+            .await
+            .unwrap();
+        for (events, peer) in [
+            (&mut local_events, remote.public_key()),
+            (&mut remote_events, local.public_key()),
+        ] {
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+                    Ok(Ok(CoreEvent::DataChannelUp { key, .. })) => {
+                        assert_eq!(key, peer);
+                        break;
+                    }
+                    Ok(Ok(_)) => (),
+                    _ => panic!("Expected a data connection to be opened"),
+                }
+            }
+        }
+        assert_eq!(local.active_data_peers(), 1);
+        assert_eq!(remote.active_data_peers(), 1);
+
+        // Packets to the peer are routed over the new connection.
+        let packet = ipv6_packet(local.address(), remote.address());
+        assert!(local.route_packet(packet).await);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while remote.bytes_rx() == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnects_to_persistent_peers() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = remote.local_addr().unwrap();
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+
+        assert!(core.add_persistent_peer(addr));
+        assert!(!core.add_persistent_peer(addr));
+        assert_eq!(core.persistent_peers(), vec![PeerAddr::from(addr)]);
+        // We never dial ourselves.
+        assert!(!core.add_persistent_peer(core.local_addrs()[0]));
+        assert_eq!(core.persistent_peers(), vec![PeerAddr::from(addr)]);
+
+        let (con, _) = accept(&remote, &peer).await;
+        drop(con);
+        // The connection is reopened after the initial backoff.
+        let (_con, handshake) =
+            tokio::time::timeout(RECONNECT_MIN_BACKOFF * 2, accept(&remote, &peer))
+                .await
+                .unwrap();
+        assert_eq!(handshake.kind, ConnectionKind::Control);
+
+        assert!(core.remove_persistent_peer(&addr.into()));
+        assert!(!core.remove_persistent_peer(&addr.into()));
+        assert!(core.persistent_peers().is_empty());
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn closes_control_connections_without_keepalive_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        core.set_keepalive(Some(Keepalive {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(50),
+            max_missed: 1,
+            degraded_rtt: DEFAULT_DEGRADED_RTT,
+        }));
+        let (local, remote) = tokio::join!(
+            TcpStream::connect(core.local_addrs()[0]),
+            core.listeners[0].accept()
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let task =
+            core.register_control_con(remote.unwrap().0, peer.clone(), peer.clone(), PROTO_VERSION);
+        let mut local = control_framed(local.unwrap()).await;
+
+        // As long as pings are answered, the connection stays open.
+        for _ in 0..3 {
+            let ControlFrame::Ping(id) = local.next().await.unwrap().unwrap() else {
+                panic!("expected a ping");
+            };
+            local.send(ControlFrame::Pong(id)).await.unwrap();
+        }
+        assert!(core.active_peers.lock().unwrap().contains_key(&peer));
+
+        // Ignore the next ping.
+        assert!(matches!(
+            local.next().await.unwrap().unwrap(),
+            ControlFrame::Ping(_)
+        ));
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!core.active_peers.lock().unwrap().contains_key(&peer));
+        assert!(local.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tracks_peer_health_from_keepalive_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        core.set_keepalive(Some(Keepalive {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(50),
+            max_missed: 3,
+            degraded_rtt: Duration::from_millis(30),
+        }));
+        let mut events = core.subscribe();
+        let (local, remote) = tokio::join!(
+            TcpStream::connect(core.local_addrs()[0]),
+            core.listeners[0].accept()
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let address = AddressScheme::Yggdrasil.derive(&peer);
+        let health = |core: &Core| core.active_peers.lock().unwrap()[&peer].health;
+        let task =
+            core.register_control_con(remote.unwrap().0, peer.clone(), peer.clone(), PROTO_VERSION);
+        let mut local = control_framed(local.unwrap()).await;
+        assert_eq!(health(&core), PeerHealth::Connecting);
+        assert_eq!(
+            next_event(&mut events).await,
+            CoreEvent::PeerConnected {
+                key: peer.clone(),
+                address
+            }
+        );
+
+        let changed = |health| CoreEvent::PeerHealthChanged {
+            key: peer.clone(),
+            address,
+            health,
+        };
+
+        // A timely pong makes the peer healthy.
+        let ControlFrame::Ping(id) = local.next().await.unwrap().unwrap() else {
+            panic!("expected a ping");
+        };
+        local.send(ControlFrame::Pong(id)).await.unwrap();
+        assert_eq!(next_event(&mut events).await, changed(PeerHealth::Healthy));
+
+        // A missed ping degrades it, without closing the connection.
+        assert!(matches!(
+            local.next().await.unwrap().unwrap(),
+            ControlFrame::Ping(_)
+        ));
+        assert_eq!(next_event(&mut events).await, changed(PeerHealth::Degraded));
+        assert_eq!(health(&core), PeerHealth::Degraded);
+
+        // Answering the next ping makes it healthy again.
+        let ControlFrame::Ping(id) = local.next().await.unwrap().unwrap() else {
+            panic!("expected a ping");
+        };
+        local.send(ControlFrame::Pong(id)).await.unwrap();
+        assert_eq!(next_event(&mut events).await, changed(PeerHealth::Healthy));
+
+        // Missing pings in a row kills it.
+        assert_eq!(next_event(&mut events).await, changed(PeerHealth::Degraded));
+        assert_eq!(next_event(&mut events).await, changed(PeerHealth::Dead));
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!core.active_peers.lock().unwrap().contains_key(&peer));
+    }
+
+    #[tokio::test]
+    async fn agrees_on_capabilities_with_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        core.set_compression(true);
+        let (local, remote) = tokio::join!(
+            TcpStream::connect(core.local_addrs()[0]),
+            core.listeners[0].accept()
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        core.register_control_con(remote.unwrap().0, peer.clone(), peer.clone(), PROTO_VERSION);
+        let mut local = Framed::new(local.unwrap(), ControlCodec::new());
+        match local.next().await.unwrap().unwrap() {
+            ControlFrame::Hello { mtu, capabilities } => {
+                assert_eq!(mtu as usize, core.mtu);
+                assert_eq!(
+                    Features::from_bits(capabilities),
+                    Features::COMPRESSION.union(Features::ENCRYPTION)
+                );
+            }
+            _ => panic!("expected a hello frame"),
+        }
+        // Nothing is agreed on until the peer says hello.
+        assert_eq!(
+            core.agreed_features(&peer, Features::COMPRESSION),
+            Features::COMPRESSION
+        );
+
+        let hello = |mtu, capabilities: Features| ControlFrame::Hello {
+            mtu,
+            capabilities: capabilities.bits(),
+        };
+        local
+            .send(hello(1280, Features::UDP_DATA.union(Features::ENCRYPTION)))
+            .await
+            .unwrap();
+        while core.connected_peers()[0].capabilities.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let info = &core.connected_peers()[0];
+        assert_eq!(info.mtu, Some(1280));
+        assert_eq!(info.capabilities, Some(Features::ENCRYPTION));
+        assert_eq!(
+            core.agreed_features(&peer, Features::COMPRESSION),
+            Features::NONE
+        );
+
+        // A peer without capabilities predates the frame, so nothing is restricted.
+        local.send(hello(9000, Features::NONE)).await.unwrap();
+        while core.connected_peers()[0].capabilities.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(core.connected_peers()[0].mtu, None);
+        assert_eq!(
+            core.agreed_features(&peer, Features::COMPRESSION),
+            Features::COMPRESSION
+        );
+    }
+
+    #[tokio::test]
+    async fn caches_announced_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let (local, remote) = tokio::join!(
+            TcpStream::connect(core.local_addrs()[0]),
+            core.listeners[0].accept()
+        );
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        core.register_control_con(remote.unwrap().0, peer.clone(), peer.clone(), PROTO_VERSION);
+        let mut local = control_framed(local.unwrap()).await;
+
+        let announced = SecretKey::from_bytes([3; 32]).public_key();
+        local
+            .send(ControlFrame::PeerAnnounce {
+                public_key: *announced.as_bytes(),
+                addrs: vec![
+                    "192.168.1.1:9651".parse().unwrap(),
+                    // Rejected by the address policy.
+                    "127.0.0.1:9651".parse().unwrap(),
+                ],
+            })
+            .await
+            .unwrap();
+        // We already know ourselves.
+        local
+            .send(ControlFrame::PeerAnnounce {
+                public_key: *core.public_key().as_bytes(),
+                addrs: vec!["192.168.1.2:9651".parse().unwrap()],
+            })
+            .await
+            .unwrap();
+        // Frames are processed in order, so once the pong arrives both announcements are handled.
+        local.send(ControlFrame::Ping(1)).await.unwrap();
+        assert!(matches!(
+            local.next().await.unwrap().unwrap(),
+            ControlFrame::Pong(1)
+        ));
+
+        {
+            let peer_cache = core.peer_cache.lock().unwrap();
+            assert_eq!(peer_cache.len(), 1);
+            let cached = peer_cache.iter().next().unwrap();
+            assert_eq!(cached.public_key(), &announced);
+            assert_eq!(
+                cached.listen_addrs(),
+                &["192.168.1.1:9651".parse::<SocketAddr>().unwrap()]
+            );
+        }
+
+        // The announced peer is reachable through the peer which announced it.
+        let subnets = core.reachable_subnets();
+        assert_eq!(subnets.len(), 1);
+        assert_eq!(subnets[0].0, Subnet::from_public_key(&announced));
+        assert!(matches!(&subnets[0].1, RouteKind::Learned(hop) if *hop == peer));
+
+        // The route goes away with the connection to the next hop.
+        drop(local);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !core.reachable_subnets().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn announces_peers_to_each_other() {
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let mut remotes = Vec::new();
+        for i in 2..=3 {
+            let (local, remote) = tokio::join!(
+                TcpStream::connect(core.local_addrs()[0]),
+                core.listeners[0].accept()
+            );
+            let peer = SecretKey::from_bytes([i; 32]).public_key();
+            core.register_control_con(remote.unwrap().0, peer.clone(), peer.clone(), PROTO_VERSION);
+            let mut local = control_framed(local.unwrap()).await;
+            local
+                .send(ControlFrame::Hello {
+                    mtu: DEFAULT_MTU as u16,
+                    capabilities: Features::UDP_DATA.bits(),
+                })
+                .await
+                .unwrap();
+            remotes.push((peer, local));
+        }
+
+        let (first, second) = (remotes[0].0.clone(), remotes[1].0.clone());
+        for ((_, local), expected) in remotes.iter_mut().zip([second, first]) {
+            match tokio::time::timeout(Duration::from_secs(1), local.next()).await {
+                Ok(Some(Ok(ControlFrame::PeerAnnounce { public_key, .. }))) => {
+                    assert_eq!(&public_key, expected.as_bytes())
+                }
+                _ => panic!("Expected a peer announce frame"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_packets_through_learned_routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (local, mut remote) = data_stream_pair(
+            &listener,
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        let core = test_core(listener);
+        let next_hop = SecretKey::from_bytes([2; 32]).public_key();
+        assert!(core.register_data_con(local, next_hop.clone(), next_hop.clone()));
+
+        let destination = SecretKey::from_bytes([3; 32]).public_key();
+        let packet = ipv6_packet(
+            core.address(),
+            AddressScheme::Yggdrasil.derive(&destination),
+        );
+        assert!(!core.route_packet(packet.clone()).await);
+
+        core.add_route(Subnet::from_public_key(&destination), next_hop);
+        assert!(core.route_packet(packet.clone()).await);
+        let relayed = tokio::time::timeout(Duration::from_secs(1), remote.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&relayed[..], &packet[..]);
+    }
+
+    #[tokio::test]
+    async fn fails_to_connect_to_closed_port() {
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        match core.connect_to_peer(addr).await {
+            Err(handshake::Error::Io(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused)
+            }
+            _ => panic!("Expected connection to be refused"),
+        }
+        assert!(core.active_peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_to_ourselves() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        // Reach ourselves through an address which is not one of our listen addresses.
+        let forwarder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let forwarder_addr = forwarder.local_addr().unwrap();
+        let listen_addr = core.local_addrs()[0];
+        tokio::spawn(async move {
+            let (mut con, _) = forwarder.accept().await.unwrap();
+            let mut upstream = TcpStream::connect(listen_addr).await.unwrap();
+            let _ = crate::splice::forward(&mut con, &mut upstream).await;
+        });
+
+        assert!(matches!(
+            core.connect_to_peer(forwarder_addr).await,
+            Err(handshake::Error::SelfConnection)
+        ));
+
+        // A remote which identifies with our own key is disconnected right after the handshake.
+        let secret = SecretKey::from_bytes([1; 32]);
+        let mut con = connect(&core, &secret, ConnectionKind::Control).await;
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), con.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        assert!(core.connected_peers().is_empty());
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn pausing_keeps_existing_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+
+        async fn ping(con: &mut Framed<TcpStream, ControlCodec>, id: u32) {
+            con.send(ControlFrame::Ping(id)).await.unwrap();
+            match tokio::time::timeout(Duration::from_secs(1), con.next()).await {
+                Ok(Some(Ok(ControlFrame::Pong(pong)))) if pong == id => (),
+                _ => panic!("Expected a pong with ID {}", id),
+            }
+        }
+
+        let existing_peer = SecretKey::from_bytes([2; 32]);
+        let mut existing =
+            control_framed(connect(&core, &existing_peer, ConnectionKind::Control).await).await;
+        ping(&mut existing, 1).await;
+
+        core.pause_accepting();
+        assert!(!core.is_accepting());
+
+        let new_secret = SecretKey::from_bytes([3; 32]);
+        let new_peer = new_secret.public_key();
+        let mut new = TcpStream::connect(core.local_addrs()[0]).await.unwrap();
+        write_handshake(
+            &mut new,
+            &new_peer,
+            ConnectionKind::Control,
+            Features::NONE,
+            AddressScheme::Yggdrasil,
+        )
+        .await
+        .unwrap();
+        // The new connection is queued, but not established.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!core.active_peers.lock().unwrap().contains_key(&new_peer));
+        ping(&mut existing, 2).await;
+
+        core.resume_accepting();
+        read_handshake(&mut new, AddressScheme::Yggdrasil)
+            .await
+            .unwrap();
+        answer_challenge(&mut new, &new_secret, &core.public_key())
+            .await
+            .unwrap();
+        let mut new = control_framed(new).await;
+        ping(&mut new, 3).await;
+        assert!(core.active_peers.lock().unwrap().contains_key(&new_peer));
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_queued_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(SecretKey::from_bytes([1; 32]), listener);
+        let peer = SecretKey::from_bytes([2; 32]);
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer.public_key()));
+
+        let mut remote = connect_data(&core, &peer).await;
+        while !core.active_data_peers.lock().unwrap().contains_key(&subnet) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        const PACKETS: usize = 100;
+        for i in 0..PACKETS {
+            assert!(core.send_packet(subnet, Bytes::from(vec![i as u8; 1280])));
+        }
+
+        let deadline = Duration::from_secs(1);
+        let received = async {
+            let mut received = 0;
+            while let Some(packet) = remote.next().await {
+                assert_eq!(packet.unwrap()[..], [received as u8; 1280]);
+                received += 1;
+            }
+            received
+        };
+        let (_, received) = tokio::time::timeout(
+            deadline * 2,
+            futures::future::join(core.shutdown(deadline), received),
+        )
+        .await
+        .unwrap();
+
+        // All packets were received before the connection was closed.
+        assert_eq!(received, PACKETS);
+        assert!(!core.send_packet(subnet, Bytes::from_static(b"too late")));
+        assert!(core.active_peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_disconnect_frame() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+
+        let con = connect(&core, &peer_secret, ConnectionKind::Control).await;
+        let mut con = control_framed(con).await;
+        while !core.active_peers.lock().unwrap().contains_key(&peer) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (_, frame) = tokio::join!(core.shutdown(Duration::from_secs(1)), con.next());
+        match frame {
+            Some(Ok(ControlFrame::Disconnect { reason })) => assert_eq!(
+                DisconnectReason::from_code(reason),
+                DisconnectReason::Shutdown
+            ),
+            _ => panic!("Expected a disconnect frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_peer_closes_connections_and_stops_reconnecting() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = remote.local_addr().unwrap();
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+
+        assert!(core.add_persistent_peer(addr));
+        let (con, _) = accept(&remote, &peer).await;
+        let mut con = control_framed(con).await;
+        let _data = connect_data(&core, &peer_secret).await;
+        while !core.active_peers.lock().unwrap().contains_key(&peer)
+            || !core.active_data_peers.lock().unwrap().contains_key(&subnet)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (removed, frame) = tokio::join!(core.remove_peer(&peer), con.next());
+        assert!(removed);
+        match frame {
+            Some(Ok(ControlFrame::Disconnect { reason })) => assert_eq!(
+                DisconnectReason::from_code(reason),
+                DisconnectReason::Removed
+            ),
+            _ => panic!("Expected a disconnect frame"),
+        }
+        assert!(core.persistent_peers().is_empty());
+        assert!(!core.active_peers.lock().unwrap().contains_key(&peer));
+        assert!(!core.active_data_peers.lock().unwrap().contains_key(&subnet));
+        // The peer is not dialed again.
+        assert!(
+            tokio::time::timeout(RECONNECT_MIN_BACKOFF * 2, remote.accept())
+                .await
+                .is_err()
+        );
+        assert!(!core.remove_peer(&peer).await);
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn accepts_connections_on_all_listeners() {
+        let (core, run) = CoreBuilder::new(SecretKey::from_bytes([1; 32]))
+            .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .listener(TcpListener::bind("[::1]:0").await.unwrap())
+            .build()
+            .await
+            .unwrap();
+        tokio::spawn(run);
+        let addrs = core.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        // None of the listeners is dialed.
+        assert!(!core.add_persistent_peer(addrs[1]));
+
+        let mut cons = Vec::new();
+        for (i, addr) in addrs.into_iter().enumerate() {
+            let mut con = TcpStream::connect(addr).await.unwrap();
+            initiate_handshake(
+                &mut con,
+                &SecretKey::from_bytes([i as u8 + 2; 32]),
+                ConnectionKind::Control,
+                Features::NONE,
+                AddressScheme::Yggdrasil,
+            )
+            .await
+            .unwrap();
+            cons.push(con);
+        }
+        while core.active_control_peers() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_peers_not_allowed_by_key_filter() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let (denied, allowed) = (
+            SecretKey::from_bytes([2; 32]),
+            SecretKey::from_bytes([3; 32]),
+        );
+        core.set_key_filter(KeyFilter {
+            allowlist: None,
+            denylist: HashSet::from([denied.public_key()]),
+        })
+        .await;
+
+        let mut denied_con = connect(&core, &denied, ConnectionKind::Control).await;
+        let _allowed_con = connect(&core, &allowed, ConnectionKind::Control).await;
+        // The rejected connection is closed.
+        let mut buf = [0; 1];
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), denied_con.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap(),
+            0
+        );
+        while core.active_control_peers() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(core.connected_peers()[0].key, allowed.public_key());
+
+        // Peers which are no longer allowed are disconnected.
+        core.set_key_filter(KeyFilter {
+            allowlist: Some(HashSet::new()),
+            denylist: HashSet::new(),
+        })
+        .await;
+        assert!(core.connected_peers().is_empty());
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn lists_connected_peers() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = remote.local_addr().unwrap();
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let address = AddressScheme::Yggdrasil.derive(&peer);
+        assert!(core.connected_peers().is_empty());
+
+        assert!(core.add_persistent_peer(addr));
+        let (_con, _) = accept(&remote, &peer).await;
+        while core.connected_peers().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let expected = PeerInfo {
+            key: peer.clone(),
+            address,
+            remote: Some(addr),
+            // We opened the connection, so nothing is observed.
+            observed_addr: None,
+            data_connection: false,
+            health: PeerHealth::Healthy,
+            mtu: None,
+            capabilities: None,
+            rtt: None,
+            control_decode_errors: 0,
+        };
+        assert_eq!(core.connected_peers(), vec![expected.clone()]);
+
+        let _data = connect_data(&core, &peer_secret).await;
+        while !core.connected_peers()[0].data_connection {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            core.connected_peers(),
+            vec![PeerInfo {
+                data_connection: true,
+                ..expected
+            }]
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn observes_address_of_inbound_peers() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let con = connect(
+            &core,
+            &SecretKey::from_bytes([2; 32]),
+            ConnectionKind::Control,
+        )
+        .await;
+        while core.connected_peers().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let peer = &core.connected_peers()[0];
+        assert_eq!(peer.observed_addr, Some(con.local_addr().unwrap()));
+        assert_eq!(peer.remote, peer.observed_addr);
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    /// Wait for the next event of a subscription.
+    async fn next_event(events: &mut broadcast::Receiver<CoreEvent>) -> CoreEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn publishes_connection_events() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let mut events = core.subscribe();
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let key = peer_secret.public_key();
+        let address = AddressScheme::Yggdrasil.derive(&key);
+
+        assert!(core.add_persistent_peer(remote.local_addr().unwrap()));
+        let (_con, _) = accept(&remote, &key).await;
+        assert_eq!(
+            next_event(&mut events).await,
+            CoreEvent::PeerConnected {
+                key: key.clone(),
+                address
+            }
+        );
+        let _data = connect_data(&core, &peer_secret).await;
+        assert_eq!(
+            next_event(&mut events).await,
+            CoreEvent::DataChannelUp {
+                key: key.clone(),
+                address
+            }
+        );
+
+        assert!(core.remove_peer(&key).await);
+        let mut down = vec![next_event(&mut events).await, next_event(&mut events).await];
+        down.sort_by_key(|event| matches!(event, CoreEvent::DataChannelDown { .. }));
+        assert_eq!(
+            down,
+            [
+                CoreEvent::PeerDisconnected {
+                    key: key.clone(),
+                    address
+                },
+                CoreEvent::DataChannelDown { key, address },
+            ]
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_catch_up() {
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let mut events = core.subscribe();
+        let key = SecretKey::from_bytes([2; 32]).public_key();
+        let address = AddressScheme::Yggdrasil.derive(&key);
+        for _ in 0..EVENT_QUEUE_SIZE + 3 {
+            core.publish(CoreEvent::PeerConnected {
+                key: key.clone(),
+                address,
+            });
+        }
+        core.publish(CoreEvent::PeerDisconnected {
+            key: key.clone(),
+            address,
+        });
+
+        assert_eq!(
+            events.recv().await,
+            Err(broadcast::error::RecvError::Lagged(4))
+        );
+        for _ in 0..EVENT_QUEUE_SIZE - 1 {
+            assert!(matches!(
+                events.recv().await,
+                Ok(CoreEvent::PeerConnected { .. })
+            ));
+        }
+        assert_eq!(
+            events.recv().await,
+            Ok(CoreEvent::PeerDisconnected { key, address })
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_frame_tears_down_peer() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+
+        let con = connect(&core, &peer_secret, ConnectionKind::Control).await;
+        let mut con = control_framed(con).await;
+        let _data = connect_data(&core, &peer_secret).await;
+        while !core.active_peers.lock().unwrap().contains_key(&peer)
+            || !core.active_data_peers.lock().unwrap().contains_key(&subnet)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        con.send(ControlFrame::Disconnect {
+            reason: DisconnectReason::Shutdown as u8,
+        })
+        .await
+        .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.active_peers.lock().unwrap().contains_key(&peer)
+                || core.active_data_peers.lock().unwrap().contains_key(&subnet)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn duplicate_control_connection_replaces_old_one() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+
+        let (first, second) = tokio::join!(
+            connect(&core, &peer_secret, ConnectionKind::Control),
+            connect(&core, &peer_secret, ConnectionKind::Control)
+        );
+        let mut cons = [control_framed(first).await, control_framed(second).await];
+
+        // Exactly 1 of the connections is closed, the other one keeps working.
+        let (frame, closed) = {
+            let next = cons.iter_mut().map(|con| con.next());
+            let (frame, closed, _) =
+                tokio::time::timeout(Duration::from_secs(1), futures::future::select_all(next))
+                    .await
+                    .unwrap();
+            (frame, closed)
+        };
+        match frame {
+            Some(Ok(ControlFrame::Disconnect { reason })) => assert_eq!(
+                DisconnectReason::from_code(reason),
+                DisconnectReason::Replaced
+            ),
+            _ => panic!("Expected a disconnect frame"),
+        }
+        assert!(cons[closed].next().await.is_none());
+
+        let kept = &mut cons[1 - closed];
+        kept.send(ControlFrame::Ping(7)).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), kept.next()).await {
+            Ok(Some(Ok(ControlFrame::Pong(7)))) => (),
+            _ => panic!("Expected a pong with ID 7"),
+        }
+        assert_eq!(core.active_control_peers(), 1);
+    }
+
+    #[tokio::test]
+    async fn dials_from_configured_address() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_builder(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .dialer(Dialer {
+                bind_addr: Some("127.0.0.3".parse().unwrap()),
+                ..Default::default()
+            })
+            .assemble()
+            .0;
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+
+        let (res, (con, handshake)) = tokio::join!(
+            core.open_data_connection(remote.local_addr().unwrap(), peer.clone()),
+            accept_data(&remote, &peer_secret)
+        );
+        res.unwrap();
+        assert_eq!(
+            con.get_ref().peer_addr().unwrap().ip(),
+            core.dialer.bind_addr.unwrap()
+        );
+
+        assert_eq!(handshake.key, core.public_key());
+        assert_eq!(handshake.kind, ConnectionKind::Data);
+        assert!(core
+            .active_data_peers
+            .lock()
+            .unwrap()
+            .contains_key(&Subnet::from_address(
+                AddressScheme::Yggdrasil.derive(&peer)
+            )));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_which_stall_during_handshake() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        core.set_handshake_timeout(Duration::from_millis(100));
+
+        let mut con = TcpStream::connect(core.local_addrs()[0]).await.unwrap();
+        // Only part of the magic is sent.
+        con.write_all(&[0x73]).await.unwrap();
+        let mut buf = Vec::new();
+        // The core closes the connection, so reading hits the end of the stream.
+        assert!(
+            tokio::time::timeout(Duration::from_secs(2), con.read_to_end(&mut buf))
+                .await
+                .is_ok()
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn limits_pending_handshakes() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        core.set_handshake_timeout(Duration::from_millis(300));
+        core.set_max_pending_handshakes(1);
+
+        let start = Instant::now();
+        let mut first = TcpStream::connect(core.local_addrs()[0]).await.unwrap();
+        let mut second = TcpStream::connect(core.local_addrs()[0]).await.unwrap();
+        // Neither connection completes the handshake. The second one is only accepted once the
+        // first one times out, so it is closed a full handshake timeout later.
+        let mut buf = Vec::new();
+        first.read_to_end(&mut buf).await.unwrap();
+        let first_closed = start.elapsed();
+        second.read_to_end(&mut buf).await.unwrap();
+        let second_closed = start.elapsed();
+        assert!(
+            first_closed < Duration::from_millis(500),
+            "{:?}",
+            first_closed
+        );
+        assert!(
+            second_closed >= Duration::from_millis(600),
+            "{:?}",
+            second_closed
+        );
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    /// Measure the throughput and latency of the data path between two instances connected over
+    /// loopback, for both data transports. Packets are queued on one instance, and counted as
+    /// they are received by the other, so this covers framing, encryption and the connection
+    /// tasks, but not the interface. UDP packets are not paced, so some are lost once the socket
+    /// buffers fill up. The results are logged, run with
+    /// `RUST_LOG=info cargo test --release bench_data_path -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_data_path() {
+        const PACKETS: usize = 200_000;
+        const PACKET_SIZE: usize = 1280;
+        const LATENCY_SAMPLES: usize = 1000;
+
+        let _ = pretty_env_logger::try_init();
+        let new_core = |seed: u8| async move {
+            Core::new(
+                SecretKey::from_bytes([seed; 32]),
+                TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            )
+        };
+        // Wait until the receiver got `bytes` in total, or stopped receiving, in which case
+        // the missing packets are lost. Returns the amount of bytes received, and when the last
+        // of them arrived.
+        let received = |receiver: Arc<Core>, bytes: usize| async move {
+            let mut last = (receiver.bytes_rx(), Instant::now());
+            while last.0 < bytes && last.1.elapsed() < Duration::from_secs(1) {
+                tokio::task::yield_now().await;
+                if receiver.bytes_rx() != last.0 {
+                    last = (receiver.bytes_rx(), Instant::now());
+                }
+            }
+            last
+        };
+
+        for transport in [TransportKind::Tcp, TransportKind::Udp] {
+            let (sender, receiver) = (new_core(1).await, new_core(2).await);
+            sender.set_data_transport(transport);
+            receiver.set_data_transport(transport);
+            sender
+                .open_data_connection(receiver.local_addrs()[0], receiver.public_key())
+                .await
+                .unwrap();
+            let subnet = Subnet::from_address(receiver.address());
+            let mut packet = BytesMut::from(&ipv6_packet(sender.address(), receiver.address())[..]);
+            packet.resize(PACKET_SIZE, 0);
+            packet[4..6].copy_from_slice(&((PACKET_SIZE - 40) as u16).to_be_bytes());
+            let packet = packet.freeze();
+
+            let start = Instant::now();
+            for _ in 0..PACKETS {
+                // Wait for room if the queue of the connection is full.
+                while !sender.send_packet(subnet, packet.clone()) {
+                    tokio::task::yield_now().await;
+                }
+            }
+            let (bytes, done) = received(receiver.clone(), PACKETS * PACKET_SIZE).await;
+            let elapsed = done - start;
+            let packets = bytes / PACKET_SIZE;
+            assert!(packets > 0, "no packets received over {:?}", transport);
+            info!(
+                "{:?} throughput: {:.0} packets/s, {:.2} MiB/s, {} of {} packets received",
+                transport,
+                packets as f64 / elapsed.as_secs_f64(),
+                bytes as f64 / (1 << 20) as f64 / elapsed.as_secs_f64(),
+                packets,
+                PACKETS
+            );
+
+            // One packet at a time, so it doesn't wait behind others.
+            let mut latencies = Vec::with_capacity(LATENCY_SAMPLES);
+            for _ in 0..LATENCY_SAMPLES {
+                let expected = receiver.bytes_rx() + PACKET_SIZE;
+                let start = Instant::now();
+                assert!(sender.send_packet(subnet, packet.clone()));
+                let (bytes, done) = received(receiver.clone(), expected).await;
+                if bytes >= expected {
+                    latencies.push(done - start);
+                }
+            }
+            latencies.sort();
+            match latencies.last() {
+                Some(max) => info!(
+                    "{:?} latency: p50 {:?}, p99 {:?}, max {:?}, {} of {} samples received",
+                    transport,
+                    latencies[latencies.len() / 2],
+                    latencies[latencies.len() * 99 / 100],
+                    max,
+                    latencies.len(),
+                    LATENCY_SAMPLES
+                ),
+                // Possible with UDP, which doesn't retransmit lost datagrams.
+                None if transport == TransportKind::Udp => {
+                    warn!("{:?} latency: all samples lost", transport)
+                }
+                None => panic!("{:?} latency: all samples lost", transport),
+            }
+
+            sender.shutdown(Duration::from_millis(10)).await;
+            receiver.shutdown(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn data_connections_use_udp_if_both_sides_support_it() {
+        let new_core = |seed: u8| {
+            let secret = SecretKey::from_bytes([seed; 32]);
+            async move { Core::new(secret, TcpListener::bind("127.0.0.1:0").await.unwrap()) }
+        };
+        let core = new_core(1).await;
+        core.set_data_transport(TransportKind::Udp);
+        let udp_peer = new_core(2).await;
+        udp_peer.set_data_transport(TransportKind::Udp);
+        // Falls back to TCP.
+        let tcp_peer = new_core(3).await;
+
+        for peer in [&udp_peer, &tcp_peer] {
+            core.open_data_connection(peer.local_addrs()[0], peer.public_key())
+                .await
+                .unwrap();
+            let packet = ipv6_packet(core.address(), peer.address());
+            assert!(core.route_packet(packet).await);
+            tokio::time::timeout(Duration::from_secs(1), async {
+                while peer.bytes_rx() < 40 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+
+            // The remote can reply over the same connection.
+            let reply = ipv6_packet(peer.address(), core.address());
+            assert!(peer.route_packet(reply).await);
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.bytes_rx() < 80 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        for core in [core, udp_peer, tcp_peer] {
+            core.shutdown(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn exchanges_jumbo_packets_if_both_sides_support_them() {
+        let new_core = |seed: u8, jumbo: bool| async move {
+            let (core, run) = CoreBuilder::new(SecretKey::from_bytes([seed; 32]))
+                .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+                .mtu(200_000)
+                .jumbo(jumbo)
+                .assemble();
+            tokio::spawn(run);
+            core
+        };
+        let core = new_core(1, true).await;
+        let jumbo_peer = new_core(2, true).await;
+        let regular_peer = new_core(3, false).await;
+        for peer in [&jumbo_peer, &regular_peer] {
+            core.open_data_connection(peer.local_addrs()[0], peer.public_key())
+                .await
+                .unwrap();
+        }
+        // The payload length is left at 0, like it is for a jumbogram.
+        let jumbo_packet = |dst| {
+            let mut packet = BytesMut::from(&ipv6_packet(core.address(), dst)[..]);
+            packet.resize(100_000, 0);
+            packet.freeze()
+        };
+
+        assert!(core.route_packet(jumbo_packet(jumbo_peer.address())).await);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while jumbo_peer.bytes_rx() < 100_000 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The packet is too large for a peer without jumbo support, but the connection is kept.
+        let packet = jumbo_packet(regular_peer.address());
+        assert!(!core.route_packet(packet).await);
+        let packet = ipv6_packet(core.address(), regular_peer.address());
+        assert!(core.route_packet(packet).await);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while regular_peer.bytes_rx() < 40 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(regular_peer.bytes_rx(), 40);
+
+        for core in [core, jumbo_peer, regular_peer] {
+            core.shutdown(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn evicts_idle_data_connections() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        core.set_idle_timeout(Some(Duration::from_millis(100)));
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+
+        let (res, (mut con, _)) = tokio::join!(
+            core.open_data_connection(remote_addr, peer.clone()),
+            accept_data(&remote, &peer_secret)
+        );
+        res.unwrap();
+
+        // Traffic keeps the connection open.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(core.send_packet(subnet, Bytes::from_static(b"ping")));
+        assert_eq!(&con.next().await.unwrap().unwrap()[..], b"ping");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(core.reap_idle_data_connections(), 0);
+
+        // Without traffic, the connection is closed.
+        let mut events = core.subscribe();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(core.reap_idle_data_connections(), 1);
+        assert!(tokio::time::timeout(Duration::from_secs(1), con.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!core.active_data_peers.lock().unwrap().contains_key(&subnet));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            CoreEvent::DataChannelDown { key, .. } if key == peer
+        ));
+
+        // The connection is reopened once there is something to send. Packets are held until
+        // it is open, without waiting for it.
+        assert!(core.forward_packet(subnet, Bytes::from_static(b"wake up")));
+        assert!(core.forward_packet(subnet, Bytes::from_static(b"again")));
+        let (mut con, _) = accept_data(&remote, &peer_secret).await;
+        assert_eq!(&con.next().await.unwrap().unwrap()[..], b"wake up");
+        assert_eq!(&con.next().await.unwrap().unwrap()[..], b"again");
+        assert!(core.active_data_peers.lock().unwrap().contains_key(&subnet));
+    }
+
+    #[tokio::test]
+    async fn keeps_pinned_idle_data_connections() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let core = test_core(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        core.set_idle_timeout(Some(Duration::from_millis(50)));
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+        core.pin_subnet(subnet);
+
+        let (res, (mut con, _)) = tokio::join!(
+            core.open_data_connection(remote_addr, peer.clone()),
+            accept_data(&remote, &peer_secret)
+        );
+        res.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(core.reap_idle_data_connections(), 0);
+        assert!(tokio::time::timeout(Duration::from_millis(50), con.next())
+            .await
+            .is_err());
+        assert!(core.active_data_peers.lock().unwrap().contains_key(&subnet));
+    }
+
+    #[tokio::test]
+    async fn sweeps_for_idle_data_connections_periodically() {
+        let core = Core::new(
+            SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        core.set_idle_timeout(Some(Duration::from_millis(50)));
+        core.set_idle_sweep_interval(Duration::from_millis(20));
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+
+        let (res, (mut con, _)) = tokio::join!(
+            core.open_data_connection(remote.local_addr().unwrap(), peer.clone()),
+            accept_data(&remote, &peer_secret)
+        );
+        res.unwrap();
+        assert!(
+            tokio::time::timeout(DEFAULT_IDLE_SWEEP_INTERVAL * 2, con.next())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(core.active_data_peers(), 0);
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn removes_closed_data_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let peer_secret = SecretKey::from_bytes([2; 32]);
+        let peer = peer_secret.public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+
+        let (local, remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer));
+
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.active_data_peers.lock().unwrap().contains_key(&subnet) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_packets_by_destination_subnet() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let reachable = Subnet::from_public_key(&peer);
+
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer));
+
+        let packet = |dst: Ipv6Addr| ipv6_packet(core.address(), dst);
+        let mut dst = reachable.network_address().octets();
+        dst[15] = 1;
+        let routed = packet(dst.into());
+        assert!(core.route_packet(routed.clone()).await);
+        assert_eq!(remote.next().await.unwrap().unwrap(), routed);
+        assert_eq!(core.dropped_packets(), 0);
+
+        let unroutable = packet(Subnet::new([0x03, 1, 2, 3, 4, 5, 6, 7]).network_address());
+        assert!(!core.route_packet(unroutable).await);
+        assert!(!core.route_packet(Bytes::from_static(&[0x45; 20])).await);
+        assert_eq!(core.dropped_packets(), 2);
+    }
+
+    #[tokio::test]
+    async fn counts_connections_which_find_the_queue_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let (tx, mut rx) = mpsc::channel(1);
+        let control = || async {
+            let con = TcpStream::connect(core.listeners[0].local_addr().unwrap())
+                .await
+                .unwrap();
+            Connection::Control(con, peer.clone(), PROTO_VERSION)
+        };
+
+        // Set up the data connection first, so it isn't confused with the control connections
+        // waiting in the backlog of the listener.
+        let (local, _remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+
+        core.queue_connection(&tx, control().await).await;
+        assert_eq!(core.connection_queue_depth(), 1);
+
+        // Data connections are dropped while the queue is full.
+        let data = Connection::Data(Box::new(DataTransport::from(local)), peer.clone());
+        core.queue_connection(&tx, data).await;
+        assert_eq!(core.connection_queue_dropped(), 1);
+        assert_eq!(core.connection_queue_depth(), 1);
+
+        // Control connections wait for room instead.
+        let second = control().await;
+        let queued = core.queue_connection(&tx, second);
+        tokio::pin!(queued);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut queued)
+            .await
+            .is_err());
+        assert_eq!(core.connection_queue_blocked(), 1);
+        assert!(rx.recv().await.is_some());
+        core.connection_queue.dequeued();
+        queued.await;
+        assert_eq!(core.connection_queue_depth(), 1);
+        assert_eq!(core.connection_queue_peak(), 2);
+    }
+
+    #[tokio::test]
+    async fn terminates_packets_for_local_keys() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let hosted = SecretKey::from_bytes([2; 32]).public_key();
+        let hosted_address = core.address_scheme.derive(&hosted);
+        assert!(core.is_local(core.address()));
+        assert!(!core.is_local(hosted_address));
+
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, hosted.clone(), hosted.clone()));
+
+        // Once hosted, packets for the key are no longer relayed to the peer. There is no
+        // interface to deliver them to, so they are dropped.
+        assert!(core.add_local_key(hosted.clone()));
+        assert!(!core.add_local_key(hosted.clone()));
+        assert!(core.is_local(hosted_address));
+        assert!(
+            !core
+                .route_packet(ipv6_packet(core.address(), hosted_address))
+                .await
+        );
+        assert_eq!(core.dropped_packets(), 1);
+
+        assert!(core.remove_local_key(&hosted));
+        assert!(core.local_keys().is_empty());
+        let packet = ipv6_packet(core.address(), hosted_address);
+        assert!(core.route_packet(packet.clone()).await);
+        assert_eq!(remote.next().await.unwrap().unwrap(), packet);
+
+        // Our own subnet follows the identity.
+        let old_address = core.address();
+        core.rotate_identity(SecretKey::from_bytes([3; 32]));
+        assert!(!core.is_local(old_address));
+        assert!(core.is_local(core.address()));
+    }
+
+    #[tokio::test]
+    async fn closes_data_connection_which_is_not_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        core.set_write_timeout(Duration::from_millis(200));
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let subnet = Subnet::from_address(AddressScheme::Yggdrasil.derive(&peer));
+        // The remote never reads, so the socket buffers fill up.
+        let (local, remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        // Small buffers fill up quickly.
+        socket2::SockRef::from(local.get_ref())
+            .set_send_buffer_size(4096)
+            .unwrap();
+        socket2::SockRef::from(remote.get_ref())
+            .set_recv_buffer_size(4096)
+            .unwrap();
+        assert!(core.register_data_con(local, peer.clone(), peer));
+
+        let packet = Bytes::from(vec![0; DEFAULT_MAX_PACKET_SIZE]);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while core.active_data_peers.lock().unwrap().contains_key(&subnet) {
+                core.send_packet(subnet, packet.clone());
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!core.send_packet(subnet, packet));
+    }
+
+    #[tokio::test]
+    async fn drops_packets_with_spoofed_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer.clone()));
+
+        // Claims to be sent by a node in another subnet.
+        let spoofed: Ipv6Addr = "300:1:2:3::1".parse().unwrap();
+        remote
+            .send(ipv6_packet(spoofed, core.address()))
+            .await
+            .unwrap();
+        remote
+            .send(ipv6_packet(
+                AddressScheme::Yggdrasil.derive(&peer),
+                core.address(),
+            ))
+            .await
+            .unwrap();
+        // Not an IPv6 packet, so it has no valid source either.
+        remote.send(Bytes::from_static(&[0x45; 20])).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.spoofed_packets() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // Give a wrongly counted packet a chance to show up.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(core.spoofed_packets(), 2);
+        // Dropped packets were still received.
+        assert_eq!(core.bytes_rx(), 40 + 40 + 20);
+        // Spoofed packets don't close the connection.
+        assert_eq!(core.active_data_peers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_traffic_per_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let peer_address = AddressScheme::Yggdrasil.derive(&peer);
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer.clone()));
+
+        for _ in 0..2 {
+            remote
+                .send(ipv6_packet(peer_address, core.address()))
+                .await
+                .unwrap();
+        }
+        assert!(
+            core.route_packet(ipv6_packet(core.address(), peer_address))
+                .await
+        );
+        remote.next().await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.bytes_rx() < 80 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let stats = core.peer_traffic_stats();
+        assert_eq!(stats.len(), 1);
+        let (key, traffic) = &stats[0];
+        assert_eq!(key, &peer);
+        assert_eq!(
+            (
+                traffic.bytes_tx,
+                traffic.bytes_rx,
+                traffic.packets_tx,
+                traffic.packets_rx
+            ),
+            (40, 80, 1, 2)
+        );
+        assert!(traffic.last_activity.is_some());
+
+        core.reset_stats();
+        let (_, traffic) = core.peer_traffic_stats()[0];
+        assert_eq!((traffic.bytes_rx, traffic.packets_tx), (0, 0));
+        // Global counters are not reset.
+        assert_eq!(core.bytes_rx(), 80);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_caps_throughput_of_flooding_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_core(listener);
+        core.set_rate_limit(Some(100_000));
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer.clone()));
+
+        let mut packet =
+            ipv6_packet(AddressScheme::Yggdrasil.derive(&peer), core.address()).to_vec();
+        packet.resize(1000, 0);
+        packet[4..6].copy_from_slice(&960u16.to_be_bytes());
+        let packet = Bytes::from(packet);
+        let flood = tokio::spawn(async move {
+            // Sending doesn't yield while the socket has room, so yield explicitly to give
+            // the connection a chance to read.
+            while remote.send(packet.clone()).await.is_ok() {
+                tokio::task::yield_now().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        flood.abort();
+
+        // 10KB of burst, 30KB over 300ms, and some slack for timing.
+        let received = core.bytes_rx();
+        assert!((20_000..=50_000).contains(&received), "{}", received);
+        // Throttling doesn't close the connection.
+        assert_eq!(core.active_data_peers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forwards_packets_read_from_tun() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-reader", crate::tun::DEFAULT_MTU) {
+            Ok(tun) => Arc::new(tun),
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_builder(listener).tun(vec![tun.clone()]).assemble().0;
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let reachable = Subnet::from_public_key(&peer);
+        let unreachable = Subnet::new([0x03, 1, 2, 3, 4, 5, 6, 7]);
+
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer));
+        let mut routes = KernelRoutes::new(tun.name()).unwrap();
+        routes.sync(&[reachable, unreachable]).unwrap();
+        tokio::spawn(Core::read_tun(core.clone(), tun));
+
+        let host = |subnet: Subnet| {
+            let mut raw = subnet.network_address().octets();
+            raw[15] = 1;
+            SocketAddr::from((Ipv6Addr::from(raw), 9651))
+        };
+        let socket = tokio::net::UdpSocket::bind("[::]:0").await.unwrap();
+        // A packet which can't be forwarded does not stop the reader.
+        socket.send_to(b"lost", host(unreachable)).await.unwrap();
+        socket.send_to(b"found", host(reachable)).await.unwrap();
+
+        let packet = tokio::time::timeout(Duration::from_secs(1), remote.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let meta = PacketMeta::from_ipv6(&packet).unwrap();
+        assert_eq!(meta.dst, reachable);
+        assert!(packet.ends_with(b"found"));
+    }
+
+    #[tokio::test]
+    async fn answers_oversized_packets_with_packet_too_big() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-ptb", crate::tun::DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let rx_packets = || {
+            std::fs::read_to_string("/sys/class/net/styx-ptb/statistics/rx_packets")
+                .unwrap()
+                .trim()
+                .parse::<u64>()
+                .unwrap()
+        };
+        let before = rx_packets();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_builder(listener).tun(vec![Arc::new(tun)]).assemble().0;
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer.clone()));
+
+        let mut oversized = BytesMut::from(
+            &ipv6_packet(core.address(), AddressScheme::Yggdrasil.derive(&peer))[..],
+        );
+        oversized.resize(core.mtu + 1, 0);
+        let payload_len = (oversized.len() - 40) as u16;
+        oversized[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        let oversized = oversized.freeze();
+        // Far more packets than the burst of the rate limit.
+        for _ in 0..100 {
+            assert!(!core.route_packet(oversized.clone()).await);
+        }
+        assert_eq!(core.dropped_packets(), 100);
+        let sent = rx_packets() - before;
+        assert!((1..=20).contains(&sent), "{}", sent);
+
+        // The data connection is not affected.
+        let packet = ipv6_packet(core.address(), AddressScheme::Yggdrasil.derive(&peer));
+        assert!(core.route_packet(packet.clone()).await);
+        assert_eq!(remote.next().await.unwrap().unwrap(), packet);
+    }
+
+    #[tokio::test]
+    async fn forwards_received_packets_to_tun() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-fwd", crate::tun::DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let rx_packets = || {
+            std::fs::read_to_string("/sys/class/net/styx-fwd/statistics/rx_packets")
+                .unwrap()
+                .trim()
+                .parse::<u64>()
+                .unwrap()
+        };
+        let before = rx_packets();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = test_builder(listener).tun(vec![Arc::new(tun)]).assemble().0;
+        let peer = SecretKey::from_bytes([2; 32]).public_key();
+
+        let (local, mut remote) = data_stream_pair(
+            &core.listeners[0],
+            &SecretKey::from_bytes([1; 32]),
+            &SecretKey::from_bytes([2; 32]),
+        )
+        .await;
+        assert!(core.register_data_con(local, peer.clone(), peer.clone()));
+
+        // A minimal IPv6 packet without payload, from the address of the peer.
+        let packet = ipv6_packet(AddressScheme::Yggdrasil.derive(&peer), core.address());
+        for _ in 0..3 {
+            remote.send(packet.clone()).await.unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while rx_packets() < before + 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_received_packets_without_tun() {
+        let (core, run) = test_builder(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .relay_only(true)
+            .assemble();
+        tokio::spawn(run);
+        // The core accepts on its own listener, so connections are set up on a separate one.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let local_secret = SecretKey::from_bytes([1; 32]);
+        let a = SecretKey::from_bytes([2; 32]);
+        let b = SecretKey::from_bytes([3; 32]);
+        let (local, mut remote_a) = data_stream_pair(&listener, &local_secret, &a).await;
+        assert!(core.register_data_con(local, a.public_key(), a.public_key()));
+        let (local, mut remote_b) = data_stream_pair(&listener, &local_secret, &b).await;
+        assert!(core.register_data_con(local, b.public_key(), b.public_key()));
+
+        let a_address = AddressScheme::Yggdrasil.derive(&a.public_key());
+        let packet = ipv6_packet(a_address, AddressScheme::Yggdrasil.derive(&b.public_key()));
+        remote_a.send(packet.clone()).await.unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(1), remote_b.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&relayed[..], &packet[..]);
+
+        // There is no interface to deliver packets for ourselves to.
+        remote_a
+            .send(ipv6_packet(a_address, core.address()))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while core.dropped_packets() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_packets_between_peers_of_relay() {
+        let mut cores = Vec::new();
+        for (i, relay_only) in [(1, false), (2, true), (3, true)] {
+            let (core, run) = CoreBuilder::new(SecretKey::from_bytes([i; 32]))
+                .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+                .relay_only(relay_only)
+                .assemble();
+            tokio::spawn(run);
+            cores.push(core);
+        }
+        // Without an interface, B drops packets for itself once they pass the spoof check.
+        let (a, relay, b) = (&cores[0], &cores[1], &cores[2]);
+        a.connect_to_peer(relay.local_addrs()[0]).await.unwrap();
+        b.connect_to_peer(relay.local_addrs()[0]).await.unwrap();
+
+        // The relay announces A and B to each other, and both open a data connection to it.
+        let a_subnet = Subnet::from_address(a.address());
+        let b_subnet = Subnet::from_address(b.address());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while a
+                .routing_table
+                .read()
+                .unwrap()
+                .next_hop(&b_subnet)
+                .is_none()
+                || b.routing_table
+                    .read()
+                    .unwrap()
+                    .next_hop(&a_subnet)
+                    .is_none()
+                || a.active_data_peers() == 0
+                || relay.active_data_peers() < 2
+            {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(a.route_packet(ipv6_packet(a.address(), b.address())).await);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while b.dropped_packets() == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(b.spoofed_packets(), 0);
+        assert_eq!(relay.spoofed_packets(), 0);
     }
 }
@@ -0,0 +1,449 @@
+//! Construction of a [`Core`], separate from running it.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize},
+    Arc, Mutex, RwLock,
+};
+
+use futures::future::join_all;
+use log::{error, info, warn};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, watch, Notify, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    Core, Identity, IdleEviction, DATA_QUEUE_SIZE, DEFAULT_CONNECTION_QUEUE_SIZE,
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_PENDING_HANDSHAKES, DEFAULT_WRITE_TIMEOUT,
+    EVENT_QUEUE_SIZE, ICMP_RATE_LIMIT,
+};
+use crate::address::AddressScheme;
+use crate::crypto::ed25519::{PublicKey, SecretKey};
+use crate::crypto::session::DEFAULT_REPLAY_WINDOW;
+use crate::data;
+use crate::net::{self, Dialer, PeerAddr, SocketOptions};
+use crate::netlink::KernelRoutes;
+use crate::pcap::PacketCapture;
+use crate::peer::{AddressPolicy, KeyFilter};
+use crate::ratelimit::TokenBucket;
+use crate::routing::RoutingTable;
+use crate::sampling::Sampler;
+use crate::stats::{QueueGauge, TrafficCounters};
+use crate::transport::TransportKind;
+use crate::tun::{Tun, DEFAULT_MTU};
+
+/// Builder for a [`Core`].
+///
+/// [`CoreBuilder::build`] only sets up the [`Core`], it doesn't accept connections or read
+/// packets yet. That happens once the returned future is driven, so the [`Core`] can be
+/// configured first, and embedded in a larger application which decides where it runs.
+pub struct CoreBuilder {
+    identity: SecretKey,
+    address_scheme: AddressScheme,
+    local_keys: HashSet<PublicKey>,
+    listen_addrs: Vec<SocketAddr>,
+    listeners: Vec<TcpListener>,
+    peers: Vec<PeerAddr>,
+    mtu: Option<usize>,
+    jumbo: bool,
+    tun: Vec<Arc<Tun>>,
+    relay_only: bool,
+    kernel_routes: Option<KernelRoutes>,
+    sampler: Option<Sampler>,
+    capture: Option<PacketCapture>,
+    address_policy: AddressPolicy,
+    key_filter: KeyFilter,
+    dialer: Dialer,
+    connection_queue_size: usize,
+}
+
+impl CoreBuilder {
+    /// Create a new [`CoreBuilder`] for a [`Core`] with the given identity. Without further
+    /// settings, the [`Core`] doesn't listen for connections, doesn't connect to any peers, and
+    /// drops all packets it receives.
+    pub fn new(identity: SecretKey) -> Self {
+        Self {
+            identity,
+            address_scheme: AddressScheme::default(),
+            local_keys: HashSet::new(),
+            listen_addrs: Vec::new(),
+            listeners: Vec::new(),
+            peers: Vec::new(),
+            mtu: None,
+            jumbo: false,
+            tun: Vec::new(),
+            relay_only: false,
+            kernel_routes: None,
+            sampler: None,
+            capture: None,
+            address_policy: AddressPolicy::default(),
+            key_filter: KeyFilter::default(),
+            dialer: Dialer::default(),
+            connection_queue_size: DEFAULT_CONNECTION_QUEUE_SIZE,
+        }
+    }
+
+    /// Set the scheme used to derive overlay addresses from public keys.
+    pub fn address_scheme(mut self, address_scheme: AddressScheme) -> Self {
+        self.address_scheme = address_scheme;
+        self
+    }
+
+    /// Host additional identities besides our own, see [`Core::add_local_key`]. By default, only
+    /// packets for our own identity are terminated here.
+    pub fn local_keys(mut self, keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        self.local_keys.extend(keys);
+        self
+    }
+
+    /// Listen for inbound connections on the given address. Can be called multiple times to
+    /// listen on several addresses. The address is bound by [`CoreBuilder::build`], with the
+    /// maximum segment size of the [`Dialer`], if any.
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.listen_addrs.push(addr);
+        self
+    }
+
+    /// Accept inbound connections on an already bound listener. Can be called multiple times,
+    /// and combined with [`CoreBuilder::listen_addr`].
+    #[cfg(test)]
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Keep a connection to the peers at the given addresses, see [`Core::add_persistent_peer`].
+    /// The peers are connected to once the [`Core`] runs.
+    pub fn peers(mut self, peers: impl IntoIterator<Item = PeerAddr>) -> Self {
+        self.peers.extend(peers);
+        self
+    }
+
+    /// Set the MTU of the overlay, which determines the largest packet accepted on data
+    /// connections. By default, this is the MTU of the TUN interface if one is set, or
+    /// [`DEFAULT_MTU`] otherwise.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Announce support for jumbo packets on data connections, so packets larger than 64KiB can
+    /// be exchanged with peers which support them as well. The MTU set with
+    /// [`CoreBuilder::mtu`] can then exceed the MTU of the interface, up to
+    /// [`data::MAX_JUMBO_PACKET_SIZE`]. Packets which are too large for a peer without jumbo
+    /// support are dropped.
+    pub fn jumbo(mut self, jumbo: bool) -> Self {
+        self.jumbo = jumbo;
+        self
+    }
+
+    /// Exchange packets with the given queues of a TUN interface, which is created and owned by
+    /// the caller. Every queue is read by its own task. Packets received on data connections are
+    /// written to one of the queues. Without any queues, received packets are dropped.
+    pub fn tun(mut self, queues: Vec<Arc<Tun>>) -> Self {
+        self.tun = queues;
+        self
+    }
+
+    /// Only relay packets between peers, without a TUN interface. Packets received on data
+    /// connections are routed to the data connection of their destination, and dropped if they
+    /// are addressed to a local subnet. Any queues set with [`CoreBuilder::tun`] are not used.
+    pub fn relay_only(mut self, relay_only: bool) -> Self {
+        self.relay_only = relay_only;
+        self
+    }
+
+    /// Install a route in the kernel for every reachable subnet while the [`Core`] runs. The
+    /// routes are removed again once it is shut down.
+    pub fn kernel_routes(mut self, kernel_routes: KernelRoutes) -> Self {
+        self.kernel_routes = Some(kernel_routes);
+        self
+    }
+
+    /// Pass forwarded packets through the sampler to export flow records.
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Capture all packets read from and written to the interface.
+    pub fn capture(mut self, capture: PacketCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Only adopt addresses advertised by peers if they are allowed by the policy.
+    pub fn address_policy(mut self, address_policy: AddressPolicy) -> Self {
+        self.address_policy = address_policy;
+        self
+    }
+
+    /// Only accept connections from peers allowed by the filter. The filter can be replaced
+    /// later with [`Core::set_key_filter`].
+    pub fn key_filter(mut self, key_filter: KeyFilter) -> Self {
+        self.key_filter = key_filter;
+        self
+    }
+
+    /// Open outbound connections with the dialer.
+    pub fn dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Set the amount of inbound connections which completed their handshake, and can be queued
+    /// until they are registered. Defaults to [`DEFAULT_CONNECTION_QUEUE_SIZE`]. See
+    /// [`Core::connection_queue_peak`] to tell if the queue is too small.
+    pub fn connection_queue_size(mut self, size: usize) -> Self {
+        // A channel can't be created without room.
+        self.connection_queue_size = size.max(1);
+        self
+    }
+
+    /// Bind the listen addresses and set up the [`Core`]. The returned future runs the
+    /// [`Core`]: it starts accepting connections, reading packets from the TUN interface, and
+    /// connecting to the configured peers. The work is spread over tasks spawned on the current
+    /// tokio runtime, which keep running until [`Core::shutdown`] is called, after which the
+    /// future resolves.
+    ///
+    /// Listen addresses which can't be bound are logged and skipped, so the node can still be
+    /// reached on the others. Fails if there is no listener left, the error mentions the last
+    /// address which failed.
+    pub async fn build(
+        mut self,
+    ) -> io::Result<(Arc<Core>, impl Future<Output = ()> + Send + 'static)> {
+        let mut failed = None;
+        for addr in std::mem::take(&mut self.listen_addrs) {
+            match bind_listener(addr, self.dialer.tcp_mss).await {
+                Ok(listener) => {
+                    info!("Listening on {}", listener.local_addr().unwrap_or(addr));
+                    self.listeners.push(listener);
+                }
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", addr, e);
+                    failed = Some(io::Error::new(
+                        e.kind(),
+                        format!("failed to listen on {}: {}", addr, e),
+                    ));
+                }
+            }
+        }
+        match failed {
+            Some(e) if self.listeners.is_empty() => Err(e),
+            _ => Ok(self.assemble()),
+        }
+    }
+
+    /// Set up the [`Core`] with the listeners which are already bound, see
+    /// [`CoreBuilder::build`].
+    pub(super) fn assemble(mut self) -> (Arc<Core>, impl Future<Output = ()> + Send + 'static) {
+        let (tx, con_receiver) = mpsc::channel(self.connection_queue_size);
+        let (accepting, accepting_rx) = watch::channel(true);
+        if self.relay_only && !self.tun.is_empty() {
+            warn!("Not using the TUN interface in relay-only mode");
+            self.tun.clear();
+        }
+        let (relay, relay_rx) = match self.relay_only {
+            true => {
+                let (relay, relay_rx) = mpsc::channel(DATA_QUEUE_SIZE);
+                (Some(relay), Some(relay_rx))
+            }
+            false => (None, None),
+        };
+        // All queues belong to the same interface, so they share the MTU.
+        let mtu = self
+            .mtu
+            .unwrap_or_else(|| match self.tun.first().map(|tun| tun.mtu()) {
+                Some(Ok(mtu)) => mtu,
+                Some(Err(e)) => {
+                    warn!(
+                        "Failed to get the MTU of the interface, using the default: {}",
+                        e
+                    );
+                    DEFAULT_MTU as usize
+                }
+                None => DEFAULT_MTU as usize,
+            });
+
+        let core = Arc::new(Core {
+            identity: RwLock::new(Identity::new(self.identity)),
+            address_scheme: self.address_scheme,
+            local_keys: RwLock::new(self.local_keys),
+            local_subnets: RwLock::new(HashSet::new()),
+            listeners: self.listeners.into_iter().map(Arc::new).collect(),
+            peer_cache: Mutex::new(HashSet::new()),
+            dial_announced_peers: AtomicBool::new(false),
+            active_peers: Mutex::new(HashMap::new()),
+            next_control_con_id: AtomicU64::new(0),
+            active_data_peers: Arc::new(Mutex::new(HashMap::new())),
+            next_data_con_id: AtomicU64::new(0),
+            idle_eviction: RwLock::new(IdleEviction::default()),
+            idle_sweep_changed: Notify::new(),
+            rate_limit: RwLock::new(None),
+            dial_addrs: Mutex::new(HashMap::new()),
+            pending_dials: Mutex::new(HashMap::new()),
+            routing_table: Arc::new(RwLock::new(RoutingTable::new())),
+            kernel_routes: self
+                .kernel_routes
+                .map(|routes| Arc::new(Mutex::new(routes))),
+            queue_stats: Mutex::new(HashMap::new()),
+            control_decode_errors: AtomicUsize::new(0),
+            connection_queue: QueueGauge::new(),
+            connection_queue_blocked: AtomicU64::new(0),
+            connection_queue_dropped: AtomicU64::new(0),
+            dropped_packets: AtomicU64::new(0),
+            spoofed_packets: Arc::new(AtomicU64::new(0)),
+            replayed_packets: Arc::new(AtomicU64::new(0)),
+            replay_window: AtomicUsize::new(DEFAULT_REPLAY_WINDOW),
+            traffic: Arc::new(TrafficCounters::default()),
+            peer_traffic: Mutex::new(HashMap::new()),
+            next_ping_id: AtomicU32::new(0),
+            outstanding_pings: Mutex::new(HashMap::new()),
+            sampler: self.sampler,
+            capture: self.capture.map(Arc::new),
+            accepting,
+            address_policy: self.address_policy,
+            key_filter: RwLock::new(self.key_filter),
+            shutdown: CancellationToken::new(),
+            dialer: self.dialer,
+            tun: self.tun,
+            relay,
+            mtu,
+            max_packet_size: data::max_packet_size(mtu),
+            jumbo: self.jumbo,
+            icmp_limiter: Mutex::new(TokenBucket::new(ICMP_RATE_LIMIT)),
+            persistent_peers: Mutex::new(HashMap::new()),
+            keepalive: RwLock::new(None),
+            control_socket_options: RwLock::new(SocketOptions::CONTROL),
+            data_socket_options: RwLock::new(SocketOptions::default()),
+            data_transport: RwLock::new(TransportKind::default()),
+            compression: AtomicBool::new(false),
+            handshake_timeout: RwLock::new(DEFAULT_HANDSHAKE_TIMEOUT),
+            write_timeout: RwLock::new(DEFAULT_WRITE_TIMEOUT),
+            handshake_slots: RwLock::new(Arc::new(Semaphore::new(DEFAULT_MAX_PENDING_HANDSHAKES))),
+            events: broadcast::channel(EVENT_QUEUE_SIZE).0,
+        });
+        core.update_local_subnets();
+
+        let peers = self.peers;
+        let run = {
+            let core = core.clone();
+            async move {
+                let mut tasks = Vec::new();
+                // All listeners feed the same channel of connections.
+                for listener in core.listeners.iter().cloned() {
+                    tasks.push(tokio::spawn(Core::start_listener(
+                        core.clone(),
+                        listener,
+                        accepting_rx.clone(),
+                        tx.clone(),
+                    )));
+                }
+                tasks.push(tokio::spawn(Core::handle_connections(
+                    core.clone(),
+                    con_receiver,
+                )));
+                tasks.push(tokio::spawn(Core::reap_idle(core.clone())));
+                if core.kernel_routes.is_some() {
+                    tasks.push(tokio::spawn(Core::maintain_kernel_routes(core.clone())));
+                }
+                if let Some(relay_rx) = relay_rx {
+                    tasks.push(tokio::spawn(Core::relay_packets(core.clone(), relay_rx)));
+                }
+                for queue in core.tun.iter().cloned() {
+                    tasks.push(tokio::spawn(Core::read_tun(core.clone(), queue)));
+                }
+                for peer in peers {
+                    if core.add_persistent_peer(peer.clone()) {
+                        info!("Added peer {}", peer);
+                    }
+                }
+                // The tasks only stop once the core is shut down.
+                join_all(tasks).await;
+            }
+        };
+
+        (core, run)
+    }
+}
+
+/// Bind a listener on the given address. Accepted connections inherit the maximum segment size,
+/// if one is given.
+async fn bind_listener(addr: SocketAddr, tcp_mss: Option<u32>) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    if let Some(mss) = tcp_mss {
+        net::set_tcp_mss(&listener, mss)?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn builds_core_which_runs_once_driven() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (core, run) = CoreBuilder::new(SecretKey::from_bytes([1; 32]))
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .peers([PeerAddr::from(remote.local_addr().unwrap())])
+            .mtu(1280)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(core.local_addrs().len(), 2);
+        assert_eq!(core.max_packet_size, data::max_packet_size(1280));
+        // Nothing happens until the core runs.
+        assert!(core.persistent_peers().is_empty());
+
+        let run = tokio::spawn(run);
+        while core.persistent_peers().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        core.shutdown(Duration::from_millis(10)).await;
+        tokio::time::timeout(Duration::from_secs(1), run)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_address_which_fails_to_bind() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let err = match CoreBuilder::new(SecretKey::from_bytes([1; 32]))
+            .listen_addr(addr)
+            .build()
+            .await
+        {
+            Ok(_) => panic!("bound an address which is in use"),
+            Err(e) => e,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().contains(&addr.to_string()));
+    }
+
+    #[tokio::test]
+    async fn skips_addresses_which_fail_to_bind() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (core, _run) = CoreBuilder::new(SecretKey::from_bytes([1; 32]))
+            .listen_addr(taken.local_addr().unwrap())
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .dialer(Dialer {
+                tcp_mss: Some(1000),
+                ..Dialer::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(core.listeners.len(), 1);
+        assert_eq!(net::tcp_mss(&*core.listeners[0]).unwrap(), 1000);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{fmt, net::Ipv6Addr};
+
+use etherparse::{EtherType, Icmpv6Type, Ipv6HeaderSlice, PacketBuilder};
+
+/// Version field of an IPv4 header.
+const IP_VERSION_4: u8 = 4;
+
+/// Version field of an IPv6 header.
+const IP_VERSION_6: u8 = 6;
+
+/// Size of a fixed IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// Smallest MTU of a link which carries IPv6. ICMPv6 error messages never exceed this.
+const IPV6_MIN_MTU: usize = 1280;
+
+/// Size of the header of an ICMPv6 error message.
+const ICMPV6_HEADER_SIZE: usize = 8;
+
+/// Next header value of ICMPv6.
+const NEXT_HEADER_ICMPV6: u8 = 58;
+
+/// ICMPv6 messages with a type below this are error messages.
+const ICMPV6_INFORMATIONAL_TYPES: u8 = 128;
+
+/// Hop limit of generated ICMPv6 messages.
+const ICMPV6_HOP_LIMIT: u8 = 64;
+
+/// Reasons a packet read from the interface is not forwarded to peers. The overlay only carries
+/// IPv6, so anything else is dropped before it reaches a data connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// The packet is empty.
+    Empty,
+    /// The packet is not an IPv6 packet. The value is the version field of the IP header.
+    NotIpv6(u8),
+    /// The packet claims to be IPv6, but is too short to hold an IPv6 header.
+    Truncated(usize),
+    /// The payload is shorter than the payload length in the IPv6 header.
+    TruncatedPayload {
+        /// Payload length in the header.
+        expected: usize,
+        /// Actual length of the payload.
+        actual: usize,
+    },
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Empty => f.pad("empty packet"),
+            Rejected::NotIpv6(version) => match ether_type(*version) {
+                Some(ether_type) => write!(f, "non IPv6 packet of type {:?}", ether_type),
+                None => write!(f, "packet with unknown IP version {}", version),
+            },
+            Rejected::Truncated(len) => write!(f, "truncated IPv6 packet of {} bytes", len),
+            Rejected::TruncatedPayload { expected, actual } => write!(
+                f,
+                "IPv6 packet with {} bytes of payload, while the header claims {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+/// The ether type matching the version field of an IP header, if it is a known IP version.
+fn ether_type(version: u8) -> Option<EtherType> {
+    match version {
+        IP_VERSION_4 => Some(EtherType::Ipv4),
+        IP_VERSION_6 => Some(EtherType::Ipv6),
+        _ => None,
+    }
+}
+
+/// Check that a raw IP packet, as read from the interface, is an IPv6 packet which can be
+/// forwarded over the overlay.
+pub fn validate(packet: &[u8]) -> Result<(), Rejected> {
+    let version = match packet.first() {
+        Some(b) => b >> 4,
+        None => return Err(Rejected::Empty),
+    };
+    if version != IP_VERSION_6 {
+        return Err(Rejected::NotIpv6(version));
+    }
+    if packet.len() < IPV6_HEADER_SIZE {
+        return Err(Rejected::Truncated(packet.len()));
+    }
+    Ok(())
+}
+
+/// The fields of an IPv6 header which matter for forwarding a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+    /// Source address.
+    pub src: Ipv6Addr,
+    /// Destination address.
+    pub dst: Ipv6Addr,
+    /// Traffic class, holding the DSCP and ECN bits.
+    pub traffic_class: u8,
+    /// Flow label, only the lower 20 bits are used.
+    pub flow_label: u32,
+}
+
+/// Parse the header of a raw IPv6 packet, as read from the interface. Packets which are not
+/// IPv6, or which are shorter than their header claims, are rejected.
+pub fn parse_header(packet: &[u8]) -> Result<Ipv6Header, Rejected> {
+    validate(packet)?;
+    // validate checks the version and the size of the header, which is all this checks.
+    let header =
+        Ipv6HeaderSlice::from_slice(packet).map_err(|_| Rejected::Truncated(packet.len()))?;
+    let expected = header.payload_length() as usize;
+    let actual = packet.len() - IPV6_HEADER_SIZE;
+    if actual < expected {
+        return Err(Rejected::TruncatedPayload { expected, actual });
+    }
+    Ok(Ipv6Header {
+        src: header.source_addr(),
+        dst: header.destination_addr(),
+        traffic_class: header.traffic_class(),
+        flow_label: header.flow_label(),
+    })
+}
+
+/// Build an ICMPv6 "Packet Too Big" message from `src`, telling the sender of `packet` to send
+/// packets of at most `mtu` bytes, so path MTU discovery works across the overlay. As much of
+/// the packet as fits in the minimum IPv6 MTU is quoted, so the sender can match it to the
+/// connection it belongs to.
+///
+/// Following RFC 4443 section 2.4, [`None`] is returned for packets which must not be answered
+/// with an error: ICMPv6 error messages, and packets from the unspecified or a multicast address.
+pub fn packet_too_big(
+    src: Ipv6Addr,
+    packet: &[u8],
+    header: &Ipv6Header,
+    mtu: u32,
+) -> Option<Vec<u8>> {
+    if header.src.is_unspecified() || header.src.is_multicast() {
+        return None;
+    }
+    if packet.get(6) == Some(&NEXT_HEADER_ICMPV6)
+        && packet
+            .get(IPV6_HEADER_SIZE)
+            .is_none_or(|t| *t < ICMPV6_INFORMATIONAL_TYPES)
+    {
+        return None;
+    }
+    let quoted = &packet[..packet
+        .len()
+        .min(IPV6_MIN_MTU - IPV6_HEADER_SIZE - ICMPV6_HEADER_SIZE)];
+    let builder = PacketBuilder::ipv6(src.octets(), header.src.octets(), ICMPV6_HOP_LIMIT)
+        .icmpv6(Icmpv6Type::PacketTooBig { mtu });
+    let mut message = Vec::with_capacity(builder.size(quoted.len()));
+    // Writing to a Vec can't fail, and the quoted packet is small enough for the checksum.
+    builder.write(&mut message, quoted).ok()?;
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_accepts_ipv6() {
+        let mut ipv6 = [0; IPV6_HEADER_SIZE];
+        ipv6[0] = 0x60;
+        assert_eq!(validate(&ipv6), Ok(()));
+
+        let mut ipv4 = [0; 20];
+        ipv4[0] = 0x45;
+        assert_eq!(validate(&ipv4), Err(Rejected::NotIpv6(4)));
+        assert_eq!(
+            Rejected::NotIpv6(4).to_string(),
+            "non IPv6 packet of type Ipv4"
+        );
+        assert_eq!(validate(&[0xf0; 64]), Err(Rejected::NotIpv6(15)));
+        assert_eq!(validate(&[]), Err(Rejected::Empty));
+        assert_eq!(validate(&ipv6[..39]), Err(Rejected::Truncated(39)));
+    }
+
+    #[test]
+    fn parses_ipv6_header() {
+        let src: Ipv6Addr = "200:1:2:3::1".parse().unwrap();
+        let dst: Ipv6Addr = "300:4:5:6::1".parse().unwrap();
+        let mut packet = [0; IPV6_HEADER_SIZE + 8];
+        // Version 6, traffic class 0xb8 (DSCP EF), flow label 0x12345.
+        packet[..4].copy_from_slice(&[0x6b, 0x81, 0x23, 0x45]);
+        packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+        packet[8..24].copy_from_slice(&src.octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+
+        assert_eq!(
+            parse_header(&packet),
+            Ok(Ipv6Header {
+                src,
+                dst,
+                traffic_class: 0xb8,
+                flow_label: 0x12345,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_headers_and_payloads() {
+        let mut packet = [0; IPV6_HEADER_SIZE + 8];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+        assert_eq!(parse_header(&packet[..20]), Err(Rejected::Truncated(20)));
+        assert_eq!(
+            parse_header(&packet[..IPV6_HEADER_SIZE + 4]),
+            Err(Rejected::TruncatedPayload {
+                expected: 8,
+                actual: 4
+            })
+        );
+        assert_eq!(parse_header(&[0x45; 20]), Err(Rejected::NotIpv6(4)));
+    }
+
+    #[test]
+    fn builds_packet_too_big() {
+        let local: Ipv6Addr = "200:1:2:3::1".parse().unwrap();
+        let sender: Ipv6Addr = "200:1:2:3::2".parse().unwrap();
+        let dst: Ipv6Addr = "300:4:5:6::1".parse().unwrap();
+        let mut packet = vec![0; 2000];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&1960u16.to_be_bytes());
+        // UDP.
+        packet[6] = 17;
+        packet[8..24].copy_from_slice(&sender.octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+        let header = parse_header(&packet).unwrap();
+
+        let message = packet_too_big(local, &packet, &header, 1420).unwrap();
+        assert_eq!(message.len(), IPV6_MIN_MTU);
+        let ip = Ipv6HeaderSlice::from_slice(&message).unwrap();
+        assert_eq!(ip.source_addr(), local);
+        assert_eq!(ip.destination_addr(), sender);
+        let icmp = etherparse::Icmpv6Slice::from_slice(&message[IPV6_HEADER_SIZE..]).unwrap();
+        assert_eq!(icmp.icmp_type(), Icmpv6Type::PacketTooBig { mtu: 1420 });
+        assert!(icmp.is_checksum_valid(local.octets(), sender.octets()));
+        assert_eq!(
+            &message[IPV6_HEADER_SIZE + ICMPV6_HEADER_SIZE..],
+            &packet[..IPV6_MIN_MTU - IPV6_HEADER_SIZE - ICMPV6_HEADER_SIZE]
+        );
+
+        // ICMPv6 errors are never answered with another error.
+        packet[6] = NEXT_HEADER_ICMPV6;
+        packet[IPV6_HEADER_SIZE] = 2;
+        assert_eq!(packet_too_big(local, &packet, &header, 1420), None);
+        // Neither are packets without a unicast source.
+        let header = Ipv6Header {
+            src: Ipv6Addr::UNSPECIFIED,
+            ..header
+        };
+        packet[6] = 17;
+        assert_eq!(packet_too_big(local, &packet, &header, 1420), None);
+    }
+}
@@ -1,18 +1,49 @@
 use std::fmt;
 
+pub mod chacha20poly1305;
 pub mod ed25519;
+pub mod rng;
+pub mod session;
 
 /// Errors related to cryptographic operations.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    /// The given data is not valid to construct a type.
-    InvalidData,
+    /// The bytes of a public key don't encode a valid point on the curve.
+    InvalidPublicKey,
+    /// Key material of the wrong length was provided.
+    InvalidKeyLength {
+        /// The length of a key in bytes.
+        expected: usize,
+        /// The amount of bytes which were provided.
+        got: usize,
+    },
+    /// A textual key is not valid hex.
+    InvalidEncoding,
+    /// A signature does not match the message and the public key it was checked against.
+    SignatureVerification,
+    /// A message failed authentication, it was tampered with or encrypted with another key.
+    AuthenticationFailed,
+    /// All nonces of a session key are used, the key can't be used to encrypt anymore.
+    NonceExhausted,
+    /// A datagram with the same sequence number was already opened, or the sequence number is
+    /// too old to tell.
+    Replayed,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::InvalidData => f.pad("invalid data"),
+            Error::InvalidPublicKey => f.pad("public key is not a valid curve point"),
+            Error::InvalidKeyLength { expected, got } => write!(
+                f,
+                "invalid key length, expected {} bytes but got {}",
+                expected, got
+            ),
+            Error::InvalidEncoding => f.pad("key is not valid hex"),
+            Error::SignatureVerification => f.pad("signature verification failed"),
+            Error::AuthenticationFailed => f.pad("message authentication failed"),
+            Error::NonceExhausted => f.pad("session nonces exhausted"),
+            Error::Replayed => f.pad("datagram was replayed or is too old"),
         }
     }
 }
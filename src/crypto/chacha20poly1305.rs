@@ -0,0 +1,88 @@
+//! The ChaCha20-Poly1305 AEAD construction, as specified in
+//! [RFC 8439](https://www.rfc-editor.org/rfc/rfc8439), on top of the implementation of the
+//! RustCrypto project.
+
+use ::chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+
+/// Size in bytes of a ChaCha20-Poly1305 key.
+pub const KEY_SIZE: usize = 32;
+
+/// Size in bytes of a ChaCha20-Poly1305 nonce.
+pub const NONCE_SIZE: usize = 12;
+
+/// Size in bytes of the authentication tag appended to every message.
+pub const TAG_SIZE: usize = 16;
+
+/// Encrypt `buf` in place, and return the tag authenticating both the ciphertext and `aad`.
+///
+/// A nonce must never be used more than once with the same key.
+///
+/// # Panics
+///
+/// This function will panic if `buf` is larger than the 256 GiB a single message can hold.
+pub fn seal(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buf: &mut [u8],
+) -> [u8; TAG_SIZE] {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt_in_place_detached(&Nonce::from(*nonce), aad, buf)
+        .expect("message fits in a single ChaCha20 keystream")
+        .into()
+}
+
+/// Verify the tag of the ciphertext in `buf` and `aad`, and decrypt `buf` in place. If the tag
+/// does not match, `buf` is left untouched and an error is returned. The tag is compared in
+/// constant time.
+pub fn open(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buf: &mut [u8],
+    tag: &[u8; TAG_SIZE],
+) -> Result<(), super::Error> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt_in_place_detached(&Nonce::from(*nonce), aad, buf, &Tag::from(*tag))
+        .map_err(|_| super::Error::AuthenticationFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(raw: &str) -> Vec<u8> {
+        let raw: String = raw.split_whitespace().collect();
+        (0..raw.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&raw[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    /// Test vector from RFC 8439, section 2.8.2.
+    fn aead_roundtrip() {
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let key: [u8; KEY_SIZE] = (0x80..0xa0).collect::<Vec<u8>>().try_into().unwrap();
+        let nonce = [
+            0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+
+        let mut buf = plaintext.to_vec();
+        let tag = seal(&key, &nonce, &aad, &mut buf);
+        assert_eq!(buf[..16], unhex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+        assert_eq!(tag[..], unhex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+
+        let mut tampered = buf.clone();
+        tampered[0] ^= 1;
+        assert!(open(&key, &nonce, &aad, &mut tampered, &tag).is_err());
+        assert!(open(&key, &nonce, &[], &mut buf.clone(), &tag).is_err());
+
+        open(&key, &nonce, &aad, &mut buf, &tag).unwrap();
+        assert_eq!(buf, plaintext);
+    }
+}
@@ -1,5 +1,21 @@
-use ed25519_dalek::{Keypair, PublicKey as DalekPublicKey, SecretKey as DalekSecretKey};
-use std::net::Ipv6Addr;
+use super::rng::Rng;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{
+    ExpandedSecretKey, PublicKey as DalekPublicKey, SecretKey as DalekSecretKey,
+    Signature as DalekSignature,
+};
+use sha2::{Digest, Sha512};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::{
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    net::Ipv6Addr,
+    path::Path,
+    str::FromStr,
+};
+use subtle::ConstantTimeEq;
 
 /// Length in bytes of an Ed25519 public key.
 pub const PUBLIC_KEY_LENGTH: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
@@ -7,8 +23,11 @@ pub const PUBLIC_KEY_LENGTH: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
 /// Length in bytes of an Ed25519 secret key.
 pub const SECRET_KEY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
 
+/// Length in bytes of an Ed25519 signature.
+pub const SIGNATURE_LENGTH: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
 /// Ported from <https://github.com/yggdrasil-network/yggdrasil-go/blob/8c454a146cb70aa07ee2c87af964f5c1394da299/src/address/address.go#L19>.
-const PREFIX: [u8; 1] = [0x02];
+pub const PREFIX: [u8; 1] = [0x02];
 
 /// Amount of bytes in an IPv6 address.
 const IPV6_OCTETS: usize = 16;
@@ -17,29 +36,150 @@ const IPV6_OCTETS: usize = 16;
 pub struct SecretKey(DalekSecretKey);
 
 /// An Ed25519 public key.
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct PublicKey(DalekPublicKey);
 
+/// An Ed25519 signature of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature([u8; SIGNATURE_LENGTH]);
+
+impl Signature {
+    /// Creates a new instance of [`Signature`] from the given bytes. Whether the bytes are a
+    /// well formed signature is only checked when it is verified.
+    pub fn from_bytes(raw: [u8; SIGNATURE_LENGTH]) -> Self {
+        Self(raw)
+    }
+
+    /// View this signature as a byte array.
+    pub fn as_bytes(&self) -> &[u8; SIGNATURE_LENGTH] {
+        &self.0
+    }
+}
+
+/// Keys are compared in constant time. Public keys aren't secret, but which keys we know can be,
+/// e.g. the keys on an allowlist. A comparison which stops at the first differing byte would let
+/// a remote probing with crafted keys learn how much of a known key it guessed right.
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+}
+
+impl Eq for PublicKey {}
+
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    /// Formats the key as lowercase hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = super::Error;
+
+    /// Parses a key from the lowercase or uppercase hex produced by its [`Display`](fmt::Display)
+    /// implementation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_bytes(decode_hex(s)?)
+    }
+}
+
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = super::Error;
+
+    /// Reads a key from a slice, which must be exactly [`PUBLIC_KEY_LENGTH`] bytes long, and
+    /// encode a valid point on the curve.
+    fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
+        let raw = raw.try_into().map_err(|_| super::Error::InvalidKeyLength {
+            expected: PUBLIC_KEY_LENGTH,
+            got: raw.len(),
+        })?;
+        Self::from_bytes(raw)
+    }
+}
+
+/// Decode lowercase or uppercase hex into a key of `N` bytes.
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N], super::Error> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(super::Error::InvalidEncoding);
+    }
+    if s.len() != 2 * N {
+        return Err(super::Error::InvalidKeyLength {
+            expected: N,
+            got: s.len() / 2,
+        });
+    }
+    let mut raw = [0; N];
+    for (b, hex) in raw.iter_mut().zip(s.as_bytes().chunks(2)) {
+        // The string only consists of hex digits, so every chunk is a valid byte.
+        *b = u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap();
+    }
+    Ok(raw)
+}
+
 impl PublicKey {
-    /// Creates a new instance of [`PublicKey`] from the given bytes.
+    /// Creates a new instance of [`PublicKey`] from the given bytes. An error is returned if
+    /// the bytes are not a valid point on the curve.
     pub fn from_bytes(raw: [u8; PUBLIC_KEY_LENGTH]) -> Result<Self, super::Error> {
         // We can ignore the invalid lenght error here since we take a fixed length slice of the
         // correct length as argument.
         Ok(Self(
-            DalekPublicKey::from_bytes(&raw[..]).map_err(|_| super::Error::InvalidData)?,
+            DalekPublicKey::from_bytes(&raw[..]).map_err(|_| super::Error::InvalidPublicKey)?,
         ))
     }
 
+    /// Verify that the signature was made over the message by the secret key of this public key.
+    /// Signatures are checked strictly, so a valid signature can't be altered into another valid
+    /// signature of the same message.
+    pub fn verify(&self, msg: &[u8], sig: &Signature) -> Result<(), super::Error> {
+        let sig = DalekSignature::try_from(&sig.0[..])
+            .map_err(|_| super::Error::SignatureVerification)?;
+        self.0
+            .verify_strict(msg, &sig)
+            .map_err(|_| super::Error::SignatureVerification)
+    }
+
     /// View this public key as a byte array
     pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
         self.0.as_bytes()
     }
 
+    /// Convert this key to the equivalent X25519 public key, so it can be used for a
+    /// Diffie-Hellman key exchange.
+    pub fn to_x25519(&self) -> x25519_dalek::PublicKey {
+        // The key was already decompressed when it was constructed, so it is a valid
+        // point.
+        let point = CompressedEdwardsY(*self.as_bytes()).decompress().unwrap();
+        point.to_montgomery().to_bytes().into()
+    }
+
     /// Derive the IPv6 address from the [`PublicKey`].
     ///
     /// This is ported from <https://github.com/yggdrasil-network/yggdrasil-go/blob/8c454a146cb70aa07ee2c87af964f5c1394da299/src/address/address.go#L51>.
     /// It is not entirely clear why this function works like this, perhaps there are better ways.
     pub fn address(&self) -> Ipv6Addr {
+        self.address_with_prefix(&PREFIX)
+    }
+
+    /// Derive the IPv6 address from the [`PublicKey`] like [`PublicKey::address`], but starting
+    /// with the given prefix instead of [`PREFIX`], so the addresses of isolated overlays don't
+    /// collide with yggdrasil addresses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the prefix is not shorter than 15 bytes, as there would be no room left for the
+    /// part derived from the key.
+    pub fn address_with_prefix(&self, prefix: &[u8]) -> Ipv6Addr {
+        assert!(prefix.len() < IPV6_OCTETS - 1, "address prefix too long");
         let mut working_buffer = [0; PUBLIC_KEY_LENGTH];
         for (b, o) in working_buffer.iter_mut().zip(self.0.as_bytes()) {
             *b = !*o;
@@ -76,18 +216,33 @@ impl PublicKey {
 
         let mut raw_addr = [0; IPV6_OCTETS];
         // SAFETY: Panic only happens if the slices have different length, but raw_addr is sliced
-        // to the size of PREFIX.
-        raw_addr[..PREFIX.len()].copy_from_slice(&PREFIX[..]);
-        raw_addr[PREFIX.len()] = ones;
+        // to the size of the prefix.
+        raw_addr[..prefix.len()].copy_from_slice(prefix);
+        raw_addr[prefix.len()] = ones;
         // SAFETY: Panic only happens if the slices have different length, but temp is sliced to the
         // same size of the raw_addr slice.
-        raw_addr[PREFIX.len() + 1..].copy_from_slice(&temp[..IPV6_OCTETS - (PREFIX.len() + 1)]);
+        raw_addr[prefix.len() + 1..].copy_from_slice(&temp[..IPV6_OCTETS - (prefix.len() + 1)]);
 
         Ipv6Addr::from(raw_addr)
     }
 }
 
+impl FromStr for SecretKey {
+    type Err = super::Error;
+
+    /// Parses a key from lowercase or uppercase hex. Errors never contain any part of the input,
+    /// so they are safe to log.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_hex(s).map(Self::from_bytes)
+    }
+}
+
 impl SecretKey {
+    /// Generate a new random [`SecretKey`].
+    pub fn generate() -> Self {
+        Self(DalekSecretKey::generate(&mut Rng))
+    }
+
     /// Creates a new instance of [`SecretKey`] from the given bytes.
     pub fn from_bytes(raw: [u8; SECRET_KEY_LENGTH]) -> Self {
         // We can ignore the invalid lenght error here since we take a fixed length slice of the
@@ -98,21 +253,103 @@ impl SecretKey {
         Self(DalekSecretKey::from_bytes(&raw[..]).unwrap())
     }
 
+    /// Creates a new instance of [`SecretKey`] from a slice, which must be exactly
+    /// [`SECRET_KEY_LENGTH`] bytes long.
+    pub fn from_slice(raw: &[u8]) -> Result<Self, super::Error> {
+        let raw = raw.try_into().map_err(|_| super::Error::InvalidKeyLength {
+            expected: SECRET_KEY_LENGTH,
+            got: raw.len(),
+        })?;
+        Ok(Self::from_bytes(raw))
+    }
+
     /// View this secret key as a byte array
     pub fn as_bytes(&self) -> &[u8; SECRET_KEY_LENGTH] {
         self.0.as_bytes()
     }
 
+    /// Load a secret key from a file containing the raw bytes of the key.
+    pub fn load_from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let raw = fs::read(path)?;
+        Self::from_slice(&raw).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid key file: {}", e),
+            )
+        })
+    }
+
+    /// Save the raw bytes of this secret key to a file. If the file already exists, it is
+    /// overwritten. On unix, the file is only accessible by its owner.
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        // The mode is only applied when the file is created, so make sure an existing file is
+        // not readable by others either.
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(self.as_bytes())?;
+        file.sync_all()
+    }
+
+    /// Sign a message with this key. The signature can be checked with [`PublicKey::verify`] on
+    /// the public key of this key.
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        let public = DalekPublicKey::from(&self.0);
+        Signature(
+            ExpandedSecretKey::from(&self.0)
+                .sign(msg, &public)
+                .to_bytes(),
+        )
+    }
+
     pub fn public_key(&self) -> PublicKey {
         PublicKey((&self.0).into())
     }
+
+    /// Convert this key to the equivalent X25519 secret key, which matches the X25519 public key
+    /// returned by [`PublicKey::to_x25519`] for the public key of this secret key.
+    pub fn to_x25519(&self) -> x25519_dalek::StaticSecret {
+        // Ed25519 uses the first half of the hash of the secret key as scalar. Clamping is done
+        // by the X25519 implementation.
+        let hash = Sha512::digest(self.as_bytes());
+        let mut scalar = [0; 32];
+        scalar.copy_from_slice(&hash[..32]);
+        scalar.into()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PublicKey;
+    use super::{PublicKey, SecretKey, Signature};
     use std::net::Ipv6Addr;
 
+    #[test]
+    fn public_key_displays_as_hex() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let hex = key.to_string();
+        assert_eq!(hex.len(), 64);
+        for (i, b) in key.as_bytes().iter().enumerate() {
+            assert_eq!(u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap(), *b);
+        }
+        assert_eq!(hex.parse::<PublicKey>().unwrap(), key);
+        assert_eq!(hex.to_uppercase().parse::<PublicKey>().unwrap(), key);
+        assert_eq!(
+            hex[..62].parse::<PublicKey>().err(),
+            Some(crate::crypto::Error::InvalidKeyLength {
+                expected: 32,
+                got: 31
+            })
+        );
+        assert_eq!(
+            format!("zz{}", &hex[2..]).parse::<PublicKey>().err(),
+            Some(crate::crypto::Error::InvalidEncoding)
+        );
+    }
+
     #[test]
     /// Test ported from
     /// <https://github.com/yggdrasil-network/yggdrasil-go/blob/8c454a146cb70aa07ee2c87af964f5c1394da299/src/address/address_test.go#L56>.
@@ -129,4 +366,167 @@ mod tests {
 
         assert_eq!(key.address(), expected_ip)
     }
+
+    #[test]
+    fn address_with_custom_prefix() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        assert_eq!(key.address_with_prefix(&super::PREFIX), key.address());
+
+        // Only the prefix differs.
+        let address = key.address_with_prefix(&[0xfd]);
+        assert_eq!(address.octets()[0], 0xfd);
+        assert_eq!(address.octets()[1..], key.address().octets()[1..]);
+
+        // Longer prefixes leave less room for the rest of the address.
+        let address = key.address_with_prefix(&[0xfd, 0x12]).octets();
+        assert_eq!(address[..2], [0xfd, 0x12]);
+        assert_eq!(address[2..], key.address().octets()[1..15]);
+    }
+
+    #[test]
+    fn reports_specific_key_errors() {
+        // Not a valid point on the curve.
+        let mut raw = [0; 32];
+        raw[0] = 2;
+        assert_eq!(
+            PublicKey::from_bytes(raw).err(),
+            Some(crate::crypto::Error::InvalidPublicKey)
+        );
+
+        let err = SecretKey::from_slice(&[1; 16]).err().unwrap();
+        assert_eq!(
+            err,
+            crate::crypto::Error::InvalidKeyLength {
+                expected: 32,
+                got: 16
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "invalid key length, expected 32 bytes but got 16"
+        );
+    }
+
+    #[test]
+    fn converts_public_key_from_slice() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        assert_eq!(PublicKey::try_from(&key.as_bytes()[..]).unwrap(), key);
+
+        // Trailing bytes are not silently ignored.
+        let mut long = key.as_bytes().to_vec();
+        long.push(0);
+        for (raw, got) in [(&key.as_bytes()[..31], 31), (&long[..], 33), (&[][..], 0)] {
+            assert_eq!(
+                PublicKey::try_from(raw).err(),
+                Some(crate::crypto::Error::InvalidKeyLength { expected: 32, got })
+            );
+        }
+
+        let mut raw = [0; 32];
+        raw[0] = 2;
+        assert_eq!(
+            PublicKey::try_from(&raw[..]).err(),
+            Some(crate::crypto::Error::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn parses_secret_key_from_hex() {
+        let key = SecretKey::generate();
+        let hex: String = key
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        let parsed: SecretKey = hex.parse().unwrap();
+        assert_eq!(parsed.as_bytes(), key.as_bytes());
+
+        assert_eq!(
+            "zz".repeat(32).parse::<SecretKey>().err(),
+            Some(crate::crypto::Error::InvalidEncoding)
+        );
+        assert_eq!(
+            hex[..40].parse::<SecretKey>().err(),
+            Some(crate::crypto::Error::InvalidKeyLength {
+                expected: 32,
+                got: 20
+            })
+        );
+    }
+
+    #[test]
+    fn signatures_roundtrip() {
+        let key = SecretKey::from_bytes([1; 32]);
+        let sig = key.sign(b"styx");
+        key.public_key().verify(b"styx", &sig).unwrap();
+        // Signatures survive a roundtrip through their raw bytes.
+        let sig = Signature::from_bytes(*sig.as_bytes());
+        key.public_key().verify(b"styx", &sig).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let key = SecretKey::from_bytes([1; 32]);
+        let sig = key.sign(b"styx");
+        let err = Some(crate::crypto::Error::SignatureVerification);
+
+        assert_eq!(key.public_key().verify(b"styX", &sig).err(), err);
+        let mut raw = *sig.as_bytes();
+        raw[0] ^= 1;
+        let public_key = key.public_key();
+        assert_eq!(
+            public_key
+                .verify(b"styx", &Signature::from_bytes(raw))
+                .err(),
+            err
+        );
+        let other = SecretKey::from_bytes([2; 32]).public_key();
+        assert_eq!(other.verify(b"styx", &sig).err(), err);
+    }
+
+    #[test]
+    fn generated_key_roundtrip() {
+        let key = SecretKey::generate();
+        let restored = SecretKey::from_bytes(*key.as_bytes());
+
+        assert_eq!(key.public_key(), restored.public_key());
+        // Keys are actually random.
+        assert_ne!(key.as_bytes(), SecretKey::generate().as_bytes());
+    }
+
+    #[test]
+    fn x25519_conversion_agrees() {
+        let a = SecretKey::from_bytes([1; 32]);
+        let b = SecretKey::from_bytes([2; 32]);
+
+        let ab = a.to_x25519().diffie_hellman(&b.public_key().to_x25519());
+        let ba = b.to_x25519().diffie_hellman(&a.public_key().to_x25519());
+        assert_eq!(ab.as_bytes(), ba.as_bytes());
+        assert_eq!(
+            x25519_dalek::PublicKey::from(&a.to_x25519()).as_bytes(),
+            a.public_key().to_x25519().as_bytes()
+        );
+    }
+
+    #[test]
+    fn save_and_load_key_file() {
+        let path = std::env::temp_dir().join(format!("styx-key-{}", std::process::id()));
+        let key = SecretKey::generate();
+        key.save_to_file(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = SecretKey::load_from_file(&path).unwrap();
+        assert_eq!(loaded.as_bytes(), key.as_bytes());
+
+        std::fs::write(&path, [1; 16]).unwrap();
+        let err = SecretKey::load_from_file(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,62 @@
+//! Randomness used for cryptographic operations.
+//!
+//! Production code always draws from [`OsRng`]. Tests can seed a deterministic generator for the
+//! current thread with [`seed`], so generated keys and handshakes can be reproduced exactly.
+
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+
+#[cfg(test)]
+use rand::{rngs::StdRng, SeedableRng};
+#[cfg(test)]
+use std::cell::RefCell;
+
+#[cfg(test)]
+thread_local! {
+    /// Seeded generator used instead of [`OsRng`] on this thread, if set.
+    static SEEDED: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Seed a deterministic generator for all cryptographic operations on the current thread.
+#[cfg(test)]
+pub fn seed(seed: u64) {
+    SEEDED.with(|rng| *rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+}
+
+/// The random number generator for all cryptographic operations. This is [`OsRng`], unless a
+/// test seeded a deterministic generator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rng;
+
+impl RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        #[cfg(test)]
+        if SEEDED.with(|rng| {
+            rng.borrow_mut()
+                .as_mut()
+                .map(|rng| rng.fill_bytes(dest))
+                .is_some()
+        }) {
+            return;
+        }
+        OsRng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Rng {}
@@ -0,0 +1,379 @@
+//! Encryption of the traffic on a connection between 2 peers.
+//!
+//! Once the handshake is done, both sides send a fresh ephemeral X25519 public key. The session
+//! keys are derived from the Diffie-Hellman of the static keys of both peers, which proves the
+//! remote owns the key it identified with, and the Diffie-Hellman of the ephemeral keys, which
+//! makes every session unique. Every direction has its own key.
+//!
+//! Messages are encrypted with ChaCha20-Poly1305. The nonce of a message is its sequence number
+//! in the direction it is sent in, so messages must be opened in the order they were sealed.
+//! Datagrams, which can be lost or reordered, carry their sequence number instead, see
+//! [`Session::seal_datagram`]. A sliding window of recently opened sequence numbers rejects
+//! datagrams which are replayed, like the anti-replay window of IPsec.
+
+use super::chacha20poly1305::{self, KEY_SIZE, NONCE_SIZE};
+use super::ed25519::PublicKey;
+use super::rng::Rng;
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+pub use super::chacha20poly1305::TAG_SIZE;
+
+/// Size in bytes of the ephemeral public key exchanged when establishing a session.
+const EPHEMERAL_KEY_SIZE: usize = 32;
+
+/// Default amount of sequence numbers below the highest one seen for which datagrams are still
+/// accepted.
+pub const DEFAULT_REPLAY_WINDOW: usize = 1024;
+
+/// Keys to encrypt messages to, and decrypt messages from a single peer.
+pub struct Session {
+    send: CipherState,
+    recv: CipherState,
+    /// Sequence numbers of datagrams which were opened.
+    replay: ReplayWindow,
+}
+
+/// Sliding window over the sequence numbers of received datagrams. Datagrams are accepted once,
+/// as long as their sequence number is not too far below the highest one accepted so far.
+struct ReplayWindow {
+    /// One bit for every sequence number in the window, indexed by the sequence number modulo the
+    /// size of the window.
+    bitmap: Vec<u64>,
+    /// One past the highest accepted sequence number, 0 if nothing was accepted yet.
+    top: u64,
+}
+
+impl ReplayWindow {
+    /// Create a new [`ReplayWindow`] covering at least `size` sequence numbers. The size is
+    /// rounded up to a multiple of 64.
+    fn new(size: usize) -> Self {
+        Self {
+            bitmap: vec![0; size.div_ceil(64).max(1)],
+            top: 0,
+        }
+    }
+
+    /// Amount of sequence numbers covered by the window.
+    fn size(&self) -> u64 {
+        self.bitmap.len() as u64 * 64
+    }
+
+    /// Word and mask of the bit of the given sequence number.
+    fn bit(&self, seq: u64) -> (usize, u64) {
+        let index = seq % self.size();
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    /// Check if a datagram with the given sequence number can be accepted. This doesn't mark it
+    /// as seen, which should only be done once it is authenticated, see [`ReplayWindow::mark`].
+    fn check(&self, seq: u64) -> Result<(), super::Error> {
+        if seq >= self.top {
+            return Ok(());
+        }
+        if self.top - seq > self.size() {
+            return Err(super::Error::Replayed);
+        }
+        let (word, mask) = self.bit(seq);
+        if self.bitmap[word] & mask != 0 {
+            return Err(super::Error::Replayed);
+        }
+        Ok(())
+    }
+
+    /// Mark the sequence number as seen, sliding the window forward if it is the highest one so
+    /// far.
+    fn mark(&mut self, seq: u64) {
+        if seq >= self.top {
+            // Forget the sequence numbers which fall out of the window.
+            if seq - self.top >= self.size() {
+                self.bitmap.iter_mut().for_each(|word| *word = 0);
+            } else {
+                for old in self.top..seq {
+                    let (word, mask) = self.bit(old);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.top = seq + 1;
+        }
+        let (word, mask) = self.bit(seq);
+        self.bitmap[word] |= mask;
+    }
+}
+
+/// A key for a single direction, with the sequence number of the next message.
+struct CipherState {
+    key: [u8; KEY_SIZE],
+    seq: u64,
+}
+
+impl CipherState {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self { key, seq: 0 }
+    }
+
+    /// Get the sequence number for the next message. Every sequence number is only handed out
+    /// once, once all of them are used, an error is returned instead.
+    fn next_seq(&mut self) -> Result<u64, super::Error> {
+        // The last sequence number is never used, so we never have to wrap.
+        if self.seq == u64::MAX {
+            return Err(super::Error::NonceExhausted);
+        }
+        self.seq += 1;
+        Ok(self.seq - 1)
+    }
+
+    /// Get the nonce for the next message.
+    fn next_nonce(&mut self) -> Result<[u8; NONCE_SIZE], super::Error> {
+        self.next_seq().map(nonce)
+    }
+}
+
+/// The nonce of the message with the given sequence number.
+fn nonce(seq: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[NONCE_SIZE - 8..].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+impl Session {
+    /// Establish a session on a connection to the given remote, after the handshake is done.
+    /// `local_secret` is the X25519 equivalent of our own secret key, see
+    /// [`SecretKey::to_x25519`](super::ed25519::SecretKey::to_x25519). Both sides must call this
+    /// at the same time.
+    pub async fn establish<S>(
+        con: &mut S,
+        local_secret: &StaticSecret,
+        local: &PublicKey,
+        remote: &PublicKey,
+    ) -> std::io::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ephemeral = EphemeralSecret::new(Rng);
+        con.write_all(x25519_dalek::PublicKey::from(&ephemeral).as_bytes())
+            .await?;
+        let mut remote_ephemeral = [0; EPHEMERAL_KEY_SIZE];
+        con.read_exact(&mut remote_ephemeral).await?;
+
+        let static_shared = local_secret.diffie_hellman(&remote.to_x25519());
+        let ephemeral_shared = ephemeral.diffie_hellman(&remote_ephemeral.into());
+        // A low order ephemeral key from the remote would make the ephemeral secret predictable,
+        // and thus the session keys equal to the ones of any other session with that peer.
+        if ephemeral_shared.as_bytes() == &[0; 32] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "remote sent a low order ephemeral key",
+            ));
+        }
+
+        let derive = |from: &PublicKey, to: &PublicKey| {
+            let mut hasher = Sha512::new();
+            hasher.update(static_shared.as_bytes());
+            hasher.update(ephemeral_shared.as_bytes());
+            hasher.update(from.as_bytes());
+            hasher.update(to.as_bytes());
+            let mut key = [0; KEY_SIZE];
+            key.copy_from_slice(&hasher.finalize()[..KEY_SIZE]);
+            key
+        };
+
+        Ok(Self {
+            send: CipherState::new(derive(local, remote)),
+            recv: CipherState::new(derive(remote, local)),
+            replay: ReplayWindow::new(DEFAULT_REPLAY_WINDOW),
+        })
+    }
+
+    /// Accept datagrams with a sequence number up to `size` below the highest one seen, rather
+    /// than [`DEFAULT_REPLAY_WINDOW`]. A larger window tolerates more reordering, at the cost of
+    /// memory. This must be set before any datagram is opened.
+    pub fn with_replay_window(mut self, size: usize) -> Self {
+        self.replay = ReplayWindow::new(size);
+        self
+    }
+
+    /// Encrypt a message in place, and return the tag which must be sent along with it.
+    pub fn seal(&mut self, buf: &mut [u8]) -> Result<[u8; TAG_SIZE], super::Error> {
+        let nonce = self.send.next_nonce()?;
+        Ok(chacha20poly1305::seal(&self.send.key, &nonce, &[], buf))
+    }
+
+    /// Decrypt the next message from the remote in place. An error is returned if the message
+    /// was tampered with, or is not the next message sent by the remote.
+    pub fn open(&mut self, buf: &mut [u8], tag: &[u8; TAG_SIZE]) -> Result<(), super::Error> {
+        let nonce = self.recv.next_nonce()?;
+        chacha20poly1305::open(&self.recv.key, &nonce, &[], buf, tag)
+    }
+
+    /// Encrypt a datagram in place, and return its sequence number and the tag which must be
+    /// sent along with it. Datagrams use the same sequence as messages, so a session should
+    /// only be used for one of both.
+    pub fn seal_datagram(&mut self, buf: &mut [u8]) -> Result<(u64, [u8; TAG_SIZE]), super::Error> {
+        let seq = self.send.next_seq()?;
+        Ok((
+            seq,
+            chacha20poly1305::seal(&self.send.key, &nonce(seq), &[], buf),
+        ))
+    }
+
+    /// Decrypt a datagram with the given sequence number in place. Unlike messages, datagrams
+    /// can be opened in any order, but only once, and only if the sequence number is within the
+    /// replay window. Other datagrams are rejected with [`Replayed`](super::Error::Replayed).
+    pub fn open_datagram(
+        &mut self,
+        seq: u64,
+        buf: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), super::Error> {
+        self.replay.check(seq)?;
+        chacha20poly1305::open(&self.recv.key, &nonce(seq), &[], buf, tag)?;
+        // Only authentic datagrams move the window, so forged sequence numbers can't push out
+        // valid ones.
+        self.replay.mark(seq);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+    use tokio::io;
+
+    /// Establish a pair of sessions between 2 peers over an in memory connection.
+    async fn session_pair() -> (Session, Session) {
+        let (a, b) = (
+            SecretKey::from_bytes([1; 32]),
+            SecretKey::from_bytes([2; 32]),
+        );
+        let (secret_a, secret_b) = (a.to_x25519(), b.to_x25519());
+        let (a, b) = (a.public_key(), b.public_key());
+        let (mut con_a, mut con_b) = io::duplex(1024);
+        let (session_a, session_b) = tokio::join!(
+            Session::establish(&mut con_a, &secret_a, &a, &b),
+            Session::establish(&mut con_b, &secret_b, &b, &a),
+        );
+        (session_a.unwrap(), session_b.unwrap())
+    }
+
+    #[tokio::test]
+    async fn sessions_roundtrip_in_both_directions() {
+        let (mut a, mut b) = session_pair().await;
+
+        for i in 0..3u8 {
+            let mut msg = vec![i; 100];
+            let tag = a.seal(&mut msg).unwrap();
+            assert_ne!(msg, vec![i; 100]);
+            b.open(&mut msg, &tag).unwrap();
+            assert_eq!(msg, vec![i; 100]);
+
+            let mut msg = vec![i; 10];
+            let tag = b.seal(&mut msg).unwrap();
+            a.open(&mut msg, &tag).unwrap();
+            assert_eq!(msg, vec![i; 10]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_and_reordered_messages() {
+        let (mut a, mut b) = session_pair().await;
+
+        let mut msg = vec![1; 32];
+        let tag = a.seal(&mut msg).unwrap();
+        msg[0] ^= 1;
+        assert!(matches!(
+            b.open(&mut msg, &tag),
+            Err(crate::crypto::Error::AuthenticationFailed)
+        ));
+
+        let (mut a, mut b) = session_pair().await;
+        let mut first = vec![1; 32];
+        a.seal(&mut first).unwrap();
+        let mut second = vec![2; 32];
+        let tag = a.seal(&mut second).unwrap();
+        // The second message can't be opened in place of the first one.
+        assert!(b.open(&mut second, &tag).is_err());
+    }
+
+    #[tokio::test]
+    async fn opens_datagrams_out_of_order() {
+        let (mut a, mut b) = session_pair().await;
+
+        let mut first = vec![1; 32];
+        let (first_seq, first_tag) = a.seal_datagram(&mut first).unwrap();
+        let mut second = vec![2; 32];
+        let (second_seq, second_tag) = a.seal_datagram(&mut second).unwrap();
+        assert_ne!(first_seq, second_seq);
+
+        b.open_datagram(second_seq, &mut second, &second_tag)
+            .unwrap();
+        assert_eq!(second, vec![2; 32]);
+        // A datagram claiming the wrong sequence number is rejected.
+        assert!(b.open_datagram(second_seq, &mut first, &first_tag).is_err());
+        b.open_datagram(first_seq, &mut first, &first_tag).unwrap();
+        assert_eq!(first, vec![1; 32]);
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_datagrams() {
+        let (mut a, b) = session_pair().await;
+        let mut b = b.with_replay_window(64);
+        let datagrams: Vec<_> = (0..200u8)
+            .map(|i| {
+                let mut buf = vec![i; 16];
+                let (seq, tag) = a.seal_datagram(&mut buf).unwrap();
+                (seq, buf, tag)
+            })
+            .collect();
+        let open = |b: &mut Session, i: usize| {
+            let (seq, mut buf, tag) = datagrams[i].clone();
+            b.open_datagram(seq, &mut buf, &tag).map(|()| buf)
+        };
+
+        // In order.
+        for i in 0..10 {
+            assert_eq!(open(&mut b, i).unwrap(), vec![i as u8; 16]);
+        }
+        // Reordered within the window.
+        for i in [100, 60, 99, 37] {
+            assert_eq!(open(&mut b, i).unwrap(), vec![i as u8; 16]);
+        }
+        // Replayed, both the highest one and older ones.
+        for i in [100, 99, 60, 37, 5] {
+            assert_eq!(open(&mut b, i), Err(crate::crypto::Error::Replayed));
+        }
+        // Too old, even if never seen.
+        assert_eq!(open(&mut b, 36), Err(crate::crypto::Error::Replayed));
+        // A forged datagram doesn't move the window.
+        let (seq, mut buf, _) = datagrams[199].clone();
+        assert!(b.open_datagram(seq, &mut buf, &[0; TAG_SIZE]).is_err());
+        assert_eq!(open(&mut b, 38).unwrap(), vec![38; 16]);
+        // A jump past the window forgets everything in it.
+        assert_eq!(open(&mut b, 199).unwrap(), vec![199; 16]);
+        assert_eq!(open(&mut b, 101), Err(crate::crypto::Error::Replayed));
+        assert_eq!(open(&mut b, 150).unwrap(), vec![150; 16]);
+    }
+
+    #[tokio::test]
+    async fn sessions_between_the_same_peers_use_different_keys() {
+        let (mut a, _) = session_pair().await;
+        let (_, mut b) = session_pair().await;
+
+        let mut msg = vec![1; 32];
+        let tag = a.seal(&mut msg).unwrap();
+        assert!(b.open(&mut msg, &tag).is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_reuse_nonces() {
+        let (mut a, _) = session_pair().await;
+        a.send.seq = u64::MAX - 1;
+        assert!(a.seal(&mut [0; 8]).is_ok());
+        assert!(matches!(
+            a.seal(&mut [0; 8]),
+            Err(crate::crypto::Error::NonceExhausted)
+        ));
+    }
+}
@@ -0,0 +1,518 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::crypto::session::{Session, TAG_SIZE};
+use crate::handshake::Features;
+
+/// Size of the regular length prefix of a data frame.
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Size of the extended length which follows the regular length prefix for jumbo frames.
+const EXTENDED_LENGTH_SIZE: usize = 4;
+
+/// Value of the regular length prefix indicating that an extended length follows.
+const EXTENDED_LENGTH_MARKER: u16 = u16::MAX;
+
+/// Largest packet which can be sent without extended length framing.
+pub const MAX_REGULAR_PACKET_SIZE: usize = EXTENDED_LENGTH_MARKER as usize - 1;
+
+/// Extra room on top of the MTU allowed for a packet on a data connection, so packets of peers
+/// with slightly different settings are not rejected outright.
+pub const PACKET_HEADROOM: usize = 80;
+
+/// Default upper bound on the size of a packet on a data connection.
+#[cfg(test)]
+pub const DEFAULT_MAX_PACKET_SIZE: usize = max_packet_size(crate::tun::DEFAULT_MTU as usize);
+
+/// Upper bound on the size of a packet on a data connection, for an interface with the given
+/// MTU.
+pub const fn max_packet_size(mtu: usize) -> usize {
+    mtu + PACKET_HEADROOM
+}
+
+/// Size of the flags in front of every packet on an encrypted data connection with compression.
+const FLAGS_SIZE: usize = 1;
+
+/// Size of the frame of a packet of the given size on an encrypted data connection, with room
+/// for the compression flags.
+pub const fn encrypted_frame_size(packet_size: usize) -> usize {
+    LENGTH_PREFIX_SIZE + FLAGS_SIZE + packet_size + TAG_SIZE
+}
+
+/// Flag indicating that the packet in a frame is compressed.
+const FLAG_COMPRESSED: u8 = 1;
+
+/// Upper bound on the size of a jumbo packet on an encrypted data connection, which bounds the
+/// MTU which can be configured for jumbo packets.
+pub const MAX_JUMBO_PACKET_SIZE: usize = 1 << 20;
+
+/// Largest MTU which can be configured for jumbo packets, so a packet and its headroom still fit
+/// in [`MAX_JUMBO_PACKET_SIZE`].
+pub const MAX_JUMBO_MTU: usize = MAX_JUMBO_PACKET_SIZE - PACKET_HEADROOM;
+
+/// A [`Codec`](tokio_util::codec) for overlay packets sent over a data connection.
+///
+/// Every packet is prefixed by its length as a 2 byte integer. If jumbo packets are enabled, a
+/// length prefix of `0xFFFF` indicates that the actual length follows as a 4 byte integer. Jumbo
+/// packets must only be enabled if both sides of the connection support them.
+pub struct DataCodec {
+    /// Largest packet we accept or send.
+    max_packet_size: usize,
+    /// Whether extended length framing can be used.
+    jumbo: bool,
+    /// Length of the packet we are currently decoding, if we already read the prefix.
+    len: Option<usize>,
+}
+
+impl DataCodec {
+    /// Create a new [`DataCodec`] which accepts packets up to the given size. The size is capped
+    /// at [`MAX_REGULAR_PACKET_SIZE`].
+    pub fn new(max_packet_size: usize) -> Self {
+        Self {
+            max_packet_size: max_packet_size.min(MAX_REGULAR_PACKET_SIZE),
+            jumbo: false,
+            len: None,
+        }
+    }
+
+    /// Create a new [`DataCodec`] which accepts jumbo packets up to the given size.
+    pub fn with_jumbo(max_packet_size: usize) -> Self {
+        Self {
+            max_packet_size: max_packet_size.min(u32::MAX as usize),
+            jumbo: true,
+            len: None,
+        }
+    }
+
+    /// Create a new [`DataCodec`] for a connection where both sides support the given features.
+    /// Jumbo packets are only enabled if both sides support [`Features::JUMBO`].
+    pub fn negotiated(features: Features, max_packet_size: usize) -> Self {
+        if features.contains(Features::JUMBO) {
+            Self::with_jumbo(max_packet_size)
+        } else {
+            Self::new(max_packet_size)
+        }
+    }
+}
+
+impl Decoder for DataCodec {
+    type Item = BytesMut;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = if let Some(len) = self.len.take() {
+            len
+        } else {
+            if src.len() < LENGTH_PREFIX_SIZE {
+                return Ok(None);
+            }
+            let prefix = u16::from_be_bytes([src[0], src[1]]);
+            let len = if prefix == EXTENDED_LENGTH_MARKER {
+                if !self.jumbo {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "received jumbo frame while jumbo frames are disabled",
+                    ));
+                }
+                if src.len() < LENGTH_PREFIX_SIZE + EXTENDED_LENGTH_SIZE {
+                    return Ok(None);
+                }
+                src.advance(LENGTH_PREFIX_SIZE);
+                src.get_u32() as usize
+            } else {
+                src.advance(LENGTH_PREFIX_SIZE);
+                prefix as usize
+            };
+
+            // NOTE: we can't recover from this, as we don't want to drain an arbitrary amount of
+            // data from the connection.
+            if len > self.max_packet_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "packet exceeds maximum packet size",
+                ));
+            }
+
+            len
+        };
+
+        if src.len() < len {
+            src.reserve(len - src.len());
+            self.len = Some(len);
+            return Ok(None);
+        }
+
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl Encoder<Bytes> for DataCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.len() > self.max_packet_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "packet exceeds maximum packet size",
+            ));
+        }
+
+        if item.len() > MAX_REGULAR_PACKET_SIZE {
+            // Since the max packet size is capped for regular codecs, we must have jumbo frames
+            // enabled here.
+            dst.reserve(LENGTH_PREFIX_SIZE + EXTENDED_LENGTH_SIZE + item.len());
+            dst.put_u16(EXTENDED_LENGTH_MARKER);
+            dst.put_u32(item.len() as u32);
+        } else {
+            dst.reserve(LENGTH_PREFIX_SIZE + item.len());
+            dst.put_u16(item.len() as u16);
+        }
+        dst.put(item);
+
+        Ok(())
+    }
+}
+
+/// A [`Codec`](tokio_util::codec) for overlay packets sent over an encrypted data connection.
+///
+/// Packets are framed like they are by [`DataCodec`], but every frame holds the encrypted packet
+/// followed by its authentication tag. Frames must be decoded in the order they were encoded, so
+/// any frame which is dropped, duplicated or reordered fails the connection.
+///
+/// If compression is enabled, the encrypted packet is prefixed with a byte of flags. If
+/// [`FLAG_COMPRESSED`] is set the packet is compressed in the [LZ4 block format], otherwise it
+/// is sent as is. Packets are only sent compressed if that makes them smaller, so packets which
+/// hold already compressed data are not inflated. Decompressed packets are bounded by the
+/// maximum packet size.
+///
+/// [LZ4 block format]: https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md
+pub struct EncryptedDataCodec {
+    /// Framing of the encrypted packets.
+    inner: DataCodec,
+    /// Keys of the connection.
+    session: Session,
+    /// Largest packet we accept or send, before compression.
+    max_packet_size: usize,
+    /// Whether packets are prefixed with flags, and can be compressed.
+    compression: bool,
+}
+
+impl EncryptedDataCodec {
+    /// Create a new [`EncryptedDataCodec`] which accepts packets up to the given size, using the
+    /// given session. The size is capped so the encrypted packet fits in a regular frame.
+    #[cfg(test)]
+    pub fn new(session: Session, max_packet_size: usize) -> Self {
+        Self::negotiated(session, Features::NONE, max_packet_size)
+    }
+
+    /// Create a new [`EncryptedDataCodec`] for a connection where both sides support the given
+    /// features. Compression is only enabled if both sides support [`Features::COMPRESSION`].
+    /// Jumbo packets up to [`MAX_JUMBO_PACKET_SIZE`] are only accepted if both sides support
+    /// [`Features::JUMBO`], otherwise the size is capped so the encrypted packet fits in a
+    /// regular frame.
+    pub fn negotiated(session: Session, features: Features, max_packet_size: usize) -> Self {
+        let compression = features.contains(Features::COMPRESSION);
+        let overhead = TAG_SIZE + if compression { FLAGS_SIZE } else { 0 };
+        let limit = if features.contains(Features::JUMBO) {
+            MAX_JUMBO_PACKET_SIZE
+        } else {
+            MAX_REGULAR_PACKET_SIZE
+        };
+        let max_packet_size = max_packet_size.min(limit - overhead);
+        Self {
+            inner: DataCodec::negotiated(features, max_packet_size + overhead),
+            session,
+            max_packet_size,
+            compression,
+        }
+    }
+
+    /// Largest packet which can be sent or received with this codec.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
+
+impl Decoder for EncryptedDataCodec {
+    type Item = BytesMut;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut frame = match self.inner.decode(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if frame.len() < TAG_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame is too short to hold an authentication tag",
+            ));
+        }
+        let tag = frame.split_off(frame.len() - TAG_SIZE);
+        // Can't fail, we just split off exactly TAG_SIZE bytes.
+        let tag: &[u8; TAG_SIZE] = tag[..].try_into().unwrap();
+        self.session
+            .open(&mut frame, tag)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if !self.compression {
+            return Ok(Some(frame));
+        }
+        if frame.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame is too short to hold flags",
+            ));
+        }
+        match frame.get_u8() {
+            0 => Ok(Some(frame)),
+            FLAG_COMPRESSED => lz4_flex::block::decompress(&frame, self.max_packet_size)
+                .map(|packet| Some(BytesMut::from(&packet[..])))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame has unknown flags set",
+            )),
+        }
+    }
+}
+
+impl Encoder<Bytes> for EncryptedDataCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.len() > self.max_packet_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "packet exceeds maximum packet size",
+            ));
+        }
+
+        let mut frame = BytesMut::with_capacity(FLAGS_SIZE + item.len() + TAG_SIZE);
+        if self.compression {
+            let compressed = lz4_flex::block::compress(&item);
+            if compressed.len() < item.len() {
+                frame.put_u8(FLAG_COMPRESSED);
+                frame.extend_from_slice(&compressed);
+            } else {
+                frame.put_u8(0);
+                frame.extend_from_slice(&item);
+            }
+        } else {
+            frame.extend_from_slice(&item);
+        }
+        // Once the nonces run out the connection can't be used anymore, a new session is
+        // needed.
+        let tag = self
+            .session
+            .seal(&mut frame)
+            .map_err(std::io::Error::other)?;
+        frame.extend_from_slice(&tag);
+        self.inner.encode(frame.freeze(), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+    use crate::tun::DEFAULT_MTU;
+    use futures::{sink::SinkExt, stream::StreamExt};
+    use tokio::io;
+    use tokio_util::codec;
+
+    #[test]
+    fn decodes_coalesced_and_split_packets() {
+        let mut codec = DataCodec::new(DEFAULT_MAX_PACKET_SIZE);
+        let first: Bytes = (0..100).collect();
+        let second: Bytes = (100..250).collect();
+        let third = Bytes::from(vec![7; DEFAULT_MTU as usize]);
+
+        // Two packets arrive in a single read.
+        let mut buf = BytesMut::new();
+        codec.encode(first.clone(), &mut buf).unwrap();
+        codec.encode(second.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), first);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), second);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        // A single packet is split over two reads.
+        let mut encoded = BytesMut::new();
+        codec.encode(third.clone(), &mut encoded).unwrap();
+        let mut buf = encoded.split_to(500);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&encoded);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), third);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decodes_packets_delivered_byte_by_byte() {
+        let mut codec = DataCodec::with_jumbo(MAX_JUMBO_PACKET_SIZE);
+        // Includes a jumbo packet, so the extended length prefix is split as well.
+        let packets: Vec<Bytes> = vec![
+            (0..100).collect(),
+            (0..70_000).map(|i| i as u8).collect(),
+            Bytes::from_static(&[0x60]),
+        ];
+        let mut encoded = BytesMut::new();
+        for packet in &packets {
+            codec.encode(packet.clone(), &mut encoded).unwrap();
+        }
+
+        // Every read from the underlay yields a single byte. A packet is only produced once it
+        // is complete.
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for (i, b) in encoded.iter().enumerate() {
+            buf.put_u8(*b);
+            if let Some(packet) = codec.decode(&mut buf).unwrap() {
+                decoded.push((i, packet));
+            }
+        }
+        assert!(buf.is_empty());
+        assert_eq!(decoded.len(), packets.len());
+        let mut end = 0;
+        for ((i, packet), expected) in decoded.into_iter().zip(packets) {
+            let prefix = if expected.len() > MAX_REGULAR_PACKET_SIZE {
+                LENGTH_PREFIX_SIZE + EXTENDED_LENGTH_SIZE
+            } else {
+                LENGTH_PREFIX_SIZE
+            };
+            end += prefix + expected.len();
+            assert_eq!(i + 1, end);
+            assert_eq!(packet, expected);
+        }
+    }
+
+    #[test]
+    fn rejects_packets_larger_than_mtu() {
+        let mut codec = DataCodec::new(DEFAULT_MAX_PACKET_SIZE);
+        let mut buf = BytesMut::new();
+        buf.put_u16(DEFAULT_MAX_PACKET_SIZE as u16 + 1);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let err = codec
+            .encode(Bytes::from(vec![0; DEFAULT_MAX_PACKET_SIZE + 1]), &mut buf)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn can_send_jumbo_packet() {
+        let (client, server) = io::duplex(4096);
+
+        let mut client_sink =
+            codec::Framed::new(client, DataCodec::with_jumbo(MAX_JUMBO_PACKET_SIZE));
+        let mut server_stream =
+            codec::Framed::new(server, DataCodec::with_jumbo(MAX_JUMBO_PACKET_SIZE));
+
+        let packet: Bytes = (0..100_000).map(|i| i as u8).collect();
+        let (sent, received) = tokio::join!(client_sink.send(packet.clone()), server_stream.next());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap(), packet);
+    }
+
+    #[tokio::test]
+    async fn rejects_jumbo_packet_if_disabled() {
+        let (client, _server) = io::duplex(4096);
+        // Remote does not support jumbo packets.
+        let features = Features::JUMBO.intersection(Features::NONE);
+        let mut client_sink =
+            codec::Framed::new(client, DataCodec::negotiated(features, usize::MAX));
+
+        let packet = Bytes::from(vec![0; 100_000]);
+        let err = client_sink.send(packet).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn encrypts_packets() {
+        let (a, b) = (
+            SecretKey::from_bytes([1; 32]),
+            SecretKey::from_bytes([2; 32]),
+        );
+        let (secret_a, secret_b) = (a.to_x25519(), b.to_x25519());
+        let (a, b) = (a.public_key(), b.public_key());
+        let (mut client, mut server) = io::duplex(4096);
+        let (client_session, server_session) = tokio::join!(
+            Session::establish(&mut client, &secret_a, &a, &b),
+            Session::establish(&mut server, &secret_b, &b, &a),
+        );
+
+        let mut client_sink = codec::Framed::new(
+            client,
+            EncryptedDataCodec::new(client_session.unwrap(), MAX_REGULAR_PACKET_SIZE),
+        );
+        // Read the raw frames on the other end, to check the packet is not sent in plaintext.
+        let mut server_stream = codec::Framed::new(server, DataCodec::new(MAX_REGULAR_PACKET_SIZE));
+        let mut server_session = server_session.unwrap();
+
+        let packet = Bytes::from_static(b"a very secret packet");
+        client_sink.send(packet.clone()).await.unwrap();
+        let mut frame = server_stream.next().await.unwrap().unwrap();
+        assert_eq!(frame.len(), packet.len() + TAG_SIZE);
+        assert!(!frame.windows(6).any(|w| w == b"secret"));
+
+        let tag = frame.split_off(packet.len());
+        server_session
+            .open(&mut frame, tag[..].try_into().unwrap())
+            .unwrap();
+        assert_eq!(frame, packet);
+    }
+
+    /// Establish the sessions of both ends of a connection.
+    async fn session_pair() -> (Session, Session) {
+        let (a, b) = (
+            SecretKey::from_bytes([1; 32]),
+            SecretKey::from_bytes([2; 32]),
+        );
+        let (secret_a, secret_b) = (a.to_x25519(), b.to_x25519());
+        let (a, b) = (a.public_key(), b.public_key());
+        let (mut client, mut server) = io::duplex(4096);
+        let (client, server) = tokio::join!(
+            Session::establish(&mut client, &secret_a, &a, &b),
+            Session::establish(&mut server, &secret_b, &b, &a),
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn compresses_packets_if_negotiated() {
+        let (client, server) = session_pair().await;
+        let features = Features::COMPRESSION.intersection(Features::COMPRESSION);
+        let mut encoder = EncryptedDataCodec::negotiated(client, features, 1500);
+        let mut decoder = EncryptedDataCodec::negotiated(server, features, 1500);
+
+        let compressible = Bytes::from(b"styx ".repeat(300));
+        let mut buf = BytesMut::new();
+        encoder.encode(compressible.clone(), &mut buf).unwrap();
+        assert!(buf.len() < compressible.len() / 10);
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap(), compressible);
+
+        // Packets which don't get smaller are sent as is, with just the flags added.
+        let incompressible: Bytes = (0..1500).map(|_| rand::random::<u8>()).collect();
+        encoder.encode(incompressible.clone(), &mut buf).unwrap();
+        assert_eq!(
+            buf.len(),
+            LENGTH_PREFIX_SIZE + FLAGS_SIZE + incompressible.len() + TAG_SIZE
+        );
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap(), incompressible);
+    }
+
+    #[tokio::test]
+    async fn rejects_compressed_packets_larger_than_maximum() {
+        let (client, server) = session_pair().await;
+        let mut encoder = EncryptedDataCodec::negotiated(client, Features::COMPRESSION, 60_000);
+        let mut decoder = EncryptedDataCodec::negotiated(server, Features::COMPRESSION, 1500);
+
+        let mut buf = BytesMut::new();
+        encoder
+            .encode(Bytes::from(vec![0; 60_000]), &mut buf)
+            .unwrap();
+        // The frame itself is small, but the packet in it is too large once decompressed.
+        assert!(buf.len() < 1500);
+        let err = decoder.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
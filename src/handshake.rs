@@ -0,0 +1,596 @@
+use std::fmt;
+
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    address::AddressScheme,
+    control::{MIN_PROTO_VERSION, PROTO_VERSION},
+    crypto::{
+        self,
+        ed25519::{PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH},
+        rng::Rng,
+    },
+};
+
+/// Magic number to identify a control connection. Value is the ASCII byte value of CTRL.
+const CONTROL_MAGIC: u32 = 0x43_54_52_4C;
+
+/// Magic number to identify a data connection. Value is the ASCII byte value of DATA.
+const DATA_MAGIC: u32 = 0x44_41_54_41;
+
+/// Size in bytes of the random challenge the accepting side sends to the initiator.
+const CHALLENGE_SIZE: usize = 32;
+
+/// Context prepended to a challenge before it is signed, so the signature can't be used for
+/// anything else.
+const CHALLENGE_CONTEXT: &[u8] = b"styx handshake challenge";
+
+/// The type of connection which is being established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    /// A control connection, carrying [`ControlFrame`](crate::control::ControlFrame)s.
+    Control,
+    /// A data connection, carrying overlay packets.
+    Data,
+}
+
+impl ConnectionKind {
+    /// The magic number identifying this kind of connection on the wire.
+    fn magic(self) -> u32 {
+        match self {
+            ConnectionKind::Control => CONTROL_MAGIC,
+            ConnectionKind::Data => DATA_MAGIC,
+        }
+    }
+}
+
+/// Optional features supported by a peer, exchanged as a bitfield during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+
+impl Features {
+    /// No optional features.
+    pub const NONE: Features = Features(0);
+
+    /// Data connections can carry packets larger than 64KiB, see
+    /// [`DataCodec::with_jumbo`](crate::data::DataCodec::with_jumbo).
+    pub const JUMBO: Features = Features(1);
+
+    /// Data connections carry packets over UDP, see [`UdpTransport`](crate::transport::UdpTransport).
+    pub const UDP_DATA: Features = Features(2);
+
+    /// Packets on data connections can be compressed, see
+    /// [`EncryptedDataCodec::negotiated`](crate::data::EncryptedDataCodec::negotiated).
+    pub const COMPRESSION: Features = Features(4);
+
+    /// Data connections are encrypted. All data connections are, so this is always announced in a
+    /// [`ControlFrame::Hello`](crate::control::ControlFrame::Hello), which sets it apart from the
+    /// empty capabilities of a peer predating the frame.
+    pub const ENCRYPTION: Features = Features(8);
+
+    /// Interpret a raw bitfield received from a peer. Unknown bits are kept, but never match any
+    /// of our features.
+    pub fn from_bits(bits: u32) -> Features {
+        Features(bits)
+    }
+
+    /// Check if all features in `other` are also set in `self`.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features supported by both `self` and `other`.
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// The features supported by either `self` or `other`.
+    pub fn union(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+
+    /// The raw bitfield.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+/// The validated result of reading a handshake from a remote.
+pub struct HandshakeResult {
+    /// Public key of the remote.
+    pub key: PublicKey,
+    /// The kind of connection the remote wants to establish.
+    pub kind: ConnectionKind,
+    /// Features supported by the remote.
+    pub features: Features,
+    /// Version of the control protocol used on the connection, which is the highest version
+    /// supported by both sides.
+    pub version: u8,
+}
+
+/// Errors which can occur while performing a handshake.
+#[derive(Debug)]
+pub enum Error {
+    /// An IO error occurred on the underlying connection.
+    Io(std::io::Error),
+    /// The remote sent an invalid public key.
+    InvalidKey(crypto::Error),
+    /// The remote sent an unknown connection identifier.
+    UnknownKind(u32),
+    /// The remote uses a different address scheme. The raw wire value of the scheme is included,
+    /// as it might be unknown to us.
+    SchemeMismatch(u32),
+    /// The remote replied with a handshake for a different kind of connection than we requested.
+    KindMismatch(ConnectionKind),
+    /// The remote identified with a different public key than the one we expected.
+    KeyMismatch,
+    /// The remote identified with our own public key, we connected to ourselves.
+    SelfConnection,
+    /// The highest control protocol version supported by the remote is older than the oldest
+    /// version we support.
+    UnsupportedVersion(u8),
+    /// The remote failed to prove it owns the secret key of the public key it identified with.
+    ChallengeFailed(crypto::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error during handshake: {}", e),
+            Error::InvalidKey(e) => write!(f, "remote sent an invalid public key: {}", e),
+            Error::UnknownKind(magic) => write!(f, "unknown connection identifier {:#x}", magic),
+            Error::SchemeMismatch(raw) => match AddressScheme::from_wire(*raw) {
+                Some(scheme) => write!(f, "remote uses address scheme {}", scheme),
+                None => write!(f, "remote uses unknown address scheme {:#x}", raw),
+            },
+            Error::KindMismatch(kind) => {
+                write!(f, "remote replied with a {:?} connection handshake", kind)
+            }
+            Error::KeyMismatch => f.pad("remote has an unexpected public key"),
+            Error::SelfConnection => f.pad("remote is ourselves"),
+            Error::ChallengeFailed(e) => write!(
+                f,
+                "remote failed to prove ownership of its public key: {}",
+                e
+            ),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "remote only supports protocol version {}, but at least version {} is required",
+                version, MIN_PROTO_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::InvalidKey(e) | Error::ChallengeFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Write our side of the handshake.
+///
+/// On the wire, the handshake consists of:
+/// - 32 byte public key
+/// - 4 byte connection kind identifier
+/// - 4 byte feature bitfield
+/// - 4 byte address scheme
+/// - 1 byte highest supported control protocol version
+pub async fn write_handshake<W>(
+    con: &mut W,
+    key: &PublicKey,
+    kind: ConnectionKind,
+    features: Features,
+    scheme: AddressScheme,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0; PUBLIC_KEY_LENGTH + 13];
+    buf[..PUBLIC_KEY_LENGTH].copy_from_slice(key.as_bytes());
+    buf[PUBLIC_KEY_LENGTH..PUBLIC_KEY_LENGTH + 4].copy_from_slice(&kind.magic().to_be_bytes());
+    buf[PUBLIC_KEY_LENGTH + 4..PUBLIC_KEY_LENGTH + 8]
+        .copy_from_slice(&features.bits().to_be_bytes());
+    buf[PUBLIC_KEY_LENGTH + 8..PUBLIC_KEY_LENGTH + 12]
+        .copy_from_slice(&scheme.to_wire().to_be_bytes());
+    buf[PUBLIC_KEY_LENGTH + 12] = PROTO_VERSION;
+    con.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Read and validate the handshake of the remote. The remote must use the given address scheme,
+/// and support a control protocol version we support as well.
+pub async fn read_handshake<R>(con: &mut R, scheme: AddressScheme) -> Result<HandshakeResult, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = [0; PUBLIC_KEY_LENGTH];
+    con.read_exact(&mut buffer[..]).await?;
+    let key = PublicKey::from_bytes(buffer).map_err(Error::InvalidKey)?;
+    let kind = match con.read_u32().await? {
+        CONTROL_MAGIC => ConnectionKind::Control,
+        DATA_MAGIC => ConnectionKind::Data,
+        magic => return Err(Error::UnknownKind(magic)),
+    };
+    let features = Features(con.read_u32().await?);
+    let remote_scheme = con.read_u32().await?;
+    if remote_scheme != scheme.to_wire() {
+        return Err(Error::SchemeMismatch(remote_scheme));
+    }
+    let remote_version = con.read_u8().await?;
+    if remote_version < MIN_PROTO_VERSION {
+        return Err(Error::UnsupportedVersion(remote_version));
+    }
+
+    Ok(HandshakeResult {
+        key,
+        kind,
+        features,
+        version: remote_version.min(PROTO_VERSION),
+    })
+}
+
+/// The message signed to answer a challenge from the node with the given public key. Including
+/// the key of the challenger means the answer can't be relayed to another node.
+fn challenge_message(challenge: &[u8; CHALLENGE_SIZE], challenger: &PublicKey) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CHALLENGE_CONTEXT.len() + CHALLENGE_SIZE + PUBLIC_KEY_LENGTH);
+    msg.extend_from_slice(CHALLENGE_CONTEXT);
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(challenger.as_bytes());
+    msg
+}
+
+/// Challenge the remote to prove it owns the secret key of its public key. A fresh random
+/// challenge is sent, which the remote must sign with its secret key, see [`answer_challenge`].
+pub async fn send_challenge<S>(
+    con: &mut S,
+    remote: &PublicKey,
+    local: &PublicKey,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut challenge = [0; CHALLENGE_SIZE];
+    Rng.fill_bytes(&mut challenge);
+    con.write_all(&challenge).await?;
+    let mut sig = [0; SIGNATURE_LENGTH];
+    con.read_exact(&mut sig).await?;
+    remote
+        .verify(
+            &challenge_message(&challenge, local),
+            &Signature::from_bytes(sig),
+        )
+        .map_err(Error::ChallengeFailed)
+}
+
+/// Answer a challenge sent with [`send_challenge`] by the remote with the given public key, by
+/// signing it with our secret key.
+pub async fn answer_challenge<S>(
+    con: &mut S,
+    key: &SecretKey,
+    remote: &PublicKey,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut challenge = [0; CHALLENGE_SIZE];
+    con.read_exact(&mut challenge).await?;
+    let sig = key.sign(&challenge_message(&challenge, remote));
+    con.write_all(sig.as_bytes()).await?;
+    Ok(())
+}
+
+/// Perform the handshake on a connection we opened. We send our side of the handshake first,
+/// after which the remote replies with its own handshake, for the same kind of connection.
+/// Finally, we prove we own the given key by answering a challenge of the remote.
+pub async fn initiate_handshake<S>(
+    con: &mut S,
+    key: &SecretKey,
+    kind: ConnectionKind,
+    features: Features,
+    scheme: AddressScheme,
+) -> Result<HandshakeResult, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_handshake(con, &key.public_key(), kind, features, scheme).await?;
+    let res = read_handshake(con, scheme).await?;
+    if res.kind != kind {
+        return Err(Error::KindMismatch(res.kind));
+    }
+    answer_challenge(con, key, &res.key).await?;
+    Ok(res)
+}
+
+/// Perform the handshake on a connection opened by the remote. We read the handshake of the
+/// remote first, and reply with our own handshake for the same kind of connection. The remote
+/// must then prove it owns the key it identified with, by answering a challenge.
+pub async fn accept_handshake<S>(
+    con: &mut S,
+    key: &PublicKey,
+    features: Features,
+    scheme: AddressScheme,
+) -> Result<HandshakeResult, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let res = read_handshake(con, scheme).await?;
+    write_handshake(con, key, res.kind, features, scheme).await?;
+    send_challenge(con, &res.key, key).await?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+    use tokio::io;
+
+    #[tokio::test]
+    async fn handshake_roundtrip() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+
+        write_handshake(
+            &mut client,
+            &key,
+            ConnectionKind::Data,
+            Features::NONE,
+            AddressScheme::Yggdrasil,
+        )
+        .await
+        .unwrap();
+        let res = read_handshake(&mut server, AddressScheme::Yggdrasil)
+            .await
+            .unwrap();
+
+        assert_eq!(res.key.as_bytes(), key.as_bytes());
+        assert_eq!(res.kind, ConnectionKind::Data);
+        assert_eq!(res.features, Features::NONE);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_key() {
+        // Not a valid point on the curve.
+        let mut raw = [0; 32];
+        raw[0] = 2;
+        let (mut client, mut server) = io::duplex(1024);
+        client.write_all(&raw).await.unwrap();
+        client.write_u32(CONTROL_MAGIC).await.unwrap();
+        client.write_u32(0).await.unwrap();
+        client.write_u32(0).await.unwrap();
+
+        assert!(matches!(
+            read_handshake(&mut server, AddressScheme::Yggdrasil).await,
+            Err(Error::InvalidKey(crypto::Error::InvalidPublicKey))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_kind() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+        client.write_all(key.as_bytes()).await.unwrap();
+        client.write_u32(0xdead_beef).await.unwrap();
+        client.write_u32(0).await.unwrap();
+        client.write_u32(0).await.unwrap();
+
+        assert!(matches!(
+            read_handshake(&mut server, AddressScheme::Yggdrasil).await,
+            Err(Error::UnknownKind(0xdead_beef))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_handshake() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+        client.write_all(&key.as_bytes()[..16]).await.unwrap();
+        drop(client);
+
+        match read_handshake(&mut server, AddressScheme::Yggdrasil).await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            _ => panic!("Expected an unexpected EOF error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_different_address_scheme() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+        let remote_scheme = AddressScheme::Sha256 { prefix: 3 };
+
+        write_handshake(
+            &mut client,
+            &key,
+            ConnectionKind::Control,
+            Features::NONE,
+            remote_scheme,
+        )
+        .await
+        .unwrap();
+
+        match read_handshake(&mut server, AddressScheme::Yggdrasil).await {
+            Err(Error::SchemeMismatch(raw)) => assert_eq!(raw, remote_scheme.to_wire()),
+            _ => panic!("Expected an address scheme mismatch"),
+        }
+    }
+
+    #[tokio::test]
+    async fn seeded_handshake_is_reproducible() {
+        async fn handshake(seed: u64) -> Vec<u8> {
+            crate::crypto::rng::seed(seed);
+            let key = SecretKey::generate().public_key();
+            let mut buf = Vec::new();
+            write_handshake(
+                &mut buf,
+                &key,
+                ConnectionKind::Control,
+                Features::NONE,
+                AddressScheme::Yggdrasil,
+            )
+            .await
+            .unwrap();
+            buf
+        }
+
+        assert_eq!(handshake(1).await, handshake(1).await);
+        assert_ne!(handshake(1).await, handshake(2).await);
+    }
+
+    #[tokio::test]
+    async fn both_sides_learn_remote_key() {
+        let client_secret = SecretKey::from_bytes([1; 32]);
+        let client_key = client_secret.public_key();
+        let server_key = SecretKey::from_bytes([2; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+
+        let (client_res, server_res) = tokio::join!(
+            initiate_handshake(
+                &mut client,
+                &client_secret,
+                ConnectionKind::Control,
+                Features::JUMBO,
+                AddressScheme::Yggdrasil,
+            ),
+            accept_handshake(
+                &mut server,
+                &server_key,
+                Features::NONE,
+                AddressScheme::Yggdrasil
+            ),
+        );
+        let (client_res, server_res) = (client_res.unwrap(), server_res.unwrap());
+
+        assert_eq!(client_res.key, server_key);
+        assert_eq!(client_res.kind, ConnectionKind::Control);
+        assert_eq!(client_res.features, Features::NONE);
+        assert_eq!(server_res.key, client_key);
+        assert_eq!(server_res.kind, ConnectionKind::Control);
+        assert_eq!(server_res.features, Features::JUMBO);
+        assert_eq!(client_res.version, PROTO_VERSION);
+        assert_eq!(server_res.version, PROTO_VERSION);
+    }
+
+    #[tokio::test]
+    async fn rejects_remote_without_secret_key() {
+        let client_key = SecretKey::from_bytes([1; 32]).public_key();
+        let server_key = SecretKey::from_bytes([2; 32]).public_key();
+        let (mut client, mut server) = io::duplex(1024);
+
+        let client = async {
+            // Claim the key of node 1, but sign with the key of node 3.
+            write_handshake(
+                &mut client,
+                &client_key,
+                ConnectionKind::Control,
+                Features::NONE,
+                AddressScheme::Yggdrasil,
+            )
+            .await?;
+            read_handshake(&mut client, AddressScheme::Yggdrasil).await?;
+            answer_challenge(&mut client, &SecretKey::from_bytes([3; 32]), &server_key).await
+        };
+        let (client_res, server_res) = tokio::join!(
+            client,
+            accept_handshake(
+                &mut server,
+                &server_key,
+                Features::NONE,
+                AddressScheme::Yggdrasil
+            ),
+        );
+        client_res.unwrap();
+        assert!(matches!(
+            server_res,
+            Err(Error::ChallengeFailed(crypto::Error::SignatureVerification))
+        ));
+    }
+
+    #[tokio::test]
+    async fn challenges_are_fresh_and_bound_to_the_challenger() {
+        let client = SecretKey::from_bytes([1; 32]);
+        let client_key = client.public_key();
+        let server_key = SecretKey::from_bytes([2; 32]).public_key();
+        let other_key = SecretKey::from_bytes([3; 32]).public_key();
+
+        // Record a challenge and its answer.
+        let (mut a, mut b) = io::duplex(1024);
+        let (res, _) = tokio::join!(
+            send_challenge(&mut a, &client_key, &server_key),
+            answer_challenge(&mut b, &client, &server_key),
+        );
+        res.unwrap();
+
+        // An answer for another challenger is rejected.
+        let (mut a, mut b) = io::duplex(1024);
+        let (res, _) = tokio::join!(
+            send_challenge(&mut a, &client_key, &server_key),
+            answer_challenge(&mut b, &client, &other_key),
+        );
+        assert!(matches!(res, Err(Error::ChallengeFailed(_))));
+
+        // Every challenge is different, so answers can't be replayed.
+        let mut challenges = Vec::new();
+        for _ in 0..2 {
+            let (mut a, mut b) = io::duplex(1024);
+            let send = send_challenge(&mut a, &client_key, &server_key);
+            let read = async {
+                let mut challenge = [0; CHALLENGE_SIZE];
+                b.read_exact(&mut challenge).await.unwrap();
+                challenges.push(challenge);
+            };
+            // The sender never gets an answer, so only drive it until the challenge is read.
+            tokio::select! {
+                _ = send => unreachable!(),
+                _ = read => (),
+            }
+        }
+        assert_ne!(challenges[0], challenges[1]);
+    }
+
+    /// Write a handshake announcing the given protocol version.
+    async fn write_raw_handshake<W: AsyncWrite + Unpin>(con: &mut W, version: u8) {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        con.write_all(key.as_bytes()).await.unwrap();
+        con.write_u32(CONTROL_MAGIC).await.unwrap();
+        con.write_u32(0).await.unwrap();
+        con.write_u32(AddressScheme::Yggdrasil.to_wire())
+            .await
+            .unwrap();
+        con.write_u8(version).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiates_lowest_common_version() {
+        let (mut client, mut server) = io::duplex(1024);
+        write_raw_handshake(&mut client, u8::MAX).await;
+        let res = read_handshake(&mut server, AddressScheme::Yggdrasil)
+            .await
+            .unwrap();
+        assert_eq!(res.version, PROTO_VERSION);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_version() {
+        let (mut client, mut server) = io::duplex(1024);
+        write_raw_handshake(&mut client, MIN_PROTO_VERSION - 1).await;
+        let err = read_handshake(&mut server, AddressScheme::Yggdrasil)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::UnsupportedVersion(v) if v == MIN_PROTO_VERSION - 1));
+        assert!(err.to_string().contains("protocol version"));
+    }
+}
@@ -1,27 +1,67 @@
-use crate::core::Core;
-use clap::Parser;
+use crate::address::{AddressScheme, DEFAULT_SHA256_PREFIX};
+use crate::config::Config;
+use crate::core::{CoreBuilder, CoreEvent, Keepalive};
+use crate::handshake::ConnectionKind;
+use crate::net::{Cidr, Dialer, PeerAddr, SocketOptions};
+use crate::netlink::{ConfiguredInterface, KernelRoutes};
+use crate::pcap::PacketCapture;
+use crate::peer::{AddressPolicy, DEFAULT_MAX_ADDRS_PER_PEER};
+use crate::sampling::{FlowSink, Sampler, UdpSink, WriterSink};
+use crate::transport::TransportKind;
+use crate::tun::{ExistingInterface, Tun};
+use clap::{Parser, Subcommand, ValueEnum};
 use crypto::ed25519::SecretKey;
-use etherparse::{ether_type, EtherType};
-use log::info;
+use crypto::session::DEFAULT_REPLAY_WINDOW;
+use log::{error, info, warn};
 use std::{
     error::Error,
-    net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::AsyncReadExt,
+    net::{TcpListener, UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
 };
-use tokio_tun::TunBuilder;
 
+mod address;
+mod admin;
+mod backoff;
+mod buffer;
+mod check;
+mod config;
 mod control;
 mod core;
 mod crypto;
+mod data;
+mod handshake;
+mod metrics;
 mod net;
+mod netlink;
+mod pcap;
 mod peer;
+mod ratelimit;
+mod routing;
+mod sampling;
+#[cfg(test)]
+mod splice;
+mod stats;
+mod transport;
+mod tun;
 
-const DEFAULT_INTERFACE_NAME: &str = "styx";
+/// Default amount of seconds between keepalive pings on control connections.
+const DEFAULT_KEEPALIVE_INTERVAL: u64 = 15;
+
+/// Default amount of seconds a peer gets to reply to a keepalive ping.
+const DEFAULT_KEEPALIVE_TIMEOUT: u64 = 10;
+
+/// Log filter used if neither RUST_LOG nor --log-level is set.
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Time allowed to send queued packets when shutting down.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(name = "Styx")]
@@ -31,122 +71,654 @@ const DEFAULT_INTERFACE_NAME: &str = "styx";
 )]
 #[command(author = "Lee Smet <lee@threefold.tech>")]
 struct Cli {
-    /// The local IP and port to listen on for incoming connections.
-    #[arg(short = 'l', long = "listen-address")]
-    listen_addr: SocketAddr,
-    /// The remote IP and port to connect to for outgoing connections.
-    #[arg(short = 'p', long = "peer-address")]
-    peer: Option<SocketAddr>,
-    /// Name of the created interface
-    #[arg(short = 'i', long = "interface-name", default_value = DEFAULT_INTERFACE_NAME)]
-    interface_name: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Config file to load settings from. Settings passed on the command line take precedence
+    /// over the ones in the file.
+    #[arg(short = 'c', long = "config")]
+    config: Option<PathBuf>,
+    /// The local IP and port to listen on for incoming connections. Can be specified multiple
+    /// times to listen on several addresses. Replaces the listen addresses in the config file,
+    /// at least one is required. Link-local IPv6 addresses can be scoped to an interface, as in
+    /// `[fe80::1%eth0]:9651`.
+    #[arg(short = 'l', long = "listen-address", value_parser = net::parse_socket_addr)]
+    listen_addrs: Vec<SocketAddr>,
+    /// The remote IP or hostname and port of a peer to connect to. Can be specified multiple
+    /// times. If a hostname resolves to multiple addresses, they are raced and the first one to
+    /// connect is used. Connections are reopened if they are lost. Replaces the peers in the
+    /// config file. Link-local IPv6 addresses can be scoped to an interface, as in
+    /// `[fe80::1%eth0]:9651`.
+    #[arg(short = 'p', long = "peer-address", value_name = "HOST:PORT")]
+    peers: Vec<PeerAddr>,
+    /// File holding the secret key of this node. If it doesn't exist, a new key is generated
+    /// and saved in it. Defaults to "styx.key". Ignored if the STYX_SECRET_KEY environment
+    /// variable holds the key as hex.
+    #[arg(short = 'k', long = "key-file")]
+    key_file: Option<PathBuf>,
+    /// Name of the created interface. Defaults to "styx".
+    #[arg(short = 'i', long = "interface-name")]
+    interface_name: Option<String>,
+    /// MTU of the interface. Packets received from peers may be slightly larger than this, to
+    /// accommodate peers with a different MTU. Defaults to 1420.
+    #[arg(long = "mtu", value_parser = clap::value_parser!(i32).range(tun::MIN_MTU as i64..=tun::MAX_MTU as i64))]
+    mtu: Option<i32>,
+    /// Enable jumbo packets with this MTU, which can exceed the largest MTU of the interface.
+    /// Replaces --mtu, the interface gets the largest MTU it supports up to this one. Jumbo
+    /// packets are only exchanged with peers which support them as well, larger packets to
+    /// other peers are dropped.
+    #[arg(long = "jumbo-mtu", value_name = "BYTES", value_parser = clap::value_parser!(u32).range(tun::MIN_MTU as i64..=data::MAX_JUMBO_MTU as i64))]
+    jumbo_mtu: Option<u32>,
+    /// Amount of queues of the interface. Every queue is processed by its own task, so packets
+    /// can be processed on multiple cores in parallel.
+    #[arg(long = "tun-queues", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    tun_queues: u16,
+    /// Attach to the interface if it already exists, e.g. after an unclean shutdown, rather than
+    /// deleting and recreating it.
+    #[arg(long = "tun-reuse")]
+    tun_reuse: bool,
+    /// Only relay packets between peers, without creating an interface. This doesn't require
+    /// CAP_NET_ADMIN. Packets addressed to this node are dropped.
+    #[arg(long = "relay-only")]
+    relay_only: bool,
+    /// Take over the interface from a running instance which serves a handoff socket at this
+    /// path, see --tun-handoff, rather than creating it. Only a single queue is taken over. The
+    /// listen addresses are bound once the previous instance exited.
+    #[arg(long = "inherit-tun", value_name = "SOCKET")]
+    inherit_tun: Option<PathBuf>,
+    /// Serve a handoff socket at this path. Once a new instance started with --inherit-tun
+    /// connects, the interface is handed off to it, and this instance shuts down without tearing
+    /// the interface down, so it can be replaced without dropping traffic.
+    #[arg(long = "tun-handoff", value_name = "SOCKET")]
+    tun_handoff: Option<PathBuf>,
+    /// Install a route in the kernel for every reachable subnet, pointing at the interface, so
+    /// the host forwards traffic for remote overlay subnets through this node. The routes are
+    /// removed on shutdown, so this can't be combined with --tun-handoff.
+    #[arg(long = "kernel-routes", conflicts_with = "tun_handoff")]
+    kernel_routes: bool,
+    /// Clamp the maximum segment size of underlay TCP connections. Defaults to the size of a
+    /// data frame holding a packet of the overlay MTU. The kernel lowers it further if the path
+    /// MTU is smaller.
+    #[arg(long = "tcp-mss", value_parser = clap::value_parser!(u32).range(net::MIN_TCP_MSS as i64..=net::MAX_TCP_MSS as i64))]
+    tcp_mss: Option<u32>,
+    /// The scheme used to derive overlay addresses from public keys. All nodes in the network
+    /// must use the same scheme.
+    #[arg(long = "address-scheme", value_enum, default_value_t = SchemeArg::Yggdrasil)]
+    address_scheme: SchemeArg,
+    /// First byte of derived addresses. Defaults to 0x02 for the yggdrasil address scheme, so
+    /// addresses match the ones of yggdrasil, and to 0x03 for the sha256 address scheme. Set this
+    /// to run an overlay which doesn't collide with yggdrasil addresses.
+    #[arg(long = "address-prefix")]
+    address_prefix: Option<u8>,
+    /// Export a flow record for 1 in every N forwarded packets. Sampling is disabled by default.
+    #[arg(long = "sample-rate", requires = "sample_sink")]
+    sample_rate: Option<u32>,
+    /// Destination of sampled flow records. Either a file path, or udp://<ip>:<port> to send
+    /// records to a collector.
+    #[arg(long = "sample-sink", requires = "sample_rate")]
+    sample_sink: Option<String>,
+    /// Capture every packet read from and written to the interface to this file, in the pcap
+    /// format. This is meant for debugging, capturing is disabled by default.
+    #[arg(long = "pcap", value_name = "PATH")]
+    pcap: Option<PathBuf>,
+    /// Size at which the capture file is rotated. The previous capture file is kept next to it,
+    /// with a .1 suffix.
+    #[arg(long = "pcap-max-size", value_name = "MEGABYTES", default_value_t = pcap::DEFAULT_MAX_CAPTURE_SIZE >> 20, value_parser = clap::value_parser!(u64).range(1..=u64::MAX >> 20), requires = "pcap")]
+    pcap_max_size: u64,
+    /// Connect to peers announced by other peers, on the advertised addresses allowed by the
+    /// address policy.
+    #[arg(long = "dial-announced")]
+    dial_announced: bool,
+    /// Only connect to addresses advertised by peers if they are publicly routable.
+    #[arg(long = "advertised-public-only")]
+    advertised_public_only: bool,
+    /// Only connect to addresses advertised by peers if they are in one of these ranges. Can be
+    /// specified multiple times.
+    #[arg(long = "advertised-allow", value_name = "CIDR")]
+    advertised_allow: Vec<Cidr>,
+    /// Local address outbound connections to peers originate from.
+    #[arg(long = "bind-address")]
+    bind_addr: Option<IpAddr>,
+    /// Milliseconds a connection attempt to a peer gets before the next address of the peer is
+    /// tried in parallel, if its hostname resolves to multiple addresses.
+    #[arg(long = "happy-eyeballs-delay", value_name = "MILLISECONDS", default_value_t = net::DEFAULT_HAPPY_EYEBALLS_DELAY.as_millis() as u64)]
+    happy_eyeballs_delay: u64,
+    /// Bind outbound connections to peers to this network interface.
+    #[arg(long = "bind-device")]
+    bind_device: Option<String>,
+    /// Close data connections which don't carry any traffic for this amount of seconds. They are
+    /// reopened when needed. By default, idle connections are kept.
+    #[arg(long = "idle-timeout", value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+    /// Seconds between checks for idle data connections, see --idle-timeout.
+    #[arg(long = "idle-sweep-interval", value_name = "SECONDS", default_value_t = core::DEFAULT_IDLE_SWEEP_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    idle_sweep_interval: u64,
+    /// Seconds a peer gets to complete the handshake after it connected to us, after which the
+    /// connection is closed.
+    #[arg(long = "handshake-timeout", value_name = "SECONDS", default_value_t = core::DEFAULT_HANDSHAKE_TIMEOUT.as_secs())]
+    handshake_timeout: u64,
+    /// Maximum amount of inbound connections which can be in the handshake at the same time.
+    /// Further connections are not accepted until one of them completes the handshake.
+    #[arg(long = "max-pending-handshakes", value_name = "COUNT", default_value_t = core::DEFAULT_MAX_PENDING_HANDSHAKES, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_pending_handshakes: usize,
+    /// Amount of inbound connections which completed their handshake, and can wait to be
+    /// registered. Once full, further control connections wait, and data connections are dropped.
+    #[arg(long = "connection-queue-size", value_name = "COUNT", default_value_t = core::DEFAULT_CONNECTION_QUEUE_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    connection_queue_size: usize,
+    /// Seconds a single write to a peer connection or the interface gets to complete. Connections
+    /// to peers which stop reading are closed after this, packets which can't be written to the
+    /// interface are dropped.
+    #[arg(long = "write-timeout", value_name = "SECONDS", default_value_t = core::DEFAULT_WRITE_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    write_timeout: u64,
+    /// Seconds between keepalive pings on control connections. Set to 0 to disable keepalive
+    /// pings.
+    #[arg(long = "keepalive-interval", value_name = "SECONDS", default_value_t = DEFAULT_KEEPALIVE_INTERVAL)]
+    keepalive_interval: u64,
+    /// Seconds a peer gets to reply to a keepalive ping, after which the ping is missed and the
+    /// peer is degraded.
+    #[arg(long = "keepalive-timeout", value_name = "SECONDS", default_value_t = DEFAULT_KEEPALIVE_TIMEOUT)]
+    keepalive_timeout: u64,
+    /// Amount of keepalive pings in a row a peer can miss before it is considered dead, and its
+    /// control connection is closed.
+    #[arg(long = "keepalive-max-missed", value_name = "COUNT", default_value_t = core::DEFAULT_KEEPALIVE_MAX_MISSED, value_parser = clap::value_parser!(u32).range(1..))]
+    keepalive_max_missed: u32,
+    /// Round trip time of keepalive pings in milliseconds above which a peer is considered
+    /// degraded.
+    #[arg(long = "degraded-rtt", value_name = "MILLISECONDS", default_value_t = core::DEFAULT_DEGRADED_RTT.as_millis() as u64)]
+    degraded_rtt: u64,
+    /// Maximum amount of packet bytes per second accepted from a single peer. A peer exceeding
+    /// this is slowed down, rather than having its packets dropped. Unlimited by default.
+    #[arg(long = "rate-limit", value_name = "BYTES_PER_SECOND", value_parser = clap::value_parser!(u64).range(1..))]
+    rate_limit: Option<u64>,
+    /// Set TCP_NODELAY on data connections, so packets are sent right away instead of being
+    /// coalesced. This lowers latency at the cost of throughput. Control connections always have
+    /// it set.
+    #[arg(long = "tcp-nodelay")]
+    tcp_nodelay: bool,
+    /// Size of the send buffer of data connections. Sized automatically by the kernel by
+    /// default.
+    #[arg(long = "send-buffer-size", value_name = "BYTES")]
+    send_buffer_size: Option<usize>,
+    /// Size of the receive buffer of data connections. Sized automatically by the kernel by
+    /// default.
+    #[arg(long = "recv-buffer-size", value_name = "BYTES")]
+    recv_buffer_size: Option<usize>,
+    /// Transport used for data connections. UDP avoids head-of-line blocking between packets,
+    /// but is only used if the peer supports it, otherwise TCP is used. Control connections
+    /// always use TCP. Defaults to TCP.
+    #[arg(long = "data-transport", value_enum)]
+    data_transport: Option<TransportArg>,
+    /// Compress packets on data connections, if the peer supports it. Packets which don't get
+    /// smaller are sent uncompressed. This only applies to data connections over TCP.
+    #[arg(long = "compression")]
+    compression: bool,
+    /// Amount of sequence numbers below the highest one received for which datagrams on UDP data
+    /// connections are still accepted. Datagrams which are reordered further than this are
+    /// dropped as if they were replayed.
+    #[arg(long = "replay-window", value_name = "PACKETS", default_value_t = DEFAULT_REPLAY_WINDOW)]
+    replay_window: usize,
+    /// Maximum amount of advertised addresses kept per peer.
+    #[arg(long = "max-advertised-addrs", default_value_t = DEFAULT_MAX_ADDRS_PER_PEER)]
+    max_advertised_addrs: usize,
+    /// Serve Prometheus metrics over HTTP on this address. Metrics are disabled by default.
+    #[arg(long = "metrics-addr")]
+    metrics_addr: Option<SocketAddr>,
+    /// Serve the control socket on this path, to inspect and change the node while it runs. The
+    /// control socket is disabled by default.
+    #[arg(long = "control-socket", value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+    /// Log filter used if RUST_LOG is not set. Either a level, or a comma separated list of
+    /// directives to set the level per module, e.g. "info,styx::core=debug,styx::control=trace".
+    #[arg(long = "log-level", default_value = DEFAULT_LOG_FILTER)]
+    log_level: String,
+}
+
+/// Subcommands which do something else than running the node.
+#[derive(Subcommand)]
+enum Command {
+    /// Print the public key and overlay address of this node, and exit. The key file is created
+    /// if it doesn't exist yet.
+    Identity,
+    /// Check that the node can be started with the current settings, without starting it. This
+    /// loads the config and key, and checks that the interface can be created and the addresses
+    /// can be bound. Exits with a non-zero status if any check fails.
+    Check,
+}
+
+/// Data transports which can be selected on the command line.
+#[derive(Clone, Copy, ValueEnum)]
+enum TransportArg {
+    /// Frame packets on a TCP connection.
+    Tcp,
+    /// Send every packet in its own UDP datagram.
+    Udp,
+}
+
+/// Address schemes which can be selected on the command line.
+#[derive(Clone, Copy, ValueEnum)]
+enum SchemeArg {
+    /// Derive addresses like yggdrasil does.
+    Yggdrasil,
+    /// Derive addresses from the SHA-256 hash of the public key.
+    Sha256,
+}
+
+impl Cli {
+    /// Build the [`Config`] of the node, by loading the config file if one is set, and applying
+    /// the settings passed on the command line on top of it.
+    fn config(&self) -> Result<Config, config::Error> {
+        let mut config = match self.config {
+            Some(ref path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen_addrs.is_empty() {
+            config.listen_addrs = self.listen_addrs.clone();
+        }
+        if !self.peers.is_empty() {
+            config.peers = self.peers.clone();
+        }
+        if let Some(ref key_file) = self.key_file {
+            config.key_file = key_file.clone();
+        }
+        if let Some(ref interface_name) = self.interface_name {
+            config.interface_name = interface_name.clone();
+        }
+        if let Some(mtu) = self.mtu {
+            config.mtu = mtu;
+        }
+        if let Some(mtu) = self.jumbo_mtu {
+            config.jumbo_mtu = Some(mtu as usize);
+        }
+        if let Some(limit) = self.rate_limit {
+            config.rate_limit = Some(limit);
+        }
+        if let Some(transport) = self.data_transport {
+            config.data_transport = match transport {
+                TransportArg::Tcp => TransportKind::Tcp,
+                TransportArg::Udp => TransportKind::Udp,
+            };
+        }
+        if self.tcp_nodelay {
+            config.tcp_nodelay = true;
+        }
+        if self.compression {
+            config.compression = true;
+        }
+        if self.relay_only {
+            config.relay_only = true;
+        }
+        if let Some(size) = self.send_buffer_size {
+            config.send_buffer_size = Some(size);
+        }
+        if let Some(size) = self.recv_buffer_size {
+            config.recv_buffer_size = Some(size);
+        }
+        Ok(config)
+    }
+
+    /// The address scheme selected on the command line.
+    fn address_scheme(&self) -> AddressScheme {
+        match self.address_scheme {
+            SchemeArg::Yggdrasil => match self.address_prefix {
+                Some(prefix) => AddressScheme::YggdrasilWithPrefix { prefix },
+                None => AddressScheme::Yggdrasil,
+            },
+            SchemeArg::Sha256 => AddressScheme::Sha256 {
+                prefix: self.address_prefix.unwrap_or(DEFAULT_SHA256_PREFIX),
+            },
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
     let args = Cli::parse();
-    // See if a target is set on the cmd line
-    // let target = std::env::args().skip(1).next();
-    // Create a listener on all interfaces, fixed port for now.
-    let listener = TcpListener::bind(args.listen_addr).await?;
-    // TODO: Investigate if MQ is a better approach to get multiple handles to the same device
-    // instead of splitting it later.
-
-    let secret_key = SecretKey::from_bytes([
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
-        25, 26, 27, 28, 29, 30, 31,
-    ]);
-    let core = Core::new(secret_key, listener);
+    init_logger(&args.log_level);
+    let config = args.config()?;
+    if let Some(Command::Identity) = args.command {
+        let public_key = load_or_generate_key(&config.key_file)?.public_key();
+        println!("Public key: {}", public_key);
+        println!("Address: {}", args.address_scheme().derive(&public_key));
+        return Ok(());
+    }
+    if let Some(Command::Check) = args.command {
+        let checks = check::run(&config, args.metrics_addr, args.control_socket.as_deref()).await;
+        for check in &checks {
+            println!("{}", check);
+        }
+        let failed = checks.iter().filter(|check| !check.passed()).count();
+        if failed > 0 {
+            return Err(format!("{} of {} checks failed", failed, checks.len()).into());
+        }
+        return Ok(());
+    }
+    if config.listen_addrs.is_empty() {
+        return Err("no listen address set on the command line or in the config file".into());
+    }
+    if config.relay_only && (args.inherit_tun.is_some() || args.tun_handoff.is_some()) {
+        return Err("there is no interface to hand off in relay-only mode".into());
+    }
+    let existing = if args.tun_reuse {
+        ExistingInterface::Reuse
+    } else {
+        ExistingInterface::Recreate
+    };
+    let tun_mtu = match config.jumbo_mtu {
+        Some(mtu) => mtu.min(tun::MAX_MTU as usize) as i32,
+        None => config.mtu,
+    };
+    let tun: Vec<_> = match args.inherit_tun {
+        _ if config.relay_only => Vec::new(),
+        Some(ref path) => vec![Arc::new(inherit_tun(path, args.tun_queues).await?)],
+        None => Tun::open(
+            &config.interface_name,
+            tun_mtu,
+            args.tun_queues as usize,
+            existing,
+        )?
+        .into_iter()
+        .map(Arc::new)
+        .collect(),
+    };
+
+    let secret_key = load_or_generate_key(&config.key_file)?;
+    // A key passed in the environment is not saved anywhere, neither is one it is rotated to.
+    let key_file = std::env::var_os(SECRET_KEY_ENV)
+        .is_none()
+        .then(|| config.key_file.clone());
+    let address_scheme = args.address_scheme();
+    let sampler = match (args.sample_rate, args.sample_sink) {
+        (Some(rate), Some(sink)) => {
+            let sink: Box<dyn FlowSink + Send> = match sink.strip_prefix("udp://") {
+                Some(collector) => Box::new(UdpSink::connect(collector.parse()?)?),
+                None => Box::new(WriterSink::open(sink)?),
+            };
+            Some(Sampler::new(rate, sink))
+        }
+        _ => None,
+    };
+    let address_policy = AddressPolicy {
+        public_only: args.advertised_public_only,
+        allowlist: args.advertised_allow,
+        max_addrs_per_peer: args.max_advertised_addrs,
+    };
+    let dialer = Dialer {
+        bind_addr: args.bind_addr,
+        bind_device: args.bind_device,
+        tcp_mss: Some(args.tcp_mss.unwrap_or_else(|| {
+            net::default_tcp_mss(config.jumbo_mtu.unwrap_or(config.mtu as usize))
+        })),
+        happy_eyeballs_delay: Duration::from_millis(args.happy_eyeballs_delay),
+    };
+    let mut builder = config
+        .listen_addrs
+        .iter()
+        .fold(CoreBuilder::new(secret_key), |builder, &addr| {
+            builder.listen_addr(addr)
+        })
+        .address_scheme(address_scheme)
+        .address_policy(address_policy)
+        .dialer(dialer)
+        .tun(tun.clone())
+        .relay_only(config.relay_only)
+        .key_filter(config.key_filter())
+        .local_keys(config.local_keys)
+        .connection_queue_size(args.connection_queue_size)
+        .peers(config.peers);
+    match tun.first() {
+        Some(tun) if args.kernel_routes => {
+            builder = builder.kernel_routes(KernelRoutes::new(tun.name())?);
+        }
+        Some(_) => {}
+        // Without an interface, the MTU can't be taken from it.
+        None => {
+            if args.kernel_routes {
+                warn!("Not installing kernel routes without an interface");
+            }
+            builder = builder.mtu(config.mtu as usize);
+        }
+    }
+    if let Some(mtu) = config.jumbo_mtu {
+        builder = builder.mtu(mtu).jumbo(true);
+    }
+    if let Some(sampler) = sampler {
+        builder = builder.sampler(sampler);
+    }
+    if let Some(path) = &args.pcap {
+        builder = builder.capture(PacketCapture::create(path, args.pcap_max_size << 20)?);
+        info!("Capturing packets to {}", path.display());
+    }
+    let (core, run) = builder.build().await?;
+    core.set_idle_timeout(args.idle_timeout.map(Duration::from_secs));
+    core.set_idle_sweep_interval(Duration::from_secs(args.idle_sweep_interval));
+    core.set_rate_limit(config.rate_limit);
+    core.set_data_transport(config.data_transport);
+    core.set_compression(config.compression);
+    core.set_replay_window(args.replay_window);
+    core.set_handshake_timeout(Duration::from_secs(args.handshake_timeout));
+    core.set_max_pending_handshakes(args.max_pending_handshakes);
+    core.set_write_timeout(Duration::from_secs(args.write_timeout));
+    core.set_socket_options(
+        ConnectionKind::Data,
+        SocketOptions {
+            nodelay: config.tcp_nodelay,
+            send_buffer_size: config.send_buffer_size,
+            recv_buffer_size: config.recv_buffer_size,
+        },
+    );
+    core.set_dial_announced_peers(args.dial_announced);
+    core.set_keepalive((args.keepalive_interval > 0).then(|| Keepalive {
+        interval: Duration::from_secs(args.keepalive_interval),
+        timeout: Duration::from_secs(args.keepalive_timeout),
+        max_missed: args.keepalive_max_missed,
+        degraded_rtt: Duration::from_millis(args.degraded_rtt),
+    }));
     info!("Our address: {}", core.address());
-    tokio::time::sleep(Duration::from_secs(60)).await;
-    // let iface = Arc::new(
-    //     TunBuilder::new()
-    //         .name(&args.interface_name)
-    //         .tap(false)
-    //         .mtu(1420)
-    //         .packet_info(false)
-    //         .up()
-    //         .try_build()?,
-    // );
-
-    // tokio::spawn({
-    //     let iface = iface.clone();
-    //     async move {
-    //         loop {
-    //             // Accept new connections.
-    //             let (con, _) = listener.accept().await.unwrap();
-    //             let (mut reader, mut writer) = con.into_split();
-    //             let iface_read = iface.clone();
-    //             let iface_write = iface.clone();
-    //             tokio::spawn(async move {
-    //                 let mut buf = [0; 65535];
-    //                 loop {
-    //                     let n = iface_read.recv(&mut buf).await.unwrap();
-    //                     let mut s = 0;
-    //                     while s < n {
-    //                         s += writer.write(&buf[s..n]).await.unwrap();
-    //                     }
-    //                 }
-    //             });
-    //             tokio::spawn(async move {
-    //                 let mut buf = [0; 65535];
-    //                 loop {
-    //                     let n = reader.read(&mut buf).await.unwrap();
-    //                     let mut s = 0;
-    //                     while s < n {
-    //                         s += iface_write.send(&buf[s..n]).await.unwrap();
-    //                     }
-    //                 }
-    //             });
-    //         }
-    //     }
-    // });
-
-    // // If we set a target, connect to it.
-    // if let Some(target) = args.peer {
-    //     tokio::task::spawn(async move {
-    //         let con = TcpStream::connect(target).await.unwrap();
-    //         let (mut reader, mut writer) = con.into_split();
-    //         let iface_read = iface.clone();
-    //         let iface_write = iface.clone();
-    //         tokio::spawn(async move {
-    //             let mut buf = [0; 65535];
-    //             loop {
-    //                 let n = iface_read.recv(&mut buf).await.unwrap();
-    //                 let mut s = 0;
-    //                 while s < n {
-    //                     s += writer.write(&buf[s..n]).await.unwrap();
-    //                 }
-    //             }
-    //         });
-    //         tokio::spawn(async move {
-    //             let mut buf = [0; 65535];
-    //             loop {
-    //                 let n = reader.read(&mut buf).await.unwrap();
-    //                 let mut s = 0;
-    //                 while s < n {
-    //                     s += iface_write.send(&buf[s..n]).await.unwrap();
-    //                 }
-    //             }
-    //         });
-    //     });
-    // };
-
-    // tokio::time::sleep(std::time::Duration::from_secs(60 * 60 * 24)).await;
+    let mut interface = match tun.first() {
+        Some(tun) => Some(
+            ConfiguredInterface::configure(
+                tun.clone(),
+                core.address(),
+                net::SUBNET_PREFIX_LENGTH,
+                address_scheme.overlay_prefix(),
+            )
+            .map_err(|e| {
+                format!(
+                    "failed to configure address on interface {}: {}",
+                    tun.name(),
+                    e
+                )
+            })?,
+        ),
+        None => {
+            info!("Running in relay-only mode, without an interface");
+            None
+        }
+    };
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Serving metrics on {}", listener.local_addr()?);
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, core).await {
+                error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+    if let Some(ref path) = args.control_socket {
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("failed to bind control socket {}: {}", path.display(), e))?;
+        info!("Serving control socket on {}", path.display());
+        let core = core.clone();
+        let config_path = args.config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, core, config_path, key_file).await {
+                error!("Control socket stopped: {}", e);
+            }
+        });
+    }
+    let handoff = match args.tun_handoff {
+        Some(ref path) => Some(
+            UnixListener::bind(path)
+                .map_err(|e| format!("failed to bind handoff socket {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let mut events = core.subscribe();
+    // Only start once everything is configured.
+    tokio::spawn(run);
+
+    let shutdown = shutdown_signal();
+    let handed_off = hand_off_tun(handoff.as_ref(), tun.first());
+    tokio::pin!(shutdown, handed_off);
+    let handed_off = loop {
+        tokio::select! {
+            res = &mut shutdown => {
+                res?;
+                info!("Shutting down");
+                break false;
+            }
+            socket = &mut handed_off => {
+                info!("Shutting down after handing off the interface");
+                // The socket is closed when the process exits, which tells the new instance
+                // that our listen addresses are free.
+                std::mem::forget(socket);
+                break true;
+            }
+            // The address of the interface follows our identity.
+            event = events.recv() => {
+                if let Ok(CoreEvent::IdentityRotated { address, .. }) = event {
+                    info!("Our address: {}", address);
+                    if let Some(interface) = &mut interface {
+                        if let Err(e) = interface.set_address(address) {
+                            error!("Failed to update the address of the interface: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    };
+    core.shutdown(SHUTDOWN_DEADLINE).await;
+    match interface {
+        // The new instance owns the interface now.
+        Some(interface) if handed_off => drop(interface.release()),
+        // Don't leave the interface behind in a usable state. This also happens if we return
+        // early with an error.
+        interface => drop(interface),
+    }
+    if let Some(path) = args.tun_handoff {
+        if let Err(e) = std::fs::remove_file(&path) {
+            error!("Failed to remove handoff socket {}: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = args.control_socket {
+        if let Err(e) = std::fs::remove_file(&path) {
+            error!("Failed to remove control socket {}: {}", path.display(), e);
+        }
+    }
 
     Ok(())
 }
 
-fn get_ether_type(input: u16) -> Option<EtherType> {
-    Some(match input {
-        ether_type::IPV4 => EtherType::Ipv4,
-        ether_type::IPV6 => EtherType::Ipv6,
-        ether_type::ARP => EtherType::Arp,
-        ether_type::WAKE_ON_LAN => EtherType::WakeOnLan,
-        ether_type::VLAN_TAGGED_FRAME => EtherType::VlanTaggedFrame,
-        ether_type::PROVIDER_BRIDGING => EtherType::ProviderBridging,
-        ether_type::VLAN_DOUBLE_TAGGED_FRAME => EtherType::VlanDoubleTaggedFrame,
-        _ => return None,
-    })
+/// Take over the interface from the instance serving the handoff socket at the given path, and
+/// wait until that instance exited.
+async fn inherit_tun(path: &Path, queues: u16) -> Result<Tun, Box<dyn Error>> {
+    if queues > 1 {
+        warn!("Only a single queue of an inherited interface is used");
+    }
+    let mut socket = UnixStream::connect(path).await.map_err(|e| {
+        format!(
+            "failed to connect to handoff socket {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let tun = Tun::inherit(&socket)
+        .await
+        .map_err(|e| format!("failed to inherit interface: {}", e))?;
+    info!("Inherited interface {}", tun.name());
+    // The previous instance closes the socket when it exits, after which we can bind its listen
+    // addresses.
+    socket.read_to_end(&mut Vec::new()).await?;
+    Ok(tun)
+}
+
+/// Hand the interface off to the first new instance which connects to the handoff socket, and
+/// return the connection to it. Attempts which fail are logged, and the next instance which
+/// connects gets a chance. Never completes without a handoff socket or interface.
+async fn hand_off_tun(listener: Option<&UnixListener>, tun: Option<&Arc<Tun>>) -> UnixStream {
+    let (listener, tun) = match (listener, tun) {
+        (Some(listener), Some(tun)) => (listener, tun),
+        _ => return std::future::pending().await,
+    };
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                error!("Failed to accept handoff connection: {}", e);
+                return std::future::pending().await;
+            }
+        };
+        match tun.hand_off(&socket).await {
+            Ok(()) => {
+                info!("Handed off interface {}", tun.name());
+                return socket;
+            }
+            Err(e) => error!("Failed to hand off interface {}: {}", tun.name(), e),
+        }
+    }
+}
+
+/// Environment variable holding the secret key as hex. If set, it takes precedence over the key
+/// file.
+const SECRET_KEY_ENV: &str = "STYX_SECRET_KEY";
+
+/// Load the secret key from the [`SECRET_KEY_ENV`] environment variable, or otherwise from the
+/// given file. If the file doesn't exist, a new key is generated and saved in it.
+fn load_or_generate_key(path: &Path) -> Result<SecretKey, Box<dyn Error>> {
+    match std::env::var(SECRET_KEY_ENV) {
+        Ok(hex) => {
+            // The error doesn't contain the key, so it is safe to report.
+            let secret_key = hex
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid secret key in {}: {}", SECRET_KEY_ENV, e))?;
+            info!("Using identity from {}", SECRET_KEY_ENV);
+            return Ok(secret_key);
+        }
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return Err(format!(
+                "invalid secret key in {}: key is not valid hex",
+                SECRET_KEY_ENV
+            )
+            .into());
+        }
+        Err(std::env::VarError::NotPresent) => {}
+    }
+    if path.exists() {
+        return Ok(SecretKey::load_from_file(path)?);
+    }
+    info!("Generating new identity in {}", path.display());
+    let secret_key = SecretKey::generate();
+    secret_key.save_to_file(path)?;
+    Ok(secret_key)
+}
+
+/// Initialize the logger. Filters set in the RUST_LOG environment variable take precedence over
+/// the given default filters.
+fn init_logger(default_filters: &str) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => builder.parse_filters(&filters),
+        Err(_) => builder.parse_filters(default_filters),
+    };
+    builder.init();
+}
+
+/// Wait until the process is asked to stop, by either SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = sigterm.recv() => Ok(()),
+    }
 }
@@ -0,0 +1,353 @@
+use std::{fmt::Write as _, io, sync::Arc, time::Duration};
+
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::core::Core;
+use crate::stats::PeerTrafficStats;
+
+/// Path on which the metrics are served.
+const METRICS_PATH: &str = "/metrics";
+
+/// Maximum size of a request we are willing to read.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Time a client gets to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Render the metrics of the given [`Core`] in the Prometheus text exposition format.
+pub fn render(core: &Core) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        // Writing to a String can't fail.
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "styx_active_control_peers",
+        "gauge",
+        "Amount of peers with an active control connection.",
+        &[("", core.active_control_peers() as u64)],
+    );
+    metric(
+        "styx_active_data_peers",
+        "gauge",
+        "Amount of subnets with an active data connection.",
+        &[("", core.active_data_peers() as u64)],
+    );
+    let peers: Vec<_> = core
+        .connected_peers()
+        .into_iter()
+        .map(|peer| {
+            let remote = peer.remote.map(|r| r.to_string()).unwrap_or_default();
+            let labels = format!("{{address=\"{}\",remote=\"{}\"}}", peer.address, remote);
+            (
+                labels,
+                peer.data_connection as u64,
+                peer.control_decode_errors,
+            )
+        })
+        .collect();
+    metric(
+        "styx_peer_data_connection",
+        "gauge",
+        "Whether a data connection to a connected peer is established.",
+        &peers
+            .iter()
+            .map(|(labels, value, _)| (labels.as_str(), *value))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "styx_peer_control_decode_errors_total",
+        "counter",
+        "Total amount of control frames received from a connected peer which failed to decode.",
+        &peers
+            .iter()
+            .map(|(labels, _, errors)| (labels.as_str(), *errors))
+            .collect::<Vec<_>>(),
+    );
+    let mut rtt_samples = Vec::new();
+    let mut jitter_samples = Vec::new();
+    // Peers which didn't answer a ping yet have no round trip times.
+    for (address, rtt) in core
+        .connected_peers()
+        .into_iter()
+        .filter_map(|peer| Some((peer.address, peer.rtt?)))
+    {
+        for (stat, value) in [
+            ("last", rtt.last),
+            ("min", rtt.min),
+            ("mean", rtt.mean),
+            ("max", rtt.max),
+        ] {
+            rtt_samples.push((
+                format!("{{address=\"{}\",stat=\"{}\"}}", address, stat),
+                value.as_micros() as u64,
+            ));
+        }
+        jitter_samples.push((
+            format!("{{address=\"{}\"}}", address),
+            rtt.jitter.as_micros() as u64,
+        ));
+    }
+    metric(
+        "styx_peer_rtt_microseconds",
+        "gauge",
+        "Round trip time of pings to a connected peer, over the recent pings.",
+        &rtt_samples
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "styx_peer_rtt_jitter_microseconds",
+        "gauge",
+        "Average difference between consecutive round trip times of pings to a connected peer.",
+        &jitter_samples
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect::<Vec<_>>(),
+    );
+    let peer_traffic = core.peer_traffic_stats();
+    let peer_labels: Vec<_> = peer_traffic
+        .iter()
+        .map(|(key, _)| format!("{{peer=\"{}\"}}", key))
+        .collect();
+    // The per-peer counters can be reset, so they are exposed as gauges.
+    let mut peer_metric =
+        |name: &str, help: &str, value: &dyn Fn(&PeerTrafficStats) -> Option<u64>| {
+            let samples: Vec<_> = peer_labels
+                .iter()
+                .zip(&peer_traffic)
+                .filter_map(|(labels, (_, traffic))| Some((labels.as_str(), value(traffic)?)))
+                .collect();
+            metric(name, "gauge", help, &samples);
+        };
+    peer_metric(
+        "styx_peer_bytes_tx",
+        "Amount of packet bytes sent to a peer since the last reset.",
+        &|t| Some(t.bytes_tx),
+    );
+    peer_metric(
+        "styx_peer_bytes_rx",
+        "Amount of packet bytes received from a peer since the last reset.",
+        &|t| Some(t.bytes_rx),
+    );
+    peer_metric(
+        "styx_peer_packets_tx",
+        "Amount of packets sent to a peer since the last reset.",
+        &|t| Some(t.packets_tx),
+    );
+    peer_metric(
+        "styx_peer_packets_rx",
+        "Amount of packets received from a peer since the last reset.",
+        &|t| Some(t.packets_rx),
+    );
+    peer_metric(
+        "styx_peer_idle_seconds",
+        "Seconds since a packet was last sent to or received from a peer, if there was any.",
+        &|t| t.last_activity.map(|t| t.elapsed().as_secs()),
+    );
+    metric(
+        "styx_bytes_tx_total",
+        "counter",
+        "Total amount of packet bytes sent to peers.",
+        &[("", core.bytes_tx() as u64)],
+    );
+    metric(
+        "styx_bytes_rx_total",
+        "counter",
+        "Total amount of packet bytes received from peers.",
+        &[("", core.bytes_rx() as u64)],
+    );
+    metric(
+        "styx_packets_dropped_total",
+        "counter",
+        "Total amount of dropped packets.",
+        &[
+            ("{reason=\"unroutable\"}", core.dropped_packets()),
+            ("{reason=\"spoofed\"}", core.spoofed_packets()),
+            ("{reason=\"replayed\"}", core.replayed_packets()),
+        ],
+    );
+    metric(
+        "styx_control_decode_errors_total",
+        "counter",
+        "Total amount of control frames received from peers which failed to decode.",
+        &[("", core.control_decode_errors() as u64)],
+    );
+    let mut queue_samples = Vec::new();
+    for (subnet, depths) in core.queue_depths() {
+        for (queue, depth, peak) in [
+            ("send", depths.send, depths.send_peak),
+            ("recv", depths.recv, depths.recv_peak),
+        ] {
+            queue_samples.push((
+                format!("{{subnet=\"{}\",queue=\"{}\"}}", subnet, queue),
+                depth as u64,
+                peak as u64,
+            ));
+        }
+    }
+    metric(
+        "styx_data_queue_depth",
+        "gauge",
+        "Packets queued on the data connection to a subnet.",
+        &queue_samples
+            .iter()
+            .map(|(labels, depth, _)| (labels.as_str(), *depth))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "styx_data_queue_peak",
+        "gauge",
+        "Highest amount of packets queued on the data connection to a subnet since the last reset.",
+        &queue_samples
+            .iter()
+            .map(|(labels, _, peak)| (labels.as_str(), *peak))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "styx_connection_queue_depth",
+        "gauge",
+        "Inbound connections which completed their handshake, waiting to be registered.",
+        &[("", core.connection_queue_depth() as u64)],
+    );
+    metric(
+        "styx_connection_queue_peak",
+        "gauge",
+        "Highest depth of the connection queue since the last reset.",
+        &[("", core.connection_queue_peak() as u64)],
+    );
+    metric(
+        "styx_connection_queue_full_total",
+        "counter",
+        "Total amount of inbound connections which found the connection queue full.",
+        &[
+            ("{action=\"blocked\"}", core.connection_queue_blocked()),
+            ("{action=\"dropped\"}", core.connection_queue_dropped()),
+        ],
+    );
+    out
+}
+
+/// Serve the metrics of `core` over HTTP on the given listener. Every request for
+/// [`METRICS_PATH`] is answered with the current metrics, other paths are not found. This only
+/// returns if the listener fails.
+pub async fn serve(listener: TcpListener, core: Arc<Core>) -> io::Result<()> {
+    loop {
+        let (con, remote) = listener.accept().await?;
+        let core = core.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle_request(con, &core)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("Failed to serve metrics to {}: {}", remote, e),
+                Err(_) => debug!("Metrics request from {} timed out", remote),
+            }
+        });
+    }
+}
+
+/// Read a single HTTP request from the connection, and answer it. The connection is closed
+/// afterwards.
+async fn handle_request(mut con: TcpStream, core: &Core) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    // Only the request line matters, but the whole head is read so the client doesn't get a
+    // reset while still sending it.
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            warn!("Metrics request too large");
+            return respond(&mut con, "431 Request Header Fields Too Large", "").await;
+        }
+        let mut chunk = [0; 1024];
+        let n = con.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = buf
+        .split(|b| *b == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(METRICS_PATH)) => respond(&mut con, "200 OK", &render(core)).await,
+        (Some("GET"), Some(_)) => respond(&mut con, "404 Not Found", "").await,
+        _ => respond(&mut con, "405 Method Not Allowed", "").await,
+    }
+}
+
+/// Write a response with the given status and body, and close the connection.
+async fn respond(con: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        CONTENT_TYPE,
+        body.len()
+    );
+    con.write_all(head.as_bytes()).await?;
+    con.write_all(body.as_bytes()).await?;
+    con.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a raw request to the metrics server, and return the full response.
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut con = TcpStream::connect(addr).await.unwrap();
+        con.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        con.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let core = Core::new(
+            crate::crypto::ed25519::SecretKey::from_bytes([1; 32]),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, core.clone()));
+
+        let response = request(addr, "GET /metrics HTTP/1.1\r\nHost: styx\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, render(&core));
+        for line in [
+            "styx_active_control_peers 0",
+            "styx_active_data_peers 0",
+            "styx_bytes_tx_total 0",
+            "styx_bytes_rx_total 0",
+            "styx_packets_dropped_total{reason=\"unroutable\"} 0",
+            "styx_packets_dropped_total{reason=\"spoofed\"} 0",
+            "styx_packets_dropped_total{reason=\"replayed\"} 0",
+            "styx_control_decode_errors_total 0",
+            "styx_connection_queue_depth 0",
+            "styx_connection_queue_full_total{action=\"dropped\"} 0",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {}", line);
+        }
+
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        core.shutdown(Duration::from_millis(10)).await;
+    }
+}
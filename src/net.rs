@@ -1,5 +1,763 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::unix::io::AsRawFd,
+    str::FromStr,
+    time::Duration,
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::address::AddressScheme;
+use crate::crypto::ed25519::PublicKey;
+use crate::data;
+use crate::netlink;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Default time a connection attempt gets before the next address is tried in parallel, as
+/// recommended by RFC 8305.
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Smallest maximum segment size the kernel accepts for a TCP socket.
+pub const MIN_TCP_MSS: u32 = 88;
+
+/// Largest maximum segment size the kernel accepts for a TCP socket.
+pub const MAX_TCP_MSS: u32 = 32767;
+
 /// Length of the unique part of a subnet.
 pub const SUBNET_LENGTH: usize = 8;
 
+/// Prefix length of a subnet.
+pub const SUBNET_PREFIX_LENGTH: u8 = 64;
+
 /// Subnet used in the overlay, this is always a /64.
+///
+/// Converting an [`Ipv6Addr`] with [`TryFrom`] only accepts addresses in the overlay prefix of
+/// the default [`AddressScheme`]. Use [`Subnet::from_address`] to take the subnet of any
+/// address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Subnet([u8; SUBNET_LENGTH]);
+
+/// Error returned when converting an address outside of the overlay into a [`Subnet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotOverlayAddress(pub Ipv6Addr);
+
+impl fmt::Display for NotOverlayAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (network, prefix_len) = AddressScheme::default().overlay_prefix();
+        write!(
+            f,
+            "{} is not in the overlay prefix {}/{}",
+            self.0, network, prefix_len
+        )
+    }
+}
+
+impl std::error::Error for NotOverlayAddress {}
+
+impl Subnet {
+    /// Create a new [`Subnet`] from the network part of the address.
+    pub fn new(raw: [u8; SUBNET_LENGTH]) -> Self {
+        Self(raw)
+    }
+
+    /// The subnet containing the given address.
+    pub fn from_address(addr: Ipv6Addr) -> Self {
+        let mut raw = [0; SUBNET_LENGTH];
+        raw.copy_from_slice(&addr.octets()[..SUBNET_LENGTH]);
+        Self(raw)
+    }
+
+    /// The subnet of the node with the given public key, i.e. the network part of
+    /// [`PublicKey::address`]. Nodes using a different [`AddressScheme`] should derive the subnet
+    /// with [`Subnet::from_address`] instead.
+    ///
+    /// [`AddressScheme`]: crate::address::AddressScheme
+    pub fn from_public_key(key: &PublicKey) -> Self {
+        Self::from_address(key.address())
+    }
+
+    /// The network address of this subnet, i.e. the first address in the subnet.
+    pub fn network_address(&self) -> Ipv6Addr {
+        let mut raw = [0; 16];
+        raw[..SUBNET_LENGTH].copy_from_slice(&self.0);
+        Ipv6Addr::from(raw)
+    }
+
+    /// Check if the given address is part of this subnet.
+    pub fn contains(&self, addr: &Ipv6Addr) -> bool {
+        addr.octets()[..SUBNET_LENGTH] == self.0
+    }
+}
+
+impl From<&PublicKey> for Subnet {
+    fn from(key: &PublicKey) -> Self {
+        Self::from_public_key(key)
+    }
+}
+
+impl TryFrom<Ipv6Addr> for Subnet {
+    type Error = NotOverlayAddress;
+
+    fn try_from(addr: Ipv6Addr) -> Result<Self, Self::Error> {
+        let (network, prefix_len) = AddressScheme::default().overlay_prefix();
+        if !prefix_matches(&network.octets(), &addr.octets(), prefix_len) {
+            return Err(NotOverlayAddress(addr));
+        }
+        Ok(Self::from_address(addr))
+    }
+}
+
+impl From<Subnet> for Ipv6Addr {
+    fn from(subnet: Subnet) -> Self {
+        subnet.network_address()
+    }
+}
+
+impl fmt::Display for Subnet {
+    /// Formats the subnet in CIDR notation, e.g. `300:1234:5678:9abc::/64`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network_address(), SUBNET_PREFIX_LENGTH)
+    }
+}
+
+/// A range of underlay IP addresses, in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    /// Address of the network.
+    addr: IpAddr,
+    /// Amount of leading bits of the address which identify the network.
+    prefix_len: u8,
+}
+
+/// Error returned when parsing a [`Cidr`] fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError;
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("invalid CIDR, expected <ip>/<prefix length>")
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl Cidr {
+    /// Create a new [`Cidr`]. Returns [`None`] if the prefix length is larger than the amount of
+    /// bits in the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return None;
+        }
+        Some(Self { addr, prefix_len })
+    }
+
+    /// Check if the given address is part of this range. IPv4 addresses are never part of an
+    /// IPv6 range and vice versa.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Check if the first `prefix_len` bits of `a` and `b` are equal.
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = prefix_len as usize / 8;
+    let remaining_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xFF << (8 - remaining_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').ok_or(CidrParseError)?;
+        let addr = addr.parse().map_err(|_| CidrParseError)?;
+        let prefix_len = prefix_len.parse().map_err(|_| CidrParseError)?;
+        Cidr::new(addr, prefix_len).ok_or(CidrParseError)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Error returned when parsing a socket address with [`parse_socket_addr`] fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketAddrParseError {
+    /// The address is malformed.
+    Invalid,
+    /// The address is scoped to an interface which doesn't exist.
+    UnknownInterface(String),
+}
+
+impl fmt::Display for SocketAddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketAddrParseError::Invalid => {
+                f.pad("invalid socket address, expected <ip>:<port> or [<ipv6>%<interface>]:<port>")
+            }
+            SocketAddrParseError::UnknownInterface(name) => {
+                write!(f, "unknown interface {} in socket address", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SocketAddrParseError {}
+
+/// Parse a socket address. On top of the formats [`SocketAddr`] accepts, IPv6 addresses can be
+/// scoped to an interface by its name rather than its index, as in `[fe80::1%eth0]:9651`. This
+/// matters for link-local addresses, which are ambiguous without a scope, as every interface has
+/// its own link-local network. The interface is looked up when parsing, so it must exist.
+pub fn parse_socket_addr(s: &str) -> Result<SocketAddr, SocketAddrParseError> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    let (ip, port) = s
+        .strip_prefix('[')
+        .and_then(|s| s.split_once("]:"))
+        .ok_or(SocketAddrParseError::Invalid)?;
+    let (ip, interface) = ip.split_once('%').ok_or(SocketAddrParseError::Invalid)?;
+    let ip: Ipv6Addr = ip.parse().map_err(|_| SocketAddrParseError::Invalid)?;
+    let port = port.parse().map_err(|_| SocketAddrParseError::Invalid)?;
+    let scope_id = netlink::interface_index(interface)
+        .map_err(|_| SocketAddrParseError::UnknownInterface(interface.to_string()))?;
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+/// Address of a peer, as configured by the user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    /// A literal IP address and port.
+    Socket(SocketAddr),
+    /// A hostname and port. The hostname is resolved every time the peer is dialed, so changes
+    /// in DNS are picked up.
+    Host(String, u16),
+}
+
+/// Error returned when parsing a [`PeerAddr`] fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerAddrParseError;
+
+impl fmt::Display for PeerAddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("invalid peer address, expected <host>:<port>")
+    }
+}
+
+impl std::error::Error for PeerAddrParseError {}
+
+impl PeerAddr {
+    /// Resolve the addresses the peer can be reached on.
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            PeerAddr::Socket(addr) => Ok(vec![*addr]),
+            PeerAddr::Host(host, port) => Ok(tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .collect()),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Socket(addr)
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = PeerAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = parse_socket_addr(s) {
+            return Ok(PeerAddr::Socket(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or(PeerAddrParseError)?;
+        // A bare IPv6 address without brackets also splits on a colon.
+        if host.is_empty() || host.contains(':') {
+            return Err(PeerAddrParseError);
+        }
+        let port = port.parse().map_err(|_| PeerAddrParseError)?;
+        Ok(PeerAddr::Host(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Socket(addr) => write!(f, "{}", addr),
+            PeerAddr::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Opens outbound underlay connections.
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    /// Local address connections originate from. By default, the kernel picks one based on the
+    /// route to the remote.
+    pub bind_addr: Option<IpAddr>,
+    /// Name of the network interface connections are bound to, with `SO_BINDTODEVICE`. This
+    /// usually requires `CAP_NET_RAW`.
+    pub bind_device: Option<String>,
+    /// Maximum segment size of the connections, see [`set_tcp_mss`]. Also applied to the listen
+    /// addresses bound by a [`CoreBuilder`](crate::core::CoreBuilder).
+    pub tcp_mss: Option<u32>,
+    /// Head start a connection attempt gets in [`Dialer::connect_any`], before the next address
+    /// is tried in parallel.
+    pub happy_eyeballs_delay: Duration,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            bind_device: None,
+            tcp_mss: None,
+            happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+        }
+    }
+}
+
+impl Dialer {
+    /// Open a new connection to the given remote.
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(
+            Domain::for_address(remote),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+        if let Some(ip) = self.bind_addr {
+            // Port 0 lets the kernel pick a free port.
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        if let Some(ref device) = self.bind_device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        if let Some(mss) = self.tcp_mss {
+            set_tcp_mss(&socket, mss)?;
+        }
+        TcpSocket::from_std_stream(socket.into())
+            .connect(remote)
+            .await
+    }
+
+    /// Open a new connection to a remote reachable on any of the given addresses, like "Happy
+    /// Eyeballs" (RFC 8305) does. Addresses are tried in order, alternating between IPv6 and
+    /// IPv4, starting with the family of the first address. Every attempt gets a head start of
+    /// [`Dialer::happy_eyeballs_delay`], after which the next address is tried in parallel. If
+    /// an attempt fails, the next address is tried right away. The first connection which is
+    /// established is returned, all other attempts are aborted.
+    ///
+    /// If all attempts fail, the error of the last failed attempt is returned.
+    pub async fn connect_any(&self, remotes: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut pending = interleave_families(remotes).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if let Some(remote) = pending.next() {
+                attempts.push(async move { (remote, self.connect(remote).await) });
+            }
+            if attempts.is_empty() {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                }));
+            }
+            tokio::select! {
+                // Attempts is not empty, so this always yields a result.
+                Some((remote, res)) = attempts.next() => match res {
+                    // Dropping the other attempts aborts them.
+                    Ok(con) => return Ok(con),
+                    Err(e) => {
+                        debug!("Failed to connect to {}: {}", remote, e);
+                        last_err = Some(e);
+                    }
+                },
+                _ = tokio::time::sleep(self.happy_eyeballs_delay), if pending.len() > 0 => (),
+            }
+        }
+    }
+}
+
+/// Socket options applied to established connections.
+///
+/// `TCP_NODELAY` disables Nagle's algorithm, so small writes are sent right away instead of
+/// being coalesced with later writes. This lowers latency, at the cost of more, smaller segments
+/// on the wire. That's a good trade for control connections, which carry small frames, but data
+/// connections carrying bulk traffic may be better off with coalescing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SocketOptions {
+    /// Set `TCP_NODELAY`.
+    pub nodelay: bool,
+    /// Size of the send buffer (`SO_SNDBUF`). By default, the kernel sizes it automatically.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the receive buffer (`SO_RCVBUF`). By default, the kernel sizes it automatically.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Default options of control connections, which have `TCP_NODELAY` set.
+    pub const CONTROL: Self = Self {
+        nodelay: true,
+        send_buffer_size: None,
+        recv_buffer_size: None,
+    };
+
+    /// Apply the options to a connection. Setting a buffer size disables automatic sizing by
+    /// the kernel, and the kernel doubles the value to leave room for bookkeeping.
+    pub fn apply(&self, con: &TcpStream) -> io::Result<()> {
+        con.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(con);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Order addresses so IPv6 and IPv4 addresses alternate, starting with the family of the first
+/// address. The relative order of addresses of the same family is kept.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(SocketAddr::is_ipv6).unwrap_or_default();
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut out = Vec::with_capacity(addrs.len());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Set the maximum segment size of a TCP socket. This must be done before the connection is
+/// established. If set on a listening socket, accepted connections inherit the value.
+///
+/// The kernel normally derives the MSS from the path MTU. Clamping it is useful if the path has
+/// a small MTU, but the kernel fails to discover it, which leads to fragmentation or dropped
+/// segments on the underlay.
+pub fn set_tcp_mss(socket: &impl AsRawFd, mss: u32) -> io::Result<()> {
+    let mss = mss as libc::c_int;
+    // SAFETY: mss is valid for reads of size_of::<c_int>() bytes.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mss as *const _ as *const _,
+            std::mem::size_of::<libc::c_int>() as _,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Default maximum segment size of underlay TCP connections, for an overlay with the given MTU.
+/// A segment fits exactly one data frame holding a packet of the full MTU, so a packet is never
+/// split over 2 segments. If the path MTU is smaller, the kernel lowers the MSS further.
+pub fn default_tcp_mss(mtu: usize) -> u32 {
+    data::encrypted_frame_size(mtu).min(MAX_TCP_MSS as usize) as u32
+}
+
+/// Get the maximum segment size of a TCP socket. For an established connection, this is the MSS
+/// currently in use, for other sockets this is the configured value.
+#[cfg(test)]
+pub fn tcp_mss(socket: &impl AsRawFd) -> io::Result<u32> {
+    let mut mss: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: mss and len are valid for writes of their respective size.
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mut mss as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(mss as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn subnet_from_public_key() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let subnet = Subnet::from_public_key(&key);
+
+        let address = u128::from(key.address());
+        let network = u128::from(subnet.network_address());
+        assert_eq!(network, address & !((1 << 64) - 1));
+        assert_eq!(network >> 64, address >> 64);
+    }
+
+    #[test]
+    fn subnet_contains_its_whole_range() {
+        let subnet = Subnet::new([0x03, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]);
+        for addr in [
+            "312:3456:789a:bcde::",
+            "312:3456:789a:bcde::1",
+            "312:3456:789a:bcde:ffff:ffff:ffff:ffff",
+        ] {
+            assert!(subnet.contains(&addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in [
+            "312:3456:789a:bcdd:ffff:ffff:ffff:ffff",
+            "312:3456:789a:bcdf::",
+            "212:3456:789a:bcde::1",
+        ] {
+            assert!(!subnet.contains(&addr.parse().unwrap()), "{}", addr);
+        }
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        assert!(Subnet::from_public_key(&key).contains(&key.address()));
+    }
+
+    #[test]
+    fn converts_between_subnets_addresses_and_keys() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let subnet = Subnet::from(&key);
+        assert_eq!(subnet, Subnet::from_public_key(&key));
+        // The subnet of an address round trips through its network address.
+        assert_eq!(Subnet::try_from(key.address()), Ok(subnet));
+        assert_eq!(Subnet::try_from(Ipv6Addr::from(subnet)), Ok(subnet));
+
+        let subnet = Subnet::new([0x03, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]);
+        assert_eq!(subnet.to_string(), "312:3456:789a:bcde::/64");
+        let addr: Ipv6Addr = "312:3456:789a:bcde::1".parse().unwrap();
+        assert_eq!(Subnet::try_from(addr), Ok(subnet));
+
+        let outside: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(Subnet::try_from(outside), Err(NotOverlayAddress(outside)));
+        assert_eq!(
+            NotOverlayAddress(outside).to_string(),
+            "2001:db8::1 is not in the overlay prefix 200::/7"
+        );
+        // Any address can still be mapped to its subnet explicitly.
+        assert_eq!(
+            Subnet::from_address(outside).network_address(),
+            "2001:db8::".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn can_clamp_tcp_mss() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let socket = TcpSocket::new_v4().unwrap();
+        set_tcp_mss(&socket, 1000).unwrap();
+        assert_eq!(tcp_mss(&socket).unwrap(), 1000);
+
+        let (con, _) = tokio::join!(socket.connect(addr), listener.accept());
+        // The effective MSS might be lower, e.g. because space is reserved for TCP options.
+        assert!(tcp_mss(&con.unwrap()).unwrap() <= 1000);
+    }
+
+    #[test]
+    fn default_tcp_mss_fits_full_packets() {
+        let mss = default_tcp_mss(crate::tun::DEFAULT_MTU as usize);
+        assert!(mss as usize > crate::tun::DEFAULT_MTU as usize);
+        // Loopback has a large MTU, so the MSS is not lowered further.
+        let socket = TcpSocket::new_v4().unwrap();
+        set_tcp_mss(&socket, mss).unwrap();
+        assert_eq!(tcp_mss(&socket).unwrap(), mss);
+        // Jumbo MTUs are capped to what the kernel accepts.
+        let socket = TcpSocket::new_v4().unwrap();
+        set_tcp_mss(&socket, default_tcp_mss(data::MAX_JUMBO_MTU)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (con, _) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        let con = con.unwrap();
+
+        SocketOptions::CONTROL.apply(&con).unwrap();
+        assert!(con.nodelay().unwrap());
+
+        let options = SocketOptions {
+            nodelay: false,
+            send_buffer_size: Some(32 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+        };
+        options.apply(&con).unwrap();
+        assert!(!con.nodelay().unwrap());
+        let socket = SockRef::from(&con);
+        // The kernel doubles the requested size.
+        assert_eq!(socket.send_buffer_size().unwrap(), 64 * 1024);
+        assert_eq!(socket.recv_buffer_size().unwrap(), 128 * 1024);
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.1.0.0/15".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains(&"10.0.255.255".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.0".parse().unwrap()));
+        assert!(!cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn parses_peer_addrs() {
+        assert_eq!(
+            "[2001:db8::1]:9651".parse(),
+            Ok(PeerAddr::Socket("[2001:db8::1]:9651".parse().unwrap()))
+        );
+        assert_eq!(
+            "peer.example.com:9651".parse(),
+            Ok(PeerAddr::Host("peer.example.com".to_string(), 9651))
+        );
+        assert_eq!(
+            "peer.example.com:9651"
+                .parse::<PeerAddr>()
+                .unwrap()
+                .to_string(),
+            "peer.example.com:9651"
+        );
+        for invalid in ["peer.example.com", ":9651", "2001:db8::1:9651", "peer:port"] {
+            assert!(invalid.parse::<PeerAddr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_scoped_link_local_addrs() {
+        let lo = netlink::interface_index("lo").unwrap();
+        let expected = SocketAddr::from(SocketAddrV6::new("fe80::1".parse().unwrap(), 9651, 0, lo));
+        assert_eq!(parse_socket_addr("[fe80::1%lo]:9651"), Ok(expected));
+        assert_eq!(
+            parse_socket_addr(&format!("[fe80::1%{}]:9651", lo)),
+            Ok(expected)
+        );
+        assert_eq!("[fe80::1%lo]:9651".parse(), Ok(PeerAddr::Socket(expected)));
+        assert_eq!(
+            parse_socket_addr("[fe80::1%styx-missing]:9651"),
+            Err(SocketAddrParseError::UnknownInterface(
+                "styx-missing".to_string()
+            ))
+        );
+        for invalid in [
+            "fe80::1%lo:9651",
+            "[fe80::1%lo]",
+            "[10.0.0.1%lo]:9651",
+            "[fe80::1%lo]:x",
+        ] {
+            assert_eq!(
+                parse_socket_addr(invalid),
+                Err(SocketAddrParseError::Invalid),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn interleaves_address_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "1.0.0.1:1", "1.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "[::3]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(interleave_families(&addrs), expected);
+    }
+
+    #[tokio::test]
+    async fn connect_any_falls_back_to_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Nothing listens on this port anymore.
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let dialer = Dialer {
+            happy_eyeballs_delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        // The next address is tried as soon as the first attempt fails, without waiting for
+        // the head start to pass.
+        let con = tokio::time::timeout(Duration::from_secs(1), dialer.connect_any(&[closed, addr]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(con.peer_addr().unwrap(), addr);
+
+        // With only a working address, it is used. Without any working address, the last error
+        // is returned.
+        let con = dialer.connect_any(&[addr]).await.unwrap();
+        assert_eq!(con.peer_addr().unwrap(), addr);
+        let err = dialer.connect_any(&[closed]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = dialer.connect_any(&[]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn dialer_binds_to_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dialer = Dialer {
+            bind_addr: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let (con, accepted) = tokio::join!(
+            dialer.connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        let con = con.unwrap();
+        let (_, remote) = accepted.unwrap();
+
+        assert_eq!(con.local_addr().unwrap().ip(), dialer.bind_addr.unwrap());
+        assert_eq!(con.local_addr().unwrap(), remote);
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    io,
+    net::Ipv6Addr,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+};
+
+use log::{debug, warn};
+
+use crate::net::{Subnet, SUBNET_PREFIX_LENGTH};
+use crate::tun::Tun;
+
+/// Size of a netlink message header.
+const NLMSG_HDR_SIZE: usize = 16;
+
+/// Size of a route message header.
+const RTMSG_SIZE: usize = 12;
+
+/// Size of an address message header.
+const IFADDRMSG_SIZE: usize = 8;
+
+/// Size of an interface info message header.
+const IFINFOMSG_SIZE: usize = 16;
+
+/// Netlink message type to remove a link.
+const RTM_DELLINK: u16 = 17;
+
+/// Netlink message type to add an address.
+const RTM_NEWADDR: u16 = 20;
+
+/// Netlink message type to remove an address.
+const RTM_DELADDR: u16 = 21;
+
+/// Netlink message type to add a route.
+const RTM_NEWROUTE: u16 = 24;
+
+/// Netlink message type to remove a route.
+const RTM_DELROUTE: u16 = 25;
+
+/// Netlink message type of an error or acknowledgement.
+const NLMSG_ERROR: u16 = 2;
+
+/// Address attribute holding the address of the interface.
+const IFA_ADDRESS: u16 = 1;
+
+/// Address flag to skip duplicate address detection. Nobody else can use our address on a TUN
+/// interface, and the address can't be used until detection finishes.
+const IFA_F_NODAD: u8 = 0x02;
+
+/// Route attribute holding the destination.
+const RTA_DST: u16 = 1;
+
+/// Route attribute holding the outgoing interface index.
+const RTA_OIF: u16 = 4;
+
+/// The main routing table.
+const RT_TABLE_MAIN: u8 = 254;
+
+/// Route protocol used for routes we install. This is RTPROT_STATIC, which marks the route as
+/// installed by an administrator (or in this case, a daemon acting on their behalf).
+const RTPROT_STATIC: u8 = 4;
+
+/// Scope of a route to a remote destination.
+const RT_SCOPE_UNIVERSE: u8 = 0;
+
+/// Type of a regular route.
+const RTN_UNICAST: u8 = 1;
+
+/// Manages kernel routes for overlay subnets, pointing them at the overlay interface.
+///
+/// Routes are installed and removed through a NETLINK_ROUTE socket. This requires
+/// CAP_NET_ADMIN, and mutates the state of the host, so it should only be used if explicitly
+/// configured.
+pub struct KernelRoutes {
+    /// The netlink socket.
+    netlink: Netlink,
+    /// Index of the interface routes point to.
+    ifindex: u32,
+    /// Subnets for which we installed a route.
+    installed: HashSet<Subnet>,
+}
+
+impl KernelRoutes {
+    /// Create a new [`KernelRoutes`] which will install routes pointing to the interface with the
+    /// given name. The interface must exist.
+    pub fn new(interface: &str) -> io::Result<Self> {
+        Ok(Self {
+            ifindex: interface_index(interface)?,
+            netlink: Netlink::open()?,
+            installed: HashSet::new(),
+        })
+    }
+
+    /// Synchronize the installed routes with the given reachable subnets. Routes are added for
+    /// subnets which became reachable, and removed for subnets which are no longer reachable.
+    pub fn sync(&mut self, reachable: &[Subnet]) -> io::Result<()> {
+        let reachable: HashSet<_> = reachable.iter().copied().collect();
+
+        let lost: Vec<_> = self.installed.difference(&reachable).copied().collect();
+        for subnet in lost {
+            debug!("Removing kernel route for {}", subnet);
+            self.netlink.route(
+                RTM_DELROUTE,
+                0,
+                subnet.network_address(),
+                SUBNET_PREFIX_LENGTH,
+                self.ifindex,
+            )?;
+            self.installed.remove(&subnet);
+        }
+
+        let new: Vec<_> = reachable.difference(&self.installed).copied().collect();
+        for subnet in new {
+            debug!("Adding kernel route for {}", subnet);
+            self.netlink.route(
+                RTM_NEWROUTE,
+                (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+                subnet.network_address(),
+                SUBNET_PREFIX_LENGTH,
+                self.ifindex,
+            )?;
+            self.installed.insert(subnet);
+        }
+
+        Ok(())
+    }
+
+    /// Remove all routes we installed.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.sync(&[])
+    }
+}
+
+/// Assign `addr` to the interface with the given name, with the given prefix length, and add a
+/// route for the network `route` through the interface. The kernel routes the subnet of the
+/// address to the interface on its own, `route` should cover the rest of the overlay.
+///
+/// An address or route which is already present is replaced, so this is safe to call for an
+/// interface which was configured before, e.g. by a previous instance.
+pub fn configure_interface(
+    interface: &str,
+    addr: Ipv6Addr,
+    prefix_len: u8,
+    route: (Ipv6Addr, u8),
+) -> io::Result<()> {
+    let ifindex = interface_index(interface)?;
+    let mut netlink = Netlink::open()?;
+    netlink.address(
+        RTM_NEWADDR,
+        (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+        addr,
+        prefix_len,
+        ifindex,
+    )?;
+    netlink.route(
+        RTM_NEWROUTE,
+        (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+        route.0,
+        route.1,
+        ifindex,
+    )
+}
+
+/// An interface configured with [`configure_interface`], which is torn down again when this is
+/// dropped: the route and address are removed, and the interface is brought down. This also
+/// happens if the process exits with an error or a panic unwinds past it, so the interface
+/// doesn't linger in a usable state.
+///
+/// Teardown happens with blocking netlink and ioctl calls in [`Drop`]. These only take a few
+/// syscalls and never wait on the network, so this is fine to do from the async runtime.
+pub struct ConfiguredInterface {
+    /// The interface, `None` once released.
+    tun: Option<Arc<Tun>>,
+    /// Address assigned to the interface, with its prefix length.
+    addr: (Ipv6Addr, u8),
+    /// Route added through the interface.
+    route: (Ipv6Addr, u8),
+}
+
+impl ConfiguredInterface {
+    /// Configure the interface of `tun` like [`configure_interface`] does.
+    pub fn configure(
+        tun: Arc<Tun>,
+        addr: Ipv6Addr,
+        prefix_len: u8,
+        route: (Ipv6Addr, u8),
+    ) -> io::Result<Self> {
+        configure_interface(tun.name(), addr, prefix_len, route)?;
+        Ok(Self {
+            tun: Some(tun),
+            addr: (addr, prefix_len),
+            route,
+        })
+    }
+
+    /// Replace the address of the interface with `addr`, with the same prefix length, e.g.
+    /// because the identity of the node was rotated. The new address is added before the old
+    /// one is removed.
+    pub fn set_address(&mut self, addr: Ipv6Addr) -> io::Result<()> {
+        // The interface is only taken when released, which consumes self.
+        let tun = self.tun.as_ref().unwrap();
+        let ifindex = interface_index(tun.name())?;
+        let mut netlink = Netlink::open()?;
+        let (old, prefix_len) = self.addr;
+        netlink.address(
+            RTM_NEWADDR,
+            (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+            addr,
+            prefix_len,
+            ifindex,
+        )?;
+        self.addr = (addr, prefix_len);
+        if old != addr {
+            netlink.address(RTM_DELADDR, 0, old, prefix_len, ifindex)?;
+        }
+        Ok(())
+    }
+
+    /// Give up ownership of the interface without tearing it down, e.g. because it is handed off
+    /// to another process.
+    pub fn release(mut self) -> Arc<Tun> {
+        // The interface is only taken here, or when dropped.
+        self.tun.take().unwrap()
+    }
+
+    /// Remove the route and address, and bring the interface down.
+    fn teardown(&self, tun: &Tun) -> io::Result<()> {
+        let ifindex = interface_index(tun.name())?;
+        let mut netlink = Netlink::open()?;
+        // Either of these might already be gone, e.g. if an administrator removed them, which
+        // shouldn't stop the rest of the teardown.
+        if let Err(e) = netlink.route(RTM_DELROUTE, 0, self.route.0, self.route.1, ifindex) {
+            debug!("Failed to remove route from {}: {}", tun.name(), e);
+        }
+        if let Err(e) = netlink.address(RTM_DELADDR, 0, self.addr.0, self.addr.1, ifindex) {
+            debug!("Failed to remove address from {}: {}", tun.name(), e);
+        }
+        tun.set_down()
+    }
+}
+
+impl Drop for ConfiguredInterface {
+    fn drop(&mut self) {
+        if let Some(tun) = self.tun.take() {
+            debug!("Tearing down interface {}", tun.name());
+            if let Err(e) = self.teardown(&tun) {
+                warn!("Failed to tear down interface {}: {}", tun.name(), e);
+            }
+        }
+    }
+}
+
+/// Delete the interface with the given name.
+pub fn delete_interface(interface: &str) -> io::Result<()> {
+    let ifindex = interface_index(interface)?;
+    let mut body = Vec::with_capacity(IFINFOMSG_SIZE);
+    // Interface info message: family, padding, device type, index, flags and the mask of changed
+    // flags.
+    body.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+    body.extend_from_slice(&ifindex.to_ne_bytes());
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    Netlink::open()?.request(RTM_DELLINK, 0, &body)
+}
+
+/// Look up the index of the interface with the given name.
+pub fn interface_index(interface: &str) -> io::Result<u32> {
+    let name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    // SAFETY: name is a valid NUL terminated string.
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ifindex)
+}
+
+/// A NETLINK_ROUTE socket, on which requests are sent one at a time.
+struct Netlink {
+    /// The netlink socket.
+    socket: OwnedFd,
+    /// Sequence number of the last request.
+    seq: u32,
+}
+
+impl Netlink {
+    /// Open a new netlink socket.
+    fn open() -> io::Result<Self> {
+        // SAFETY: socket does not touch memory.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: socket returned a new valid file descriptor.
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        Ok(Self { socket, seq: 0 })
+    }
+
+    /// Send a route request for the given destination through the interface, and wait for the
+    /// acknowledgement.
+    fn route(
+        &mut self,
+        msg_type: u16,
+        flags: u16,
+        dst: Ipv6Addr,
+        prefix_len: u8,
+        ifindex: u32,
+    ) -> io::Result<()> {
+        let mut body = Vec::with_capacity(RTMSG_SIZE + 4 + 16 + 4 + 4);
+        // Route message.
+        body.extend_from_slice(&[
+            libc::AF_INET6 as u8,
+            prefix_len,
+            0,
+            0,
+            RT_TABLE_MAIN,
+            RTPROT_STATIC,
+            RT_SCOPE_UNIVERSE,
+            RTN_UNICAST,
+        ]);
+        // Route flags.
+        body.extend_from_slice(&0u32.to_ne_bytes());
+        // Destination attribute is an IPv6 address, interface attribute is a u32. Both are a
+        // multiple of 4 bytes, so no padding is needed.
+        push_attr(&mut body, RTA_DST, &dst.octets());
+        push_attr(&mut body, RTA_OIF, &ifindex.to_ne_bytes());
+
+        self.request(msg_type, flags, &body)
+    }
+
+    /// Send an address request for the given address on the interface, and wait for the
+    /// acknowledgement.
+    fn address(
+        &mut self,
+        msg_type: u16,
+        flags: u16,
+        addr: Ipv6Addr,
+        prefix_len: u8,
+        ifindex: u32,
+    ) -> io::Result<()> {
+        let mut body = Vec::with_capacity(IFADDRMSG_SIZE + 4 + 16);
+        // Address message.
+        body.extend_from_slice(&[
+            libc::AF_INET6 as u8,
+            prefix_len,
+            IFA_F_NODAD,
+            RT_SCOPE_UNIVERSE,
+        ]);
+        body.extend_from_slice(&ifindex.to_ne_bytes());
+        push_attr(&mut body, IFA_ADDRESS, &addr.octets());
+
+        self.request(msg_type, flags, &body)
+    }
+
+    /// Send a request with the given body, and wait for the acknowledgement.
+    fn request(&mut self, msg_type: u16, flags: u16, body: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let len = NLMSG_HDR_SIZE + body.len();
+        let mut msg = Vec::with_capacity(len);
+        // Netlink header.
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(
+            &((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16 | flags).to_ne_bytes(),
+        );
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        // Port ID, 0 means the kernel.
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(body);
+
+        // SAFETY: msg is valid for reads of msg.len() bytes.
+        let n = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                msg.as_ptr() as *const _,
+                msg.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.read_ack()
+    }
+
+    /// Read the acknowledgement of the last request.
+    fn read_ack(&self) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        loop {
+            // SAFETY: buf is valid for writes of buf.len() bytes.
+            let n = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let n = n as usize;
+            // An error message contains the header, followed by a 4 byte error code, followed by
+            // the header of the original request.
+            if n < NLMSG_HDR_SIZE + 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink response",
+                ));
+            }
+            let msg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+            let seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+            if msg_type != NLMSG_ERROR || seq != self.seq {
+                // Not the response we are looking for.
+                continue;
+            }
+            let code = i32::from_ne_bytes([
+                buf[NLMSG_HDR_SIZE],
+                buf[NLMSG_HDR_SIZE + 1],
+                buf[NLMSG_HDR_SIZE + 2],
+                buf[NLMSG_HDR_SIZE + 3],
+            ]);
+            return match code {
+                0 => Ok(()),
+                // Error codes are negative errno values.
+                code => Err(io::Error::from_raw_os_error(-code)),
+            };
+        }
+    }
+}
+
+/// Append a route attribute with the given type and value to a message. The value must be a
+/// multiple of 4 bytes long, as no padding is added.
+fn push_attr(msg: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    debug_assert_eq!(value.len() % 4, 0);
+    msg.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+    msg.extend_from_slice(&attr_type.to_ne_bytes());
+    msg.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tun::{Tun, DEFAULT_MTU};
+
+    /// Format an address like the kernel does in procfs.
+    fn proc_hex(addr: Ipv6Addr) -> String {
+        addr.octets().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check if the kernel has a route for the network on the given interface.
+    fn has_route(dst: Ipv6Addr, prefix_len: u8, interface: &str) -> bool {
+        std::fs::read_to_string("/proc/net/ipv6_route")
+            .unwrap()
+            .lines()
+            .any(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                fields[0] == proc_hex(dst)
+                    && fields[1] == format!("{:02x}", prefix_len)
+                    && fields[9] == interface
+            })
+    }
+
+    #[tokio::test]
+    async fn routes_follow_reachable_subnets() {
+        // Creating an interface and modifying routes requires CAP_NET_ADMIN.
+        let tun = match Tun::create("styx-routes", DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let mut routes = KernelRoutes::new(tun.name()).unwrap();
+        let subnet = Subnet::new([0x03, 1, 2, 3, 4, 5, 6, 7]);
+
+        routes.sync(&[subnet]).unwrap();
+        assert!(has_route(
+            subnet.network_address(),
+            SUBNET_PREFIX_LENGTH,
+            tun.name()
+        ));
+
+        routes.sync(&[]).unwrap();
+        assert!(!has_route(
+            subnet.network_address(),
+            SUBNET_PREFIX_LENGTH,
+            tun.name()
+        ));
+    }
+
+    #[tokio::test]
+    async fn configures_interface_idempotently() {
+        // Creating an interface and modifying addresses requires CAP_NET_ADMIN.
+        let tun = match Tun::create("styx-addr", DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let addr = Ipv6Addr::new(0x0301, 2, 3, 4, 5, 6, 7, 8);
+        let route = (Ipv6Addr::new(0x0200, 0, 0, 0, 0, 0, 0, 0), 7);
+
+        // Configuring an already configured interface, e.g. after a restart, is not an error.
+        for _ in 0..2 {
+            configure_interface(tun.name(), addr, SUBNET_PREFIX_LENGTH, route).unwrap();
+        }
+        let addresses = std::fs::read_to_string("/proc/net/if_inet6").unwrap();
+        let assigned: Vec<_> = addresses
+            .lines()
+            .filter(|line| line.ends_with(tun.name()))
+            .filter(|line| line.starts_with(&proc_hex(addr)))
+            .collect();
+        assert_eq!(assigned.len(), 1);
+        assert!(assigned[0].contains(" 40 "));
+        assert!(has_route(route.0, route.1, tun.name()));
+    }
+
+    #[tokio::test]
+    async fn tears_down_interface_on_drop() {
+        // Creating an interface and modifying addresses requires CAP_NET_ADMIN.
+        let tun = match Tun::create("styx-drop", DEFAULT_MTU) {
+            Ok(tun) => Arc::new(tun),
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let addr = Ipv6Addr::new(0x0301, 2, 3, 4, 5, 6, 7, 9);
+        let route = (Ipv6Addr::new(0x0200, 0, 0, 0, 0, 0, 0, 0), 7);
+        let is_up = || {
+            let flags = std::fs::read_to_string("/sys/class/net/styx-drop/flags").unwrap();
+            let flags = u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap();
+            flags & libc::IFF_UP as u32 != 0
+        };
+        let is_assigned = || {
+            std::fs::read_to_string("/proc/net/if_inet6")
+                .unwrap()
+                .lines()
+                .any(|line| line.starts_with(&proc_hex(addr)) && line.ends_with("styx-drop"))
+        };
+
+        let interface =
+            ConfiguredInterface::configure(tun.clone(), addr, SUBNET_PREFIX_LENGTH, route).unwrap();
+        assert!(is_up() && is_assigned() && has_route(route.0, route.1, tun.name()));
+
+        drop(interface);
+        assert!(!is_up());
+        assert!(!is_assigned());
+        assert!(!has_route(route.0, route.1, tun.name()));
+    }
+
+    #[tokio::test]
+    async fn replaces_address_of_configured_interface() {
+        // Creating an interface and modifying addresses requires CAP_NET_ADMIN.
+        let tun = match Tun::create("styx-rotate", DEFAULT_MTU) {
+            Ok(tun) => Arc::new(tun),
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let old = Ipv6Addr::new(0x0301, 2, 3, 4, 5, 6, 7, 10);
+        let new = Ipv6Addr::new(0x0301, 2, 3, 4, 5, 6, 7, 11);
+        let route = (Ipv6Addr::new(0x0200, 0, 0, 0, 0, 0, 0, 0), 7);
+        let is_assigned = |addr| {
+            std::fs::read_to_string("/proc/net/if_inet6")
+                .unwrap()
+                .lines()
+                .any(|line| line.starts_with(&proc_hex(addr)) && line.ends_with("styx-rotate"))
+        };
+
+        let mut interface =
+            ConfiguredInterface::configure(tun, old, SUBNET_PREFIX_LENGTH, route).unwrap();
+        interface.set_address(new).unwrap();
+        assert!(is_assigned(new));
+        assert!(!is_assigned(old));
+
+        // The new address is removed on teardown.
+        drop(interface);
+        assert!(!is_assigned(new));
+    }
+}
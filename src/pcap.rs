@@ -0,0 +1,189 @@
+//! Capture of overlay packets to a file in the pcap format, for debugging.
+//!
+//! Packets are written as raw IPv6 packets, without a link layer header, so tools like wireshark
+//! and tcpdump can read the file as is. The file is rotated once it reaches its maximum size, the
+//! previous file is kept with a `.1` suffix, so at most twice the maximum size is used on disk.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+/// Magic number at the start of a pcap file with microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// Version of the pcap format which is written.
+const PCAP_VERSION: (u16, u16) = (2, 4);
+
+/// Link type of raw IPv6 packets, without a link layer header.
+const LINKTYPE_IPV6: u32 = 229;
+
+/// Largest amount of bytes of a single packet which is captured.
+const SNAPLEN: u32 = 65535;
+
+/// Size of the header at the start of a pcap file.
+const FILE_HEADER_SIZE: u64 = 24;
+
+/// Size of the header in front of every packet in a pcap file.
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Default maximum size of a capture file, in bytes.
+pub const DEFAULT_MAX_CAPTURE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Writes packets to a pcap file, rotating it once it reaches its maximum size.
+pub struct PacketCapture {
+    /// Path of the current capture file.
+    path: PathBuf,
+    /// Size at which the file is rotated.
+    max_size: u64,
+    /// The open capture file.
+    file: Mutex<CaptureFile>,
+}
+
+/// An open capture file.
+struct CaptureFile {
+    /// The file packets are appended to.
+    file: File,
+    /// Amount of bytes written to the file.
+    size: u64,
+}
+
+impl PacketCapture {
+    /// Create a new capture file at the given path, replacing any existing file. The file is
+    /// rotated once writing a packet would make it larger than `max_size` bytes.
+    pub fn create(path: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let path = path.into();
+        let file = CaptureFile::create(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Capture a single IPv6 packet. Failures to write are logged, so capturing never affects
+    /// forwarding.
+    pub fn capture(&self, packet: &[u8]) {
+        let captured = &packet[..packet.len().min(SNAPLEN as usize)];
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + captured.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(captured);
+
+        let mut file = self.file.lock().unwrap();
+        if file.size + record.len() as u64 > self.max_size && file.size > FILE_HEADER_SIZE {
+            match self.rotate() {
+                Ok(new) => *file = new,
+                Err(e) => {
+                    warn!(
+                        "Failed to rotate capture file {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+        if let Err(e) = file.file.write_all(&record) {
+            warn!(
+                "Failed to write to capture file {}: {}",
+                self.path.display(),
+                e
+            );
+            return;
+        }
+        file.size += record.len() as u64;
+    }
+
+    /// Move the current capture file aside, replacing the previous one, and start a new one.
+    fn rotate(&self) -> io::Result<CaptureFile> {
+        let mut previous = OsString::from(self.path.as_os_str());
+        previous.push(".1");
+        std::fs::rename(&self.path, &previous)?;
+        info!("Rotated capture file {}", self.path.display());
+        CaptureFile::create(&self.path)
+    }
+}
+
+impl CaptureFile {
+    /// Create a new capture file, and write the file header.
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION.0.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION.1.to_le_bytes());
+        // Timezone offset and timestamp accuracy, which are always 0.
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_IPV6.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file,
+            size: FILE_HEADER_SIZE,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the packets from a pcap file, checking the file header.
+    fn read_packets(path: &Path) -> Vec<Vec<u8>> {
+        let data = std::fs::read(path).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), PCAP_MAGIC);
+        assert_eq!(u32_at(20), LINKTYPE_IPV6);
+        let mut packets = Vec::new();
+        let mut offset = FILE_HEADER_SIZE as usize;
+        while offset < data.len() {
+            let len = u32_at(offset + 8) as usize;
+            assert_eq!(u32_at(offset + 12) as usize, len);
+            offset += RECORD_HEADER_SIZE;
+            packets.push(data[offset..offset + len].to_vec());
+            offset += len;
+        }
+        packets
+    }
+
+    #[test]
+    fn captures_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("styx-pcap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.pcap");
+        let rotated = dir.join("capture.pcap.1");
+        let _ = std::fs::remove_file(&rotated);
+
+        // Room for the header and 2 packets of 100 bytes.
+        let record_size = (RECORD_HEADER_SIZE + 100) as u64;
+        let capture = PacketCapture::create(&path, FILE_HEADER_SIZE + 2 * record_size).unwrap();
+        capture.capture(&[1; 100]);
+        capture.capture(&[2; 100]);
+        assert_eq!(read_packets(&path), [vec![1; 100], vec![2; 100]]);
+        assert!(!rotated.exists());
+
+        capture.capture(&[3; 100]);
+        assert_eq!(read_packets(&rotated), [vec![1; 100], vec![2; 100]]);
+        assert_eq!(read_packets(&path), [vec![3; 100]]);
+
+        // A packet larger than the maximum size is still captured in a file of its own.
+        capture.capture(&[4; 300]);
+        assert_eq!(read_packets(&rotated), [vec![3; 100]]);
+        assert_eq!(read_packets(&path), [vec![4; 300]]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,15 @@
 use crate::crypto::ed25519::PublicKey;
-use std::net::SocketAddr;
+use crate::net::Cidr;
+use log::debug;
+use std::{
+    collections::HashSet,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use subtle::{Choice, ConstantTimeEq};
+
+/// Default amount of advertised addresses adopted per peer.
+pub const DEFAULT_MAX_ADDRS_PER_PEER: usize = 8;
 
 /// A remote client identified by a public key.
 pub struct Peer {
@@ -17,7 +27,305 @@ impl Peer {
     }
 
     /// Get a reference to the [`PublicKey`] associated with this peer.
+    #[cfg(test)]
     pub fn public_key(&self) -> &PublicKey {
         &self.public_key
     }
+
+    /// Get the known listening addresses of this peer.
+    pub fn listen_addrs(&self) -> &[SocketAddr] {
+        &self.listen_addrs
+    }
+
+    /// Adopt listening addresses advertised for this peer, e.g. by the peer itself or through
+    /// gossip. Addresses are only adopted if they are allowed by the given policy, and only as
+    /// long as the peer has less than the maximum amount of addresses allowed by the policy.
+    pub fn adopt_advertised_addrs(
+        &mut self,
+        advertised: impl IntoIterator<Item = SocketAddr>,
+        policy: &AddressPolicy,
+    ) {
+        for addr in advertised {
+            if self.listen_addrs.contains(&addr) {
+                continue;
+            }
+            if self.listen_addrs.len() >= policy.max_addrs_per_peer {
+                debug!(
+                    "Rejecting advertised address {}, peer has too many addresses",
+                    addr
+                );
+                continue;
+            }
+            if !policy.allows(&addr) {
+                debug!("Rejecting advertised address {}, denied by policy", addr);
+                continue;
+            }
+            self.listen_addrs.push(addr);
+        }
+    }
+}
+
+/// Peers are identified by their public key only, regardless of the addresses they listen on.
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.public_key.hash(state)
+    }
+}
+
+/// Policy deciding which advertised addresses of peers we are willing to connect to. Without
+/// this, a remote could make us connect to arbitrary hosts by advertising their address.
+///
+/// Loopback, unspecified and multicast addresses, as well as port 0, are always rejected.
+#[derive(Debug, Clone)]
+pub struct AddressPolicy {
+    /// Only allow addresses which are publicly routable.
+    pub public_only: bool,
+    /// If not empty, only allow addresses in one of these ranges.
+    pub allowlist: Vec<Cidr>,
+    /// Maximum amount of addresses adopted per peer.
+    pub max_addrs_per_peer: usize,
+}
+
+impl Default for AddressPolicy {
+    fn default() -> Self {
+        Self {
+            public_only: false,
+            allowlist: Vec::new(),
+            max_addrs_per_peer: DEFAULT_MAX_ADDRS_PER_PEER,
+        }
+    }
+}
+
+impl AddressPolicy {
+    /// Check if an advertised address is allowed by this policy.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        if addr.port() == 0 {
+            return false;
+        }
+        // Treat IPv4 mapped addresses as the IPv4 address they map to, so they can't be used to
+        // bypass the policy.
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(ip),
+            },
+            ip => ip,
+        };
+        if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+            return false;
+        }
+        if self.public_only && !is_public(&ip) {
+            return false;
+        }
+        self.allowlist.is_empty() || self.allowlist.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+/// Filter deciding which peers are allowed to connect to us, by their public key. As the overlay
+/// address of a peer is derived from its key, this also decides which subnets are reachable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyFilter {
+    /// If set, only these keys are allowed.
+    pub allowlist: Option<HashSet<PublicKey>>,
+    /// Keys which are never allowed, even if they are on the allowlist.
+    pub denylist: HashSet<PublicKey>,
+}
+
+impl KeyFilter {
+    /// Check if a peer with the given key is allowed. If it isn't, the reason is returned.
+    pub fn check(&self, key: &PublicKey) -> Result<(), &'static str> {
+        if contains_key(&self.denylist, key) {
+            return Err("public key is denied");
+        }
+        match self.allowlist {
+            Some(ref allowlist) if !contains_key(allowlist, key) => {
+                Err("public key is not on the allowlist")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check if a key is in a set, in constant time. Every key in the set is compared, rather than
+/// only the ones in the bucket the key hashes to, so the time this takes only depends on the size
+/// of the set.
+fn contains_key(keys: &HashSet<PublicKey>, key: &PublicKey) -> bool {
+    keys.iter()
+        .fold(Choice::from(0), |found, k| {
+            found | k.as_bytes().ct_eq(key.as_bytes())
+        })
+        .into()
+}
+
+/// Check if an address is publicly routable.
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // Shared address space, 100.64.0.0/10.
+        || (octets[0] == 100 && octets[1] & 0xC0 == 64)
+        // "This network", 0.0.0.0/8.
+        || octets[0] == 0)
+}
+
+fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // Only global unicast, 2000::/3, is publicly routable.
+    segments[0] & 0xE000 == 0x2000
+        // Documentation, 2001:db8::/32.
+        && !(segments[0] == 0x2001 && segments[1] == 0x0db8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+
+    fn addrs(raw: &[&str]) -> Vec<SocketAddr> {
+        raw.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn peers_are_identified_by_key() {
+        let key = SecretKey::from_bytes([1; 32]).public_key();
+        let mut peers = std::collections::HashSet::new();
+        peers.insert(Peer::new(key.clone(), addrs(&["1.1.1.1:9651"])));
+        peers.insert(Peer::new(
+            key,
+            addrs(&["1.1.1.2:9651", "[2a02:1802::1]:9651"]),
+        ));
+        assert_eq!(peers.len(), 1);
+
+        peers.insert(Peer::new(
+            SecretKey::from_bytes([2; 32]).public_key(),
+            addrs(&["1.1.1.1:9651"]),
+        ));
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[test]
+    fn finds_keys_in_set() {
+        let keys: HashSet<_> = (1..=16u8)
+            .map(|i| SecretKey::from_bytes([i; 32]).public_key())
+            .collect();
+        for i in 1..=16u8 {
+            assert!(contains_key(
+                &keys,
+                &SecretKey::from_bytes([i; 32]).public_key()
+            ));
+        }
+        let other = SecretKey::from_bytes([17; 32]).public_key();
+        assert!(!contains_key(&keys, &other));
+        assert!(!contains_key(&HashSet::new(), &other));
+    }
+
+    #[test]
+    fn filters_keys() {
+        let (a, b) = (
+            SecretKey::from_bytes([1; 32]).public_key(),
+            SecretKey::from_bytes([2; 32]).public_key(),
+        );
+        let mut filter = KeyFilter::default();
+        assert!(filter.check(&a).is_ok());
+
+        filter.denylist.insert(a.clone());
+        assert_eq!(filter.check(&a), Err("public key is denied"));
+        assert!(filter.check(&b).is_ok());
+
+        filter.allowlist = Some(HashSet::from([a.clone()]));
+        assert_eq!(filter.check(&a), Err("public key is denied"));
+        assert_eq!(filter.check(&b), Err("public key is not on the allowlist"));
+        filter.denylist.clear();
+        assert!(filter.check(&a).is_ok());
+    }
+
+    #[test]
+    fn filters_disallowed_advertised_addrs() {
+        let mut peer = Peer::new(SecretKey::from_bytes([1; 32]).public_key(), Vec::new());
+        let policy = AddressPolicy::default();
+
+        peer.adopt_advertised_addrs(
+            addrs(&[
+                "127.0.0.1:9651",
+                "[::1]:9651",
+                "0.0.0.0:9651",
+                "[ff02::1]:9651",
+                "[::ffff:127.0.0.1]:9651",
+                "192.168.1.1:0",
+                "192.168.1.1:9651",
+                "[2a02:1802::1]:9651",
+            ]),
+            &policy,
+        );
+
+        assert_eq!(
+            peer.listen_addrs(),
+            addrs(&["192.168.1.1:9651", "[2a02:1802::1]:9651"])
+        );
+    }
+
+    #[test]
+    fn restricts_to_public_and_allowlisted_addrs() {
+        let mut peer = Peer::new(SecretKey::from_bytes([1; 32]).public_key(), Vec::new());
+        let policy = AddressPolicy {
+            public_only: true,
+            allowlist: vec!["2a02::/16".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+
+        peer.adopt_advertised_addrs(
+            addrs(&[
+                // Allowlisted, but not public.
+                "10.1.1.1:9651",
+                "192.168.1.1:9651",
+                "[fd00::1]:9651",
+                // Public, but not allowlisted.
+                "1.1.1.1:9651",
+                "[2001:1::1]:9651",
+                "[2a02:1802::1]:9651",
+            ]),
+            &policy,
+        );
+
+        assert_eq!(peer.listen_addrs(), addrs(&["[2a02:1802::1]:9651"]));
+    }
+
+    #[test]
+    fn caps_addrs_per_peer() {
+        let mut peer = Peer::new(
+            SecretKey::from_bytes([1; 32]).public_key(),
+            addrs(&["1.1.1.1:9651"]),
+        );
+        let policy = AddressPolicy {
+            max_addrs_per_peer: 2,
+            ..Default::default()
+        };
+
+        peer.adopt_advertised_addrs(
+            addrs(&["1.1.1.1:9651", "1.1.1.2:9651", "1.1.1.3:9651"]),
+            &policy,
+        );
+
+        assert_eq!(
+            peer.listen_addrs(),
+            addrs(&["1.1.1.1:9651", "1.1.1.2:9651"])
+        );
+    }
 }
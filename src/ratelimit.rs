@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Time worth of tokens a [`TokenBucket`] can save up, which bounds the burst allowed after a
+/// quiet period.
+const BURST_DURATION: Duration = Duration::from_millis(100);
+
+/// Token bucket limiting the amount of bytes processed per second.
+///
+/// Tokens are added continuously at the configured rate, up to a capacity of
+/// [`BURST_DURATION`] worth of tokens. Taking more tokens than are available puts the bucket in
+/// debt, and the caller waits until the debt is paid off. This way items larger than the
+/// capacity still get through, while the average rate is respected.
+pub struct TokenBucket {
+    /// Tokens added per second.
+    rate: u64,
+    /// Maximum amount of tokens in the bucket.
+    capacity: f64,
+    /// Tokens currently in the bucket, negative if the bucket is in debt.
+    tokens: f64,
+    /// Last time tokens were added.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new, full [`TokenBucket`] allowing `rate` bytes per second.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `rate` is 0.
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "rate limit must be positive");
+        let capacity = rate as f64 * BURST_DURATION.as_secs_f64();
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens from the bucket. If this puts the bucket in debt, the time at which
+    /// the debt is paid off is returned, and the caller should hold off until then.
+    pub fn take(&mut self, amount: usize) -> Option<Instant> {
+        self.refill();
+        self.tokens -= amount as f64;
+        (self.tokens < 0.)
+            .then(|| self.last_refill + Duration::from_secs_f64(-self.tokens / self.rate as f64))
+    }
+
+    /// Take `amount` tokens from the bucket, but only if that doesn't put the bucket in debt.
+    /// Returns whether the tokens were taken.
+    pub fn try_take(&mut self, amount: usize) -> bool {
+        self.refill();
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+
+    /// Add the tokens accumulated since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_into_debt_once_burst_is_spent() {
+        // 100 bytes of burst.
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last_refill;
+        assert_eq!(bucket.take(100), None);
+
+        // 200 bytes at 1000 bytes per second.
+        let resume = bucket.take(200).unwrap();
+        let wait = resume - start;
+        assert!(
+            wait > Duration::from_millis(190) && wait < Duration::from_millis(210),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn try_take_never_goes_into_debt() {
+        // 10 tokens of burst.
+        let mut bucket = TokenBucket::new(100);
+        assert!(bucket.try_take(10));
+        assert!(!bucket.try_take(1));
+        assert!(bucket.tokens >= 0.);
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use crate::{crypto::ed25519::PublicKey, net::Subnet};
+
+/// How a [`Subnet`] can be reached from the local node.
+#[derive(Clone)]
+pub enum RouteKind {
+    /// We have a data connection to the node owning the subnet.
+    Direct,
+    /// The subnet is reachable through an intermediate peer, identified by its public key.
+    Learned(PublicKey),
+}
+
+/// Routes to subnets which are not directly connected, but reachable through another peer.
+pub struct RoutingTable {
+    /// Mapping of a remote subnet to the peer acting as next hop.
+    routes: HashMap<Subnet, PublicKey>,
+}
+
+impl RoutingTable {
+    /// Create a new, empty, [`RoutingTable`].
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Add a route to the given [`Subnet`] through the given next hop. If a route for the subnet
+    /// already exists, it is replaced, and the old next hop is returned.
+    pub fn insert(&mut self, subnet: Subnet, next_hop: PublicKey) -> Option<PublicKey> {
+        self.routes.insert(subnet, next_hop)
+    }
+
+    /// Remove the route to the given [`Subnet`], if any.
+    pub fn remove(&mut self, subnet: &Subnet) -> Option<PublicKey> {
+        self.routes.remove(subnet)
+    }
+
+    /// Remove all routes through the given next hop. Returns the amount of removed routes.
+    pub fn remove_next_hop(&mut self, next_hop: &PublicKey) -> usize {
+        let before = self.routes.len();
+        self.routes.retain(|_, hop| hop != next_hop);
+        before - self.routes.len()
+    }
+
+    /// Route everything which goes through the next hop `old` through `new` instead, e.g.
+    /// because the next hop rotated its identity. Returns the amount of changed routes.
+    pub fn replace_next_hop(&mut self, old: &PublicKey, new: &PublicKey) -> usize {
+        let mut replaced = 0;
+        for hop in self.routes.values_mut().filter(|hop| *hop == old) {
+            *hop = new.clone();
+            replaced += 1;
+        }
+        replaced
+    }
+
+    /// Get the next hop for the given [`Subnet`], if a route exists.
+    pub fn next_hop(&self, subnet: &Subnet) -> Option<&PublicKey> {
+        self.routes.get(subnet)
+    }
+
+    /// Iterate over all learned routes.
+    pub fn iter(&self) -> impl Iterator<Item = (&Subnet, &PublicKey)> {
+        self.routes.iter()
+    }
+}
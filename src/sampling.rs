@@ -0,0 +1,300 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    net::{Ipv6Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::net::Subnet;
+
+/// Size of a fixed IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// Offset of the next header field in an IPv6 header.
+const NEXT_HEADER_OFFSET: usize = 6;
+
+/// Offset of the source address in an IPv6 header.
+const SOURCE_OFFSET: usize = 8;
+
+/// Offset of the destination address in an IPv6 header.
+const DESTINATION_OFFSET: usize = 24;
+
+/// Size of a flow record on the wire.
+pub const FLOW_RECORD_WIRE_SIZE: usize = 29;
+
+/// Metadata of a single overlay packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketMeta {
+    /// Subnet of the sender of the packet.
+    pub src: Subnet,
+    /// Subnet of the receiver of the packet.
+    pub dst: Subnet,
+    /// Value of the next header field in the IPv6 header.
+    pub protocol: u8,
+    /// Total size of the packet, including the IPv6 header.
+    pub size: usize,
+}
+
+impl PacketMeta {
+    /// Extract the metadata of an IPv6 packet. Returns [`None`] if the packet is not a valid IPv6
+    /// packet.
+    pub fn from_ipv6(packet: &[u8]) -> Option<Self> {
+        if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+            return None;
+        }
+
+        let address = |offset: usize| {
+            let mut raw = [0; 16];
+            raw.copy_from_slice(&packet[offset..offset + 16]);
+            Subnet::from_address(Ipv6Addr::from(raw))
+        };
+
+        Some(Self {
+            src: address(SOURCE_OFFSET),
+            dst: address(DESTINATION_OFFSET),
+            protocol: packet[NEXT_HEADER_OFFSET],
+            size: packet.len(),
+        })
+    }
+}
+
+/// A sampled packet, as exported to a [`FlowSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowRecord {
+    /// Metadata of the sampled packet.
+    pub meta: PacketMeta,
+    /// Time the packet was sampled, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// The sampling rate in effect when the packet was sampled, so collectors can scale the
+    /// records up to an estimate of the total traffic.
+    pub rate: u32,
+}
+
+impl FlowRecord {
+    /// Encode the record in its compact binary form.
+    ///
+    /// On the wire, a record consists of:
+    /// - 8 byte source subnet
+    /// - 8 byte destination subnet
+    /// - 1 byte protocol
+    /// - 4 byte packet size
+    /// - 8 byte timestamp
+    ///
+    /// The sampling rate is not included, as it is fixed for an exporter.
+    pub fn to_bytes(self) -> [u8; FLOW_RECORD_WIRE_SIZE] {
+        let mut buf = [0; FLOW_RECORD_WIRE_SIZE];
+        buf[..8].copy_from_slice(&self.meta.src.network_address().octets()[..8]);
+        buf[8..16].copy_from_slice(&self.meta.dst.network_address().octets()[..8]);
+        buf[16] = self.meta.protocol;
+        buf[17..21].copy_from_slice(&(self.meta.size.min(u32::MAX as usize) as u32).to_be_bytes());
+        buf[21..].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf
+    }
+}
+
+impl fmt::Display for FlowRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} 1/{}",
+            self.timestamp,
+            self.meta.src.network_address(),
+            self.meta.dst.network_address(),
+            self.meta.protocol,
+            self.meta.size,
+            self.rate,
+        )
+    }
+}
+
+/// Destination of exported [`FlowRecord`]s.
+pub trait FlowSink {
+    /// Export a single record.
+    fn export(&mut self, record: &FlowRecord) -> io::Result<()>;
+}
+
+/// A [`FlowSink`] which writes records as lines of text, e.g. to a file.
+pub struct WriterSink<W> {
+    writer: W,
+}
+
+impl<W: Write> WriterSink<W> {
+    /// Create a new [`WriterSink`] writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl WriterSink<File> {
+    /// Create a [`WriterSink`] appending to the file at the given path.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(
+            File::options().create(true).append(true).open(path)?,
+        ))
+    }
+}
+
+impl<W: Write> FlowSink for WriterSink<W> {
+    fn export(&mut self, record: &FlowRecord) -> io::Result<()> {
+        writeln!(self.writer, "{}", record)
+    }
+}
+
+/// A [`FlowSink`] which sends every record in a single datagram to a collector.
+pub struct UdpSink {
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    /// Create a new [`UdpSink`] sending records to the given collector.
+    pub fn connect(collector: SocketAddr) -> io::Result<Self> {
+        let bind: SocketAddr = if collector.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(collector)?;
+        // Never block the forwarding path on the collector.
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+}
+
+impl FlowSink for UdpSink {
+    fn export(&mut self, record: &FlowRecord) -> io::Result<()> {
+        self.socket.send(&record.to_bytes()).map(|_| ())
+    }
+}
+
+/// Samples 1 in every N forwarded packets, and exports a [`FlowRecord`] for the sampled packets.
+pub struct Sampler {
+    /// Export 1 in every `rate` packets.
+    rate: u32,
+    /// Amount of packets seen.
+    seen: AtomicU64,
+    /// Destination of the records.
+    sink: Mutex<Box<dyn FlowSink + Send>>,
+}
+
+impl Sampler {
+    /// Create a new [`Sampler`] exporting 1 in every `rate` packets to the given sink. A rate of
+    /// 0 is treated as 1.
+    pub fn new(rate: u32, sink: Box<dyn FlowSink + Send>) -> Self {
+        Self {
+            rate: rate.max(1),
+            seen: AtomicU64::new(0),
+            sink: Mutex::new(sink),
+        }
+    }
+
+    /// Account for a forwarded IPv6 packet, exporting a record if it is sampled. Packets which
+    /// are not valid IPv6 packets are ignored.
+    pub fn sample(&self, packet: &[u8]) {
+        if !self
+            .seen
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.rate as u64)
+        {
+            return;
+        }
+
+        let meta = match PacketMeta::from_ipv6(packet) {
+            Some(meta) => meta,
+            None => return,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let record = FlowRecord {
+            meta,
+            timestamp,
+            rate: self.rate,
+        };
+
+        if let Err(e) = self.sink.lock().unwrap().export(&record) {
+            warn!("Failed to export flow record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// A writer which can still be inspected after being moved into a sampler.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn ipv6_packet(src: Ipv6Addr, dst: Ipv6Addr, payload: usize) -> Vec<u8> {
+        let mut packet = vec![0; IPV6_HEADER_SIZE + payload];
+        packet[0] = 6 << 4;
+        packet[4..6].copy_from_slice(&(payload as u16).to_be_bytes());
+        // UDP
+        packet[NEXT_HEADER_OFFSET] = 17;
+        packet[SOURCE_OFFSET..SOURCE_OFFSET + 16].copy_from_slice(&src.octets());
+        packet[DESTINATION_OFFSET..DESTINATION_OFFSET + 16].copy_from_slice(&dst.octets());
+        packet
+    }
+
+    #[test]
+    fn exports_every_packet_at_full_rate() {
+        let buf = SharedBuf::default();
+        let sampler = Sampler::new(1, Box::new(WriterSink::new(buf.clone())));
+
+        let src: Ipv6Addr = "200:1:2:3::1".parse().unwrap();
+        let dst: Ipv6Addr = "300:4:5:6::1".parse().unwrap();
+        for i in 0..10 {
+            sampler.sample(&ipv6_packet(src, dst, i * 10));
+        }
+        // Not an IPv6 packet, this is never exported.
+        sampler.sample(&[0x45; 20]);
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<_> = output.lines().collect();
+        assert_eq!(records.len(), 10);
+        for (i, record) in records.iter().enumerate() {
+            let fields: Vec<_> = record.split(' ').collect();
+            assert_eq!(fields[1], "200:1:2:3::");
+            assert_eq!(fields[2], "300:4:5:6::");
+            assert_eq!(fields[3], "17");
+            assert_eq!(fields[4], (IPV6_HEADER_SIZE + i * 10).to_string());
+            assert_eq!(fields[5], "1/1");
+        }
+    }
+
+    #[test]
+    fn exports_one_in_n_packets() {
+        let buf = SharedBuf::default();
+        let sampler = Sampler::new(4, Box::new(WriterSink::new(buf.clone())));
+
+        let addr: Ipv6Addr = "200::1".parse().unwrap();
+        for _ in 0..10 {
+            sampler.sample(&ipv6_packet(addr, addr, 0));
+        }
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        // Packets 0, 4 and 8 are sampled.
+        assert_eq!(output.lines().count(), 3);
+    }
+}
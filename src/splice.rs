@@ -0,0 +1,244 @@
+//! Forwarding of bytes between 2 TCP connections.
+//!
+//! On Linux, bytes are moved in the kernel with `splice(2)` through a pipe, so they are never
+//! copied to userspace. Other platforms fall back to a buffered copy. This only applies when the
+//! bytes are forwarded as is: data connections are encrypted per hop, so relaying packets
+//! between peers which terminate their own sessions still needs to go through userspace.
+//!
+//! Nothing in the node forwards raw bytes between connections yet, so the module is only built
+//! for its tests and benchmark.
+
+use std::io;
+
+use tokio::net::TcpStream;
+
+/// Forward bytes in both directions between `a` and `b`, until both directions are closed. Once
+/// one side closes its write half, the write half of the other side is shut down as well. Returns
+/// the amount of bytes forwarded from `a` to `b`, and from `b` to `a` respectively.
+#[cfg(target_os = "linux")]
+pub async fn forward(a: &mut TcpStream, b: &mut TcpStream) -> io::Result<(u64, u64)> {
+    tokio::try_join!(linux::splice_copy(a, b), linux::splice_copy(b, a))
+}
+
+/// Forward bytes in both directions between `a` and `b`, until both directions are closed. Once
+/// one side closes its write half, the write half of the other side is shut down as well. Returns
+/// the amount of bytes forwarded from `a` to `b`, and from `b` to `a` respectively.
+#[cfg(not(target_os = "linux"))]
+pub async fn forward(a: &mut TcpStream, b: &mut TcpStream) -> io::Result<(u64, u64)> {
+    tokio::io::copy_bidirectional(a, b).await
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    use socket2::SockRef;
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    /// Maximum amount of bytes moved by a single splice. This is the default capacity of a pipe,
+    /// so a splice into an empty pipe never blocks on the pipe.
+    const PIPE_SIZE: usize = 64 * 1024;
+
+    /// Move bytes from `src` to `dst` through a pipe, until `src` is closed. The write half of
+    /// `dst` is shut down afterwards.
+    pub(super) async fn splice_copy(src: &TcpStream, dst: &TcpStream) -> io::Result<u64> {
+        let (pipe_r, pipe_w) = pipe()?;
+        let mut total = 0;
+        loop {
+            // The pipe is always drained before reading again, so a splice into it only blocks
+            // if the socket has no data.
+            let n = loop {
+                src.readable().await?;
+                match src.try_io(Interest::READABLE, || {
+                    splice(src.as_raw_fd(), pipe_w.as_raw_fd(), PIPE_SIZE)
+                }) {
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            if n == 0 {
+                break;
+            }
+
+            let mut pending = n;
+            while pending > 0 {
+                dst.writable().await?;
+                match dst.try_io(Interest::WRITABLE, || {
+                    splice(pipe_r.as_raw_fd(), dst.as_raw_fd(), pending)
+                }) {
+                    Ok(n) => pending -= n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            total += n as u64;
+        }
+
+        match SockRef::from(dst).shutdown(Shutdown::Write) {
+            // The peer might have closed the connection entirely already.
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(total),
+        }
+    }
+
+    /// Move up to `len` bytes from `from` to `to` without blocking. Either side must be a pipe.
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: no offsets are passed, which is required as sockets and pipes aren't seekable.
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Create a non blocking pipe, returning the read and write end.
+    fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+        let mut fds = [0; 2];
+        // SAFETY: fds is valid for writes of 2 file descriptors.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 returned 2 new, valid, file descriptors which are not owned by anything
+        // else.
+        Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Create a connected pair of TCP streams.
+    async fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, server) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    /// Connect 2 clients through a forwarder, returning both clients and the forwarding task.
+    async fn forwarded_pair(
+        forward: impl FnOnce(TcpStream, TcpStream) -> tokio::task::JoinHandle<io::Result<(u64, u64)>>,
+    ) -> (
+        TcpStream,
+        TcpStream,
+        tokio::task::JoinHandle<io::Result<(u64, u64)>>,
+    ) {
+        let (client_a, relay_a) = stream_pair().await;
+        let (client_b, relay_b) = stream_pair().await;
+        (client_a, client_b, forward(relay_a, relay_b))
+    }
+
+    #[tokio::test]
+    async fn forwards_both_directions() {
+        let (mut a, mut b, task) = forwarded_pair(|mut x, mut y| {
+            tokio::spawn(async move { forward(&mut x, &mut y).await })
+        })
+        .await;
+
+        let data: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+        let (mut a_read, mut a_write) = a.split();
+        let (mut b_read, mut b_write) = b.split();
+        let (_, _, from_a, from_b) = tokio::join!(
+            async {
+                a_write.write_all(&data).await.unwrap();
+                a_write.shutdown().await.unwrap();
+            },
+            async {
+                b_write.write_all(b"pong").await.unwrap();
+                b_write.shutdown().await.unwrap();
+            },
+            async {
+                let mut buf = Vec::new();
+                b_read.read_to_end(&mut buf).await.unwrap();
+                buf
+            },
+            async {
+                let mut buf = Vec::new();
+                a_read.read_to_end(&mut buf).await.unwrap();
+                buf
+            },
+        );
+        assert_eq!(from_a, data);
+        assert_eq!(from_b, b"pong");
+        assert_eq!(
+            task.await.unwrap().unwrap(),
+            (data.len() as u64, "pong".len() as u64)
+        );
+    }
+
+    /// Measure the throughput of forwarding through [`forward`], and through a buffered copy.
+    /// The results are logged, run with
+    /// `RUST_LOG=info cargo test --release splice -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_forward() {
+        const TOTAL: usize = 2 << 30;
+
+        let _ = pretty_env_logger::try_init();
+
+        async fn run(
+            forward: impl FnOnce(
+                TcpStream,
+                TcpStream,
+            ) -> tokio::task::JoinHandle<io::Result<(u64, u64)>>,
+        ) -> Duration {
+            let (mut a, mut b, task) = forwarded_pair(forward).await;
+            let chunk = vec![0; 1 << 16];
+            let start = Instant::now();
+            let writer = tokio::spawn(async move {
+                let mut sent = 0;
+                while sent < TOTAL {
+                    a.write_all(&chunk).await.unwrap();
+                    sent += chunk.len();
+                }
+                a.shutdown().await.unwrap();
+            });
+            let mut buf = vec![0; 1 << 16];
+            while b.read(&mut buf).await.unwrap() != 0 {}
+            let elapsed = start.elapsed();
+            writer.await.unwrap();
+            drop(b);
+            task.await.unwrap().unwrap();
+            elapsed
+        }
+
+        let report = |name: &str, elapsed: Duration| {
+            info!(
+                "{}: {:.2} GiB/s",
+                name,
+                TOTAL as f64 / (1u64 << 30) as f64 / elapsed.as_secs_f64()
+            )
+        };
+        report(
+            "forward",
+            run(|mut x, mut y| tokio::spawn(async move { forward(&mut x, &mut y).await })).await,
+        );
+        report(
+            "buffered",
+            run(|mut x, mut y| {
+                tokio::spawn(async move { tokio::io::copy_bidirectional(&mut x, &mut y).await })
+            })
+            .await,
+        );
+    }
+}
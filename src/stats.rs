@@ -0,0 +1,407 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// A gauge tracking the amount of items in a queue, as well as the highest amount of items seen
+/// since the last reset.
+#[derive(Default)]
+pub struct QueueGauge {
+    /// Current amount of items in the queue.
+    current: AtomicUsize,
+    /// High-water mark of the queue.
+    peak: AtomicUsize,
+}
+
+impl QueueGauge {
+    /// Create a new, empty, [`QueueGauge`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that an item entered the queue.
+    pub fn enqueued(&self) {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+    }
+
+    /// Record that an item left the queue.
+    pub fn dequeued(&self) {
+        // Saturate at 0 rather than wrapping around, in case a reset raced with an item leaving
+        // the queue.
+        let _ = self
+            .current
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+    }
+
+    /// The current amount of items in the queue.
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// The highest amount of items in the queue since the last reset.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Reset the high-water mark to the current depth of the queue.
+    pub fn reset_peak(&self) {
+        self.peak.store(self.current(), Ordering::Relaxed);
+    }
+}
+
+/// Queue depths of a single connection.
+#[derive(Default)]
+pub struct ConnectionQueues {
+    /// Items waiting to be sent to the remote.
+    pub send: QueueGauge,
+    /// Items received from the remote, waiting to be processed.
+    pub recv: QueueGauge,
+}
+
+/// A snapshot of the queue depths of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepths {
+    /// Current depth of the send queue.
+    pub send: usize,
+    /// Peak depth of the send queue since the last reset.
+    pub send_peak: usize,
+    /// Current depth of the receive queue.
+    pub recv: usize,
+    /// Peak depth of the receive queue since the last reset.
+    pub recv_peak: usize,
+}
+
+impl ConnectionQueues {
+    /// Take a snapshot of the current queue depths.
+    pub fn snapshot(&self) -> QueueDepths {
+        QueueDepths {
+            send: self.send.current(),
+            send_peak: self.send.peak(),
+            recv: self.recv.current(),
+            recv_peak: self.recv.peak(),
+        }
+    }
+
+    /// Reset the high-water marks of both queues.
+    pub fn reset(&self) {
+        self.send.reset_peak();
+        self.recv.reset_peak();
+    }
+}
+
+/// Amount of overlay traffic sent to and received from peers.
+#[derive(Default)]
+pub struct TrafficCounters {
+    /// Total amount of bytes sent on data connections.
+    bytes_tx: AtomicUsize,
+    /// Total amount of bytes received on data connections.
+    bytes_rx: AtomicUsize,
+}
+
+impl TrafficCounters {
+    /// Record that a packet of the given size was sent to a peer.
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_tx.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that a packet of the given size was received from a peer.
+    pub fn received(&self, bytes: usize) {
+        self.bytes_rx.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Total amount of bytes sent.
+    pub fn bytes_tx(&self) -> usize {
+        self.bytes_tx.load(Ordering::Relaxed)
+    }
+
+    /// Total amount of bytes received.
+    pub fn bytes_rx(&self) -> usize {
+        self.bytes_rx.load(Ordering::Relaxed)
+    }
+}
+
+/// Amount of overlay traffic sent to and received from a single peer.
+pub struct PeerTraffic {
+    /// Amount of bytes sent to the peer.
+    bytes_tx: AtomicU64,
+    /// Amount of bytes received from the peer.
+    bytes_rx: AtomicU64,
+    /// Amount of packets sent to the peer.
+    packets_tx: AtomicU64,
+    /// Amount of packets received from the peer.
+    packets_rx: AtomicU64,
+    /// Milliseconds between `created` and the last packet sent or received, plus 1. This is 0
+    /// if there was no traffic yet.
+    last_activity: AtomicU64,
+    /// Time the counters were created, which `last_activity` is relative to.
+    created: Instant,
+}
+
+/// A snapshot of the traffic of a single peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTrafficStats {
+    /// Amount of bytes sent to the peer since the last reset.
+    pub bytes_tx: u64,
+    /// Amount of bytes received from the peer since the last reset.
+    pub bytes_rx: u64,
+    /// Amount of packets sent to the peer since the last reset.
+    pub packets_tx: u64,
+    /// Amount of packets received from the peer since the last reset.
+    pub packets_rx: u64,
+    /// Time a packet was last sent to or received from the peer, if ever. This is not affected
+    /// by resets.
+    pub last_activity: Option<Instant>,
+}
+
+impl Default for PeerTraffic {
+    fn default() -> Self {
+        Self {
+            bytes_tx: AtomicU64::new(0),
+            bytes_rx: AtomicU64::new(0),
+            packets_tx: AtomicU64::new(0),
+            packets_rx: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            created: Instant::now(),
+        }
+    }
+}
+
+impl PeerTraffic {
+    /// Record that a packet of the given size was sent to the peer.
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_tx.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_tx.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Record that a packet of the given size was received from the peer.
+    pub fn received(&self, bytes: usize) {
+        self.bytes_rx.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_rx.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Take a snapshot of the current counters.
+    pub fn snapshot(&self) -> PeerTrafficStats {
+        let last_activity = match self.last_activity.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(self.created + Duration::from_millis(millis - 1)),
+        };
+        PeerTrafficStats {
+            bytes_tx: self.bytes_tx.load(Ordering::Relaxed),
+            bytes_rx: self.bytes_rx.load(Ordering::Relaxed),
+            packets_tx: self.packets_tx.load(Ordering::Relaxed),
+            packets_rx: self.packets_rx.load(Ordering::Relaxed),
+            last_activity,
+        }
+    }
+
+    /// Reset all counters to 0. The time of the last activity is kept.
+    pub fn reset(&self) {
+        self.bytes_tx.store(0, Ordering::Relaxed);
+        self.bytes_rx.store(0, Ordering::Relaxed);
+        self.packets_tx.store(0, Ordering::Relaxed);
+        self.packets_rx.store(0, Ordering::Relaxed);
+    }
+
+    /// Record that there was traffic right now.
+    fn touch(&self) {
+        let millis = self.created.elapsed().as_millis() as u64 + 1;
+        self.last_activity.fetch_max(millis, Ordering::Relaxed);
+    }
+}
+
+/// The time of the last activity on a connection, which is updated by the task driving the
+/// connection, and can be read from anywhere without locking.
+pub struct LastActivity {
+    /// Milliseconds between `created` and the last activity.
+    millis: AtomicU64,
+    /// Time the tracker was created, which counts as activity.
+    created: Instant,
+}
+
+impl Default for LastActivity {
+    fn default() -> Self {
+        Self {
+            millis: AtomicU64::new(0),
+            created: Instant::now(),
+        }
+    }
+}
+
+impl LastActivity {
+    /// Create a new [`LastActivity`], with activity right now.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that there was activity right now.
+    pub fn touch(&self) {
+        let millis = self.created.elapsed().as_millis() as u64;
+        self.millis.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    /// The time since the last activity.
+    pub fn idle(&self) -> Duration {
+        self.created
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.millis.load(Ordering::Relaxed)))
+    }
+}
+
+/// Amount of round trip times kept per peer in a [`RttWindow`].
+pub const RTT_WINDOW_SIZE: usize = 32;
+
+/// The most recent round trip times measured to a peer. Only the last [`RTT_WINDOW_SIZE`]
+/// measurements are kept, so the statistics follow changes of the link, and the memory used per
+/// peer is bounded.
+#[derive(Debug, Clone, Default)]
+pub struct RttWindow {
+    /// Measurements from oldest to newest.
+    samples: VecDeque<Duration>,
+}
+
+/// Statistics over the round trip times in a [`RttWindow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    /// The most recent round trip time.
+    pub last: Duration,
+    /// Lowest round trip time in the window.
+    pub min: Duration,
+    /// Highest round trip time in the window.
+    pub max: Duration,
+    /// Average round trip time in the window.
+    pub mean: Duration,
+    /// Average difference between consecutive round trip times in the window, 0 if there is only
+    /// one.
+    pub jitter: Duration,
+}
+
+impl RttWindow {
+    /// Record a new round trip time, forgetting the oldest one if the window is full.
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == RTT_WINDOW_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// Compute the statistics over the window, if any round trip time was recorded.
+    pub fn stats(&self) -> Option<RttStats> {
+        let last = *self.samples.back()?;
+        let count = self.samples.len() as u32;
+        let deltas = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .map(|(a, b)| a.abs_diff(*b));
+        Some(RttStats {
+            last,
+            // The window is not empty, so there is a minimum and a maximum.
+            min: *self.samples.iter().min().unwrap(),
+            max: *self.samples.iter().max().unwrap(),
+            mean: self.samples.iter().sum::<Duration>() / count,
+            jitter: deltas.sum::<Duration>() / (count - 1).max(1),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_depth_and_peak() {
+        let queues = ConnectionQueues::default();
+        for _ in 0..5 {
+            queues.send.enqueued();
+        }
+        for _ in 0..3 {
+            queues.send.dequeued();
+        }
+        queues.recv.enqueued();
+
+        assert_eq!(
+            queues.snapshot(),
+            QueueDepths {
+                send: 2,
+                send_peak: 5,
+                recv: 1,
+                recv_peak: 1,
+            }
+        );
+
+        queues.reset();
+        assert_eq!(queues.send.peak(), 2);
+        assert_eq!(queues.recv.peak(), 1);
+    }
+
+    #[test]
+    fn counts_peer_traffic() {
+        let traffic = PeerTraffic::default();
+        assert_eq!(traffic.snapshot().last_activity, None);
+
+        let before = Instant::now();
+        traffic.sent(100);
+        traffic.sent(50);
+        traffic.received(1000);
+        let stats = traffic.snapshot();
+        assert_eq!(
+            (
+                stats.bytes_tx,
+                stats.bytes_rx,
+                stats.packets_tx,
+                stats.packets_rx
+            ),
+            (150, 1000, 2, 1)
+        );
+        let last_activity = stats.last_activity.unwrap();
+        // The timestamp is truncated to milliseconds.
+        assert!(last_activity + Duration::from_millis(1) >= before);
+        assert!(last_activity <= Instant::now());
+
+        traffic.reset();
+        let stats = traffic.snapshot();
+        assert_eq!((stats.bytes_tx, stats.packets_rx), (0, 0));
+        assert_eq!(stats.last_activity, Some(last_activity));
+    }
+
+    #[test]
+    fn computes_rtt_stats_over_window() {
+        let mut window = RttWindow::default();
+        assert_eq!(window.stats(), None);
+
+        let ms = Duration::from_millis;
+        window.record(ms(10));
+        assert_eq!(
+            window.stats(),
+            Some(RttStats {
+                last: ms(10),
+                min: ms(10),
+                max: ms(10),
+                mean: ms(10),
+                jitter: ms(0),
+            })
+        );
+        for rtt in [20, 10, 40] {
+            window.record(ms(rtt));
+        }
+        assert_eq!(
+            window.stats(),
+            Some(RttStats {
+                last: ms(40),
+                min: ms(10),
+                max: ms(40),
+                mean: ms(20),
+                jitter: ms(50) / 3,
+            })
+        );
+
+        // Old measurements fall out of the window.
+        for _ in 0..RTT_WINDOW_SIZE {
+            window.record(ms(5));
+        }
+        assert_eq!(window.stats().unwrap().max, ms(5));
+        assert_eq!(window.stats().unwrap().jitter, ms(0));
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::crypto::{
+    self,
+    session::{Session, TAG_SIZE},
+};
+use crate::data::{DataCodec, EncryptedDataCodec};
+
+/// Size of the sequence number in front of every datagram.
+const SEQ_SIZE: usize = 8;
+
+/// Largest payload of a UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// A data connection over TCP, on which an encrypted session is established.
+pub type DataStream = Framed<TcpStream, EncryptedDataCodec>;
+
+/// The underlay transport used for data connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportKind {
+    /// Packets are framed on a TCP stream. Packets are never lost, but a lost segment holds up
+    /// all packets behind it.
+    #[default]
+    Tcp,
+    /// Every packet is sent in its own UDP datagram, see [`UdpTransport`].
+    Udp,
+}
+
+/// An underlay transport which carries overlay packets between 2 peers.
+pub trait PacketTransport {
+    /// Send a single packet.
+    fn send_packet(&mut self, packet: Bytes) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Receive the next packet. Returns [`None`] once the remote closed the transport. This is
+    /// cancel safe, so it can be used in a `select!` loop.
+    fn recv_packet(&mut self) -> impl Future<Output = Option<io::Result<BytesMut>>> + Send;
+
+    /// Close the transport, after all sent packets are flushed.
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// A data connection over UDP.
+///
+/// Every datagram carries a single packet, encrypted with the session established on the TCP
+/// connection the transport was negotiated on, prefixed with its sequence number. Datagrams
+/// which are lost or reordered don't affect other packets, which avoids the head-of-line
+/// blocking of TCP. Datagrams which can't be decrypted are dropped, as are datagrams which were
+/// already received, or which are too far behind the latest one to tell.
+///
+/// There is no connection state, so the transport never sees the remote close it. It is closed
+/// once it is idle, like any other data connection.
+pub struct UdpTransport {
+    /// Socket connected to the remote.
+    socket: UdpSocket,
+    /// Keys of the connection.
+    session: Session,
+    /// Largest packet which is accepted.
+    max_packet_size: usize,
+    /// Counter of dropped datagrams which were replayed.
+    replayed: Arc<AtomicU64>,
+}
+
+impl UdpTransport {
+    /// Switch the data connection `con` to UDP, once the session is established on it. Both
+    /// sides bind a UDP socket on the address the TCP connection uses, and exchange the port
+    /// over the TCP connection, which is no longer needed afterwards. Replayed datagrams are
+    /// counted in `replayed`. The maximum packet size is capped so every packet fits in a
+    /// single datagram.
+    pub async fn negotiate(
+        con: &mut TcpStream,
+        session: Session,
+        max_packet_size: usize,
+        replayed: Arc<AtomicU64>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(con.local_addr()?.ip(), 0)).await?;
+        con.write_u16(socket.local_addr()?.port()).await?;
+        let port = con.read_u16().await?;
+        socket
+            .connect(SocketAddr::new(con.peer_addr()?.ip(), port))
+            .await?;
+        Ok(Self {
+            socket,
+            session,
+            max_packet_size: max_packet_size.min(MAX_DATAGRAM_SIZE - SEQ_SIZE - TAG_SIZE),
+            replayed,
+        })
+    }
+}
+
+impl PacketTransport for UdpTransport {
+    async fn send_packet(&mut self, packet: Bytes) -> io::Result<()> {
+        if packet.len() > self.max_packet_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet exceeds maximum packet size",
+            ));
+        }
+        let mut datagram = BytesMut::with_capacity(SEQ_SIZE + packet.len() + TAG_SIZE);
+        datagram.put_u64(0);
+        datagram.extend_from_slice(&packet);
+        // Once the nonces run out the transport can't be used anymore, a new session is needed.
+        let (seq, tag) = self
+            .session
+            .seal_datagram(&mut datagram[SEQ_SIZE..])
+            .map_err(io::Error::other)?;
+        datagram[..SEQ_SIZE].copy_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(&tag);
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    async fn recv_packet(&mut self) -> Option<io::Result<BytesMut>> {
+        let mut buf = BytesMut::zeroed(SEQ_SIZE + self.max_packet_size + TAG_SIZE);
+        loop {
+            let n = match self.socket.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => return Some(Err(e)),
+            };
+            if n < SEQ_SIZE + TAG_SIZE {
+                debug!("Dropping truncated datagram of {} bytes", n);
+                continue;
+            }
+            let mut packet = BytesMut::from(&buf[SEQ_SIZE..n]);
+            let tag = packet.split_off(packet.len() - TAG_SIZE);
+            // Can't fail, the datagram is long enough to hold a sequence number and a tag.
+            let seq = u64::from_be_bytes(buf[..SEQ_SIZE].try_into().unwrap());
+            let tag: &[u8; TAG_SIZE] = tag[..].try_into().unwrap();
+            match self.session.open_datagram(seq, &mut packet, tag) {
+                Ok(()) => return Some(Ok(packet)),
+                Err(e @ crypto::Error::Replayed) => {
+                    self.replayed.fetch_add(1, Ordering::Relaxed);
+                    debug!("Dropping datagram {}: {}", seq, e);
+                }
+                Err(e) => debug!("Dropping datagram {}: {}", seq, e),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        // Datagrams are sent right away, so there is nothing to flush.
+        Ok(())
+    }
+}
+
+/// A data connection over any of the supported transports.
+pub enum DataTransport {
+    /// Packets are framed on a TCP stream.
+    Tcp(PeerDataChannel<TcpStream, EncryptedDataCodec>),
+    /// Packets are sent as UDP datagrams.
+    Udp(UdpTransport),
+}
+
+impl From<DataStream> for DataTransport {
+    fn from(stream: DataStream) -> Self {
+        DataTransport::Tcp(PeerDataChannel::from_framed(stream))
+    }
+}
+
+impl From<UdpTransport> for DataTransport {
+    fn from(transport: UdpTransport) -> Self {
+        DataTransport::Udp(transport)
+    }
+}
+
+impl DataTransport {
+    /// Largest packet which can be sent on the transport.
+    pub fn max_packet_size(&self) -> usize {
+        match self {
+            DataTransport::Tcp(channel) => channel.codec().max_packet_size(),
+            DataTransport::Udp(transport) => transport.max_packet_size,
+        }
+    }
+}
+
+impl PacketTransport for DataTransport {
+    async fn send_packet(&mut self, packet: Bytes) -> io::Result<()> {
+        match self {
+            DataTransport::Tcp(channel) => channel.send_packet(packet).await,
+            DataTransport::Udp(transport) => transport.send_packet(packet).await,
+        }
+    }
+
+    async fn recv_packet(&mut self) -> Option<io::Result<BytesMut>> {
+        match self {
+            DataTransport::Tcp(channel) => channel.recv_packet().await,
+            DataTransport::Udp(transport) => transport.recv_packet().await,
+        }
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        match self {
+            DataTransport::Tcp(channel) => PacketTransport::close(channel).await,
+            DataTransport::Udp(transport) => transport.close().await,
+        }
+    }
+}
+
+/// A typed handle to a framed data connection with a peer. Data connections over TCP are
+/// carried by a [`PeerDataChannel`] on a [`DataStream`], see [`DataTransport::Tcp`].
+///
+/// Packets received from the peer are yielded by the [`Stream`] implementation, which ends once
+/// the peer closes the connection, or once a packet can't be decoded. The error which ended the
+/// stream, if any, is kept, see [`PeerDataChannel::take_error`]. Packets are sent to the peer with
+/// the [`Sink`] implementation. Sent packets are buffered up to the backpressure boundary of the
+/// underlying [`Framed`], after which the sink is only ready once the buffer is flushed, so a
+/// peer which reads slowly slows down whoever sends to it.
+pub struct PeerDataChannel<T, C = DataCodec> {
+    /// The framed connection.
+    inner: Framed<T, C>,
+    /// Error which ended the stream of received packets.
+    error: Option<io::Error>,
+}
+
+impl<T: AsyncRead + AsyncWrite> PeerDataChannel<T> {
+    /// Create a new [`PeerDataChannel`] on an unencrypted connection, which frames packets with
+    /// a [`DataCodec`] accepting packets up to the given size.
+    #[cfg(test)]
+    pub fn new(io: T, max_packet_size: usize) -> Self {
+        Self::from_framed(Framed::new(io, DataCodec::new(max_packet_size)))
+    }
+}
+
+impl<T, C> PeerDataChannel<T, C> {
+    /// Create a new [`PeerDataChannel`] on a connection which is already framed, such as a
+    /// [`DataStream`].
+    pub fn from_framed(inner: Framed<T, C>) -> Self {
+        Self { inner, error: None }
+    }
+
+    /// Take the error which ended the stream of received packets, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Codec which frames the packets.
+    pub fn codec(&self) -> &C {
+        self.inner.codec()
+    }
+
+    /// Get back the framed connection.
+    #[cfg(test)]
+    pub fn into_inner(self) -> Framed<T, C> {
+        self.inner
+    }
+}
+
+impl<T, C> Stream for PeerDataChannel<T, C>
+where
+    T: AsyncRead + Unpin,
+    C: Decoder<Item = BytesMut, Error = io::Error> + Unpin,
+{
+    type Item = BytesMut;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.error.is_some() {
+            return Poll::Ready(None);
+        }
+        match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Ok(packet)) => Poll::Ready(Some(packet)),
+            Some(Err(e)) => {
+                debug!("Closing peer data channel: {}", e);
+                self.error = Some(e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl<T, C> Sink<BytesMut> for PeerDataChannel<T, C>
+where
+    T: AsyncWrite + Unpin,
+    C: Encoder<Bytes, Error = io::Error> + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: BytesMut) -> io::Result<()> {
+        self.inner.start_send_unpin(packet.freeze())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl<T, C> PacketTransport for PeerDataChannel<T, C>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+    C: Decoder<Item = BytesMut, Error = io::Error>
+        + Encoder<Bytes, Error = io::Error>
+        + Unpin
+        + Send,
+{
+    async fn send_packet(&mut self, packet: Bytes) -> io::Result<()> {
+        self.inner.send(packet).await
+    }
+
+    async fn recv_packet(&mut self) -> Option<io::Result<BytesMut>> {
+        match self.next().await {
+            Some(packet) => Some(Ok(packet)),
+            None => self.take_error().map(Err),
+        }
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        SinkExt::close(&mut self.inner).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::SecretKey;
+    use crate::crypto::session::DEFAULT_REPLAY_WINDOW;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Establish a pair of UDP transports between 2 peers over loopback.
+    async fn udp_pair() -> (UdpTransport, UdpTransport) {
+        udp_pair_with_window(DEFAULT_REPLAY_WINDOW).await
+    }
+
+    /// Establish a pair of UDP transports between 2 peers over loopback, which accept datagrams
+    /// within a replay window of `window` sequence numbers.
+    async fn udp_pair_with_window(window: usize) -> (UdpTransport, UdpTransport) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (a, b) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        let (mut a_con, (mut b_con, _)) = (a.unwrap(), b.unwrap());
+        let (a, b) = (
+            SecretKey::from_bytes([1; 32]),
+            SecretKey::from_bytes([2; 32]),
+        );
+        let (a_secret, b_secret) = (a.to_x25519(), b.to_x25519());
+        let (a, b) = (a.public_key(), b.public_key());
+        let (a_session, b_session) = tokio::join!(
+            Session::establish(&mut a_con, &a_secret, &a, &b),
+            Session::establish(&mut b_con, &b_secret, &b, &a),
+        );
+        let (a, b) = tokio::join!(
+            UdpTransport::negotiate(
+                &mut a_con,
+                a_session.unwrap().with_replay_window(window),
+                1500,
+                Arc::default()
+            ),
+            UdpTransport::negotiate(
+                &mut b_con,
+                b_session.unwrap().with_replay_window(window),
+                1500,
+                Arc::default()
+            ),
+        );
+        (a.unwrap(), b.unwrap())
+    }
+
+    #[tokio::test]
+    async fn udp_transport_roundtrip() {
+        let (mut a, mut b) = udp_pair().await;
+
+        for i in 0..3u8 {
+            let packet = Bytes::from(vec![i; 100 * (i as usize + 1)]);
+            a.send_packet(packet.clone()).await.unwrap();
+            assert_eq!(b.recv_packet().await.unwrap().unwrap(), packet);
+        }
+        let packet = Bytes::from_static(&[7; 10]);
+        b.send_packet(packet.clone()).await.unwrap();
+        assert_eq!(a.recv_packet().await.unwrap().unwrap(), packet);
+
+        let too_large = Bytes::from(vec![0; 1501]);
+        assert!(a.send_packet(too_large).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_transport_drops_forged_and_lost_datagrams() {
+        let (mut a, mut b) = udp_pair().await;
+
+        // Datagrams which are not sealed with the session, or too short to be, are dropped
+        // without affecting the packets after them.
+        a.socket.send(&[0; SEQ_SIZE + 32 + TAG_SIZE]).await.unwrap();
+        a.socket.send(&[0; 4]).await.unwrap();
+        // Packets after a lost datagram are still delivered.
+        a.session.seal_datagram(&mut [0; 8]).unwrap();
+        let packet = Bytes::from_static(&[1; 64]);
+        a.send_packet(packet.clone()).await.unwrap();
+        assert_eq!(b.recv_packet().await.unwrap().unwrap(), packet);
+    }
+
+    #[tokio::test]
+    async fn udp_transport_drops_replayed_datagrams() {
+        let (mut a, mut b) = udp_pair_with_window(64).await;
+
+        let mut sent = Vec::new();
+        for i in 0..100u8 {
+            let mut datagram = vec![0; SEQ_SIZE + 1];
+            datagram[SEQ_SIZE] = i;
+            let (seq, tag) = a.session.seal_datagram(&mut datagram[SEQ_SIZE..]).unwrap();
+            datagram[..SEQ_SIZE].copy_from_slice(&seq.to_be_bytes());
+            datagram.extend_from_slice(&tag);
+            sent.push(datagram);
+        }
+        // In order, then reordered within the window, then replayed and too old.
+        for i in [0, 1, 99, 50, 40, 99, 50, 1, 2] {
+            a.socket.send(&sent[i]).await.unwrap();
+        }
+        let packet = Bytes::from_static(&[7; 8]);
+        a.send_packet(packet.clone()).await.unwrap();
+
+        for i in [0, 1, 99, 50, 40] {
+            assert_eq!(b.recv_packet().await.unwrap().unwrap()[..], [i]);
+        }
+        assert_eq!(b.recv_packet().await.unwrap().unwrap(), packet);
+        assert_eq!(b.replayed.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn peer_data_channel_roundtrip() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (mut a, mut b) = (PeerDataChannel::new(a, 1500), PeerDataChannel::new(b, 1500));
+
+        for i in 0..3u8 {
+            let packet = BytesMut::from(&vec![i; 100 * (i as usize + 1)][..]);
+            a.send(packet.clone()).await.unwrap();
+            assert_eq!(b.next().await.unwrap(), packet);
+        }
+        // Packets which are too large are rejected by the codec, and end the stream.
+        let mut c = PeerDataChannel::new(a.into_inner().into_inner(), 100);
+        b.send(BytesMut::from(&[1; 200][..])).await.unwrap();
+        assert!(c.next().await.is_none());
+        assert_eq!(c.take_error().unwrap().kind(), io::ErrorKind::InvalidData);
+        // Once the peer is gone, the stream ends without an error.
+        drop(c);
+        assert!(b.next().await.is_none());
+        assert!(b.take_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn peer_data_channel_propagates_backpressure() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (mut a, mut b) = (PeerDataChannel::new(a, 1500), PeerDataChannel::new(b, 1500));
+        let packet = BytesMut::from(&[7; 1000][..]);
+
+        // Nobody reads from b, so once the pipe and the write buffer are full, sending blocks.
+        let mut sent = 0;
+        while tokio::time::timeout(Duration::from_millis(50), a.send(packet.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 1000, "sending never blocked");
+        }
+        // Once b reads, sending resumes.
+        let (_, received) = tokio::join!(
+            async {
+                a.send(packet.clone()).await.unwrap();
+                SinkExt::close(&mut a).await.unwrap();
+            },
+            b.by_ref().collect::<Vec<_>>()
+        );
+        assert!(received.len() > sent);
+        assert!(received.iter().all(|p| p == &packet));
+    }
+}
@@ -0,0 +1,728 @@
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+};
+
+use log::warn;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    net::UnixStream,
+};
+use tokio_tun::TunBuilder;
+
+/// Maximum length of an interface name on linux, including the trailing NUL byte.
+const IFNAMSIZ: usize = 16;
+
+/// Ioctl to attach a file descriptor of `/dev/net/tun` to an interface.
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// Default MTU of the TUN interface.
+pub const DEFAULT_MTU: i32 = 1420;
+
+/// Smallest MTU of the TUN interface, which is the minimum MTU of IPv6.
+pub const MIN_MTU: i32 = 1280;
+
+/// Largest MTU of the TUN interface, which is the largest packet which fits in the payload
+/// length of an IPv6 header without jumbograms.
+pub const MAX_MTU: i32 = 65535;
+
+/// What to do if the interface to create already exists, but can't be attached to. This
+/// happens if it is left behind by a process which didn't shut down cleanly, or was created with
+/// other settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingInterface {
+    /// Delete the existing interface, and create a new one.
+    #[default]
+    Recreate,
+    /// Attach to the existing interface. An interface with a single queue is only attached to
+    /// once, regardless of the requested amount of queues. This fails if another process is
+    /// still attached to it.
+    Reuse,
+}
+
+/// A handle to a TUN device.
+///
+/// Unlike [`tokio_tun::Tun`], this handle can be constructed from an existing file descriptor.
+/// This allows a new process to take over the interface from an old one during a restart, in
+/// which case the interface, as well as any address or route assigned to it, is preserved.
+pub struct Tun {
+    /// Name of the interface.
+    name: String,
+    /// The file descriptor of the TUN device.
+    io: AsyncFd<OwnedFd>,
+}
+
+impl Tun {
+    /// Create a new TUN interface with the given name and MTU, and bring it up. The MTU must be
+    /// between [`MIN_MTU`] and [`MAX_MTU`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if not called from within a tokio runtime.
+    pub fn create(name: &str, mtu: i32) -> io::Result<Self> {
+        check_mtu(mtu)?;
+        let tun = TunBuilder::new()
+            .name(name)
+            .tap(false)
+            .mtu(mtu)
+            .packet_info(false)
+            .up()
+            .try_build()
+            .map_err(|e| build_error(name, e))?;
+
+        Self::adopt(&tun)
+    }
+
+    /// Create a new TUN interface with the given name and MTU, with the given amount of queues,
+    /// and bring it up. Every queue has its own file descriptor, so packets can be read and
+    /// written on all of them in parallel. The kernel spreads packets over the queues per flow.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if not called from within a tokio runtime.
+    pub fn create_multi_queue(name: &str, mtu: i32, queues: usize) -> io::Result<Vec<Self>> {
+        check_mtu(mtu)?;
+        if queues == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least 1 queue is required",
+            ));
+        }
+        TunBuilder::new()
+            .name(name)
+            .tap(false)
+            .mtu(mtu)
+            .packet_info(false)
+            .up()
+            .try_build_mq(queues)
+            .map_err(|e| build_error(name, e))?
+            .iter()
+            .map(Self::adopt)
+            .collect()
+    }
+
+    /// Create a TUN interface with the given name, MTU and amount of queues, and bring it up. If
+    /// the interface already exists, but can't be attached to, it is handled as set by
+    /// `existing`. Only TUN interfaces are reused or deleted.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if not called from within a tokio runtime.
+    pub fn open(
+        name: &str,
+        mtu: i32,
+        queues: usize,
+        existing: ExistingInterface,
+    ) -> io::Result<Vec<Self>> {
+        let create = || match queues {
+            1 => Self::create(name, mtu).map(|tun| vec![tun]),
+            _ => Self::create_multi_queue(name, mtu, queues),
+        };
+        let err = match create() {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => e,
+            res => return res,
+        };
+        let flags = match tun_flags(name) {
+            Some(flags) if flags & libc::IFF_TUN != 0 => flags,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "interface {} already exists and is not a TUN interface",
+                        name
+                    ),
+                ))
+            }
+        };
+        match existing {
+            ExistingInterface::Recreate => {
+                warn!("Recreating interface {}: {}", name, err);
+                crate::netlink::delete_interface(name)?;
+                create()
+            }
+            ExistingInterface::Reuse => {
+                warn!("Reusing existing interface {}", name);
+                Self::attach(name, mtu, flags, queues)
+            }
+        }
+    }
+
+    /// Attach to an existing TUN interface which has the given flags, set its MTU, and bring it
+    /// up.
+    fn attach(name: &str, mtu: i32, flags: libc::c_int, queues: usize) -> io::Result<Vec<Self>> {
+        check_mtu(mtu)?;
+        let multi_queue = flags & libc::IFF_MULTI_QUEUE;
+        let queues = if multi_queue != 0 { queues } else { 1 };
+        let req = IfReqFlags {
+            name: ifreq_name(name)?,
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI | multi_queue) as libc::c_short,
+            _pad: [0; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_short>()],
+        };
+        let tuns = (0..queues)
+            .map(|_| {
+                // SAFETY: the path is a valid C string.
+                let fd =
+                    unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: open returned a new, valid, file descriptor which is not owned by
+                // anything else.
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                // SAFETY: req is a valid ifreq for the duration of the call.
+                if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETIFF, &req) } < 0 {
+                    let e = io::Error::last_os_error();
+                    return Err(match e.raw_os_error() {
+                        Some(libc::EBUSY) => io::Error::new(
+                            e.kind(),
+                            format!("interface {} is in use by another process", name),
+                        ),
+                        _ => e,
+                    });
+                }
+                Self::from_fd(name.to_string(), fd)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        tuns[0].set_mtu(mtu)?;
+        tuns[0].update_flags(|flags| flags | libc::IFF_UP as libc::c_short)?;
+        Ok(tuns)
+    }
+
+    /// Take ownership of a duplicate of the file descriptor of the given [`tokio_tun::Tun`].
+    fn adopt(tun: &tokio_tun::Tun) -> io::Result<Self> {
+        // Duplicate the file descriptor so we own it. The interface stays alive as long as at
+        // least 1 file descriptor referring to it is open, so dropping the original handle
+        // afterwards does not remove the interface.
+        // SAFETY: the file descriptor is valid as long as tun is not dropped.
+        let fd = unsafe { libc::dup(tun.as_raw_fd()) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: dup returned a new, valid, file descriptor which is not owned by anything else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        Self::from_fd(tun.name().to_string(), fd)
+    }
+
+    /// Construct a new [`Tun`] from an existing file descriptor referring to a TUN device with
+    /// the given name. The file descriptor is put in non blocking mode.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if not called from within a tokio runtime.
+    pub fn from_fd(name: String, fd: OwnedFd) -> io::Result<Self> {
+        set_nonblocking(fd.as_raw_fd())?;
+        Ok(Self {
+            name,
+            io: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// The name of the interface.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receive a single packet from the TUN interface.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|fd| {
+                // SAFETY: buf is valid for writes of buf.len() bytes.
+                let n = unsafe {
+                    libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len() as _)
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            }) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Send a single packet to the TUN interface. The interface is packet oriented, so `buf` must
+    /// hold exactly 1 complete IP packet. It is written as a whole, a packet which is only
+    /// partially written is reported as an error of kind
+    /// [`WriteZero`](io::ErrorKind::WriteZero).
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|fd| {
+                // SAFETY: buf is valid for reads of buf.len() bytes.
+                let n = unsafe {
+                    libc::write(fd.as_raw_fd(), buf.as_ptr() as *const _, buf.len() as _)
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Writing the remainder later would inject a bogus packet, so this can't be
+                // retried.
+                if (n as usize) < buf.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "packet was only partially written",
+                    ));
+                }
+                Ok(n as usize)
+            }) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Bring the interface down. The interface itself is only removed once all file descriptors
+    /// referring to it are closed, but it can't carry traffic anymore, and the kernel removes
+    /// all routes through it.
+    pub fn set_down(&self) -> io::Result<()> {
+        self.update_flags(|flags| flags & !(libc::IFF_UP as libc::c_short))
+    }
+
+    /// Change the flags of the interface.
+    fn update_flags(&self, f: impl FnOnce(libc::c_short) -> libc::c_short) -> io::Result<()> {
+        let mut req = IfReqFlags {
+            name: ifreq_name(&self.name)?,
+            flags: 0,
+            _pad: [0; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_short>()],
+        };
+        let sock = ioctl_socket()?;
+
+        // SAFETY: req is a valid ifreq for the duration of the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        req.flags = f(req.flags);
+        // SAFETY: req is a valid ifreq for the duration of the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Set the MTU of the interface.
+    fn set_mtu(&self, mtu: i32) -> io::Result<()> {
+        let req = IfReqMtu {
+            name: ifreq_name(&self.name)?,
+            mtu,
+            _pad: [0; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_int>()],
+        };
+        let sock = ioctl_socket()?;
+        // SAFETY: req is a valid ifreq for the duration of the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFMTU, &req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The current MTU of the interface, as configured in the kernel.
+    pub fn mtu(&self) -> io::Result<usize> {
+        let mut req = IfReqMtu {
+            name: ifreq_name(&self.name)?,
+            mtu: 0,
+            _pad: [0; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_int>()],
+        };
+        let sock = ioctl_socket()?;
+        // SAFETY: req is a valid ifreq for the duration of the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFMTU, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(req.mtu as usize)
+    }
+
+    /// Hand off the file descriptor of this TUN device to another process over the given unix
+    /// socket, by sending it as `SCM_RIGHTS` ancillary data. The name of the interface is sent as
+    /// regular data in the same message.
+    ///
+    /// The receiving side should use [`Tun::inherit`]. This handle remains usable after the
+    /// handoff, it is up to the caller to stop using it once the other side took over.
+    pub async fn hand_off(&self, socket: &UnixStream) -> io::Result<()> {
+        let name = self.name.as_bytes();
+        // Name must fit in the buffer of the receiver, including the NUL byte.
+        if name.len() >= IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        let fd = self.io.as_raw_fd();
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || send_fd(socket.as_raw_fd(), fd, name)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    /// Take over a TUN device from another process, which sends it over the given unix socket
+    /// with [`Tun::hand_off`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if not called from within a tokio runtime.
+    pub async fn inherit(socket: &UnixStream) -> io::Result<Self> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || recv_fd(socket.as_raw_fd())) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => {
+                    let (fd, name) = res?;
+                    return Self::from_fd(name, fd);
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+/// Size of the union in a `struct ifreq`.
+const IFREQ_UNION_SIZE: usize = 24;
+
+/// A `struct ifreq`, as used to get and set the flags of an interface.
+#[repr(C)]
+struct IfReqFlags {
+    name: [libc::c_char; IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_short>()],
+}
+
+/// A `struct ifreq`, as used to get and set the MTU of an interface.
+#[repr(C)]
+struct IfReqMtu {
+    name: [libc::c_char; IFNAMSIZ],
+    mtu: libc::c_int,
+    _pad: [u8; IFREQ_UNION_SIZE - std::mem::size_of::<libc::c_int>()],
+}
+
+/// Encode an interface name for use in a `struct ifreq`.
+fn ifreq_name(name: &str) -> io::Result<[libc::c_char; IFNAMSIZ]> {
+    let name = name.as_bytes();
+    if name.len() >= IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    let mut raw = [0; IFNAMSIZ];
+    for (dst, src) in raw.iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(raw)
+}
+
+/// Open a socket to manage interfaces with. Interface settings can be changed through any
+/// socket.
+fn ioctl_socket() -> io::Result<OwnedFd> {
+    // SAFETY: socket does not touch memory.
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: socket returned a new, valid, file descriptor which is not owned by anything else.
+    Ok(unsafe { OwnedFd::from_raw_fd(sock) })
+}
+
+/// Convert an error of [`TunBuilder`], which is an [`Errno`](nix::errno::Errno) if a system
+/// call failed. The kernel refuses to attach to an existing interface if it is in use, or has
+/// other settings. This is reported as [`AlreadyExists`](io::ErrorKind::AlreadyExists), so it
+/// can be told apart from other failures.
+fn build_error(name: &str, e: Box<dyn std::error::Error>) -> io::Error {
+    let e = match e.downcast::<nix::errno::Errno>() {
+        Ok(errno) => io::Error::from(*errno),
+        Err(e) => match e.downcast::<io::Error>() {
+            Ok(e) => *e,
+            Err(e) => return io::Error::other(e.to_string()),
+        },
+    };
+    match e.raw_os_error() {
+        Some(libc::EBUSY | libc::EINVAL | libc::EPERM)
+            if Path::new("/sys/class/net").join(name).exists() =>
+        {
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("interface {} already exists: {}", name, e),
+            )
+        }
+        _ => e,
+    }
+}
+
+/// The TUN flags of the interface with the given name, or [`None`] if it doesn't exist or isn't
+/// a TUN or TAP interface.
+fn tun_flags(name: &str) -> Option<libc::c_int> {
+    let raw =
+        std::fs::read_to_string(Path::new("/sys/class/net").join(name).join("tun_flags")).ok()?;
+    libc::c_int::from_str_radix(raw.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Check that an MTU is between [`MIN_MTU`] and [`MAX_MTU`].
+fn check_mtu(mtu: i32) -> io::Result<()> {
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("MTU must be between {} and {}", MIN_MTU, MAX_MTU),
+        ));
+    }
+    Ok(())
+}
+
+/// Set the O_NONBLOCK flag on a file descriptor.
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl with F_GETFL and F_SETFL does not touch memory.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send a single file descriptor over a unix socket, together with some data.
+fn send_fd(socket: RawFd, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    // Buffer for the control message. Use u64 to make sure the buffer is properly aligned for a
+    // cmsghdr.
+    let mut cmsg_buf = [0u64; 4];
+    // SAFETY: CMSG_SPACE only does arithmetic.
+    let cmsg_space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    debug_assert!(cmsg_space <= std::mem::size_of_val(&cmsg_buf));
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    // SAFETY: msghdr is a plain C struct, for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = cmsg_space as _;
+
+    // SAFETY: msg_control points to a buffer of at least cmsg_space bytes, so the first header
+    // and its data fit in it.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    // SAFETY: msg and all the buffers it references are valid for the duration of the call.
+    let n = unsafe { libc::sendmsg(socket, &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if (n as usize) < data.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write full handoff message",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Receive a single file descriptor and accompanying data over a unix socket. The data is
+/// interpreted as an interface name.
+fn recv_fd(socket: RawFd) -> io::Result<(OwnedFd, String)> {
+    let mut data = [0u8; IFNAMSIZ];
+    let mut cmsg_buf = [0u64; 4];
+    // SAFETY: CMSG_SPACE only does arithmetic.
+    let cmsg_space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    // SAFETY: msghdr is a plain C struct, for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = cmsg_space as _;
+
+    // SAFETY: msg and all the buffers it references are valid for the duration of the call.
+    // MSG_CMSG_CLOEXEC makes sure the received fd is not leaked to child processes.
+    let n = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "handoff socket closed before receiving a file descriptor",
+        ));
+    }
+
+    // SAFETY: the kernel filled in the control buffer, and CMSG_FIRSTHDR checks msg_controllen
+    // before returning a header.
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handoff message does not contain a file descriptor",
+            ));
+        }
+        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd)
+    };
+    // SAFETY: the kernel installed a new file descriptor in our process, which we now own.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let name = std::str::from_utf8(&data[..n as usize])
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid interface name"))?
+        .to_string();
+
+    Ok((fd, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    /// A minimal IPv6 packet without payload, addressed to a link local address.
+    const IPV6_PACKET: [u8; 40] = [
+        0x60, 0, 0, 0, 0, 0, 59, 64, 0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xfe,
+        0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+    ];
+
+    #[tokio::test]
+    async fn can_create_multi_queue_tun() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let queues = match Tun::create_multi_queue("styx-mq", DEFAULT_MTU, 4) {
+            Ok(queues) => queues,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+
+        assert_eq!(queues.len(), 4);
+        for queue in &queues {
+            assert_eq!(queue.name(), "styx-mq");
+            assert_eq!(queue.send(&IPV6_PACKET).await.unwrap(), IPV6_PACKET.len());
+        }
+        let rx_packets =
+            std::fs::read_to_string("/sys/class/net/styx-mq/statistics/rx_packets").unwrap();
+        assert_eq!(rx_packets.trim(), "4");
+    }
+
+    #[tokio::test]
+    async fn recreates_existing_interface() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let _existing = match Tun::create("styx-recreate", DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+
+        // The interface is in use, so it can't be attached to again.
+        let err = Tun::create("styx-recreate", DEFAULT_MTU).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(Tun::open("styx-recreate", DEFAULT_MTU, 1, ExistingInterface::Reuse).is_err());
+
+        let tun = Tun::open("styx-recreate", MIN_MTU, 1, ExistingInterface::Recreate).unwrap();
+        assert_eq!(tun[0].mtu().unwrap(), MIN_MTU as usize);
+        assert_eq!(tun[0].send(&IPV6_PACKET).await.unwrap(), IPV6_PACKET.len());
+    }
+
+    #[tokio::test]
+    async fn reuses_existing_interface() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let _existing = match Tun::create_multi_queue("styx-reuse", DEFAULT_MTU, 2) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+
+        // A single queue can't be attached to an interface with multiple queues.
+        let err = Tun::create("styx-reuse", DEFAULT_MTU).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let tun = Tun::open("styx-reuse", MIN_MTU, 1, ExistingInterface::Reuse).unwrap();
+        assert_eq!(tun.len(), 1);
+        assert_eq!(tun[0].mtu().unwrap(), MIN_MTU as usize);
+        assert_eq!(tun[0].send(&IPV6_PACKET).await.unwrap(), IPV6_PACKET.len());
+    }
+
+    #[tokio::test]
+    async fn can_bring_tun_down() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-down", DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        let flags = || {
+            let raw = std::fs::read_to_string("/sys/class/net/styx-down/flags").unwrap();
+            i32::from_str_radix(raw.trim().trim_start_matches("0x"), 16).unwrap()
+        };
+
+        assert_ne!(flags() & libc::IFF_UP, 0);
+        tun.set_down().unwrap();
+        assert_eq!(flags() & libc::IFF_UP, 0);
+    }
+
+    #[tokio::test]
+    async fn creates_tun_with_configured_mtu() {
+        for mtu in [MIN_MTU - 1, MAX_MTU + 1] {
+            let err = Tun::create("styx-mtu", mtu).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-mtu", 9000) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+        assert_eq!(tun.mtu().unwrap(), 9000);
+    }
+
+    #[tokio::test]
+    async fn can_hand_off_tun() {
+        // Creating a TUN interface requires CAP_NET_ADMIN, skip the test if we don't have it.
+        let tun = match Tun::create("styx-handoff", DEFAULT_MTU) {
+            Ok(tun) => tun,
+            Err(e) => {
+                eprintln!("Skipping test, could not create TUN interface: {}", e);
+                return;
+            }
+        };
+
+        let (old, new) = UnixStream::pair().unwrap();
+        tun.hand_off(&old).await.unwrap();
+        let inherited = Tun::inherit(&new).await.unwrap();
+        // Old handle is not used anymore after the handoff.
+        drop(tun);
+
+        assert_eq!(inherited.name(), "styx-handoff");
+        assert_eq!(
+            inherited.send(&IPV6_PACKET).await.unwrap(),
+            IPV6_PACKET.len()
+        );
+    }
+}